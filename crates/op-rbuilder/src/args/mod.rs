@@ -3,7 +3,7 @@ use crate::{
     metrics::{LONG_VERSION, SHORT_VERSION},
 };
 use clap_builder::{CommandFactory, FromArgMatches};
-pub use op::{FlashblocksArgs, OpRbuilderArgs, TelemetryArgs};
+pub use op::{DexArgs, FlashblocksArgs, OpRbuilderArgs, TelemetryArgs};
 use playground::PlaygroundOptions;
 use reth_optimism_cli::{chainspec::OpChainSpecParser, commands::Commands};
 
@@ -5,7 +5,9 @@
 //! clap [Args](clap::Args) for optimism rollup configuration
 
 use crate::{
-    flashtestations::args::FlashtestationsArgs, gas_limiter::args::GasLimiterArgs,
+    dex::fees::{PairFeesArg, parse_bps},
+    flashtestations::args::FlashtestationsArgs,
+    gas_limiter::args::GasLimiterArgs,
     tx_signer::Signer,
 };
 use alloy_primitives::Address;
@@ -61,6 +63,8 @@ pub struct OpRbuilderArgs {
     #[command(flatten)]
     pub flashblocks: FlashblocksArgs,
     #[command(flatten)]
+    pub dex: DexArgs,
+    #[command(flatten)]
     pub telemetry: TelemetryArgs,
     #[command(flatten)]
     pub flashtestations: FlashtestationsArgs,
@@ -234,6 +238,168 @@ pub struct FlashblocksP2pArgs {
     pub p2p_max_peer_count: u32,
 }
 
+/// Parameters for the enshrined DEX, which is run by the flashblocks builder
+#[derive(Debug, Clone, PartialEq, Eq, clap::Args)]
+pub struct DexArgs {
+    /// Run the flashblocks builder without the enshrined DEX.
+    ///
+    /// Calls to the DEX address are executed like calls to any other account without code, and
+    /// the DEX RPC methods aren't served.
+    #[arg(long = "dex.disabled", env = "DEX_DISABLED", default_value = "false")]
+    pub disabled: bool,
+
+    /// Address the DEX is installed at, instead of the predeploy address
+    /// 0x4200000000000000000000000000000000000042.
+    #[arg(long = "dex.address", env = "DEX_ADDRESS")]
+    pub address: Option<Address>,
+
+    /// JSON or TOML file listing the pairs the DEX starts out with at genesis.
+    ///
+    /// If not set, the pairs are read from the `dex` entry of the `config` section of the chain
+    /// spec's genesis, if there is one.
+    #[arg(long = "dex.genesis-pairs", env = "DEX_GENESIS_PAIRS", value_parser = expand_path)]
+    pub genesis_pairs: Option<PathBuf>,
+
+    /// Only let the DEX admin and the listers create pairs, instead of anyone.
+    #[arg(
+        long = "dex.permissioned-listing",
+        env = "DEX_PERMISSIONED_LISTING",
+        default_value = "false"
+    )]
+    pub permissioned_listing: bool,
+
+    /// Comma-separated list of accounts allowed to create pairs besides the DEX admin, if listing
+    /// is permissioned.
+    ///
+    /// Listers may be contracts, such as an on-chain registry that lists pairs by its own rules.
+    #[arg(long = "dex.listers", env = "DEX_LISTERS", value_delimiter = ',')]
+    pub listers: Vec<Address>,
+
+    /// Don't let pairs of native ETH be created, which the DEX stands for by the zero address.
+    ///
+    /// The tokens of all other pairs have to be contracts.
+    #[arg(
+        long = "dex.no-native-eth",
+        env = "DEX_NO_NATIVE_ETH",
+        default_value = "false"
+    )]
+    pub no_native_eth: bool,
+
+    /// Gas the builder spends per flashblock at most on transactions placing signed orders.
+    ///
+    /// Signed orders are placed by the builder, so this keeps makers from crowding out the
+    /// transactions of the pool with orders they don't pay for.
+    #[arg(
+        long = "dex.signed-order-gas",
+        env = "DEX_SIGNED_ORDER_GAS",
+        default_value = "5000000"
+    )]
+    pub signed_order_gas: u64,
+
+    /// Number of blocks between snapshots of the DEX state written to the datadir.
+    ///
+    /// On startup the DEX state is restored from the newest snapshot on the canonical chain,
+    /// so only the blocks after it have to be replayed. Set to 0 to disable snapshots.
+    #[arg(
+        long = "dex.snapshot-interval",
+        env = "DEX_SNAPSHOT_INTERVAL",
+        default_value = "1000"
+    )]
+    pub snapshot_interval: u64,
+
+    /// Maximum number of blocks replayed to rebuild the DEX state on startup.
+    ///
+    /// If the newest snapshot on the canonical chain is further below the head, or there is no
+    /// snapshot and the head is further from genesis, startup fails instead of replaying the
+    /// chain for an unbounded time. Set to 0 to replay any number of blocks.
+    #[arg(
+        long = "dex.max-replay-blocks",
+        env = "DEX_MAX_REPLAY_BLOCKS",
+        default_value = "100000"
+    )]
+    pub max_replay_blocks: u64,
+
+    /// Clear DEX orders in a batch auction at the end of every flashblock.
+    ///
+    /// Limit orders placed during a flashblock don't trade right away, but are cleared together
+    /// at a single price per pair by a builder transaction at the end of the flashblock. Only the
+    /// builder signer may clear, so nodes following the chain need the same builder signer.
+    #[arg(
+        long = "dex.batch-auction",
+        env = "DEX_BATCH_AUCTION",
+        default_value = "false"
+    )]
+    pub batch_auction: bool,
+
+    /// Fee charged on what resting DEX orders receive when they are filled, in basis points.
+    #[arg(
+        long = "dex.maker-fee-bps",
+        env = "DEX_MAKER_FEE_BPS",
+        default_value = "0",
+        value_parser = parse_bps
+    )]
+    pub maker_fee_bps: u16,
+
+    /// Fee charged on what incoming DEX orders and swaps receive, in basis points.
+    #[arg(
+        long = "dex.taker-fee-bps",
+        env = "DEX_TAKER_FEE_BPS",
+        default_value = "0",
+        value_parser = parse_bps
+    )]
+    pub taker_fee_bps: u16,
+
+    /// Fees of individual DEX pairs, overriding the default maker and taker fees.
+    ///
+    /// Each entry is given as `<token>:<token>:<maker bps>:<taker bps>`, with the tokens of the
+    /// pair in either order.
+    #[arg(long = "dex.pair-fees", env = "DEX_PAIR_FEES", value_delimiter = ',')]
+    pub pair_fees: Vec<PairFeesArg>,
+
+    /// Account DEX fees are paid to.
+    ///
+    /// If not set, fees are paid to the fee recipient of the block they are charged in.
+    #[arg(long = "dex.fee-treasury", env = "DEX_FEE_TREASURY")]
+    pub fee_treasury: Option<Address>,
+
+    /// Maximum distance of DEX fills from the last traded price of their pair, in basis points.
+    ///
+    /// Orders and swaps that would trade further away from it fail. Set to 0 to disable price
+    /// bands.
+    #[arg(
+        long = "dex.price-band-bps",
+        env = "DEX_PRICE_BAND_BPS",
+        default_value = "0"
+    )]
+    pub price_band_bps: u16,
+
+    /// Number of consecutive price band violations within a block after which a DEX pair halts.
+    ///
+    /// Halted pairs don't trade until the DEX admin resumes them. Set to 0 to never halt pairs.
+    #[arg(
+        long = "dex.max-band-violations",
+        env = "DEX_MAX_BAND_VIOLATIONS",
+        default_value = "3"
+    )]
+    pub max_band_violations: u32,
+
+    /// Account allowed to resume halted DEX pairs.
+    ///
+    /// The admin may also create pairs if listing is permissioned, see `--dex.permissioned-listing`.
+    #[arg(long = "dex.admin", env = "DEX_ADMIN")]
+    pub admin: Option<Address>,
+}
+
+impl Default for DexArgs {
+    fn default() -> Self {
+        let args = crate::args::Cli::parse_from(["dummy", "node"]);
+        let Commands::Node(node_command) = args.command else {
+            unreachable!()
+        };
+        node_command.ext.dex
+    }
+}
+
 /// Parameters for telemetry configuration
 #[derive(Debug, Clone, Default, PartialEq, Eq, clap::Args)]
 pub struct TelemetryArgs {
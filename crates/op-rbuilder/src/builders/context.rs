@@ -30,7 +30,7 @@ use reth_optimism_txpool::{
     interop::{MaybeInteropTransaction, is_valid_interop},
 };
 use reth_payload_builder::PayloadId;
-use reth_primitives::SealedHeader;
+use reth_primitives::{Recovered, SealedHeader};
 use reth_primitives_traits::{InMemorySize, SignedTransaction};
 use reth_revm::{State, context::Block};
 use reth_transaction_pool::{BestTransactionsAttributes, PoolTransaction};
@@ -40,6 +40,11 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, trace, warn};
 
 use crate::{
+    builders::flashblocks::dex_integration::{
+        clear_batch_transaction, finish_dex_transaction, halt_pair_transaction, maker_can_fund,
+        signed_order_transaction, sweep_transaction,
+    },
+    dex::{DexHandler, DexPrecompile, signed::SignedOrderPool},
     gas_limiter::AddressGasLimiter,
     metrics::OpRBuilderMetrics,
     primitives::reth::{ExecutionInfo, TxnExecutionResult},
@@ -243,6 +248,14 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
     pub fn chain_id(&self) -> u64 {
         self.chain_spec.chain_id()
     }
+
+    /// Returns the DEX precompile executing on top of the DEX state of the block, if the DEX is
+    /// enabled
+    pub fn dex_precompile(&self) -> Option<DexPrecompile> {
+        self.dex_handler.as_deref().cloned().map(|handler| {
+            DexPrecompile::new(handler, self.evm_config.clone(), self.evm_env.clone())
+        })
+    }
 }
 
 impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
@@ -286,6 +299,10 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
         let mut info = ExecutionInfo::with_capacity(self.attributes().transactions.len());
 
         let mut evm = self.evm_config.evm_with_env(&mut *db, self.evm_env.clone());
+        let dex = self.dex_precompile();
+        if let Some(dex) = &dex {
+            dex.install(evm.precompiles_mut());
+        }
 
         for sequencer_tx in &self.attributes().transactions {
             // A sequencer's block should never contain blob transactions.
@@ -324,7 +341,10 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
                     ))
                 })?;
 
-            let ResultAndState { result, state } = match evm.transact(&sequencer_tx) {
+            if let Some(dex) = &dex {
+                dex.begin();
+            }
+            let result_and_state = match evm.transact(&sequencer_tx) {
                 Ok(res) => res,
                 Err(err) => {
                     if err.is_invalid_tx_err() {
@@ -336,6 +356,16 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
                 }
             };
 
+            let (ResultAndState { result, state }, dex_execution) = match &dex {
+                Some(dex) => finish_dex_transaction(
+                    dex,
+                    &self.evm_env,
+                    sequencer_tx.signer(),
+                    result_and_state,
+                ),
+                None => (result_and_state, None),
+            };
+
             // add gas used by the transaction to cumulative gas used, before creating the receipt
             let gas_used = result.gas_used();
             info.cumulative_gas_used += gas_used;
@@ -358,6 +388,12 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
 
             // commit changes
             evm.db_mut().commit(state);
+            if let (Some(dex_handler), Some(dex_state)) = (
+                &self.dex_handler,
+                dex_execution.and_then(|execution| execution.state),
+            ) {
+                dex_handler.restore(dex_state);
+            }
 
             // append sender and transaction to the respective lists
             info.executed_senders.push(sequencer_tx.signer());
@@ -377,6 +413,294 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
         Ok(info)
     }
 
+    /// Sweeps the DEX orders that expired by the start of flashblock `flashblock_index` off the
+    /// book, with a transaction signed by the builder.
+    ///
+    /// Sweeps can't advance past the flashblock being built, so no transaction can expire orders
+    /// early. Without a builder signer nothing is included, anyone can still sweep though.
+    pub(super) fn execute_dex_sweep<E: Debug + Default>(
+        &self,
+        info: &mut ExecutionInfo<E>,
+        db: &mut State<impl Database>,
+        flashblock_index: u64,
+    ) -> Result<(), PayloadBuilderError> {
+        let Some(dex_handler) = &self.dex_handler else {
+            return Ok(());
+        };
+        let block_number = self.block_number();
+        dex_handler.set_flashblock_limit(block_number, flashblock_index);
+        let expired = dex_handler.expired_order_count(block_number, flashblock_index);
+        let (Some(signer), Some(dex)) = (&self.builder_signer, self.dex_precompile()) else {
+            return Ok(());
+        };
+        if expired == 0 {
+            return Ok(());
+        }
+
+        let nonce = self.builder_nonce(db, signer)?;
+        let sweep_tx = sweep_transaction(
+            signer,
+            dex.address(),
+            self.chain_id(),
+            nonce,
+            self.base_fee(),
+            flashblock_index,
+            expired,
+        )
+        .map_err(|e| PayloadBuilderError::Other(e.into()))?;
+        // The orders are left to the sweep of the next flashblock if the sweep fails
+        self.execute_dex_builder_tx(info, db, dex_handler, &dex, sweep_tx, "sweep")
+    }
+
+    /// Halts the DEX pairs that ran into their price band too often, each with a transaction
+    /// signed by the builder.
+    ///
+    /// Violations of reverted operations are only counted in the DEX state, so pairs halt on-chain
+    /// through these transactions alone. Without a builder signer no pair halts. Pairs whose
+    /// transaction doesn't fit into `gas_limit` are halted in the next flashblock, unless a trade
+    /// ends their run of violations first.
+    pub(super) fn execute_dex_halts<E: Debug + Default>(
+        &self,
+        info: &mut ExecutionInfo<E>,
+        db: &mut State<impl Database>,
+        gas_limit: u64,
+    ) -> Result<(), PayloadBuilderError> {
+        let (Some(dex_handler), Some(signer), Some(dex)) = (
+            &self.dex_handler,
+            &self.builder_signer,
+            self.dex_precompile(),
+        ) else {
+            return Ok(());
+        };
+
+        let state = dex_handler.snapshot();
+        for pair_id in state.pairs_to_halt() {
+            let (Some(pair), Some(dex_gas)) = (state.pair(pair_id), dex_handler.halt_gas(pair_id))
+            else {
+                continue;
+            };
+
+            let nonce = self.builder_nonce(db, signer)?;
+            let halt_tx = halt_pair_transaction(
+                signer,
+                dex.address(),
+                self.chain_id(),
+                nonce,
+                self.base_fee(),
+                (pair.token0, pair.token1),
+                dex_gas,
+            )
+            .map_err(|e| PayloadBuilderError::Other(e.into()))?;
+            if info.cumulative_gas_used + halt_tx.gas_limit() > gas_limit {
+                warn!(
+                    target: "payload_builder",
+                    %pair_id,
+                    "DEX pair halt transaction doesn't fit into the flashblock, skipping."
+                );
+                break;
+            }
+            self.execute_dex_builder_tx(info, db, dex_handler, &dex, halt_tx, "pair halt")?;
+        }
+        Ok(())
+    }
+
+    /// Clears the DEX orders collected during the flashblock in a batch auction, with a
+    /// transaction signed by the builder.
+    ///
+    /// Only does anything in batch auction mode once orders were collected. If the transaction
+    /// doesn't fit into `gas_limit`, the orders wait for the auction of the next flashblock.
+    pub(super) fn execute_dex_batch_auction<E: Debug + Default>(
+        &self,
+        info: &mut ExecutionInfo<E>,
+        db: &mut State<impl Database>,
+        gas_limit: u64,
+    ) -> Result<(), PayloadBuilderError> {
+        let (Some(dex_handler), Some(signer), Some(dex)) = (
+            &self.dex_handler,
+            &self.builder_signer,
+            self.dex_precompile(),
+        ) else {
+            return Ok(());
+        };
+        let Some(dex_gas) = dex_handler.batch_clearing_gas() else {
+            return Ok(());
+        };
+
+        let nonce = self.builder_nonce(db, signer)?;
+        let clear_tx = clear_batch_transaction(
+            signer,
+            dex.address(),
+            self.chain_id(),
+            nonce,
+            self.base_fee(),
+            dex_gas,
+        )
+        .map_err(|e| PayloadBuilderError::Other(e.into()))?;
+        if info.cumulative_gas_used + clear_tx.gas_limit() > gas_limit {
+            warn!(
+                target: "payload_builder",
+                gas_limit = clear_tx.gas_limit(),
+                "DEX batch auction transaction doesn't fit into the flashblock, skipping."
+            );
+            return Ok(());
+        }
+        self.execute_dex_builder_tx(info, db, dex_handler, &dex, clear_tx, "batch auction")
+    }
+
+    /// Places the signed DEX orders waiting in `pool`, each with a transaction signed by the
+    /// builder.
+    ///
+    /// Orders that can't be placed, e.g. because their maker can't fund the escrow on the state
+    /// built so far, are dropped from the pool before the builder spends gas on them. Once an
+    /// order doesn't fit into `gas_limit`, the remaining ones wait for the next flashblock.
+    pub(super) fn execute_dex_signed_orders<E: Debug + Default>(
+        &self,
+        info: &mut ExecutionInfo<E>,
+        db: &mut State<impl Database>,
+        pool: &SignedOrderPool,
+        gas_limit: u64,
+    ) -> Result<(), PayloadBuilderError> {
+        let (Some(dex_handler), Some(signer), Some(dex)) = (
+            &self.dex_handler,
+            &self.builder_signer,
+            self.dex_precompile(),
+        ) else {
+            return Ok(());
+        };
+
+        for pending in pool.pending(&dex_handler.snapshot()) {
+            let (maker, order_nonce) = (pending.order.maker, pending.order.nonce);
+            let Some(dex_gas) =
+                dex_handler.estimate_gas(signer.address, &pending.calldata(), U256::ZERO)
+            else {
+                debug!(
+                    target: "payload_builder",
+                    %maker,
+                    order_nonce,
+                    "Signed DEX order can't be placed, dropping it."
+                );
+                pool.remove(maker, order_nonce);
+                continue;
+            };
+            let funded = {
+                let mut evm = self.evm_config.evm_with_env(&mut *db, self.evm_env.clone());
+                maker_can_fund(&mut evm, dex.address(), &pending.order)
+            }
+            .map_err(|e| PayloadBuilderError::Other(e.into()))?;
+            if !funded {
+                debug!(
+                    target: "payload_builder",
+                    %maker,
+                    order_nonce,
+                    "Maker of signed DEX order can't fund its escrow, dropping it."
+                );
+                pool.remove(maker, order_nonce);
+                continue;
+            }
+
+            let nonce = self.builder_nonce(db, signer)?;
+            let order_tx = signed_order_transaction(
+                signer,
+                dex.address(),
+                self.chain_id(),
+                nonce,
+                self.base_fee(),
+                &pending,
+                dex_gas,
+            )
+            .map_err(|e| PayloadBuilderError::Other(e.into()))?;
+            if info.cumulative_gas_used + order_tx.gas_limit() > gas_limit {
+                break;
+            }
+            self.execute_dex_builder_tx(info, db, dex_handler, &dex, order_tx, "signed order")?;
+            // The nonce is only used up if the order was placed and its escrow settled
+            if dex_handler.nonce(maker) < order_nonce {
+                pool.remove(maker, order_nonce);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the nonce of the builder signer.
+    fn builder_nonce(
+        &self,
+        db: &mut State<impl Database>,
+        signer: &Signer,
+    ) -> Result<u64, PayloadBuilderError> {
+        db.load_cache_account(signer.address)
+            .map(|acc| acc.account_info().unwrap_or_default().nonce)
+            .map_err(|_| {
+                PayloadBuilderError::other(OpPayloadBuilderError::AccountLoadFailed(signer.address))
+            })
+    }
+
+    /// Executes a builder transaction calling the DEX predeploy and commits it along with the DEX
+    /// state it leaves behind.
+    ///
+    /// Transactions that are invalid or revert are skipped with a warning naming the `operation`.
+    fn execute_dex_builder_tx<E: Debug + Default>(
+        &self,
+        info: &mut ExecutionInfo<E>,
+        db: &mut State<impl Database>,
+        dex_handler: &DexHandler,
+        dex: &DexPrecompile,
+        tx: Recovered<OpTransactionSigned>,
+        operation: &str,
+    ) -> Result<(), PayloadBuilderError> {
+        let mut evm = self.evm_config.evm_with_env(&mut *db, self.evm_env.clone());
+        dex.install(evm.precompiles_mut());
+        dex.begin();
+        let result_and_state = match evm.transact(&tx) {
+            Ok(res) => res,
+            Err(err) => {
+                if err.is_invalid_tx_err() {
+                    warn!(
+                        target: "payload_builder",
+                        %err,
+                        operation,
+                        "Error in DEX builder transaction, skipping."
+                    );
+                    return Ok(());
+                }
+                return Err(PayloadBuilderError::EvmExecutionError(Box::new(err)));
+            }
+        };
+        let (ResultAndState { result, state }, dex_execution) =
+            finish_dex_transaction(dex, &self.evm_env, tx.signer(), result_and_state);
+        if !result.is_success() {
+            warn!(
+                target: "payload_builder",
+                ?result,
+                operation,
+                "DEX builder transaction reverted, skipping."
+            );
+            return Ok(());
+        }
+
+        info.cumulative_gas_used += result.gas_used();
+        info.cumulative_da_bytes_used +=
+            op_alloy_flz::tx_estimated_size_fjord_bytes(tx.encoded_2718().as_slice());
+        let ctx = ReceiptBuilderCtx {
+            tx: tx.inner(),
+            evm: &evm,
+            result,
+            state: &state,
+            cumulative_gas_used: info.cumulative_gas_used,
+        };
+        info.receipts.push(self.build_receipt(ctx, None));
+
+        evm.db_mut().commit(state);
+        if let Some(dex_execution) = dex_execution {
+            info.total_fees += dex_execution.fees;
+            if let Some(dex_state) = dex_execution.state {
+                dex_handler.restore(dex_state);
+            }
+        }
+        info.executed_senders.push(tx.signer());
+        info.executed_transactions.push(tx.into_inner());
+        Ok(())
+    }
+
     /// Executes the given best transactions and updates the execution info.
     ///
     /// Returns `Ok(Some(())` if the job was cancelled.
@@ -400,6 +724,10 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
 
         let tx_da_limit = self.da_config.max_da_tx_size();
         let mut evm = self.evm_config.evm_with_env(&mut *db, self.evm_env.clone());
+        let dex = self.dex_precompile();
+        if let Some(dex) = &dex {
+            dex.install(evm.precompiles_mut());
+        }
 
         debug!(
             target: "payload_builder",
@@ -497,44 +825,10 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
 
             let tx_simulation_start_time = Instant::now();
 
-            // Check if this is a DEX transaction - if so, handle it specially
-            if let Some(dex_handler) = &self.dex_handler {
-                use alloy_consensus::Transaction as _;
-                if let Some(to) = tx.to() {
-                    if to == crate::dex::DEX_PREDEPLOY_ADDRESS {
-                        // This is a DEX transaction - intercept and handle it
-                        let sender = tx.signer();
-                        match super::flashblocks::dex_integration::execute_dex_transaction(
-                            dex_handler,
-                            &tx,
-                            sender,
-                            info,
-                        ) {
-                            Ok(()) => {
-                                // DEX transaction executed successfully
-                                // Continue to next transaction
-                                self.metrics
-                                    .tx_simulation_duration
-                                    .record(tx_simulation_start_time.elapsed());
-                                self.metrics.tx_byte_size.record(tx.inner().size() as f64);
-                                continue;
-                            }
-                            Err(e) => {
-                                // DEX transaction failed - it's already been recorded in info
-                                // Continue to next transaction
-                                warn!(target: "payload_builder", error = ?e, "DEX transaction failed");
-                                self.metrics
-                                    .tx_simulation_duration
-                                    .record(tx_simulation_start_time.elapsed());
-                                self.metrics.tx_byte_size.record(tx.inner().size() as f64);
-                                continue;
-                            }
-                        }
-                    }
-                }
+            if let Some(dex) = &dex {
+                dex.begin();
             }
-
-            let ResultAndState { result, state } = match evm.transact(&tx) {
+            let result_and_state = match evm.transact(&tx) {
                 Ok(res) => res,
                 Err(err) => {
                     if let Some(err) = err.as_invalid_tx_err() {
@@ -558,6 +852,14 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
                 }
             };
 
+            // The DEX state is taken along with the transaction
+            let (ResultAndState { result, state }, dex_execution) = match &dex {
+                Some(dex) => {
+                    finish_dex_transaction(dex, &self.evm_env, tx.signer(), result_and_state)
+                }
+                None => (result_and_state, None),
+            };
+
             self.metrics
                 .tx_simulation_duration
                 .record(tx_simulation_start_time.elapsed());
@@ -578,14 +880,23 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
                 continue;
             }
 
+            let is_dex_tx = dex_execution.is_some();
             if result.is_success() {
-                log_txn(TxnExecutionResult::Success);
+                if is_dex_tx {
+                    log_txn(TxnExecutionResult::DexSuccess);
+                    self.metrics.successful_dex_txs.increment(1);
+                } else {
+                    log_txn(TxnExecutionResult::Success);
+                }
                 num_txs_simulated_success += 1;
                 self.metrics.successful_tx_gas_used.record(gas_used as f64);
             } else {
                 num_txs_simulated_fail += 1;
                 reverted_gas_used += gas_used as i32;
                 self.metrics.reverted_tx_gas_used.record(gas_used as f64);
+                if is_dex_tx {
+                    self.metrics.reverted_dex_txs.increment(1);
+                }
                 if is_bundle_tx {
                     num_bundles_reverted += 1;
                 }
@@ -594,6 +905,8 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
                     info!(target: "payload_builder", tx_hash = ?tx.tx_hash(), result = ?result, "skipping reverted transaction");
                     best_txs.mark_invalid(tx.signer(), tx.nonce());
                     continue;
+                } else if is_dex_tx {
+                    log_txn(TxnExecutionResult::DexReverted);
                 } else {
                     log_txn(TxnExecutionResult::Reverted);
                 }
@@ -625,6 +938,18 @@ impl<ExtraCtx: Debug + Default> OpPayloadBuilderCtx<ExtraCtx> {
 
             // commit changes
             evm.db_mut().commit(state);
+            // DEX operations of transactions that were skipped above are dropped with their
+            // state changes, only included ones are applied to the DEX state
+            if let Some(dex_execution) = dex_execution {
+                if let (Some(dex_handler), Some(dex_state)) =
+                    (&self.dex_handler, dex_execution.state)
+                {
+                    dex_handler.restore(dex_state);
+                }
+                // ETH fees paid to the fee recipient add to the value of the block
+                info.total_fees += dex_execution.fees;
+                self.metrics.dex_tx_gas_used.record(gas_used as f64);
+            }
 
             // update add to total fees
             let miner_fee = tx
@@ -1,6 +1,16 @@
 use alloy_primitives::Address;
-
-use crate::{args::OpRbuilderArgs, builders::BuilderConfig};
+use eyre::WrapErr as _;
+
+use crate::{
+    args::OpRbuilderArgs,
+    builders::BuilderConfig,
+    dex::{
+        bands::PriceBands,
+        fees::{FeeSchedule, PairFees},
+        genesis::DexGenesis,
+        listing::ListingPolicy,
+    },
+};
 use core::{
     net::{Ipv4Addr, SocketAddr},
     time::Duration,
@@ -56,6 +66,39 @@ pub struct FlashblocksConfig {
 
     /// Maximum number of peers for the p2p node
     pub p2p_max_peer_count: u32,
+
+    /// Whether the enshrined DEX is run
+    pub dex_enabled: bool,
+
+    /// Address the DEX is installed at, the predeploy address if not set
+    pub dex_address: Option<Address>,
+
+    /// Pairs the DEX starts out with, read from the chain spec's genesis if not set
+    pub dex_genesis: Option<DexGenesis>,
+
+    /// Number of blocks between snapshots of the DEX state, 0 disables snapshots
+    pub dex_snapshot_interval: u64,
+
+    /// Number of blocks replayed at most to rebuild the DEX state, 0 doesn't limit it
+    pub dex_max_replay_blocks: u64,
+
+    /// Whether DEX orders are cleared in a batch auction at the end of every flashblock
+    pub dex_batch_auction: bool,
+
+    /// Fees charged on DEX trades and where they are paid to
+    pub dex_fees: FeeSchedule,
+
+    /// Price bands DEX fills have to lie within
+    pub dex_price_bands: PriceBands,
+
+    /// Account allowed to resume halted DEX pairs, and to create pairs if listing is permissioned
+    pub dex_admin: Option<Address>,
+
+    /// Policy deciding who may create DEX pairs
+    pub dex_listing: ListingPolicy,
+
+    /// Gas spent per flashblock at most on placing signed DEX orders
+    pub dex_signed_order_gas: u64,
 }
 
 impl Default for FlashblocksConfig {
@@ -73,6 +116,17 @@ impl Default for FlashblocksConfig {
             p2p_private_key_file: None,
             p2p_known_peers: None,
             p2p_max_peer_count: 50,
+            dex_enabled: true,
+            dex_address: None,
+            dex_genesis: None,
+            dex_snapshot_interval: 1000,
+            dex_max_replay_blocks: 100_000,
+            dex_batch_auction: false,
+            dex_fees: FeeSchedule::default(),
+            dex_price_bands: PriceBands::default(),
+            dex_admin: None,
+            dex_listing: ListingPolicy::default(),
+            dex_signed_order_gas: 5_000_000,
         }
     }
 }
@@ -100,6 +154,42 @@ impl TryFrom<OpRbuilderArgs> for FlashblocksConfig {
         let flashblocks_number_contract_use_permit =
             args.flashblocks.flashblocks_number_contract_use_permit;
 
+        let dex_fees = FeeSchedule {
+            default: PairFees {
+                maker_bps: args.dex.maker_fee_bps,
+                taker_bps: args.dex.taker_fee_bps,
+            },
+            pairs: args
+                .dex
+                .pair_fees
+                .iter()
+                .map(|pair| ((pair.token_a, pair.token_b), pair.fees))
+                .collect(),
+            treasury: args.dex.fee_treasury,
+        };
+
+        let dex_price_bands = PriceBands {
+            band_bps: args.dex.price_band_bps,
+            max_violations: args.dex.max_band_violations,
+        };
+
+        let dex_listing = ListingPolicy {
+            permissioned: args.dex.permissioned_listing,
+            listers: args.dex.listers.iter().copied().collect(),
+            native_eth: !args.dex.no_native_eth,
+        };
+
+        let dex_genesis = args
+            .dex
+            .genesis_pairs
+            .as_deref()
+            .map(|path| {
+                DexGenesis::from_file(path).wrap_err_with(|| {
+                    format!("failed to read DEX genesis pairs from {}", path.display())
+                })
+            })
+            .transpose()?;
+
         Ok(Self {
             ws_addr,
             interval,
@@ -113,6 +203,17 @@ impl TryFrom<OpRbuilderArgs> for FlashblocksConfig {
             p2p_private_key_file: args.flashblocks.p2p.p2p_private_key_file,
             p2p_known_peers: args.flashblocks.p2p.p2p_known_peers,
             p2p_max_peer_count: args.flashblocks.p2p.p2p_max_peer_count,
+            dex_enabled: !args.dex.disabled,
+            dex_address: args.dex.address,
+            dex_genesis,
+            dex_snapshot_interval: args.dex.snapshot_interval,
+            dex_max_replay_blocks: args.dex.max_replay_blocks,
+            dex_batch_auction: args.dex.batch_auction,
+            dex_fees,
+            dex_price_bands,
+            dex_admin: args.dex.admin,
+            dex_listing,
+            dex_signed_order_gas: args.dex.signed_order_gas,
         })
     }
 }
@@ -1,5 +1,6 @@
 use crate::{
     builders::{BuilderConfig, OpPayloadBuilderCtx, flashblocks::FlashblocksConfig},
+    dex::{DexHandler, DexJournal},
     gas_limiter::{AddressGasLimiter, args::GasLimiterArgs},
     metrics::OpRBuilderMetrics,
     traits::ClientBounds,
@@ -29,8 +30,8 @@ pub(super) struct OpPayloadSyncerCtx {
     max_gas_per_txn: Option<u64>,
     /// The metrics for the builder
     metrics: Arc<OpRBuilderMetrics>,
-    /// Enshrined DEX handler
-    dex_handler: Arc<crate::dex::DexHandler>,
+    /// Journal of DEX states that synced blocks are executed on top of
+    dex_journal: Arc<DexJournal>,
 }
 
 impl OpPayloadSyncerCtx {
@@ -39,7 +40,7 @@ impl OpPayloadSyncerCtx {
         builder_config: BuilderConfig<FlashblocksConfig>,
         evm_config: OpEvmConfig,
         metrics: Arc<OpRBuilderMetrics>,
-        dex_handler: Arc<crate::dex::DexHandler>,
+        dex_journal: Arc<DexJournal>,
     ) -> eyre::Result<Self>
     where
         Client: ClientBounds,
@@ -51,7 +52,7 @@ impl OpPayloadSyncerCtx {
             chain_spec,
             max_gas_per_txn: builder_config.max_gas_per_txn,
             metrics,
-            dex_handler,
+            dex_journal,
         })
     }
 
@@ -63,8 +64,8 @@ impl OpPayloadSyncerCtx {
         self.max_gas_per_txn
     }
 
-    pub(super) fn dex_handler(&self) -> &Arc<crate::dex::DexHandler> {
-        &self.dex_handler
+    pub(super) fn dex_journal(&self) -> &Arc<DexJournal> {
+        &self.dex_journal
     }
 
     pub(super) fn into_op_payload_builder_ctx(
@@ -73,6 +74,7 @@ impl OpPayloadSyncerCtx {
         evm_env: EvmEnv<OpSpecId>,
         block_env_attributes: OpNextBlockEnvAttributes,
         cancel: CancellationToken,
+        dex_handler: Option<DexHandler>,
     ) -> OpPayloadBuilderCtx {
         OpPayloadBuilderCtx {
             evm_config: self.evm_config,
@@ -88,7 +90,7 @@ impl OpPayloadSyncerCtx {
             extra_ctx: (),
             max_gas_per_txn: self.max_gas_per_txn,
             address_gas_limiter: AddressGasLimiter::new(GasLimiterArgs::default()),
-            dex_handler: Some(self.dex_handler),
+            dex_handler: dex_handler.map(Arc::new),
         }
     }
 }
@@ -1,227 +1,273 @@
 /// DEX integration for Flashblocks builder
 ///
 /// This module provides the integration between the Flashblocks builder and the enshrined DEX.
-/// It intercepts transactions sent to the DEX predeploy address and executes them using the
-/// in-memory DEX library.
+/// The DEX is installed as a precompile at the predeploy address, so transactions and contracts
+/// call it like any other contract and the EVM takes care of the nonce, the attached value, gas
+/// fees, the L1 data fee and reverts. The DEX operations settle within the EVM as well, the
+/// builder only adopts the DEX state a transaction leaves behind once it is committed.
+///
+/// The precompile only exists in op-rbuilder, so other execution clients such as op-geth or
+/// op-reth compute different state roots for blocks calling the DEX. Running the DEX is a fork of
+/// the chain: every node following it has to execute blocks with op-rbuilder and the same DEX
+/// configuration.
 use crate::{
-    dex::{DexHandler, DexResult, DEX_PREDEPLOY_ADDRESS},
-    primitives::reth::ExecutionInfo,
+    dex::{
+        DexPrecompile, DexState, SignedOrder,
+        gas::sweep_gas,
+        precompile::DexCalls,
+        predeploy::selectors,
+        settlement::{IERC20, NATIVE_TOKEN},
+        signed::PendingSignedOrder,
+    },
+    tx_signer::Signer,
 };
-use alloy_consensus::Transaction as _;
-use alloy_primitives::{Address, Bytes, Log, U256};
+use alloy_consensus::TxEip1559;
+use alloy_evm::{Evm, EvmEnv};
+use alloy_primitives::{Address, Bytes, TxKind, U256};
+use alloy_sol_types::{SolCall, SolValue};
 use eyre::Result;
-use op_alloy_consensus::OpTxEnvelope;
-use reth_optimism_primitives::OpReceipt;
-use tracing::{debug, warn};
+use op_alloy_consensus::OpTypedTransaction;
+use op_revm::OpSpecId;
+use reth_optimism_primitives::OpTransactionSigned;
+use reth_primitives::Recovered;
+use revm::{Database as _, context::result::ResultAndState};
 
-/// Check if a transaction is targeting the DEX predeploy
-pub(super) fn is_dex_transaction(tx: &OpTxEnvelope) -> bool {
-    match tx.to() {
-        Some(addr) => addr == DEX_PREDEPLOY_ADDRESS,
-        None => false,
-    }
+/// Outcome of a transaction that called the DEX
+#[derive(Debug, Default)]
+pub(crate) struct DexExecution {
+    /// The DEX state to adopt with [`DexHandler::restore`](crate::dex::DexHandler::restore) once
+    /// the transaction is committed, `None` if the transaction left the DEX untouched
+    pub(crate) state: Option<DexState>,
+    /// ETH the DEX paid to the fee recipient of the block in protocol fees, which adds to the
+    /// value of the block
+    pub(crate) fees: U256,
 }
 
-/// Execute a DEX transaction
-///
-/// # Arguments
-/// * `dex_handler` - The DEX handler instance
-/// * `tx` - The transaction to execute
-/// * `sender` - The recovered sender address
-/// * `info` - Execution info to update with results
+/// Build the builder transaction sweeping `expired` orders that expired by the start of
+/// flashblock `flashblock_index` off the book of the DEX at `dex`
 ///
-/// # Returns
-/// * `Ok(())` if the transaction was executed successfully
-/// * `Err(e)` if the transaction failed
-pub(crate) fn execute_dex_transaction<Extra: std::fmt::Debug + Default>(
-    dex_handler: &DexHandler,
-    tx: &OpTxEnvelope,
-    sender: Address,
-    info: &mut ExecutionInfo<Extra>,
-) -> Result<()> {
-    debug!(
-        target: "dex",
-        tx_hash = ?tx.tx_hash(),
-        sender = ?sender,
-        "Executing DEX transaction"
-    );
-
-    let calldata: Bytes = tx.input().clone();
-    let value: U256 = tx.value();
-
-    // Execute the DEX operation
-    let result = match dex_handler.handle_transaction(sender, &calldata, value) {
-        Ok(result) => result,
-        Err(e) => {
-            warn!(
-                target: "dex",
-                error = ?e,
-                tx_hash = ?tx.tx_hash(),
-                "DEX transaction failed"
-            );
-            // Create a failed receipt
-            let receipt = create_failed_dex_receipt(info.cumulative_gas_used, 21000);
-            info.receipts.push(receipt);
-            info.cumulative_gas_used += 21000; // Charge base gas for failed tx
-            info.executed_senders.push(sender);
-            info.executed_transactions.push(tx.clone());
-            return Ok(()); // Don't propagate error - just mark as reverted
-        }
-    };
-
-    // Calculate gas used for DEX operation
-    // DEX operations use a fixed amount of gas based on the operation type
-    let gas_used = estimate_dex_gas(&result);
-    info.cumulative_gas_used += gas_used;
-
-    // Create logs for the DEX operation
-    let logs = create_dex_logs(&result, tx.tx_hash());
-
-    // Create receipt
-    let receipt = create_dex_receipt(info.cumulative_gas_used, gas_used, logs, &result);
-    info.receipts.push(receipt);
-
-    // Track transaction execution
-    info.executed_senders.push(sender);
-    info.executed_transactions.push(tx.clone());
+/// The transaction pays the base fee only and its gas limit covers sweeping every one of the
+/// orders.
+pub(crate) fn sweep_transaction(
+    signer: &Signer,
+    dex: Address,
+    chain_id: u64,
+    nonce: u64,
+    base_fee: u64,
+    flashblock_index: u64,
+    expired: usize,
+) -> Result<Recovered<OpTransactionSigned>, secp256k1::Error> {
+    let input = [
+        selectors::SWEEP_EXPIRED_ORDERS.as_slice(),
+        &flashblock_index.abi_encode(),
+    ]
+    .concat()
+    .into();
+    dex_transaction(
+        signer,
+        dex,
+        chain_id,
+        nonce,
+        base_fee,
+        input,
+        sweep_gas(expired),
+    )
+}
 
-    debug!(
-        target: "dex",
-        tx_hash = ?tx.tx_hash(),
-        gas_used,
-        "DEX transaction executed successfully"
-    );
+/// Build the builder transaction clearing the orders collected during a flashblock in a batch
+/// auction, which the DEX at `dex` charges `dex_gas` for
+///
+/// The transaction pays the base fee only.
+pub(crate) fn clear_batch_transaction(
+    signer: &Signer,
+    dex: Address,
+    chain_id: u64,
+    nonce: u64,
+    base_fee: u64,
+    dex_gas: u64,
+) -> Result<Recovered<OpTransactionSigned>, secp256k1::Error> {
+    let input = selectors::CLEAR_BATCH.to_vec().into();
+    dex_transaction(signer, dex, chain_id, nonce, base_fee, input, dex_gas)
+}
 
-    Ok(())
+/// Build the builder transaction halting the pair with id `pair_id`, which ran into its price
+/// band too often, on the DEX at `dex`, which charges `dex_gas` for it
+///
+/// The transaction pays the base fee only.
+pub(crate) fn halt_pair_transaction(
+    signer: &Signer,
+    dex: Address,
+    chain_id: u64,
+    nonce: u64,
+    base_fee: u64,
+    pair: (Address, Address),
+    dex_gas: u64,
+) -> Result<Recovered<OpTransactionSigned>, secp256k1::Error> {
+    let input = [selectors::HALT_PAIR.as_slice(), &pair.abi_encode()]
+        .concat()
+        .into();
+    dex_transaction(signer, dex, chain_id, nonce, base_fee, input, dex_gas)
 }
 
-/// Estimate gas used for a DEX operation
-fn estimate_dex_gas(result: &DexResult) -> u64 {
-    match result {
-        DexResult::PairCreated { .. } => 100_000,
-        DexResult::OrderPlaced { .. } => 150_000,
-        DexResult::OrderCancelled { .. } => 50_000,
-        DexResult::SwapExecuted { .. } => 200_000,
-        DexResult::Quote { .. } => 0, // View function, no gas
-    }
+/// Build the builder transaction placing a signed order on behalf of its maker, which the DEX at
+/// `dex` charges `dex_gas` for
+///
+/// The transaction pays the base fee only. The escrow of the order is pulled from the maker, not
+/// the builder.
+pub(crate) fn signed_order_transaction(
+    signer: &Signer,
+    dex: Address,
+    chain_id: u64,
+    nonce: u64,
+    base_fee: u64,
+    order: &PendingSignedOrder,
+    dex_gas: u64,
+) -> Result<Recovered<OpTransactionSigned>, secp256k1::Error> {
+    dex_transaction(
+        signer,
+        dex,
+        chain_id,
+        nonce,
+        base_fee,
+        order.calldata(),
+        dex_gas,
+    )
 }
 
-/// Create logs for a DEX operation
-fn create_dex_logs(result: &DexResult, _tx_hash: alloy_primitives::B256) -> Vec<Log> {
-    // For now, we'll create simple logs
-    // In production, you'd want to emit proper events with indexed topics
-    let mut logs = Vec::new();
+/// Whether the maker of `order` holds the escrow of the order on the state `evm` executes on and,
+/// for ERC-20 tokens, allows the DEX at `dex` to pull it
+///
+/// The builder only places signed orders whose maker passes this check, so it doesn't spend gas
+/// on orders that are bound to revert.
+pub(crate) fn maker_can_fund<E: Evm>(
+    evm: &mut E,
+    dex: Address,
+    order: &SignedOrder,
+) -> Result<bool> {
+    let Some((token, escrow)) = order.escrow() else {
+        return Ok(false);
+    };
+    if token == NATIVE_TOKEN {
+        let balance = evm
+            .db_mut()
+            .basic(order.maker)
+            .map_err(|err| eyre::eyre!("failed to load maker account: {err}"))?
+            .map(|account| account.balance)
+            .unwrap_or_default();
+        return Ok(balance >= escrow);
+    }
 
-    match result {
-        DexResult::PairCreated {
-            token0,
-            token1,
-            pair_id,
-        } => {
-            // PairCreated(address indexed token0, address indexed token1, bytes32 indexed pairId)
-            logs.push(Log {
-                address: DEX_PREDEPLOY_ADDRESS,
-                data: alloy_primitives::LogData::new_unchecked(
-                    vec![
-                        alloy_primitives::keccak256("PairCreated(address,address,bytes32)"),
-                        alloy_primitives::B256::left_padding_from(token0.as_slice()),
-                        alloy_primitives::B256::left_padding_from(token1.as_slice()),
-                        *pair_id,
-                    ],
-                    Bytes::new(),
-                ),
-            });
-        }
-        DexResult::OrderPlaced {
-            order_id,
-            trader,
-            token_in,
-            token_out: _,
-            amount,
-        } => {
-            // OrderPlaced event
-            logs.push(Log {
-                address: DEX_PREDEPLOY_ADDRESS,
-                data: alloy_primitives::LogData::new_unchecked(
-                    vec![
-                        alloy_primitives::keccak256("LimitOrderPlaced(bytes32,address,address,address,bool,uint256,uint256,uint256)"),
-                        *order_id,
-                        alloy_primitives::B256::left_padding_from(trader.as_slice()),
-                        alloy_primitives::B256::left_padding_from(token_in.as_slice()),
-                    ],
-                    amount.abi_encode().into(),
-                ),
-            });
-        }
-        DexResult::OrderCancelled { order_id } => {
-            logs.push(Log {
-                address: DEX_PREDEPLOY_ADDRESS,
-                data: alloy_primitives::LogData::new_unchecked(
-                    vec![
-                        alloy_primitives::keccak256("OrderCancelled(bytes32,address)"),
-                        *order_id,
-                    ],
-                    Bytes::new(),
-                ),
-            });
-        }
-        DexResult::SwapExecuted {
-            trader,
-            token_in,
-            token_out,
-            amount_in,
-            amount_out,
-        } => {
-            // Swap event
-            let data = (*amount_in, *amount_out).abi_encode();
-            logs.push(Log {
-                address: DEX_PREDEPLOY_ADDRESS,
-                data: alloy_primitives::LogData::new_unchecked(
-                    vec![
-                        alloy_primitives::keccak256(
-                            "Swap(address,address,address,uint256,uint256,bytes32[])",
-                        ),
-                        alloy_primitives::B256::left_padding_from(trader.as_slice()),
-                        alloy_primitives::B256::left_padding_from(token_in.as_slice()),
-                        alloy_primitives::B256::left_padding_from(token_out.as_slice()),
-                    ],
-                    data.into(),
-                ),
-            });
+    let mut view = |input: Vec<u8>| -> Result<U256> {
+        let result = evm
+            .transact_system_call(order.maker, token, input.into())
+            .map_err(|err| eyre::eyre!("failed to call token {token}: {err}"))?
+            .result;
+        Ok(result
+            .output()
+            .filter(|_| result.is_success())
+            .and_then(|output| U256::abi_decode(output).ok())
+            .unwrap_or_default())
+    };
+    let balance = view(
+        IERC20::balanceOfCall {
+            account: order.maker,
         }
-        DexResult::Quote { .. } => {
-            // View function, no logs
+        .abi_encode(),
+    )?;
+    let allowance = view(
+        IERC20::allowanceCall {
+            owner: order.maker,
+            spender: dex,
         }
-    }
+        .abi_encode(),
+    )?;
+    Ok(balance >= escrow && allowance >= escrow)
+}
 
-    logs
+/// Build a builder transaction calling the DEX at `dex` with `input`, with a gas limit covering
+/// the intrinsic gas and `dex_gas` for the DEX operation
+fn dex_transaction(
+    signer: &Signer,
+    dex: Address,
+    chain_id: u64,
+    nonce: u64,
+    base_fee: u64,
+    input: Bytes,
+    dex_gas: u64,
+) -> Result<Recovered<OpTransactionSigned>, secp256k1::Error> {
+    let calldata_gas = input
+        .iter()
+        .map(|byte| if *byte == 0 { 4 } else { 16 })
+        .sum::<u64>();
+    let tx = OpTypedTransaction::Eip1559(TxEip1559 {
+        chain_id,
+        nonce,
+        gas_limit: 21_000 + calldata_gas + dex_gas,
+        max_fee_per_gas: base_fee.into(),
+        max_priority_fee_per_gas: 0,
+        to: TxKind::Call(dex),
+        input,
+        ..Default::default()
+    });
+    signer.sign_tx(tx)
 }
 
-/// Create a receipt for a successful DEX operation
-fn create_dex_receipt(
-    cumulative_gas_used: u64,
-    _gas_used: u64,
-    logs: Vec<Log>,
-    _result: &DexResult,
-) -> OpReceipt {
-    use alloy_consensus::{Eip658Value, Receipt};
+/// Adopt the DEX operations of a transaction executed with the [`DexPrecompile`] installed
+///
+/// The operations settled their token transfers and wrote the predeploy storage within the EVM,
+/// so the outcome of the transaction already includes them, and the precompile rolled back the
+/// operations the EVM reverted. What is left is the DEX state the transaction leaves behind.
+///
+/// Price band violations are the only thing the DEX keeps of operations that were reverted, so
+/// its circuit breakers trip no matter whether the attempts running into a band were reverted.
+/// They are counted once per sender and pair in the DEX state only, which leaves the state
+/// changes of the transaction as they are. Pairs due to halt are halted by the builder with a
+/// transaction of its own, see [`halt_pair_transaction`].
+///
+/// # Arguments
+/// * `dex` - The DEX precompile the transaction was executed with
+/// * `evm_env` - The EVM environment of the block
+/// * `sender` - The sender of the transaction
+/// * `outcome` - The result and state changes of executing the transaction
+///
+/// # Returns
+/// The outcome of the transaction including the DEX operations, and `None` instead of the DEX
+/// execution if the DEX wasn't called
+pub(crate) fn finish_dex_transaction<H>(
+    dex: &DexPrecompile,
+    evm_env: &EvmEnv<OpSpecId>,
+    sender: Address,
+    outcome: ResultAndState<H>,
+) -> (ResultAndState<H>, Option<DexExecution>) {
+    let ResultAndState { result, state } = outcome;
+    let Some(DexCalls {
+        handler,
+        calls,
+        band_violations,
+    }) = dex.finish(&state)
+    else {
+        return (ResultAndState { result, state }, None);
+    };
+    if calls.is_empty() && band_violations.is_empty() {
+        let result_and_state = ResultAndState { result, state };
+        return (result_and_state, Some(DexExecution::default()));
+    }
 
-    OpReceipt::Eip1559(Receipt {
-        status: Eip658Value::Eip658(true),
-        cumulative_gas_used,
-        logs,
-    })
-}
+    // ERC-20 fees are paid to the fee recipient as well, but don't add to the block value
+    let fees = calls
+        .iter()
+        .flat_map(|call| &call.fees)
+        .filter(|fee| fee.is_native() && fee.to == evm_env.block_env.beneficiary)
+        .fold(U256::ZERO, |acc, fee| acc.saturating_add(fee.amount));
 
-/// Create a receipt for a failed DEX operation
-fn create_failed_dex_receipt(cumulative_gas_used: u64, gas_used: u64) -> OpReceipt {
-    use alloy_consensus::{Eip658Value, Receipt};
+    for pair_id in &band_violations {
+        handler.record_band_violation(sender, *pair_id);
+    }
 
-    OpReceipt::Eip1559(Receipt {
-        status: Eip658Value::Eip658(false),
-        cumulative_gas_used: cumulative_gas_used + gas_used,
-        logs: vec![],
-    })
+    let result_and_state = ResultAndState { result, state };
+    let execution = DexExecution {
+        state: Some(handler.snapshot()),
+        fees,
+    };
+    (result_and_state, Some(execution))
 }
-
-use alloy_sol_types::SolValue;
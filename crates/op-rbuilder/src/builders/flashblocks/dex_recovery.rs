@@ -0,0 +1,346 @@
+/// Recovery of the DEX state from the chain
+///
+/// The DEX order book only lives in memory, so it is lost whenever the builder restarts while the
+/// chain still contains the transactions that created it. On startup the DEX state is rebuilt by
+/// executing the blocks again on top of their parent state, the same way received flashblocks are
+/// replayed, before the payload service accepts any payload jobs. Every block is replayed, as
+/// calls that leave no event behind, such as reverted operations running into a price band, still
+/// change the DEX state. If snapshots are enabled, only the blocks after the newest snapshot on
+/// the canonical chain are replayed. Without a snapshot, the DEX starts out with its genesis
+/// pairs. Canonical blocks that were neither built nor synced while the builder is running are
+/// replayed the same way by the task following the canonical chain, off the path of payload jobs.
+use super::{
+    payload::FlashblocksExecutionInfo,
+    payload_handler::{execute_transactions, is_canyon_active, is_regolith_active},
+};
+use crate::{
+    dex::{DexHandler, DexJournal, DexState, genesis::genesis_state, snapshot::DexSnapshots},
+    primitives::reth::ExecutionInfo,
+    traits::ClientBounds,
+};
+use alloy_consensus::TxReceipt;
+use alloy_primitives::{Address, Log};
+use eyre::{WrapErr as _, bail};
+use futures_util::{Stream, StreamExt};
+use reth::revm::{State, database::StateProviderDatabase};
+use reth_evm::{ConfigureEvm, execute::BlockBuilder};
+use reth_node_api::NodePrimitives;
+use reth_optimism_evm::{OpEvmConfig, OpNextBlockEnvAttributes};
+use reth_optimism_primitives::OpReceipt;
+use reth_provider::{
+    BlockHashReader, BlockNumReader, BlockReader, CanonStateNotification, ChainSpecProvider,
+    HeaderProvider, ReceiptProvider, StateProviderFactory,
+};
+use std::{sync::Arc, time::Instant};
+use tracing::{debug, info, warn};
+
+/// Number of blocks between progress reports while rebuilding the DEX state
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Rebuild the DEX state of the canonical head and make it the canonical state of the journal.
+///
+/// The state is rebuilt from the newest usable snapshot, or from the genesis pairs of the journal
+/// if there is none. Every block after it is executed again with the DEX precompile installed and
+/// the DEX events it produces are checked against its receipts. If blocks had to be replayed, a
+/// snapshot of the head is written so the next restart doesn't have to replay them again. If the
+/// journal limits the number of blocks to replay, startup fails fast rather than replaying more.
+///
+/// # Returns
+/// * `Ok(())` once the journal holds the DEX state of the canonical head
+/// * `Err(e)` if more blocks would have to be replayed than the journal allows, if a block or its
+///   parent state is unavailable, or if executing a block again didn't reproduce its DEX events
+pub(crate) fn rebuild_dex_state<Client: ClientBounds>(
+    client: &Client,
+    evm_config: &OpEvmConfig,
+    journal: &DexJournal,
+) -> eyre::Result<()> {
+    let start = Instant::now();
+    let head = client
+        .best_block_number()
+        .wrap_err("failed to get canonical head")?;
+    let head_hash = client
+        .block_hash(head)
+        .wrap_err("failed to get canonical head hash")?
+        .ok_or_else(|| eyre::eyre!("canonical head hash not found"))?;
+
+    let snapshot = match journal.snapshots() {
+        Some(snapshots) => latest_snapshot(client, snapshots, head)?,
+        None => None,
+    };
+    let start_block = snapshot.as_ref().map_or(0, |(number, _)| *number);
+    if let Some(max_replay_blocks) = journal.max_replay_blocks()
+        && head - start_block > max_replay_blocks
+    {
+        bail!(
+            "rebuilding the DEX state would replay {} blocks since block {start_block}, more than \
+             the limit of {max_replay_blocks}: provide a snapshot of a recent block or raise the \
+             limit",
+            head - start_block
+        );
+    }
+    let mut state = match snapshot {
+        Some((_, state)) => state,
+        None => genesis_state(journal.genesis_pairs())
+            .map_err(|err| eyre::eyre!("failed to create DEX genesis pairs: {err}"))?,
+    };
+    state.set_batch_auction(journal.batch_auction());
+    state.set_fee_schedule(journal.fee_schedule());
+    state.set_chain_id(journal.chain_id());
+    state.set_price_bands(journal.price_bands());
+    state.set_admin(journal.admin());
+    state.set_builder(journal.builder());
+    state.set_listing_policy(journal.listing_policy());
+    state.set_address(journal.address());
+    info!(target: "dex", start_block, head, "Rebuilding DEX state from chain history");
+
+    let handler = DexHandler::from_state(state);
+    let replayed_blocks = replay_blocks(client, evm_config, &handler, start_block + 1, head)?;
+
+    let state = handler.snapshot();
+    if let Some(snapshots) = journal.snapshots()
+        && replayed_blocks > 0
+        && let Err(err) = snapshots.write(head_hash, head, &state)
+    {
+        warn!(target: "dex", error = %err, "Failed to write DEX snapshot of canonical head");
+    }
+    journal.reset_canonical(head_hash, head, state);
+    info!(
+        target: "dex",
+        head,
+        ?head_hash,
+        replayed_blocks,
+        elapsed = ?start.elapsed(),
+        "Rebuilt DEX state"
+    );
+    Ok(())
+}
+
+/// Follow the canonical chain with the DEX state of `journal`.
+///
+/// Every notification commits the DEX state of the new canonical tip. If the tip's DEX state is
+/// unknown, the blocks since the last known one are replayed on a blocking thread, so payload jobs
+/// never wait for a replay. They build the fallback block only until the replay caught up.
+pub(crate) async fn follow_canonical_state<Client, N>(
+    client: Client,
+    evm_config: OpEvmConfig,
+    journal: Arc<DexJournal>,
+    mut notifications: impl Stream<Item = CanonStateNotification<N>> + Unpin,
+) where
+    Client: ClientBounds + Send + Sync + 'static,
+    N: NodePrimitives,
+{
+    while let Some(notification) = notifications.next().await {
+        journal.on_canonical_state(&notification);
+        if journal.unknown_tip().is_none() {
+            continue;
+        }
+
+        let (client, evm_config, journal) = (client.clone(), evm_config.clone(), journal.clone());
+        let catch_up =
+            tokio::task::spawn_blocking(move || catch_up_dex_state(&client, &evm_config, &journal))
+                .await;
+        match catch_up {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!(target: "dex", error = %err, "Failed to catch up with DEX state"),
+            Err(err) => warn!(target: "dex", error = %err, "DEX catch-up task failed"),
+        }
+    }
+}
+
+/// Replay the canonical blocks after the newest one whose DEX state is known up to the canonical
+/// tip, whose DEX state is unknown, and commit the DEX state of the tip.
+///
+/// The journal leaves the DEX state of canonical blocks that were neither built nor synced by
+/// this node unknown, and nothing but fallback blocks is built on top of them until this caught
+/// up. If the last known block has been reorged out in the meantime, the whole DEX state is
+/// rebuilt instead.
+fn catch_up_dex_state<Client: ClientBounds>(
+    client: &Client,
+    evm_config: &OpEvmConfig,
+    journal: &DexJournal,
+) -> eyre::Result<()> {
+    let (Some((tip_hash, tip)), Some((known_hash, known, state))) =
+        (journal.unknown_tip(), journal.lagging_canonical_state())
+    else {
+        return Ok(());
+    };
+    let start = Instant::now();
+    let canonical = client
+        .block_hash(known)
+        .wrap_err_with(|| format!("failed to get hash of block {known}"))?;
+    if canonical != Some(known_hash) {
+        warn!(
+            target: "dex",
+            known,
+            ?known_hash,
+            "Last known DEX state was reorged out, rebuilding DEX state"
+        );
+        return rebuild_dex_state(client, evm_config, journal);
+    }
+
+    let handler = DexHandler::from_state(state);
+    let replayed_blocks = replay_blocks(client, evm_config, &handler, known + 1, tip)?;
+    let canonical = client
+        .block_hash(tip)
+        .wrap_err_with(|| format!("failed to get hash of block {tip}"))?;
+    if canonical != Some(tip_hash) {
+        bail!("canonical tip {tip_hash} was reorged out while replaying it");
+    }
+
+    journal.resolve_unknown_tip(tip_hash, tip, handler.snapshot());
+    info!(
+        target: "dex",
+        known,
+        tip,
+        ?tip_hash,
+        replayed_blocks,
+        elapsed = ?start.elapsed(),
+        "Caught up with canonical DEX state"
+    );
+    Ok(())
+}
+
+/// Replay the blocks from `first` up to `last` on top of `handler`.
+///
+/// Returns the number of blocks that were replayed.
+fn replay_blocks<Client: ClientBounds>(
+    client: &Client,
+    evm_config: &OpEvmConfig,
+    handler: &DexHandler,
+    first: u64,
+    last: u64,
+) -> eyre::Result<u64> {
+    let mut replayed_blocks = 0u64;
+    for number in first..=last {
+        if number % PROGRESS_INTERVAL == 0 {
+            info!(target: "dex", number, last, replayed_blocks, "Replaying DEX operations");
+        }
+
+        let receipts = client
+            .receipts_by_block(number.into())
+            .wrap_err_with(|| format!("failed to get receipts of block {number}"))?
+            .ok_or_else(|| eyre::eyre!("receipts of block {number} not found"))?;
+        replay_block(
+            client,
+            evm_config,
+            number,
+            handler,
+            &dex_logs(&receipts, handler.address()),
+        )?;
+        replayed_blocks += 1;
+    }
+    Ok(replayed_blocks)
+}
+
+/// Load the newest snapshot of a block on the canonical chain up to `head`.
+///
+/// Snapshots of blocks that were reorged out and snapshots that fail to load, for example because
+/// they are corrupted, are skipped.
+fn latest_snapshot<Client: ClientBounds>(
+    client: &Client,
+    snapshots: &DexSnapshots,
+    head: u64,
+) -> eyre::Result<Option<(u64, DexState)>> {
+    let available = snapshots
+        .available()
+        .wrap_err("failed to list DEX snapshots")?;
+    for (number, hash) in available {
+        if number > head {
+            continue;
+        }
+        let canonical = client
+            .block_hash(number)
+            .wrap_err_with(|| format!("failed to get hash of block {number}"))?;
+        if canonical != Some(hash) {
+            debug!(target: "dex", number, ?hash, "Skipping DEX snapshot of non-canonical block");
+            continue;
+        }
+
+        match snapshots.read(hash, number) {
+            Ok(state) => {
+                info!(target: "dex", number, ?hash, "Loaded DEX snapshot");
+                return Ok(Some((number, state)));
+            }
+            Err(err) => {
+                warn!(target: "dex", number, ?hash, error = %err, "Skipping unusable DEX snapshot");
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Execute a block again on top of its parent state, applying its DEX operations to `handler`.
+fn replay_block<Client: ClientBounds>(
+    client: &Client,
+    evm_config: &OpEvmConfig,
+    number: u64,
+    handler: &DexHandler,
+    expected: &[&Log],
+) -> eyre::Result<()> {
+    let block = client
+        .block_by_number(number)
+        .wrap_err_with(|| format!("failed to get block {number}"))?
+        .ok_or_else(|| eyre::eyre!("block {number} not found"))?;
+    let parent = client
+        .sealed_header(number - 1)
+        .wrap_err_with(|| format!("failed to get parent of block {number}"))?
+        .ok_or_else(|| eyre::eyre!("parent of block {number} not found"))?;
+
+    let state_provider = client
+        .state_by_block_hash(parent.hash())
+        .wrap_err_with(|| format!("failed to get parent state of block {number}"))?;
+    let mut state = State::builder()
+        .with_database(StateProviderDatabase::new(&state_provider))
+        .with_bundle_update()
+        .build();
+
+    let header = &block.header;
+    let evm_env = evm_config
+        .evm_env(header)
+        .wrap_err("failed to create evm env")?;
+    let block_env_attributes = OpNextBlockEnvAttributes {
+        timestamp: header.timestamp,
+        suggested_fee_recipient: header.beneficiary,
+        prev_randao: header.mix_hash,
+        gas_limit: header.gas_limit,
+        parent_beacon_block_root: header.parent_beacon_block_root,
+        extra_data: header.extra_data.clone(),
+    };
+    evm_config
+        .builder_for_next_block(&mut state, &Arc::new(parent), block_env_attributes)
+        .wrap_err("failed to create evm builder for block")?
+        .apply_pre_execution_changes()
+        .wrap_err("failed to apply pre execution changes")?;
+
+    let chain_spec = client.chain_spec();
+    let mut info =
+        ExecutionInfo::<FlashblocksExecutionInfo>::with_capacity(block.body.transactions.len());
+    execute_transactions(
+        &mut info,
+        &mut state,
+        block.body.transactions,
+        header.gas_limit,
+        evm_config,
+        evm_env,
+        None,
+        is_canyon_active(&chain_spec, header.timestamp),
+        is_regolith_active(&chain_spec, header.timestamp),
+        Some(handler),
+    )
+    .wrap_err_with(|| format!("failed to execute block {number}"))?;
+
+    if dex_logs(&info.receipts, handler.address()) != expected {
+        bail!("executing block {number} again didn't reproduce its DEX events");
+    }
+    debug!(target: "dex", number, events = expected.len(), "Replayed DEX operations of block");
+    Ok(())
+}
+
+/// The events emitted by the DEX at `dex` in the given receipts
+fn dex_logs(receipts: &[OpReceipt], dex: Address) -> Vec<&Log> {
+    receipts
+        .iter()
+        .flat_map(|receipt| receipt.logs())
+        .filter(|log| log.address == dex)
+        .collect()
+}
@@ -8,6 +8,7 @@ mod builder_tx;
 mod config;
 mod ctx;
 pub(super) mod dex_integration;
+pub(crate) mod dex_recovery;
 mod p2p;
 mod payload;
 mod payload_handler;
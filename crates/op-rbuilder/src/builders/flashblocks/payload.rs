@@ -7,6 +7,7 @@ use crate::{
         flashblocks::{best_txs::BestFlashblocksTxs, config::FlashBlocksConfigExt},
         generator::{BlockCell, BuildArguments, PayloadBuilder},
     },
+    dex::{DexHandler, DexJournal, DexState},
     gas_limiter::AddressGasLimiter,
     metrics::OpRBuilderMetrics,
     primitives::reth::ExecutionInfo,
@@ -177,8 +178,8 @@ pub(super) struct OpPayloadBuilder<Pool, Client, BuilderTx> {
     pub builder_tx: BuilderTx,
     /// Rate limiting based on gas. This is an optional feature.
     pub address_gas_limiter: AddressGasLimiter,
-    /// Enshrined DEX handler
-    pub dex_handler: Arc<crate::dex::DexHandler>,
+    /// Journal of the enshrined DEX states built on by payload jobs
+    pub dex_journal: Arc<DexJournal>,
 }
 
 impl<Pool, Client, BuilderTx> OpPayloadBuilder<Pool, Client, BuilderTx> {
@@ -196,8 +197,7 @@ impl<Pool, Client, BuilderTx> OpPayloadBuilder<Pool, Client, BuilderTx> {
     ) -> Self {
         let address_gas_limiter = AddressGasLimiter::new(config.gas_limiter_config.clone());
 
-        // Initialize DEX handler
-        let dex_handler = Arc::new(crate::dex::DexHandler::new());
+        let dex_journal = config.dex_journal.clone();
 
         Self {
             evm_config,
@@ -209,7 +209,7 @@ impl<Pool, Client, BuilderTx> OpPayloadBuilder<Pool, Client, BuilderTx> {
             metrics,
             builder_tx,
             address_gas_limiter,
-            dex_handler,
+            dex_journal,
         }
     }
 }
@@ -255,6 +255,7 @@ where
         >,
         cancel: CancellationToken,
         extra_ctx: FlashblocksExtraCtx,
+        dex_handler: Option<Arc<DexHandler>>,
     ) -> eyre::Result<OpPayloadBuilderCtx<FlashblocksExtraCtx>> {
         let chain_spec = self.client.chain_spec();
         let timestamp = config.attributes.timestamp();
@@ -308,7 +309,7 @@ where
             extra_ctx,
             max_gas_per_txn: self.config.max_gas_per_txn,
             address_gas_limiter: self.address_gas_limiter.clone(),
-            dex_handler: Some(self.dex_handler.clone()),
+            dex_handler,
         })
     }
 
@@ -351,6 +352,26 @@ where
 
         let timestamp = config.attributes.timestamp();
         let disable_state_root = self.config.specific.disable_state_root;
+        // DEX operations of this job are executed on a private copy of the parent's DEX state.
+        // If the parent is a canonical block whose DEX state is unknown, because it was neither
+        // built nor synced by us, it is replayed by the canonical state task. Until then only the
+        // fallback block is built, as pool transactions may call the DEX, and deposits are
+        // executed without it.
+        let dex_handler = if self.dex_journal.is_enabled() {
+            self.dex_journal
+                .handler_at(config.parent_header.hash())
+                .map(Arc::new)
+        } else {
+            None
+        };
+        let dex_unavailable = self.dex_journal.is_enabled() && dex_handler.is_none();
+        if dex_unavailable {
+            warn!(
+                target: "payload_builder",
+                parent_hash = ?config.parent_header.hash(),
+                "DEX state of parent block is unknown, building fallback block only"
+            );
+        }
         let ctx = self
             .get_op_payload_builder_ctx(
                 config.clone(),
@@ -360,6 +381,7 @@ where
                     disable_state_root,
                     ..Default::default()
                 },
+                dex_handler.clone(),
             )
             .map_err(|e| PayloadBuilderError::Other(e.into()))?;
 
@@ -374,6 +396,10 @@ where
             .with_bundle_update()
             .build();
 
+        // Sweeps of expired DEX orders can't advance past the flashblock being built
+        if let Some(dex_handler) = &dex_handler {
+            dex_handler.set_flashblock_limit(ctx.block_number(), 0);
+        }
         let mut info = execute_pre_steps(&mut state, &ctx)?;
         let sequencer_tx_time = sequencer_tx_start_time.elapsed();
         ctx.metrics.sequencer_tx_duration.record(sequencer_tx_time);
@@ -398,6 +424,7 @@ where
             &mut info,
             !disable_state_root || ctx.attributes().no_tx_pool, // need to calculate state root for CL sync
         )?;
+        let dex_state = self.commit_dex_state(&ctx, &payload);
 
         self.payload_tx
             .send(payload.clone())
@@ -417,12 +444,13 @@ where
                 .ws_pub
                 .publish(&fb_payload)
                 .map_err(PayloadBuilderError::other)?;
+            self.publish_dex_state(&fb_payload, dex_state);
             ctx.metrics
                 .flashblock_byte_size_histogram
                 .record(flashblock_byte_size as f64);
         }
 
-        if ctx.attributes().no_tx_pool {
+        if ctx.attributes().no_tx_pool || dex_unavailable {
             info!(
                 target: "payload_builder",
                 "No transaction pool, skipping transaction pool processing",
@@ -495,7 +523,7 @@ where
 
         let mut fb_cancel = block_cancel.child_token();
         let mut ctx = self
-            .get_op_payload_builder_ctx(config, fb_cancel.clone(), extra_ctx)
+            .get_op_payload_builder_ctx(config, fb_cancel.clone(), extra_ctx, dex_handler)
             .map_err(|e| PayloadBuilderError::Other(e.into()))?;
 
         // Create best_transaction iterator
@@ -690,6 +718,27 @@ where
             *footprint = footprint.saturating_sub(builder_tx_da_size.saturating_mul(scalar as u64));
         }
 
+        // Orders that expire at this flashblock are swept before anything can trade against them
+        if let Err(e) = ctx.execute_dex_sweep(info, state, flashblock_index) {
+            error!(target: "payload_builder", "Error sweeping expired DEX orders: {}", e);
+        }
+
+        // Signed orders submitted over RPC are placed ahead of the pool's transactions, within
+        // their own gas budget
+        let signed_order_gas_limit = info
+            .cumulative_gas_used
+            .saturating_add(self.config.specific.dex_signed_order_gas)
+            .min(target_gas_for_batch)
+            .min(ctx.block_gas_limit());
+        if let Err(e) = ctx.execute_dex_signed_orders(
+            info,
+            state,
+            self.dex_journal.signed_orders(),
+            signed_order_gas_limit,
+        ) {
+            error!(target: "payload_builder", "Error placing signed DEX orders: {}", e);
+        }
+
         let best_txs_start_time = Instant::now();
         best_txs.refresh_iterator(
             BestPayloadTransactions::new(
@@ -727,6 +776,7 @@ where
         // We got block cancelled, we won't need anything from the block at this point
         // Caution: this assume that block cancel token only cancelled when new FCU is received
         if block_cancel.is_cancelled() {
+            discard_dex_state(ctx);
             self.record_flashblocks_metrics(
                 ctx,
                 info,
@@ -745,6 +795,24 @@ where
             .payload_transaction_simulation_gauge
             .set(payload_transaction_simulation_time);
 
+        // Pairs that ran into their price band too often halt before the batch auction
+        if let Err(e) = ctx.execute_dex_halts(
+            info,
+            state,
+            ctx.block_gas_limit().saturating_sub(builder_tx_gas),
+        ) {
+            error!(target: "payload_builder", "Error halting DEX pairs: {}", e);
+        }
+
+        // Orders collected during the flashblock are cleared before it is sealed
+        if let Err(e) = ctx.execute_dex_batch_auction(
+            info,
+            state,
+            ctx.block_gas_limit().saturating_sub(builder_tx_gas),
+        ) {
+            error!(target: "payload_builder", "Error clearing DEX batch auction: {}", e);
+        }
+
         if let Err(e) = self
             .builder_tx
             .add_builder_txs(&state_provider, info, ctx, state, false)
@@ -769,6 +837,7 @@ where
 
         match build_result {
             Err(err) => {
+                discard_dex_state(ctx);
                 ctx.metrics.invalid_built_blocks_count.increment(1);
                 Err(err).wrap_err("failed to build payload")
             }
@@ -779,6 +848,7 @@ where
                 // If main token got canceled in here that means we received get_payload and we should drop everything and now update best_payload
                 // To ensure that we will return same blocks as rollup-boost (to leverage caches)
                 if block_cancel.is_cancelled() {
+                    discard_dex_state(ctx);
                     self.record_flashblocks_metrics(
                         ctx,
                         info,
@@ -788,10 +858,12 @@ where
                     );
                     return Ok(None);
                 }
+                let dex_state = self.commit_dex_state(ctx, &new_payload);
                 let flashblock_byte_size = self
                     .ws_pub
                     .publish(&fb_payload)
                     .wrap_err("failed to publish flashblock via websocket")?;
+                self.publish_dex_state(&fb_payload, dex_state);
                 self.payload_tx
                     .send(new_payload.clone())
                     .await
@@ -850,6 +922,39 @@ where
         }
     }
 
+    /// Checkpoint the DEX state of a sealed flashblock and record it for the block it produced,
+    /// so that jobs building on top of this block start from it. Returns the recorded state.
+    fn commit_dex_state(
+        &self,
+        ctx: &OpPayloadBuilderCtx<FlashblocksExtraCtx>,
+        payload: &OpBuiltPayload,
+    ) -> Option<DexState> {
+        let dex_handler = ctx.dex_handler.as_ref()?;
+        dex_handler.checkpoint();
+        let state = dex_handler.snapshot();
+        self.dex_journal.record(
+            payload.block().hash(),
+            payload.block().header().number,
+            state.clone(),
+        );
+        Some(state)
+    }
+
+    /// Publish the order book changes of a flashblock to the DEX subscribers of the websocket.
+    ///
+    /// The DEX feed is best effort, failing to publish it doesn't stop block building.
+    fn publish_dex_state(&self, fb_payload: &OpFlashblockPayload, state: Option<DexState>) {
+        let Some(state) = state else {
+            return;
+        };
+        if let Err(err) =
+            self.ws_pub
+                .publish_dex(fb_payload.metadata.block_number, fb_payload.index, &state)
+        {
+            warn!(target: "payload_builder", error = %err, "Failed to publish DEX feed");
+        }
+    }
+
     /// Do some logging and metric recording when we stop build flashblocks
     fn record_flashblocks_metrics(
         &self,
@@ -966,6 +1071,13 @@ where
     }
 }
 
+/// Roll back DEX operations executed since the last sealed flashblock.
+fn discard_dex_state(ctx: &OpPayloadBuilderCtx<FlashblocksExtraCtx>) {
+    if let Some(dex_handler) = &ctx.dex_handler {
+        dex_handler.revert_to_checkpoint();
+    }
+}
+
 fn execute_pre_steps<DB, ExtraCtx>(
     state: &mut State<DB>,
     ctx: &OpPayloadBuilderCtx<ExtraCtx>,
@@ -191,6 +191,20 @@ where
 
     let mut info = ExecutionInfo::with_capacity(payload.block().body().transactions.len());
 
+    // the received block is executed on a private copy of the parent's DEX state, which is only
+    // recorded once the block is known to match. Without the parent's state the DEX operations
+    // can't be reproduced, so the block is rejected.
+    let dex_handler = if ctx.dex_journal().is_enabled() {
+        let dex_handler = ctx
+            .dex_journal()
+            .handler_at(parent_hash)
+            .ok_or_else(|| eyre::eyre!("DEX state of parent block {parent_hash} is unknown"))?;
+        Some(dex_handler)
+    } else {
+        None
+    };
+    let dex_journal = ctx.dex_journal().clone();
+
     let extra_data = payload.block().sealed_header().extra_data.clone();
     if extra_data.len() != 9 {
         tracing::error!(len = extra_data.len(), data = ?extra_data, "invalid extra data length in flashblock");
@@ -231,7 +245,7 @@ where
         ctx.max_gas_per_txn(),
         is_canyon_active(&chain_spec, timestamp),
         is_regolith_active(&chain_spec, timestamp),
-        Some(ctx.dex_handler().as_ref()),
+        dex_handler.as_ref(),
     )
     .wrap_err("failed to execute best transactions")?;
 
@@ -240,6 +254,7 @@ where
         evm_env.clone(),
         block_env_attributes,
         cancel,
+        dex_handler.clone(),
     );
 
     let (built_payload, fb_payload) = crate::builders::flashblocks::payload::build_block(
@@ -265,6 +280,14 @@ where
         bail!("flashblock hash mismatch after execution");
     }
 
+    if let Some(dex_handler) = dex_handler {
+        dex_journal.record(
+            built_payload.block().hash(),
+            built_payload.block().header().number,
+            dex_handler.snapshot(),
+        );
+    }
+
     builder_ctx.metrics.block_synced_success.increment(1);
 
     tracing::info!(header = ?built_payload.block().header(), "successfully executed flashblock");
@@ -272,7 +295,7 @@ where
 }
 
 #[allow(clippy::too_many_arguments)]
-fn execute_transactions(
+pub(super) fn execute_transactions(
     info: &mut ExecutionInfo<FlashblocksExecutionInfo>,
     state: &mut State<impl alloy_evm::Database>,
     txs: Vec<op_alloy_consensus::OpTxEnvelope>,
@@ -311,6 +334,7 @@ fn execute_transactions_with_dex(
     is_regolith_active: bool,
     dex_handler: Option<&crate::dex::DexHandler>,
 ) -> eyre::Result<()> {
+    use alloy_eips::eip2718::Encodable2718 as _;
     use alloy_evm::{Evm as _, EvmError as _};
     use op_revm::{OpTransaction, transaction::deposit::DepositTransactionParts};
     use reth_evm::ConfigureEvm as _;
@@ -320,27 +344,20 @@ fn execute_transactions_with_dex(
         context::{TxEnv, result::ResultAndState},
     };
 
+    let dex_evm_env = evm_env.clone();
     let mut evm = evm_config.evm_with_env(&mut *state, evm_env);
+    let dex = dex_handler.cloned().map(|handler| {
+        crate::dex::DexPrecompile::new(handler, evm_config.clone(), dex_evm_env.clone())
+    });
+    if let Some(dex) = &dex {
+        dex.install(evm.precompiles_mut());
+    }
 
     for tx in txs {
         let sender = tx
             .recover_signer()
             .wrap_err("failed to recover tx signer")?;
 
-        // Check if this is a DEX transaction
-        if let Some(dex_handler) = dex_handler {
-            if super::dex_integration::is_dex_transaction(&tx) {
-                // Execute via DEX handler instead of EVM
-                super::dex_integration::execute_dex_transaction(
-                    dex_handler,
-                    &tx,
-                    sender,
-                    info,
-                )?;
-                continue; // Skip normal EVM execution
-            }
-        }
-
         let tx_env = TxEnv::from_recovered_tx(&tx, sender);
         let executable_tx = match tx {
             OpTxEnvelope::Deposit(ref tx) => {
@@ -355,29 +372,21 @@ fn execute_transactions_with_dex(
                     deposit,
                 }
             }
-            OpTxEnvelope::Legacy(_) => {
-                let mut tx = OpTransaction::new(tx_env);
-                tx.enveloped_tx = Some(vec![0x00].into());
-                tx
-            }
-            OpTxEnvelope::Eip2930(_) => {
-                let mut tx = OpTransaction::new(tx_env);
-                tx.enveloped_tx = Some(vec![0x00].into());
-                tx
-            }
-            OpTxEnvelope::Eip1559(_) => {
-                let mut tx = OpTransaction::new(tx_env);
-                tx.enveloped_tx = Some(vec![0x00].into());
-                tx
-            }
-            OpTxEnvelope::Eip7702(_) => {
-                let mut tx = OpTransaction::new(tx_env);
-                tx.enveloped_tx = Some(vec![0x00].into());
-                tx
+            // the L1 data fee is charged for the encoded transaction, like the builder does
+            OpTxEnvelope::Legacy(_)
+            | OpTxEnvelope::Eip2930(_)
+            | OpTxEnvelope::Eip1559(_)
+            | OpTxEnvelope::Eip7702(_) => {
+                let mut op_tx = OpTransaction::new(tx_env);
+                op_tx.enveloped_tx = Some(tx.encoded_2718().into());
+                op_tx
             }
         };
 
-        let ResultAndState { result, state } = match evm.transact_raw(executable_tx) {
+        if let Some(dex) = &dex {
+            dex.begin();
+        }
+        let result_and_state = match evm.transact_raw(executable_tx) {
             Ok(res) => res,
             Err(err) => {
                 if let Some(err) = err.as_invalid_tx_err() {
@@ -390,6 +399,17 @@ fn execute_transactions_with_dex(
             }
         };
 
+        // The DEX state is taken along with the transaction
+        let (ResultAndState { result, state }, dex_execution) = match &dex {
+            Some(dex) => super::dex_integration::finish_dex_transaction(
+                dex,
+                &dex_evm_env,
+                sender,
+                result_and_state,
+            ),
+            None => (result_and_state, None),
+        };
+
         if let Some(max_gas_per_txn) = max_gas_per_txn
             && result.gas_used() > max_gas_per_txn
         {
@@ -434,6 +454,12 @@ fn execute_transactions_with_dex(
         ));
 
         evm.db_mut().commit(state);
+        if let (Some(dex_handler), Some(dex_state)) = (
+            dex_handler,
+            dex_execution.and_then(|execution| execution.state),
+        ) {
+            dex_handler.restore(dex_state);
+        }
 
         // append sender and transaction to the respective lists
         info.executed_senders.push(sender);
@@ -480,12 +506,12 @@ fn build_receipt<E: alloy_evm::Evm>(
     }
 }
 
-fn is_canyon_active(chain_spec: &OpChainSpec, timestamp: u64) -> bool {
+pub(super) fn is_canyon_active(chain_spec: &OpChainSpec, timestamp: u64) -> bool {
     use reth_optimism_chainspec::OpHardforks as _;
     chain_spec.is_canyon_active_at_timestamp(timestamp)
 }
 
-fn is_regolith_active(chain_spec: &OpChainSpec, timestamp: u64) -> bool {
+pub(super) fn is_regolith_active(chain_spec: &OpChainSpec, timestamp: u64) -> bool {
     use reth_optimism_chainspec::OpHardforks as _;
     chain_spec.is_regolith_active_at_timestamp(timestamp)
 }
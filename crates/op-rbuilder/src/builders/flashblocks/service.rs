@@ -12,12 +12,14 @@ use crate::{
         },
         generator::BlockPayloadJobGenerator,
     },
+    dex::{genesis::DexGenesis, snapshot::DexSnapshots},
     flashtestations::service::bootstrap_flashtestations,
     metrics::OpRBuilderMetrics,
     traits::{NodeBounds, PoolBounds},
 };
 use eyre::WrapErr as _;
 use reth_basic_payload_builder::BasicPayloadJobGeneratorConfig;
+use reth_chainspec::EthChainSpec;
 use reth_node_api::NodeTypes;
 use reth_node_builder::{BuilderContext, components::PayloadServiceBuilder};
 use reth_optimism_evm::OpEvmConfig;
@@ -105,6 +107,76 @@ impl FlashblocksServiceBuilder {
             (incoming_message_rx, outgoing_message_tx)
         };
 
+        if !self.0.specific.dex_enabled {
+            tracing::info!("Enshrined DEX disabled");
+            self.0.dex_journal.disable();
+        } else {
+            tracing::warn!(
+                "Enshrined DEX enabled, blocks calling it are only valid for nodes running the same DEX"
+            );
+            if self.0.specific.dex_snapshot_interval > 0 {
+                self.0.dex_journal.enable_snapshots(DexSnapshots::new(
+                    ctx.config().datadir().data_dir().join("dex"),
+                    self.0.specific.dex_snapshot_interval,
+                ));
+            }
+            if self.0.specific.dex_max_replay_blocks > 0 {
+                self.0
+                    .dex_journal
+                    .set_max_replay_blocks(self.0.specific.dex_max_replay_blocks);
+            }
+
+            if self.0.specific.dex_batch_auction {
+                self.0.dex_journal.enable_batch_auction();
+            }
+            if let Some(signer) = &self.0.builder_signer {
+                self.0.dex_journal.set_builder(signer.address);
+            }
+            self.0
+                .dex_journal
+                .set_fee_schedule(self.0.specific.dex_fees.clone());
+            self.0.dex_journal.set_chain_id(ctx.chain_spec().chain_id());
+            self.0
+                .dex_journal
+                .set_price_bands(self.0.specific.dex_price_bands);
+            if let Some(admin) = self.0.specific.dex_admin {
+                self.0.dex_journal.set_admin(admin);
+            }
+            self.0
+                .dex_journal
+                .set_listing_policy(self.0.specific.dex_listing.clone());
+            if let Some(address) = self.0.specific.dex_address {
+                self.0.dex_journal.set_address(address);
+            }
+            let genesis = match &self.0.specific.dex_genesis {
+                Some(genesis) => genesis.clone(),
+                None => {
+                    DexGenesis::from_chain_config(&ctx.chain_spec().genesis().config.extra_fields)
+                        .wrap_err("failed to read DEX genesis pairs from chain spec")?
+                }
+            };
+            self.0.dex_journal.set_genesis_pairs(genesis.pairs);
+
+            // The DEX state has to match the chain before the first payload job builds on top of
+            // it
+            super::dex_recovery::rebuild_dex_state(
+                ctx.provider(),
+                &OpEvmConfig::optimism(ctx.chain_spec()),
+                &self.0.dex_journal,
+            )
+            .wrap_err("failed to rebuild DEX state")?;
+
+            ctx.task_executor().spawn_critical(
+                "dex canonical state",
+                Box::pin(super::dex_recovery::follow_canonical_state(
+                    ctx.provider().clone(),
+                    OpEvmConfig::optimism(ctx.chain_spec()),
+                    self.0.dex_journal.clone(),
+                    ctx.provider().canonical_state_stream(),
+                )),
+            );
+        }
+
         let metrics = Arc::new(OpRBuilderMetrics::default());
         let (built_payload_tx, built_payload_rx) = tokio::sync::mpsc::channel(16);
 
@@ -123,8 +195,8 @@ impl FlashblocksServiceBuilder {
             metrics.clone(),
         );
 
-        // Clone the DEX handler before moving the payload_builder
-        let dex_handler = payload_builder.dex_handler.clone();
+        // Share the DEX journal with the syncer before moving the payload_builder
+        let dex_journal = payload_builder.dex_journal.clone();
 
         let payload_job_config = BasicPayloadJobGeneratorConfig::default();
 
@@ -145,7 +217,7 @@ impl FlashblocksServiceBuilder {
             self.0,
             OpEvmConfig::optimism(ctx.chain_spec()),
             metrics.clone(),
-            dex_handler,
+            dex_journal,
         )
         .wrap_err("failed to create flashblocks payload builder context")?;
 
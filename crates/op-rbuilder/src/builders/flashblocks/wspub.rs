@@ -1,3 +1,4 @@
+use alloy_primitives::B256;
 use core::{
     fmt::{Debug, Formatter},
     net::SocketAddr,
@@ -20,32 +21,55 @@ use tokio_tungstenite::{
 };
 use tracing::{debug, warn};
 
-use crate::metrics::OpRBuilderMetrics;
+use crate::{
+    dex::{
+        DexState,
+        feed::{DexFeed, DexFeedMessage, DexFeedRequest, DexSubscription},
+    },
+    metrics::OpRBuilderMetrics,
+};
+
+/// An update of the DEX feed for one pair, serialized once for all subscribers
+#[derive(Debug, Clone)]
+struct DexUpdate {
+    pair_id: B256,
+    sequence: u64,
+    message: Utf8Bytes,
+}
 
 /// A WebSockets publisher that accepts connections from client websockets and broadcasts to them
 /// updates about new flashblocks. It maintains a count of sent messages and active subscriptions.
 ///
+/// Clients can additionally subscribe to the order books of DEX pairs by sending a
+/// [`DexFeedRequest`], after which they receive a snapshot of each book followed by its updates.
+///
 /// This is modelled as a `futures::Sink` that can be used to send `OpFlashblockPayload` messages.
 pub(super) struct WebSocketPublisher {
     sent: Arc<AtomicUsize>,
     subs: Arc<AtomicUsize>,
     term: watch::Sender<bool>,
     pipe: broadcast::Sender<Utf8Bytes>,
+    dex_feed: Arc<DexFeed>,
+    dex_pipe: broadcast::Sender<DexUpdate>,
 }
 
 impl WebSocketPublisher {
     pub(super) fn new(addr: SocketAddr, metrics: Arc<OpRBuilderMetrics>) -> io::Result<Self> {
         let (pipe, _) = broadcast::channel(100);
+        let (dex_pipe, _) = broadcast::channel(1000);
         let (term, _) = watch::channel(false);
 
         let sent = Arc::new(AtomicUsize::new(0));
         let subs = Arc::new(AtomicUsize::new(0));
+        let dex_feed = Arc::new(DexFeed::new());
         let listener = TcpListener::bind(addr)?;
 
         tokio::spawn(listener_loop(
             listener,
             metrics,
             pipe.subscribe(),
+            Arc::clone(&dex_feed),
+            dex_pipe.subscribe(),
             term.subscribe(),
             Arc::clone(&sent),
             Arc::clone(&subs),
@@ -56,9 +80,32 @@ impl WebSocketPublisher {
             subs,
             term,
             pipe,
+            dex_feed,
+            dex_pipe,
         })
     }
 
+    /// Publish the order book changes and trades of the DEX state after a flashblock to the
+    /// clients subscribed to the affected pairs.
+    pub(super) fn publish_dex(
+        &self,
+        block_number: u64,
+        index: u64,
+        state: &DexState,
+    ) -> io::Result<()> {
+        for message in self.dex_feed.update(block_number, index, state) {
+            let update = DexUpdate {
+                pair_id: message.pair_id(),
+                sequence: message.sequence(),
+                message: Utf8Bytes::from(serde_json::to_string(&message)?),
+            };
+            self.dex_pipe
+                .send(update)
+                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))?;
+        }
+        Ok(())
+    }
+
     pub(super) fn publish(&self, payload: &OpFlashblockPayload) -> io::Result<usize> {
         // Serialize the payload to a UTF-8 string
         // serialize only once, then just copy around only a pointer
@@ -90,10 +137,13 @@ impl Drop for WebSocketPublisher {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn listener_loop(
     listener: TcpListener,
     metrics: Arc<OpRBuilderMetrics>,
     receiver: Receiver<Utf8Bytes>,
+    dex_feed: Arc<DexFeed>,
+    dex_receiver: Receiver<DexUpdate>,
     term: watch::Receiver<bool>,
     sent: Arc<AtomicUsize>,
     subs: Arc<AtomicUsize>,
@@ -131,6 +181,8 @@ async fn listener_loop(
                 let sent = Arc::clone(&sent);
                 let term = term.clone();
                 let receiver_clone = receiver.resubscribe();
+                let dex_feed = Arc::clone(&dex_feed);
+                let dex_receiver = dex_receiver.resubscribe();
 
                 match accept_async(connection).await {
                     Ok(stream) => {
@@ -139,7 +191,16 @@ async fn listener_loop(
                             tracing::debug!("WebSocket connection established with {}", peer_addr);
 
                             // Handle the WebSocket connection in a dedicated task
-                            broadcast_loop(stream, metrics, term, receiver_clone, sent).await;
+                            broadcast_loop(
+                                stream,
+                                metrics,
+                                term,
+                                receiver_clone,
+                                dex_feed,
+                                dex_receiver,
+                                sent,
+                            )
+                            .await;
 
                             subs.fetch_sub(1, Ordering::Relaxed);
                             tracing::debug!("WebSocket connection closed for {}", peer_addr);
@@ -156,6 +217,7 @@ async fn listener_loop(
 
 /// An instance of this loop is spawned for each connected WebSocket client.
 /// It listens for broadcast updates about new flashblocks and sends them to the client.
+/// Updates of the DEX pairs the client subscribed to are sent as well.
 /// It also handles termination signals to gracefully close the connection.
 /// Any connectivity errors will terminate the loop, which will in turn
 /// decrement the subscription count in the `WebSocketPublisher`.
@@ -164,10 +226,14 @@ async fn broadcast_loop(
     metrics: Arc<OpRBuilderMetrics>,
     term: watch::Receiver<bool>,
     blocks: broadcast::Receiver<Utf8Bytes>,
+    dex_feed: Arc<DexFeed>,
+    dex_updates: broadcast::Receiver<DexUpdate>,
     sent: Arc<AtomicUsize>,
 ) {
     let mut term = term;
     let mut blocks = blocks;
+    let mut dex_updates = dex_updates;
+    let mut dex_subscription = DexSubscription::default();
     let mut stream = stream;
     let Ok(peer_addr) = stream.get_ref().peer_addr() else {
         return;
@@ -208,6 +274,32 @@ async fn broadcast_loop(
                 }
             },
 
+            // Receive DEX updates, of which only the subscribed pairs are sent
+            update = dex_updates.recv() => match update {
+                Ok(update) => {
+                    if !dex_subscription.wants(update.pair_id, update.sequence) {
+                        continue;
+                    }
+                    if let Err(e) = stream.send(Message::Text(update.message)).await {
+                        tracing::debug!("Closing flashblocks subscription for {peer_addr}: {e}");
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => {
+                    tracing::debug!("DEX broadcast channel closed, exiting broadcast loop");
+                    return;
+                }
+                Err(RecvError::Lagged(_)) => {
+                    // The client missed updates, so its books have to be rebuilt
+                    tracing::warn!("DEX broadcast channel lagged, resending book snapshots");
+                    let snapshots = dex_subscription.resync(&dex_feed);
+                    if let Err(e) = send_dex_messages(&mut stream, snapshots).await {
+                        tracing::debug!("Closing flashblocks subscription for {peer_addr}: {e}");
+                        break;
+                    }
+                }
+            },
+
             // Ping-pong handled by tokio_tungstenite when you perform read on the socket
             message = stream.next() => if let Some(message) = message { match message {
                 // We handle only close frame to highlight conn closing
@@ -215,6 +307,20 @@ async fn broadcast_loop(
                     tracing::info!("Closing frame received, stopping connection for {peer_addr}");
                     break;
                 }
+                Ok(Message::Text(text)) => {
+                    let request = match serde_json::from_str::<DexFeedRequest>(&text) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            tracing::debug!("Ignoring invalid request from {peer_addr}: {e}");
+                            continue;
+                        }
+                    };
+                    let snapshots = dex_subscription.handle(&dex_feed, request);
+                    if let Err(e) = send_dex_messages(&mut stream, snapshots).await {
+                        tracing::debug!("Closing flashblocks subscription for {peer_addr}: {e}");
+                        break;
+                    }
+                }
                 Err(e) => {
                     tracing::warn!("Received error. Closing flashblocks subscription for {peer_addr}: {e}");
                     break;
@@ -225,6 +331,20 @@ async fn broadcast_loop(
     }
 }
 
+/// Send messages of the DEX feed to a single client.
+async fn send_dex_messages(
+    stream: &mut WebSocketStream<TcpStream>,
+    messages: Vec<DexFeedMessage>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    for message in messages {
+        let serialized = serde_json::to_string(&message).map_err(io::Error::from)?;
+        stream
+            .send(Message::Text(Utf8Bytes::from(serialized)))
+            .await?;
+    }
+    Ok(())
+}
+
 impl Debug for WebSocketPublisher {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         let subs = self.subs.load(Ordering::Relaxed);
@@ -6,9 +6,11 @@ use core::{
 use reth_node_builder::components::PayloadServiceBuilder;
 use reth_optimism_evm::OpEvmConfig;
 use reth_optimism_payload_builder::config::{OpDAConfig, OpGasLimitConfig};
+use std::sync::Arc;
 
 use crate::{
     args::OpRbuilderArgs,
+    dex::DexJournal,
     flashtestations::args::FlashtestationsArgs,
     gas_limiter::args::GasLimiterArgs,
     traits::{NodeBounds, PoolBounds},
@@ -17,7 +19,7 @@ use crate::{
 
 mod builder_tx;
 mod context;
-mod flashblocks;
+pub(crate) mod flashblocks;
 mod generator;
 mod standard;
 
@@ -126,6 +128,9 @@ pub struct BuilderConfig<Specific: Clone> {
 
     /// Address gas limiter stuff
     pub gas_limiter_config: GasLimiterArgs,
+
+    /// Journal of the enshrined DEX states, shared between the payload builder and the RPC
+    pub dex_journal: Arc<DexJournal>,
 }
 
 impl<S: Debug + Clone> core::fmt::Debug for BuilderConfig<S> {
@@ -166,6 +171,7 @@ impl<S: Default + Clone> Default for BuilderConfig<S> {
             sampling_ratio: 100,
             max_gas_per_txn: None,
             gas_limiter_config: GasLimiterArgs::default(),
+            dex_journal: Arc::new(DexJournal::new()),
         }
     }
 }
@@ -188,6 +194,7 @@ where
             sampling_ratio: args.telemetry.sampling_ratio,
             max_gas_per_txn: args.max_gas_per_txn,
             gas_limiter_config: args.gas_limiter.clone(),
+            dex_journal: Arc::new(DexJournal::new()),
             specific: S::try_from(args)?,
         })
     }
@@ -0,0 +1,597 @@
+//! The `dex` JSON-RPC namespace.
+//!
+//! Trading interfaces need the order books, orders and trades in a form that doesn't require
+//! ABI encoding calls to the predeploy. The methods of this namespace read the DEX state of the
+//! requested block from the [`DexJournal`] directly. Besides the usual block tags, `pending`
+//! refers to the state after the latest flashblock. Makers can also submit
+//! [signed orders](super::signed) here, which the builder places on their behalf.
+
+use super::{
+    DexError, DexHandler, DexJournal, DexState, SignedOrder,
+    fees::PairFees,
+    handler::{order_id_from_b256, order_id_to_b256},
+    signed::PendingSignedOrder,
+    state::{BookLevel, OrderRecord, PairRecord, TradeSample},
+};
+use alloy_eips::BlockId;
+use alloy_primitives::{Address, B256, Bytes, U64, U256};
+use jsonrpsee::{
+    core::{RpcResult, async_trait},
+    proc_macros::rpc,
+};
+use reth_provider::BlockReaderIdExt;
+use reth_rpc_eth_types::EthApiError;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Number of price levels per side returned by `dex_getOrderBook` if no depth is requested
+const DEFAULT_BOOK_DEPTH: usize = 20;
+
+/// Maximum number of price levels per side returned by `dex_getOrderBook`
+const MAX_BOOK_DEPTH: usize = 1_000;
+
+/// A trading pair with its statistics
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcPair {
+    pub pair_id: B256,
+    pub token0: Address,
+    pub token1: Address,
+    pub decimals0: u8,
+    pub decimals1: u8,
+    pub symbol0: String,
+    pub symbol1: String,
+    /// Price increment of orders, in `token1` per `token0` scaled by 1e18. Zero allows any price
+    pub tick_size: U256,
+    /// Amount increment of orders, in `token0`. Zero allows any amount
+    pub lot_size: U256,
+    #[serde(with = "alloy_serde::quantity")]
+    pub trade_count: u64,
+    pub volume0: U256,
+    pub volume1: U256,
+    /// Numerator of the last traded price, in `token1` per `token0`
+    #[serde(with = "alloy_serde::quantity")]
+    pub last_price_num: u128,
+    /// Denominator of the last traded price, in `token1` per `token0`
+    #[serde(with = "alloy_serde::quantity")]
+    pub last_price_denom: u128,
+    /// Fee charged on what resting orders receive, in basis points
+    pub maker_fee_bps: u16,
+    /// Fee charged on what incoming orders and swaps receive, in basis points
+    pub taker_fee_bps: u16,
+    /// Whether trading is halted until the admin resumes the pair
+    pub halted: bool,
+}
+
+/// The liquidity resting at one price
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcBookLevel {
+    #[serde(with = "alloy_serde::quantity")]
+    pub price_num: u128,
+    #[serde(with = "alloy_serde::quantity")]
+    pub price_denom: u128,
+    /// Amount of the base token available at this price
+    pub amount: U256,
+}
+
+/// Order book of a pair, with `token0` as the base and `token1` as the quote token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcOrderBook {
+    pub pair_id: B256,
+    pub base: Address,
+    pub quote: Address,
+    /// Bids from the highest price down
+    pub bids: Vec<RpcBookLevel>,
+    /// Asks from the lowest price up
+    pub asks: Vec<RpcBookLevel>,
+}
+
+/// A resting order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcOrder {
+    pub order_id: B256,
+    pub owner: Address,
+    pub pair_id: B256,
+    pub token_in: Address,
+    pub token_out: Address,
+    pub is_buy: bool,
+    #[serde(with = "alloy_serde::quantity")]
+    pub price_num: u128,
+    #[serde(with = "alloy_serde::quantity")]
+    pub price_denom: u128,
+    pub remaining: U256,
+    pub escrow: U256,
+    /// Where the order expires, `null` if it rests until filled or cancelled
+    pub expiry: Option<RpcExpiry>,
+}
+
+/// The start of the flashblock an order expires at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcExpiry {
+    #[serde(with = "alloy_serde::quantity")]
+    pub block_number: u64,
+    #[serde(with = "alloy_serde::quantity")]
+    pub flashblock_index: u64,
+}
+
+/// A limit order signed by its maker with EIP-712
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcSignedOrder {
+    pub maker: Address,
+    pub token_in: Address,
+    pub token_out: Address,
+    pub is_buy: bool,
+    pub amount: U256,
+    pub price_num: U256,
+    pub price_denom: U256,
+    /// Where the order expires, `null` if it rests until filled or cancelled
+    pub expiry: Option<RpcExpiry>,
+    /// Has to be greater than the last nonce of the maker that was used
+    #[serde(with = "alloy_serde::quantity")]
+    pub nonce: u64,
+    /// Signature of the order's EIP-712 hash by the maker, 65 bytes of `r`, `s` and `v`
+    pub signature: Bytes,
+}
+
+/// What a swap would return
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcQuote {
+    pub amount_out: U256,
+    /// Ids of the pairs the swap would be routed through
+    pub route: Vec<B256>,
+}
+
+/// A fill of a resting order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcTrade {
+    pub pair_id: B256,
+    #[serde(with = "alloy_serde::quantity")]
+    pub block_number: u64,
+    #[serde(with = "alloy_serde::quantity")]
+    pub timestamp: u64,
+    pub order_id: B256,
+    pub maker: Address,
+    pub taker: Address,
+    /// Numerator of the traded price, in `token1` per `token0`
+    #[serde(with = "alloy_serde::quantity")]
+    pub price_num: u128,
+    /// Denominator of the traded price, in `token1` per `token0`
+    #[serde(with = "alloy_serde::quantity")]
+    pub price_denom: u128,
+    /// Amount of `token0` traded
+    pub amount0: U256,
+    /// Amount of `token1` traded
+    pub amount1: U256,
+}
+
+#[rpc(server, namespace = "dex")]
+pub trait DexApi {
+    /// Returns all pairs, in the order they were created.
+    #[method(name = "getPairs")]
+    async fn get_pairs(&self, block: Option<BlockId>) -> RpcResult<Vec<RpcPair>>;
+
+    /// Returns up to `depth` price levels per side of the order book of a pair.
+    #[method(name = "getOrderBook")]
+    async fn get_order_book(
+        &self,
+        pair_id: B256,
+        depth: Option<usize>,
+        block: Option<BlockId>,
+    ) -> RpcResult<RpcOrderBook>;
+
+    /// Returns a resting order, or `null` if it isn't on the book.
+    #[method(name = "getOrder")]
+    async fn get_order(
+        &self,
+        order_id: B256,
+        block: Option<BlockId>,
+    ) -> RpcResult<Option<RpcOrder>>;
+
+    /// Returns the resting orders of an account.
+    #[method(name = "getOpenOrders")]
+    async fn get_open_orders(
+        &self,
+        owner: Address,
+        block: Option<BlockId>,
+    ) -> RpcResult<Vec<RpcOrder>>;
+
+    /// Returns what swapping `amount_in` of `token_in` into `token_out` would return.
+    #[method(name = "getQuote")]
+    async fn get_quote(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+        block: Option<BlockId>,
+    ) -> RpcResult<RpcQuote>;
+
+    /// Returns the trades of a pair since `from_block` up to the given block, oldest first. Only
+    /// the trades of the 24 hours before the given block are available.
+    #[method(name = "getTrades")]
+    async fn get_trades(
+        &self,
+        pair_id: B256,
+        from_block: Option<U64>,
+        block: Option<BlockId>,
+    ) -> RpcResult<Vec<RpcTrade>>;
+
+    /// Submits an order signed by its maker, which the builder places with a transaction of its
+    /// own. Returns the EIP-712 hash of the order.
+    ///
+    /// Fails if the maker has too many orders pending already. Orders whose maker can't fund the
+    /// escrow by the time the builder gets to them are dropped.
+    #[method(name = "submitSignedOrder")]
+    async fn submit_signed_order(&self, order: RpcSignedOrder) -> RpcResult<B256>;
+
+    /// Returns the last nonce of `maker` that was used by a signed order, zero if none was.
+    #[method(name = "getNonce")]
+    async fn get_nonce(&self, maker: Address, block: Option<BlockId>) -> RpcResult<U64>;
+}
+
+#[derive(Clone)]
+pub struct DexApiExt<Provider> {
+    provider: Provider,
+    dex_journal: Arc<DexJournal>,
+}
+
+impl<Provider> DexApiExt<Provider> {
+    pub fn new(provider: Provider, dex_journal: Arc<DexJournal>) -> Self {
+        Self {
+            provider,
+            dex_journal,
+        }
+    }
+}
+
+impl<Provider> DexApiExt<Provider>
+where
+    Provider: BlockReaderIdExt<Header = alloy_consensus::Header>,
+{
+    /// Returns the DEX state after the given block, `latest` if none is given.
+    ///
+    /// The state is a copy that shares its pairs and order books with the journal, so it is cheap
+    /// to take and requests read it without holding up the payload builder.
+    fn state_at(&self, block: Option<BlockId>) -> Result<DexState, EthApiError> {
+        let block_id = block.unwrap_or_default();
+        if block_id.is_pending() {
+            return Ok(self.dex_journal.pending_state());
+        }
+
+        let header = self
+            .provider
+            .sealed_header_by_id(block_id)?
+            .ok_or(EthApiError::HeaderNotFound(block_id))?;
+        self.dex_journal.state_at(header.hash()).ok_or_else(|| {
+            EthApiError::InvalidParams(format!(
+                "DEX state of block {} is not available",
+                header.hash()
+            ))
+        })
+    }
+}
+
+#[async_trait]
+impl<Provider> DexApiServer for DexApiExt<Provider>
+where
+    Provider: BlockReaderIdExt<Header = alloy_consensus::Header> + Send + Sync + 'static,
+{
+    async fn get_pairs(&self, block: Option<BlockId>) -> RpcResult<Vec<RpcPair>> {
+        let state = self.state_at(block)?;
+        Ok(state
+            .pairs()
+            .map(|(pair_id, pair)| rpc_pair(pair_id, pair, state.pair_fees(pair_id)))
+            .collect())
+    }
+
+    async fn get_order_book(
+        &self,
+        pair_id: B256,
+        depth: Option<usize>,
+        block: Option<BlockId>,
+    ) -> RpcResult<RpcOrderBook> {
+        let state = self.state_at(block)?;
+        let pair = state
+            .pair(pair_id)
+            .ok_or_else(|| invalid_params(DexError::PairDoesNotExist))?;
+        let depth = depth.unwrap_or(DEFAULT_BOOK_DEPTH).min(MAX_BOOK_DEPTH);
+        let (bids, asks) = state.depth(pair.token0, pair.token1, depth);
+
+        Ok(RpcOrderBook {
+            pair_id,
+            base: pair.token0,
+            quote: pair.token1,
+            bids: bids.into_iter().map(rpc_book_level).collect(),
+            asks: asks.into_iter().map(rpc_book_level).collect(),
+        })
+    }
+
+    async fn get_order(
+        &self,
+        order_id: B256,
+        block: Option<BlockId>,
+    ) -> RpcResult<Option<RpcOrder>> {
+        let state = self.state_at(block)?;
+        Ok(order_id_from_b256(order_id).and_then(|id| Some(rpc_order(id, state.order(id)?))))
+    }
+
+    async fn get_open_orders(
+        &self,
+        owner: Address,
+        block: Option<BlockId>,
+    ) -> RpcResult<Vec<RpcOrder>> {
+        let state = self.state_at(block)?;
+        Ok(state
+            .orders_of(owner)
+            .map(|(order_id, order)| rpc_order(order_id, order))
+            .collect())
+    }
+
+    async fn get_quote(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+        block: Option<BlockId>,
+    ) -> RpcResult<RpcQuote> {
+        let handler = DexHandler::from_state(self.state_at(block)?);
+        let (amount_out, route) = handler
+            .quote(token_in, token_out, amount_in)
+            .map_err(invalid_params)?;
+        Ok(RpcQuote { amount_out, route })
+    }
+
+    async fn get_trades(
+        &self,
+        pair_id: B256,
+        from_block: Option<U64>,
+        block: Option<BlockId>,
+    ) -> RpcResult<Vec<RpcTrade>> {
+        let state = self.state_at(block)?;
+        if state.pair(pair_id).is_none() {
+            return Err(invalid_params(DexError::PairDoesNotExist).into());
+        }
+        let from_block = from_block.map_or(0, |number| number.to());
+        Ok(state
+            .trades(pair_id, from_block)
+            .map(|trade| rpc_trade(pair_id, trade))
+            .collect())
+    }
+
+    async fn submit_signed_order(&self, order: RpcSignedOrder) -> RpcResult<B256> {
+        let pending = pending_signed_order(order, &self.dex_journal.pending_state())
+            .map_err(invalid_params)?;
+        let hash = pending.hash;
+        self.dex_journal
+            .signed_orders()
+            .insert(pending)
+            .map_err(invalid_params)?;
+        Ok(hash)
+    }
+
+    async fn get_nonce(&self, maker: Address, block: Option<BlockId>) -> RpcResult<U64> {
+        Ok(U64::from(self.state_at(block)?.nonce(maker)))
+    }
+}
+
+/// The error returned for requests the DEX rejects
+fn invalid_params(err: DexError) -> EthApiError {
+    EthApiError::InvalidParams(err.to_string())
+}
+
+/// Verify a submitted signed order against the pending state.
+///
+/// Orders that can't be placed on top of the pending state are rejected right away, rather than
+/// failing once the builder places them.
+fn pending_signed_order(
+    order: RpcSignedOrder,
+    state: &DexState,
+) -> Result<PendingSignedOrder, DexError> {
+    let expiry = order.expiry.unwrap_or(RpcExpiry {
+        block_number: 0,
+        flashblock_index: 0,
+    });
+    let signature = order.signature;
+    let order = SignedOrder {
+        maker: order.maker,
+        tokenIn: order.token_in,
+        tokenOut: order.token_out,
+        isBuy: order.is_buy,
+        amount: order.amount,
+        priceNum: order.price_num,
+        priceDenom: order.price_denom,
+        expiryBlock: expiry.block_number,
+        expiryFlashblockIndex: expiry.flashblock_index,
+        nonce: order.nonce,
+    };
+    let hash = order.verify(&signature, &state.signing_domain())?;
+
+    let last = state.nonce(order.maker);
+    if order.nonce <= last {
+        return Err(DexError::NonceTooLow {
+            nonce: order.nonce,
+            last,
+        });
+    }
+    if state.pair_id(order.tokenIn, order.tokenOut).is_none() {
+        return Err(DexError::PairDoesNotExist);
+    }
+    if order.amount.is_zero() {
+        return Err(DexError::InvalidAmount);
+    }
+    if order.priceNum.is_zero() || order.priceDenom.is_zero() {
+        return Err(DexError::InvalidPrice);
+    }
+    if order
+        .expiry()
+        .is_some_and(|expiry| expiry <= state.position())
+    {
+        return Err(DexError::InvalidExpiry);
+    }
+
+    Ok(PendingSignedOrder {
+        order,
+        signature,
+        hash,
+    })
+}
+
+fn rpc_pair(pair_id: B256, pair: &PairRecord, fees: PairFees) -> RpcPair {
+    RpcPair {
+        pair_id,
+        token0: pair.token0,
+        token1: pair.token1,
+        decimals0: pair.spec.decimals0,
+        decimals1: pair.spec.decimals1,
+        symbol0: pair.spec.symbol0.clone(),
+        symbol1: pair.spec.symbol1.clone(),
+        tick_size: pair.spec.tick_size,
+        lot_size: pair.spec.lot_size,
+        trade_count: pair.stats.trade_count,
+        volume0: pair.stats.volume0,
+        volume1: pair.stats.volume1,
+        last_price_num: pair.stats.last_price_num,
+        last_price_denom: pair.stats.last_price_denom,
+        maker_fee_bps: fees.maker_bps,
+        taker_fee_bps: fees.taker_bps,
+        halted: pair.halted,
+    }
+}
+
+pub(super) fn rpc_book_level(level: BookLevel) -> RpcBookLevel {
+    RpcBookLevel {
+        price_num: level.price_num,
+        price_denom: level.price_denom,
+        amount: level.amount,
+    }
+}
+
+fn rpc_order(order_id: u64, order: &OrderRecord) -> RpcOrder {
+    RpcOrder {
+        order_id: order_id_to_b256(order_id),
+        owner: order.owner,
+        pair_id: order.pair_id,
+        token_in: order.token_in,
+        token_out: order.token_out,
+        is_buy: order.is_buy,
+        price_num: order.price_num,
+        price_denom: order.price_denom,
+        remaining: order.remaining,
+        escrow: order.escrow,
+        expiry: order.expiry.map(|expiry| RpcExpiry {
+            block_number: expiry.block_number,
+            flashblock_index: expiry.flashblock_index,
+        }),
+    }
+}
+
+pub(super) fn rpc_trade(pair_id: B256, trade: &TradeSample) -> RpcTrade {
+    RpcTrade {
+        pair_id,
+        block_number: trade.block_number,
+        timestamp: trade.timestamp,
+        order_id: order_id_to_b256(trade.order_id),
+        maker: trade.maker,
+        taker: trade.taker,
+        price_num: trade.price_num,
+        price_denom: trade.price_denom,
+        amount0: trade.volume0,
+        amount1: trade.volume1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dex::{DexResult, predeploy::selectors};
+    use alloy_primitives::{Bytes, address};
+    use alloy_sol_types::SolValue;
+
+    #[test]
+    fn test_trades_are_reported_with_both_sides() {
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let maker = address!("00000000000000000000000000000000000000aa");
+        let taker = address!("00000000000000000000000000000000000000bb");
+        let handler = DexHandler::new();
+        let call = |caller: Address, calldata: Vec<u8>, value: U256| {
+            handler
+                .handle_transaction(caller, &Bytes::from(calldata), value)
+                .unwrap()
+        };
+
+        call(
+            Address::ZERO,
+            [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()].concat(),
+            U256::ZERO,
+        );
+        // sell 10 ETH at 2 USDC each
+        handler.set_block(5, 1_000);
+        let DexResult::OrderPlaced { order_id, .. } = call(
+            maker,
+            [
+                selectors::PLACE_LIMIT_ORDER.as_slice(),
+                &(
+                    eth,
+                    usdc,
+                    false,
+                    U256::from(10),
+                    U256::from(2),
+                    U256::from(1),
+                )
+                    .abi_encode(),
+            ]
+            .concat(),
+            U256::from(10),
+        ) else {
+            panic!("expected the order to be placed");
+        };
+        handler.set_block(6, 1_002);
+        call(
+            taker,
+            [
+                selectors::SWAP.as_slice(),
+                &(usdc, eth, U256::from(8), U256::ZERO).abi_encode(),
+            ]
+            .concat(),
+            U256::ZERO,
+        );
+
+        let state = handler.snapshot();
+        let (pair_id, pair) = state.pairs().next().unwrap();
+        let order_book = state.depth(pair.token0, pair.token1, DEFAULT_BOOK_DEPTH);
+        assert_eq!(order_book.1.len(), 1);
+        assert_eq!(order_book.1[0].amount, U256::from(6));
+
+        let trades: Vec<_> = state
+            .trades(pair_id, 6)
+            .map(|trade| rpc_trade(pair_id, trade))
+            .collect();
+        assert_eq!(
+            trades,
+            vec![RpcTrade {
+                pair_id,
+                block_number: 6,
+                timestamp: 1_002,
+                order_id,
+                maker,
+                taker,
+                price_num: 2,
+                price_denom: 1,
+                amount0: U256::from(4),
+                amount1: U256::from(8),
+            }]
+        );
+        assert_eq!(state.trades(pair_id, 7).count(), 0);
+
+        let json = serde_json::to_value(&trades[0]).unwrap();
+        assert_eq!(json["blockNumber"], "0x6");
+        assert_eq!(json["priceNum"], "0x2");
+    }
+}
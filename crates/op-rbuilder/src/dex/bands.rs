@@ -0,0 +1,83 @@
+//! Price bands and circuit breakers of DEX pairs.
+//!
+//! A single fat-finger order or swap shouldn't be able to walk the whole book of a pair. Every
+//! fill is checked against a band around the reference price of its pair, the price of its last
+//! trade, and the operation fails with `PriceBandExceeded` if any fill lies outside of it. In
+//! batch auction mode, a pair whose clearing price lies outside of its band keeps its orders for
+//! the next auction instead.
+//!
+//! Pairs running into their band too many times in a row within a block are halted: nothing
+//! trades on them until the DEX admin resumes them with `resumePair`, while cancelling orders
+//! keeps working. Violations are counted even though the operation running into the band is
+//! reverted, including the transaction it was part of, so the circuit breaker trips no matter how
+//! the attempts are made. Each sender counts once per pair until the count starts over, so a
+//! single account can't halt a pair by repeating its attempts.
+//!
+//! As reverted operations leave no state behind, the count lives in the DEX state only. A pair
+//! that is due to halt is halted by the builder calling `haltPair` at the end of the flashblock,
+//! so the halt lands on-chain like any other operation. Batch auctions run into bands within a
+//! committed transaction, and halt the pair right away.
+
+use alloy_primitives::U256;
+
+use super::fees::BPS_DENOMINATOR;
+
+/// Price band protection of the pairs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PriceBands {
+    /// Maximum distance of fills from the reference price, in basis points of the reference
+    /// price. Zero disables price bands
+    pub band_bps: u16,
+    /// Number of consecutive violations within a block after which a pair halts. Zero never
+    /// halts pairs
+    pub max_violations: u32,
+}
+
+impl PriceBands {
+    /// Whether a fill at `price` lies within the band around `reference`, both given as a
+    /// numerator and denominator.
+    ///
+    /// Every price lies within the band if bands are disabled or there is no reference price yet.
+    pub fn contains(&self, reference: (u128, u128), price: (u128, u128)) -> bool {
+        let ((reference_num, reference_denom), (price_num, price_denom)) = (reference, price);
+        if self.band_bps == 0 || reference_num == 0 || reference_denom == 0 || price_denom == 0 {
+            return true;
+        }
+        // |price - reference| <= reference * band, with both sides scaled by both denominators
+        let price = U256::from(price_num) * U256::from(reference_denom);
+        let reference = U256::from(reference_num) * U256::from(price_denom);
+        let distance = price.abs_diff(reference);
+        distance.saturating_mul(U256::from(BPS_DENOMINATOR))
+            <= reference.saturating_mul(U256::from(self.band_bps))
+    }
+
+    /// Whether `violations` consecutive violations halt a pair.
+    pub fn halts(&self, violations: u32) -> bool {
+        self.max_violations != 0 && violations >= self.max_violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_band_around_reference_price() {
+        let bands = PriceBands {
+            band_bps: 500,
+            max_violations: 3,
+        };
+        // 5% around 2 per unit
+        assert!(bands.contains((2, 1), (21, 10)));
+        assert!(bands.contains((4, 2), (19, 10)));
+        assert!(!bands.contains((2, 1), (211, 100)));
+        assert!(!bands.contains((2, 1), (1, 1)));
+        // without a reference price, or with bands disabled, anything goes
+        assert!(bands.contains((0, 0), (1_000, 1)));
+        assert!(PriceBands::default().contains((2, 1), (1_000, 1)));
+
+        assert!(!bands.halts(2));
+        assert!(bands.halts(3));
+        assert!(!PriceBands::default().halts(100));
+    }
+}
@@ -0,0 +1,462 @@
+//! Feed of order book changes and trades for websocket clients.
+//!
+//! Market makers follow the order books through the flashblocks websocket instead of polling the
+//! RPC. For every flashblock, the feed compares the books of the new DEX state with the ones it
+//! published before, and publishes the price levels that were added, modified or removed along
+//! with the trades executed since, one message per pair. Clients subscribe to pairs and first
+//! receive a full snapshot of each book, which the updates are applied to. Every update carries a
+//! sequence number, so that updates already contained in a snapshot can be skipped.
+
+use super::{
+    DexState,
+    api::{RpcBookLevel, RpcTrade, rpc_book_level, rpc_trade},
+    state::BookLevel,
+};
+use alloy_primitives::{B256, U256};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A message sent to subscribers of the DEX feed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DexFeedMessage {
+    /// The complete order book of a pair, as of the update with the given sequence number
+    #[serde(rename_all = "camelCase")]
+    BookSnapshot {
+        pair_id: B256,
+        #[serde(with = "alloy_serde::quantity")]
+        sequence: u64,
+        /// Bids from the highest price down
+        bids: Vec<RpcBookLevel>,
+        /// Asks from the lowest price up
+        asks: Vec<RpcBookLevel>,
+    },
+    /// Changes of the order book of a pair and its trades in a flashblock
+    #[serde(rename_all = "camelCase")]
+    BookUpdate {
+        pair_id: B256,
+        #[serde(with = "alloy_serde::quantity")]
+        sequence: u64,
+        #[serde(with = "alloy_serde::quantity")]
+        block_number: u64,
+        /// Index of the flashblock within the block
+        #[serde(with = "alloy_serde::quantity")]
+        index: u64,
+        bids: BookDelta,
+        asks: BookDelta,
+        trades: Vec<RpcTrade>,
+    },
+}
+
+impl DexFeedMessage {
+    /// Returns the pair the message is about.
+    pub fn pair_id(&self) -> B256 {
+        match self {
+            Self::BookSnapshot { pair_id, .. } | Self::BookUpdate { pair_id, .. } => *pair_id,
+        }
+    }
+
+    /// Returns the sequence number of the message.
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Self::BookSnapshot { sequence, .. } | Self::BookUpdate { sequence, .. } => *sequence,
+        }
+    }
+}
+
+/// Changes of one side of an order book
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookDelta {
+    /// Levels at prices that had no liquidity before
+    pub added: Vec<RpcBookLevel>,
+    /// Levels whose amount changed, with the new amount
+    pub modified: Vec<RpcBookLevel>,
+    /// Levels without liquidity left, with an amount of zero
+    pub removed: Vec<RpcBookLevel>,
+}
+
+impl BookDelta {
+    /// Returns the changes from the levels `before` to the levels `after`.
+    fn between(before: &[BookLevel], after: &[BookLevel]) -> Self {
+        let before: BTreeMap<_, _> = before
+            .iter()
+            .map(|level| (price_key(level), level))
+            .collect();
+        let after: BTreeMap<_, _> = after
+            .iter()
+            .map(|level| (price_key(level), level))
+            .collect();
+
+        let mut delta = Self::default();
+        for (key, level) in &after {
+            match before.get(key) {
+                None => delta.added.push(rpc_book_level(**level)),
+                Some(old) if old.amount != level.amount => {
+                    delta.modified.push(rpc_book_level(**level))
+                }
+                Some(_) => {}
+            }
+        }
+        for (key, level) in &before {
+            if !after.contains_key(key) {
+                delta.removed.push(rpc_book_level(BookLevel {
+                    amount: U256::ZERO,
+                    ..**level
+                }));
+            }
+        }
+        delta
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A request sent by a websocket client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params")]
+pub enum DexFeedRequest {
+    /// Subscribe to the given pairs, or to all pairs if none are given
+    #[serde(rename = "dex_subscribe")]
+    Subscribe(Vec<B256>),
+    /// Unsubscribe from the given pairs, or from all pairs if none are given
+    #[serde(rename = "dex_unsubscribe")]
+    Unsubscribe(Vec<B256>),
+}
+
+/// Order book and trade count of a pair as last published
+#[derive(Debug, Default)]
+struct PublishedPair {
+    bids: Vec<BookLevel>,
+    asks: Vec<BookLevel>,
+    trade_count: u64,
+}
+
+#[derive(Debug, Default)]
+struct FeedInner {
+    /// Sequence number of the last update
+    sequence: u64,
+    /// Pairs as of the last update, `None` until the first DEX state was published
+    pairs: Option<BTreeMap<B256, PublishedPair>>,
+}
+
+/// Turns the DEX states of consecutive flashblocks into order book updates.
+#[derive(Debug, Default)]
+pub struct DexFeed {
+    inner: Mutex<FeedInner>,
+}
+
+impl DexFeed {
+    /// Create a feed that hasn't published anything yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish the DEX state after a flashblock.
+    ///
+    /// Returns an update for every pair whose book changed or that traded since the last call.
+    /// The first state only serves as the base for later updates, as clients start from a
+    /// snapshot anyway.
+    pub fn update(&self, block_number: u64, index: u64, state: &DexState) -> Vec<DexFeedMessage> {
+        let mut books = state.books();
+        let current: BTreeMap<_, _> = state
+            .pairs()
+            .map(|(pair_id, pair)| {
+                let (bids, asks) = books.remove(&pair_id).unwrap_or_default();
+                let published = PublishedPair {
+                    bids,
+                    asks,
+                    trade_count: pair.stats.trade_count,
+                };
+                (pair_id, published)
+            })
+            .collect();
+
+        let mut inner = self.inner.lock();
+        let Some(previous) = inner.pairs.replace(current) else {
+            return Vec::new();
+        };
+        inner.sequence += 1;
+        let sequence = inner.sequence;
+        let current = inner.pairs.as_ref().expect("pairs were just published");
+
+        let mut messages = Vec::new();
+        for (pair_id, pair) in current {
+            let before = previous.get(pair_id);
+            let (bids, asks, trade_count) = before.map_or((&[][..], &[][..], 0), |before| {
+                (&before.bids[..], &before.asks[..], before.trade_count)
+            });
+            // The newest trades of the pair are the ones since the last update
+            let new_trades = pair.trade_count.saturating_sub(trade_count) as usize;
+            let trades: Vec<RpcTrade> = state
+                .pair(*pair_id)
+                .map(|record| {
+                    let recent = &record.stats.recent_trades;
+                    recent
+                        .iter()
+                        .skip(recent.len().saturating_sub(new_trades))
+                        .map(|trade| rpc_trade(*pair_id, trade))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let bids = BookDelta::between(bids, &pair.bids);
+            let asks = BookDelta::between(asks, &pair.asks);
+            if before.is_some() && bids.is_empty() && asks.is_empty() && trades.is_empty() {
+                continue;
+            }
+            messages.push(DexFeedMessage::BookUpdate {
+                pair_id: *pair_id,
+                sequence,
+                block_number,
+                index,
+                bids,
+                asks,
+                trades,
+            });
+        }
+
+        // Pairs only disappear if the flashblocks that created them were reorged out
+        for (pair_id, pair) in previous {
+            if !current.contains_key(&pair_id) {
+                messages.push(DexFeedMessage::BookUpdate {
+                    pair_id,
+                    sequence,
+                    block_number,
+                    index,
+                    bids: BookDelta::between(&pair.bids, &[]),
+                    asks: BookDelta::between(&pair.asks, &[]),
+                    trades: Vec::new(),
+                });
+            }
+        }
+        messages
+    }
+
+    /// Returns snapshots of the books of the given pairs, or of all pairs if `pairs` is `None`,
+    /// along with the sequence number of the last update they contain. Pairs that don't exist
+    /// yet have an empty book.
+    pub fn snapshot(&self, pairs: Option<&[B256]>) -> (u64, Vec<DexFeedMessage>) {
+        let inner = self.inner.lock();
+        let empty = BTreeMap::new();
+        let published = inner.pairs.as_ref().unwrap_or(&empty);
+        let snapshot = |pair_id: B256| {
+            let (bids, asks) = published
+                .get(&pair_id)
+                .map_or((&[][..], &[][..]), |pair| (&pair.bids[..], &pair.asks[..]));
+            DexFeedMessage::BookSnapshot {
+                pair_id,
+                sequence: inner.sequence,
+                bids: bids.iter().copied().map(rpc_book_level).collect(),
+                asks: asks.iter().copied().map(rpc_book_level).collect(),
+            }
+        };
+        let snapshots = match pairs {
+            Some(pairs) => pairs.iter().copied().map(snapshot).collect(),
+            None => published.keys().copied().map(snapshot).collect(),
+        };
+        (inner.sequence, snapshots)
+    }
+}
+
+/// Pairs a websocket client subscribed to, with the sequence number of the snapshot it received
+#[derive(Debug, Default)]
+pub struct DexSubscription {
+    /// Set if the client subscribed to all pairs
+    all: Option<u64>,
+    /// Pairs the client subscribed to individually
+    pairs: BTreeMap<B256, u64>,
+}
+
+impl DexSubscription {
+    /// Apply a request of the client, returning the snapshots to send to it.
+    pub fn handle(&mut self, feed: &DexFeed, request: DexFeedRequest) -> Vec<DexFeedMessage> {
+        match request {
+            DexFeedRequest::Subscribe(pairs) if pairs.is_empty() => {
+                let (sequence, snapshots) = feed.snapshot(None);
+                self.all = Some(sequence);
+                // Individual subscriptions are covered by the new snapshots
+                self.pairs.clear();
+                snapshots
+            }
+            DexFeedRequest::Subscribe(pairs) => {
+                let (sequence, snapshots) = feed.snapshot(Some(&pairs));
+                self.pairs
+                    .extend(pairs.into_iter().map(|pair_id| (pair_id, sequence)));
+                snapshots
+            }
+            DexFeedRequest::Unsubscribe(pairs) if pairs.is_empty() => {
+                *self = Self::default();
+                Vec::new()
+            }
+            DexFeedRequest::Unsubscribe(pairs) => {
+                for pair_id in pairs {
+                    self.pairs.remove(&pair_id);
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Send new snapshots of all subscribed pairs, after the client missed updates.
+    pub fn resync(&mut self, feed: &DexFeed) -> Vec<DexFeedMessage> {
+        let pairs: Vec<_> = self.pairs.keys().copied().collect();
+        let mut snapshots = Vec::new();
+        if self.all.is_some() {
+            snapshots = self.handle(feed, DexFeedRequest::Subscribe(Vec::new()));
+        }
+        if !pairs.is_empty() {
+            snapshots.extend(self.handle(feed, DexFeedRequest::Subscribe(pairs)));
+        }
+        snapshots
+    }
+
+    /// Whether the client has to receive the update of a pair with the given sequence number.
+    pub fn wants(&self, pair_id: B256, sequence: u64) -> bool {
+        self.pairs
+            .get(&pair_id)
+            .or(self.all.as_ref())
+            .is_some_and(|snapshot| sequence > *snapshot)
+    }
+}
+
+/// Returns the price of a level as a reduced fraction, so that equal prices compare equal.
+fn price_key(level: &BookLevel) -> (u128, u128) {
+    let (mut a, mut b) = (level.price_num, level.price_denom);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let gcd = a.max(1);
+    (level.price_num / gcd, level.price_denom / gcd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dex::{DexHandler, predeploy::selectors};
+    use alloy_primitives::{Address, Bytes, address};
+    use alloy_sol_types::SolValue;
+
+    #[test]
+    fn test_book_updates_follow_snapshot() {
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let maker = address!("00000000000000000000000000000000000000aa");
+        let taker = address!("00000000000000000000000000000000000000bb");
+        let handler = DexHandler::new();
+        let call = |caller: Address, calldata: Vec<u8>, value: U256| {
+            handler
+                .handle_transaction(caller, &Bytes::from(calldata), value)
+                .unwrap();
+        };
+        let feed = DexFeed::new();
+
+        call(
+            Address::ZERO,
+            [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()].concat(),
+            U256::ZERO,
+        );
+        assert!(feed.update(5, 0, &handler.snapshot()).is_empty());
+        let pair_id = handler.snapshot().pairs().next().unwrap().0;
+
+        let mut subscription = DexSubscription::default();
+        let snapshots = subscription.handle(&feed, DexFeedRequest::Subscribe(vec![pair_id]));
+        assert_eq!(
+            snapshots,
+            vec![DexFeedMessage::BookSnapshot {
+                pair_id,
+                sequence: 0,
+                bids: Vec::new(),
+                asks: Vec::new(),
+            }]
+        );
+
+        // sell 10 ETH at 2 USDC each
+        handler.set_block(5, 1_000);
+        call(
+            maker,
+            [
+                selectors::PLACE_LIMIT_ORDER.as_slice(),
+                &(
+                    eth,
+                    usdc,
+                    false,
+                    U256::from(10),
+                    U256::from(2),
+                    U256::from(1),
+                )
+                    .abi_encode(),
+            ]
+            .concat(),
+            U256::from(10),
+        );
+        let messages = feed.update(5, 1, &handler.snapshot());
+        let [DexFeedMessage::BookUpdate { asks, trades, .. }] = &messages[..] else {
+            panic!("expected a single update, got {messages:?}");
+        };
+        assert!(subscription.wants(pair_id, messages[0].sequence()));
+        assert_eq!(asks.added.len(), 1);
+        assert_eq!(asks.added[0].amount, U256::from(10));
+        assert!(trades.is_empty());
+
+        // nothing changed, so nothing is published
+        assert!(feed.update(5, 2, &handler.snapshot()).is_empty());
+
+        call(
+            taker,
+            [
+                selectors::SWAP.as_slice(),
+                &(usdc, eth, U256::from(8), U256::ZERO).abi_encode(),
+            ]
+            .concat(),
+            U256::ZERO,
+        );
+        let messages = feed.update(5, 3, &handler.snapshot());
+        let [DexFeedMessage::BookUpdate { asks, trades, .. }] = &messages[..] else {
+            panic!("expected a single update, got {messages:?}");
+        };
+        assert_eq!(asks.modified.len(), 1);
+        assert_eq!(asks.modified[0].amount, U256::from(6));
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].amount0, U256::from(4));
+
+        // a later subscriber receives the current book and skips the updates it contains
+        let mut late = DexSubscription::default();
+        let snapshots = late.handle(&feed, DexFeedRequest::Subscribe(Vec::new()));
+        let [DexFeedMessage::BookSnapshot { sequence, asks, .. }] = &snapshots[..] else {
+            panic!("expected a single snapshot, got {snapshots:?}");
+        };
+        assert_eq!(*sequence, messages[0].sequence());
+        assert_eq!(asks[0].amount, U256::from(6));
+        assert!(!late.wants(pair_id, messages[0].sequence()));
+        assert!(late.wants(pair_id, messages[0].sequence() + 1));
+
+        late.handle(&feed, DexFeedRequest::Unsubscribe(Vec::new()));
+        assert!(!late.wants(pair_id, u64::MAX));
+    }
+
+    #[test]
+    fn test_requests_are_parsed_from_json() {
+        let request: DexFeedRequest =
+            serde_json::from_str(r#"{"method":"dex_subscribe","params":[]}"#).unwrap();
+        assert_eq!(request, DexFeedRequest::Subscribe(Vec::new()));
+
+        let pair_id = B256::repeat_byte(1);
+        let json = format!(r#"{{"method":"dex_unsubscribe","params":["{pair_id}"]}}"#);
+        let request: DexFeedRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(request, DexFeedRequest::Unsubscribe(vec![pair_id]));
+    }
+
+    #[test]
+    fn test_equal_prices_share_a_key() {
+        let level = |price_num, price_denom| BookLevel {
+            price_num,
+            price_denom,
+            amount: U256::ZERO,
+        };
+        assert_eq!(price_key(&level(4, 2)), price_key(&level(2, 1)));
+        assert_ne!(price_key(&level(3, 2)), price_key(&level(2, 1)));
+    }
+}
@@ -0,0 +1,141 @@
+//! Protocol fees charged on DEX trades.
+//!
+//! Every fill of a resting order charges the maker fee of its pair on what the maker receives, and
+//! the taker fee on what the incoming order or swap receives, so fees are always collected in the
+//! token that was traded. The fees an operation collects are paid out at its end, to the treasury
+//! if one is configured and to the fee recipient of the block otherwise.
+
+use alloy_primitives::{Address, U256};
+use std::{collections::BTreeMap, str::FromStr};
+
+/// Fees are given in basis points of the traded amount
+pub const BPS_DENOMINATOR: u16 = 10_000;
+
+/// Maker and taker fees of a pair, in basis points.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PairFees {
+    /// Fee charged on what resting orders receive when they are filled
+    pub maker_bps: u16,
+    /// Fee charged on what incoming orders and swaps receive
+    pub taker_bps: u16,
+}
+
+impl PairFees {
+    /// Returns the maker fee on `amount`, rounded down.
+    pub fn maker_fee(&self, amount: U256) -> U256 {
+        fee(amount, self.maker_bps)
+    }
+
+    /// Returns the taker fee on `amount`, rounded down.
+    pub fn taker_fee(&self, amount: U256) -> U256 {
+        fee(amount, self.taker_bps)
+    }
+}
+
+/// The fees of every pair and where they are paid to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    /// Fees of the pairs without fees of their own
+    pub default: PairFees,
+    /// Fees of individual pairs, by the tokens of the pair in either order
+    pub pairs: BTreeMap<(Address, Address), PairFees>,
+    /// Account the fees are paid to, the fee recipient of the block if `None`
+    pub treasury: Option<Address>,
+}
+
+impl FeeSchedule {
+    /// Returns the fees of the pair of `token0` and `token1`.
+    pub fn pair_fees(&self, token0: Address, token1: Address) -> PairFees {
+        self.pairs
+            .get(&(token0, token1))
+            .or_else(|| self.pairs.get(&(token1, token0)))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Returns the account collected fees are paid to in a block with the given fee recipient.
+    pub fn recipient(&self, beneficiary: Address) -> Address {
+        self.treasury.unwrap_or(beneficiary)
+    }
+}
+
+/// Fees of a single pair as given on the command line, `<token>:<token>:<maker bps>:<taker bps>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairFeesArg {
+    /// One token of the pair
+    pub token_a: Address,
+    /// The other token of the pair
+    pub token_b: Address,
+    /// Fees of the pair
+    pub fees: PairFees,
+}
+
+impl FromStr for PairFeesArg {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [token_a, token_b, maker_bps, taker_bps] = s
+            .split(':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| eyre::eyre!("expected <token>:<token>:<maker bps>:<taker bps>"))?;
+        let fees = PairFees {
+            maker_bps: parse_bps(maker_bps)?,
+            taker_bps: parse_bps(taker_bps)?,
+        };
+        Ok(Self {
+            token_a: token_a.parse()?,
+            token_b: token_b.parse()?,
+            fees,
+        })
+    }
+}
+
+/// Parse a fee in basis points, which can't exceed the traded amount.
+pub fn parse_bps(s: &str) -> eyre::Result<u16> {
+    let bps: u16 = s.parse()?;
+    eyre::ensure!(
+        bps <= BPS_DENOMINATOR,
+        "fee of {bps} bps exceeds {BPS_DENOMINATOR} bps"
+    );
+    Ok(bps)
+}
+
+fn fee(amount: U256, bps: u16) -> U256 {
+    amount.saturating_mul(U256::from(bps)) / U256::from(BPS_DENOMINATOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::address;
+
+    #[test]
+    fn test_pair_fees_fall_back_to_default() {
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let dai = address!("0000000000000000000000000000000000000002");
+        let arg: PairFeesArg = format!("{usdc}:{eth}:5:30").parse().unwrap();
+        let schedule = FeeSchedule {
+            default: PairFees {
+                maker_bps: 0,
+                taker_bps: 10,
+            },
+            pairs: [((arg.token_a, arg.token_b), arg.fees)].into(),
+            treasury: None,
+        };
+
+        let fees = schedule.pair_fees(eth, usdc);
+        assert_eq!((fees.maker_bps, fees.taker_bps), (5, 30));
+        assert_eq!(fees.taker_fee(U256::from(1_000)), U256::from(3));
+        assert_eq!(fees.maker_fee(U256::from(1_999)), U256::ZERO);
+        assert_eq!(schedule.pair_fees(eth, dai), schedule.default);
+
+        assert!("0x01:0x02:5".parse::<PairFeesArg>().is_err());
+        assert!(
+            format!("{usdc}:{eth}:5:10001")
+                .parse::<PairFeesArg>()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Gas schedule of DEX operations.
+//!
+//! Operations are charged for the work they actually do rather than a flat fee per operation, so
+//! a swap walking hundreds of resting orders pays for each of them. The costs are modelled after
+//! the EVM operations a contract doing the same work would need: reading and writing the storage
+//! slots of the orders involved, and calling out to the tokens that have to be moved.
+
+use super::{
+    settlement::{NATIVE_TOKEN, Transfer},
+    state::DexWork,
+    storage,
+};
+use revm::interpreter::gas::COLD_ACCOUNT_ACCESS_COST;
+
+/// Charged for every call to the DEX, including calls that revert
+pub const CALL_GAS: u64 = 2_600;
+
+/// Charged per resting order read, a warm read of each of its slots
+pub const ORDER_READ_GAS: u64 = 800;
+
+/// Charged per fill of a resting order for matching it
+pub const ORDER_FILL_GAS: u64 = 5_000;
+
+/// Charged per pair an order is routed through
+pub const HOP_GAS: u64 = 10_000;
+
+/// Charged per predeploy storage slot written
+pub const SLOT_WRITE_GAS: u64 = 5_000;
+
+/// Charged per ETH transfer out of the predeploy
+pub const NATIVE_TRANSFER_GAS: u64 = 9_000;
+
+/// Gas limit of the call to the token settling an ERC-20 transfer, which is charged the gas the
+/// call actually uses
+pub const TOKEN_TRANSFER_GAS_LIMIT: u64 = 100_000;
+
+/// Charged per order signature verified, the cost of the `ecrecover` precompile
+pub const SIGNATURE_GAS: u64 = 3_000;
+
+/// Charged per entry of the DEX state copied before an operation could write to it, like a word
+/// copied by `MCOPY`
+pub const ENTRY_COPY_GAS: u64 = 3;
+
+/// Returns the gas of a DEX operation that did `work` and has to settle `transfers`.
+///
+/// The calls to tokens settling ERC-20 transfers are not included, they are charged the gas they
+/// use on top.
+pub fn operation_gas(work: &DexWork, transfers: &[Transfer]) -> u64 {
+    let transfer_gas = transfers
+        .iter()
+        .filter(|transfer| transfer.token == NATIVE_TOKEN)
+        .map(|_| NATIVE_TRANSFER_GAS)
+        .fold(0u64, u64::saturating_add);
+
+    CALL_GAS
+        .saturating_add(work.orders_read.saturating_mul(ORDER_READ_GAS))
+        .saturating_add(work.orders_filled.saturating_mul(ORDER_FILL_GAS))
+        .saturating_add(work.hops.saturating_mul(HOP_GAS))
+        .saturating_add(work.slots_written.saturating_mul(SLOT_WRITE_GAS))
+        .saturating_add(work.signatures.saturating_mul(SIGNATURE_GAS))
+        .saturating_add(work.entries_copied.saturating_mul(ENTRY_COPY_GAS))
+        .saturating_add(transfer_gas)
+}
+
+/// Returns an upper bound of the gas of a DEX operation that did `work` and has to settle
+/// `transfers`, with every call to a token accessing a cold account and using up its gas limit.
+pub fn max_operation_gas(work: &DexWork, transfers: &[Transfer]) -> u64 {
+    let token_calls = transfers
+        .iter()
+        .filter(|transfer| transfer.token != NATIVE_TOKEN)
+        .count() as u64;
+    operation_gas(work, transfers).saturating_add(
+        token_calls.saturating_mul(COLD_ACCOUNT_ACCESS_COST + TOKEN_TRANSFER_GAS_LIMIT),
+    )
+}
+
+/// Returns an upper bound of the gas of sweeping `orders` expired orders off the book.
+///
+/// Each order is read, zeroed out in storage and refunded its escrow.
+pub fn sweep_gas(orders: usize) -> u64 {
+    let per_order = ORDER_READ_GAS
+        .saturating_add((storage::ORDER_FIELDS as u64).saturating_mul(SLOT_WRITE_GAS))
+        .saturating_add(COLD_ACCOUNT_ACCESS_COST + TOKEN_TRANSFER_GAS_LIMIT);
+    CALL_GAS.saturating_add((orders as u64).saturating_mul(per_order))
+}
@@ -0,0 +1,202 @@
+//! Pairs the DEX starts out with.
+//!
+//! Chains can list pairs from their very first block on, instead of having somebody call
+//! `createPair` for each of them. The pairs are either read from a JSON or TOML file given with
+//! `--dex.genesis-pairs`, or from the `dex` entry of the `config` section of the chain spec's
+//! genesis:
+//!
+//! ```json
+//! { "pairs": [{ "token0": "0x0000000000000000000000000000000000000000", "token1": "0x..." }] }
+//! ```
+//!
+//! Pairs may also give the metadata of their tokens and their tick and lot sizes, with the same
+//! fields as `listPair` takes, which default to those of pairs created with `createPair`. See
+//! [`listing`](super::listing).
+//!
+//! Genesis pairs are created when the DEX state is rebuilt from genesis, in the order they are
+//! listed, so every node of the chain has to start out with the same ones. They are written to
+//! the predeploy storage along with the changes of the first transaction calling the DEX, which
+//! isn't charged for them.
+
+use super::{
+    DexError, DexState,
+    listing::{DEFAULT_DECIMALS, PairSpec},
+};
+use alloy_primitives::{Address, U256};
+use alloy_serde::OtherFields;
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+/// Key of the genesis pairs in the `config` section of the chain spec's genesis
+pub const CHAIN_CONFIG_KEY: &str = "dex";
+
+/// A pair created at genesis.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct GenesisPair {
+    /// First token of the pair
+    pub token0: Address,
+    /// Second token of the pair
+    pub token1: Address,
+    /// Decimals of `token0`
+    #[serde(default = "default_decimals")]
+    pub decimals0: u8,
+    /// Decimals of `token1`
+    #[serde(default = "default_decimals")]
+    pub decimals1: u8,
+    /// Symbol of `token0`
+    #[serde(default)]
+    pub symbol0: String,
+    /// Symbol of `token1`
+    #[serde(default)]
+    pub symbol1: String,
+    /// Price increment of orders, zero allows any price
+    #[serde(default)]
+    pub tick_size: U256,
+    /// Amount increment of orders, zero allows any amount
+    #[serde(default)]
+    pub lot_size: U256,
+}
+
+impl GenesisPair {
+    /// Returns the token metadata and trading granularity the pair is listed with.
+    pub fn spec(&self) -> PairSpec {
+        PairSpec {
+            decimals0: self.decimals0,
+            decimals1: self.decimals1,
+            symbol0: self.symbol0.clone(),
+            symbol1: self.symbol1.clone(),
+            tick_size: self.tick_size,
+            lot_size: self.lot_size,
+        }
+    }
+}
+
+fn default_decimals() -> u8 {
+    DEFAULT_DECIMALS
+}
+
+/// Genesis configuration of the DEX.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DexGenesis {
+    /// Pairs to create, in order
+    #[serde(default)]
+    pub pairs: Vec<GenesisPair>,
+}
+
+impl DexGenesis {
+    /// Read the genesis configuration from a TOML file if `path` ends in `.toml`, and from a JSON
+    /// file otherwise.
+    pub fn from_file(path: &Path) -> eyre::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            Ok(toml::from_str(&contents)?)
+        } else {
+            Ok(serde_json::from_str(&contents)?)
+        }
+    }
+
+    /// Read the genesis configuration from the extra fields of the `config` section of the chain
+    /// spec's genesis, which lists no pairs if it has no [`CHAIN_CONFIG_KEY`] entry.
+    pub fn from_chain_config(extra_fields: &OtherFields) -> eyre::Result<Self> {
+        Ok(extra_fields
+            .get_deserialized(CHAIN_CONFIG_KEY)
+            .transpose()?
+            .unwrap_or_default())
+    }
+}
+
+/// Returns the DEX state at genesis, holding the given pairs.
+///
+/// Pairs are listed regardless of the listing policy. Fails if a pair is listed twice or with an
+/// invalid spec.
+pub fn genesis_state(pairs: &[GenesisPair]) -> Result<DexState, DexError> {
+    let mut state = DexState::new();
+    for pair in pairs {
+        state.create_pair(pair.token0, pair.token1, pair.spec())?;
+    }
+    // the pairs are part of the chain, not of the transaction that happens to write them
+    state.take_work();
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dex::state::DexWork;
+    use alloy_primitives::address;
+
+    #[test]
+    fn test_genesis_pairs_from_json_toml_and_chain_config() {
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let expected = DexGenesis {
+            pairs: vec![GenesisPair {
+                token0: eth,
+                token1: usdc,
+                decimals0: DEFAULT_DECIMALS,
+                decimals1: 6,
+                symbol0: String::new(),
+                symbol1: "USDC".to_string(),
+                tick_size: U256::from(1000),
+                lot_size: U256::ZERO,
+            }],
+        };
+
+        let dir = std::env::temp_dir().join(format!("dex-genesis-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("pairs.json");
+        fs::write(
+            &json,
+            format!(
+                r#"{{"pairs":[{{"token0":"{eth}","token1":"{usdc}","decimals1":6,"symbol1":"USDC","tickSize":"0x3e8"}}]}}"#
+            ),
+        )
+        .unwrap();
+        let toml = dir.join("pairs.toml");
+        fs::write(
+            &toml,
+            format!(
+                "[[pairs]]\ntoken0 = \"{eth}\"\ntoken1 = \"{usdc}\"\ndecimals1 = 6\nsymbol1 = \"USDC\"\ntickSize = \"0x3e8\"\n"
+            ),
+        )
+        .unwrap();
+        assert_eq!(DexGenesis::from_file(&json).unwrap(), expected);
+        assert_eq!(DexGenesis::from_file(&toml).unwrap(), expected);
+        fs::remove_dir_all(dir).unwrap();
+
+        let mut extra_fields = OtherFields::default();
+        assert_eq!(
+            DexGenesis::from_chain_config(&extra_fields).unwrap(),
+            DexGenesis::default()
+        );
+        extra_fields.insert(
+            CHAIN_CONFIG_KEY.to_string(),
+            serde_json::json!({ "pairs": [{
+                "token0": eth,
+                "token1": usdc,
+                "decimals1": 6,
+                "symbol1": "USDC",
+                "tickSize": "0x3e8",
+            }] }),
+        );
+        assert_eq!(
+            DexGenesis::from_chain_config(&extra_fields).unwrap(),
+            expected
+        );
+
+        let mut state = genesis_state(&expected.pairs).unwrap();
+        let pair_id = state.pair_id(eth, usdc).unwrap();
+        assert_eq!(state.pair(pair_id).unwrap().spec, expected.pairs[0].spec());
+        assert_eq!(state.take_work(), DexWork::default());
+        let duplicate = [expected.pairs.clone(), expected.pairs].concat();
+        assert!(matches!(
+            genesis_state(&duplicate),
+            Err(DexError::PairAlreadyExists)
+        ));
+    }
+}
@@ -2,7 +2,15 @@
 ///
 /// This module handles transactions sent to the DEX predeploy address,
 /// decoding calldata and executing operations on the enshrined DEX.
-use super::{predeploy::selectors, types::*};
+use super::{
+    gas::max_operation_gas,
+    listing::PairSpec,
+    oracle::PRICE_SCALE,
+    predeploy::selectors,
+    settlement::{NATIVE_TOKEN, Transfer},
+    state::{BookLevel, DexState, DexWork, FlashblockPosition, OrderRecord},
+    types::*,
+};
 use alloy_primitives::{Address, Bytes, B256, U256};
 use alloy_sol_types::SolValue;
 use dex::{OrderSide, PoolManager, Price};
@@ -11,9 +19,16 @@ use parking_lot::RwLock;
 use std::sync::Arc;
 
 /// Handler for enshrined DEX operations
+///
+/// Each payload job works on its own handler created by the
+/// [`DexJournal`](super::DexJournal), so operations are only visible to the job that executed
+/// them. The handler keeps a checkpoint of the state as of the last sealed flashblock, which
+/// work that never makes it into a flashblock is rolled back to.
 pub struct DexHandler {
-    /// The underlying pool manager from enshrined-dex
-    pool_manager: Arc<RwLock<PoolManager>>,
+    /// The working DEX state
+    state: Arc<RwLock<DexState>>,
+    /// The DEX state as of the last checkpoint
+    checkpoint: Arc<RwLock<DexState>>,
 }
 
 impl std::fmt::Debug for DexHandler {
@@ -26,21 +41,193 @@ impl std::fmt::Debug for DexHandler {
 impl DexHandler {
     /// Create a new DexHandler with a fresh PoolManager
     pub fn new() -> Self {
-        Self {
-            pool_manager: Arc::new(RwLock::new(PoolManager::new())),
-        }
+        Self::from_state(DexState::new())
     }
 
     /// Create a DexHandler from an existing PoolManager
     pub fn from_pool_manager(pool_manager: PoolManager) -> Self {
+        Self::from_state(DexState::from_pool_manager(pool_manager))
+    }
+
+    /// Create a DexHandler working on the given state, which is also the initial checkpoint
+    pub fn from_state(state: DexState) -> Self {
         Self {
-            pool_manager: Arc::new(RwLock::new(pool_manager)),
+            checkpoint: Arc::new(RwLock::new(state.clone())),
+            state: Arc::new(RwLock::new(state)),
         }
     }
 
-    /// Get a reference to the pool manager (for inspection/queries)
-    pub fn pool_manager(&self) -> Arc<RwLock<PoolManager>> {
-        Arc::clone(&self.pool_manager)
+    /// Returns a copy of the current working state
+    pub fn snapshot(&self) -> DexState {
+        self.state.read().clone()
+    }
+
+    /// Mark the current working state as the state to roll back to
+    pub fn checkpoint(&self) {
+        *self.checkpoint.write() = self.snapshot();
+    }
+
+    /// Discard every operation executed since the last checkpoint
+    pub fn revert_to_checkpoint(&self) {
+        *self.state.write() = self.checkpoint.read().clone();
+    }
+
+    /// Replace the working state, e.g. with the DEX state left behind by a transaction once the
+    /// transaction is committed
+    pub fn restore(&self, state: DexState) {
+        *self.state.write() = state;
+    }
+
+    /// Set the block subsequent operations are executed in
+    pub fn set_block(&self, block_number: u64, timestamp: u64) {
+        self.state.write().set_block(block_number, timestamp);
+    }
+
+    /// Set the fee recipient of the block subsequent operations are executed in
+    pub fn set_beneficiary(&self, beneficiary: Address) {
+        self.state.write().set_beneficiary(beneficiary);
+    }
+
+    /// Limit sweeps to flashblock `flashblock_index` of block `block_number`, the flashblock the
+    /// builder is building
+    pub fn set_flashblock_limit(&self, block_number: u64, flashblock_index: u64) {
+        self.state.write().set_flashblock_limit(FlashblockPosition {
+            block_number,
+            flashblock_index,
+        });
+    }
+
+    /// Returns the number of resting orders that are expired at the start of flashblock
+    /// `flashblock_index` of block `block_number`
+    pub fn expired_order_count(&self, block_number: u64, flashblock_index: u64) -> usize {
+        self.state
+            .read()
+            .expired_orders(FlashblockPosition {
+                block_number,
+                flashblock_index,
+            })
+            .count()
+    }
+
+    /// Returns the last nonce of `maker` that was used by a signed order
+    pub fn nonce(&self, maker: Address) -> u64 {
+        self.state.read().nonce(maker)
+    }
+
+    /// Whether the listing policy lets pairs of `token` be created, given whether its account has
+    /// code
+    pub fn may_list_token(&self, token: Address, has_code: bool) -> bool {
+        self.state
+            .read()
+            .listing_policy()
+            .may_list_token(token, has_code)
+    }
+
+    /// Returns the address the DEX is installed at
+    pub fn address(&self) -> Address {
+        self.state.read().address()
+    }
+
+    /// Count a price band violation of the pair with the given id by a transaction of `sender`,
+    /// which outlives the operation running into the band being reverted. Each sender counts once
+    /// per pair until the count starts over. Returns whether the pair is due to halt
+    pub(crate) fn record_band_violation(&self, sender: Address, pair_id: B256) -> bool {
+        self.state.write().record_band_violation_of(sender, pair_id)
+    }
+
+    /// Returns the gas of clearing the orders collected since the last batch auction, or `None` if
+    /// no orders were collected, nobody may clear them or clearing them fails
+    pub fn batch_clearing_gas(&self) -> Option<u64> {
+        let clearer = {
+            let state = self.state.read();
+            if state.batch_len() == 0 {
+                return None;
+            }
+            state.builder()?
+        };
+        self.estimate_gas(clearer, &selectors::CLEAR_BATCH.to_vec().into(), U256::ZERO)
+    }
+
+    /// Returns the gas of halting the pair with the given id, or `None` if the pair isn't due to
+    /// halt, nobody may halt it or halting fails
+    pub fn halt_gas(&self, pair_id: B256) -> Option<u64> {
+        let (builder, calldata) = {
+            let state = self.state.read();
+            let pair = state.pair(pair_id)?;
+            let params = (pair.token0, pair.token1).abi_encode();
+            let calldata = [selectors::HALT_PAIR.as_slice(), &params].concat();
+            (state.builder()?, calldata)
+        };
+        self.estimate_gas(builder, &calldata.into(), U256::ZERO)
+    }
+
+    /// Returns the most gas the DEX would charge `caller` for calling it with `calldata` and
+    /// `value` on the current state, or `None` if the call fails
+    pub fn estimate_gas(&self, caller: Address, calldata: &Bytes, value: U256) -> Option<u64> {
+        // Execute on a copy with nothing pending, so only the work of the call is accounted for
+        let mut state = self.snapshot();
+        state.take_work();
+        state.take_transfers();
+        state.take_storage_changes();
+        let trial = Self::from_state(state);
+        trial.handle_transaction(caller, calldata, value).ok()?;
+        trial.pay_fees();
+        let transfers = trial.take_transfers();
+        Some(max_operation_gas(&trial.take_work(), &transfers))
+    }
+
+    /// Pay out the fees charged by the operations executed since the last call, returning the
+    /// transfers paying them, which are part of the [transfers](Self::take_transfers) as well
+    pub(crate) fn pay_fees(&self) -> Vec<Transfer> {
+        self.state.write().pay_fees()
+    }
+
+    /// Returns the token movements required by the operations executed since the last call
+    pub(crate) fn take_transfers(&self) -> Vec<Transfer> {
+        self.state.write().take_transfers()
+    }
+
+    /// Returns the work done by the operations executed since the last call
+    pub(crate) fn take_work(&self) -> DexWork {
+        self.state.write().take_work()
+    }
+
+    /// Returns the predeploy storage slots changed by the operations executed since the last
+    /// call, to be committed into the EVM state.
+    pub(crate) fn take_storage_changes(&self) -> Vec<(U256, U256)> {
+        self.state.write().take_storage_changes()
+    }
+
+    /// Quote swapping `amount_in` of `token_in` into `token_out` on the current state
+    ///
+    /// # Returns
+    /// * `Ok((amount_out, route))` - The amount the swap would return and the ids of the pairs it
+    ///   would be routed through
+    /// * `Err(DexError)` - If there is no route or not enough liquidity
+    pub fn quote(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+    ) -> Result<(U256, Vec<B256>), DexError> {
+        let result = self
+            .state
+            .read()
+            .pool_manager()
+            .get_quote(token_in, token_out, amount_in)
+            .map_err(DexError::from)?;
+
+        // Convert route to B256 array (extract pair IDs from hops)
+        let route = result
+            .route
+            .hops
+            .iter()
+            .map(|hop| {
+                let pair_id = hop.pair.id();
+                B256::from_slice(&pair_id.0)
+            })
+            .collect();
+        Ok((result.amount_out, route))
     }
 
     /// Handle a transaction to the DEX predeploy
@@ -71,14 +258,59 @@ impl DexHandler {
             s if s == selectors::CREATE_PAIR.as_slice() => {
                 self.handle_create_pair(caller, &calldata[4..])
             }
+            s if s == selectors::LIST_PAIR.as_slice() => {
+                self.handle_list_pair(caller, &calldata[4..])
+            }
             s if s == selectors::PLACE_LIMIT_ORDER.as_slice() => {
-                self.handle_place_limit_order(caller, &calldata[4..], value)
+                self.handle_place_order(caller, &calldata[4..], value, TimeInForce::GoodTillCancel)
+            }
+            s if s == selectors::PLACE_LIMIT_ORDER_WITH_EXPIRY.as_slice() => {
+                self.handle_place_order(caller, &calldata[4..], value, TimeInForce::GoodTillExpiry)
+            }
+            s if s == selectors::PLACE_IOC_ORDER.as_slice() => self.handle_place_order(
+                caller,
+                &calldata[4..],
+                value,
+                TimeInForce::ImmediateOrCancel,
+            ),
+            s if s == selectors::PLACE_FOK_ORDER.as_slice() => {
+                self.handle_place_order(caller, &calldata[4..], value, TimeInForce::FillOrKill)
+            }
+            s if s == selectors::PLACE_POST_ONLY_ORDER.as_slice() => {
+                self.handle_place_order(caller, &calldata[4..], value, TimeInForce::PostOnly)
+            }
+            s if s == selectors::SUBMIT_SIGNED_ORDER.as_slice() => {
+                self.handle_submit_signed_order(&calldata[4..], value)
             }
             s if s == selectors::CANCEL_ORDER.as_slice() => {
                 self.handle_cancel_order(caller, &calldata[4..])
             }
+            s if s == selectors::SWEEP_EXPIRED_ORDERS.as_slice() => {
+                self.handle_sweep_expired_orders(&calldata[4..])
+            }
+            s if s == selectors::CLEAR_BATCH.as_slice() => self.handle_clear_batch(caller),
+            s if s == selectors::HALT_PAIR.as_slice() => {
+                self.handle_halt_pair(caller, &calldata[4..])
+            }
+            s if s == selectors::RESUME_PAIR.as_slice() => {
+                self.handle_resume_pair(caller, &calldata[4..])
+            }
             s if s == selectors::SWAP.as_slice() => self.handle_swap(caller, &calldata[4..], value),
             s if s == selectors::GET_QUOTE.as_slice() => self.handle_get_quote(&calldata[4..]),
+            s if s == selectors::GET_ORDERBOOK_DEPTH.as_slice() => {
+                self.handle_get_orderbook_depth(&calldata[4..])
+            }
+            s if s == selectors::GET_PAIR_STATS.as_slice() => {
+                self.handle_get_pair_stats(&calldata[4..])
+            }
+            s if s == selectors::GET_TWAP.as_slice() => self.handle_get_twap(&calldata[4..]),
+            s if s == selectors::GET_USER_ORDERS.as_slice() => {
+                self.handle_get_user_orders(&calldata[4..])
+            }
+            s if s == selectors::GET_ORDER.as_slice() => self.handle_get_order(&calldata[4..]),
+            s if s == selectors::GET_PAIR_LISTING.as_slice() => {
+                self.handle_get_pair_listing(&calldata[4..])
+            }
             _ => Err(DexError::InvalidCalldata(format!(
                 "unknown function selector: 0x{}",
                 hex::encode(selector)
@@ -87,43 +319,138 @@ impl DexHandler {
     }
 
     /// Handle createPair(address,address)
-    fn handle_create_pair(&self, _caller: Address, data: &[u8]) -> Result<DexResult, DexError> {
+    fn handle_create_pair(&self, caller: Address, data: &[u8]) -> Result<DexResult, DexError> {
         let (token0, token1): (Address, Address) = <(Address, Address)>::abi_decode(data)
             .map_err(|e| {
                 DexError::InvalidCalldata(format!("failed to decode createPair: {}", e))
             })?;
 
-        let mut pm = self.pool_manager.write();
-        let pair = pm.create_pair(token0, token1).map_err(DexError::from)?;
+        self.list_pair(caller, token0, token1, PairSpec::default())
+    }
+
+    /// Handle listPair((address,address,uint8,uint8,string,string,uint256,uint256))
+    ///
+    /// Creates a pair like createPair, with the token metadata and tick and lot sizes of the
+    /// given [`PairListing`].
+    fn handle_list_pair(&self, caller: Address, data: &[u8]) -> Result<DexResult, DexError> {
+        let listing = PairListing::abi_decode(data)
+            .map_err(|e| DexError::InvalidCalldata(format!("failed to decode listPair: {}", e)))?;
+
+        let spec = PairSpec {
+            decimals0: listing.decimals0,
+            decimals1: listing.decimals1,
+            symbol0: listing.symbol0,
+            symbol1: listing.symbol1,
+            tick_size: listing.tickSize,
+            lot_size: listing.lotSize,
+        };
+        self.list_pair(caller, listing.token0, listing.token1, spec)
+    }
 
-        // Convert PairId to B256 for the event
-        let pair_id = pair.id();
-        let pair_id_bytes = B256::from_slice(&pair_id.0);
+    /// Create a pair on behalf of `caller`, if the [listing policy](super::listing) allows it
+    fn list_pair(
+        &self,
+        caller: Address,
+        token0: Address,
+        token1: Address,
+        spec: PairSpec,
+    ) -> Result<DexResult, DexError> {
+        let mut state = self.state.write();
+        if !state.listing_policy().may_list(caller, state.admin()) {
+            return Err(DexError::Unauthorized);
+        }
+        let pair_id = state.create_pair(token0, token1, spec)?;
 
         Ok(DexResult::PairCreated {
             token0,
             token1,
-            pair_id: pair_id_bytes,
+            pair_id,
         })
     }
 
-    /// Handle placeLimitOrder(address,address,bool,uint256,uint256,uint256)
-    fn handle_place_limit_order(
+    /// Handle placeLimitOrder, placeIocOrder, placeFokOrder and placePostOnlyOrder, which all
+    /// take (address,address,bool,uint256,uint256,uint256), and placeLimitOrderWithExpiry, which
+    /// additionally takes the (uint64,uint64) block and flashblock index the order expires at
+    ///
+    /// The order first trades against the book. What happens to the unfilled remainder depends on
+    /// the time in force:
+    /// * good-till-cancel and post-only orders rest on the book with their remaining escrow
+    /// * good-till-expiry orders rest on the book until they are swept once expired
+    /// * immediate-or-cancel orders are refunded the remaining escrow
+    /// * fill-or-kill orders fail unless they fill completely, so nothing is escrowed
+    ///
+    /// Post-only orders fail instead of trading if they would cross the book. What the order
+    /// receives from the book is charged the taker fee of the pair. Orders fail on halted pairs,
+    /// if they would trade outside of the price band of the pair and unless their price and amount
+    /// respect the tick and lot sizes of the pair.
+    ///
+    /// In batch auction mode, good-till-cancel and good-till-expiry orders don't trade right away
+    /// but wait for the next [batch auction](Self::handle_clear_batch), and the other kinds of
+    /// orders are unavailable.
+    fn handle_place_order(
         &self,
         caller: Address,
         data: &[u8],
-        _value: U256,
+        value: U256,
+        time_in_force: TimeInForce,
     ) -> Result<DexResult, DexError> {
-        let (token_in, token_out, is_buy, amount, price_num, price_denom): (
-            Address,
-            Address,
-            bool,
-            U256,
-            U256,
-            U256,
-        ) = <(Address, Address, bool, U256, U256, U256)>::abi_decode(data).map_err(|e| {
-            DexError::InvalidCalldata(format!("failed to decode placeLimitOrder: {}", e))
-        })?;
+        let invalid_calldata = |e: alloy_sol_types::Error| {
+            DexError::InvalidCalldata(format!(
+                "failed to decode {}: {}",
+                time_in_force.function_name(),
+                e
+            ))
+        };
+        let ((token_in, token_out, is_buy, amount, price_num, price_denom), expiry) =
+            if time_in_force == TimeInForce::GoodTillExpiry {
+                let (token_in, token_out, is_buy, amount, price_num, price_denom, block, index) =
+                    <(Address, Address, bool, U256, U256, U256, u64, u64)>::abi_decode(data)
+                        .map_err(invalid_calldata)?;
+                let expiry = FlashblockPosition {
+                    block_number: block,
+                    flashblock_index: index,
+                };
+                let order = (token_in, token_out, is_buy, amount, price_num, price_denom);
+                (order, Some(expiry))
+            } else {
+                let order = <(Address, Address, bool, U256, U256, U256)>::abi_decode(data)
+                    .map_err(invalid_calldata)?;
+                (order, None)
+            };
+
+        self.place_order(
+            caller,
+            NewOrder {
+                token_in,
+                token_out,
+                is_buy,
+                amount,
+                price_num,
+                price_denom,
+                expiry,
+            },
+            time_in_force,
+            Funding::Attached(value),
+        )
+    }
+
+    /// Place `order` on behalf of `owner`, with its escrow paid as `funding` says
+    fn place_order(
+        &self,
+        owner: Address,
+        order: NewOrder,
+        time_in_force: TimeInForce,
+        funding: Funding,
+    ) -> Result<DexResult, DexError> {
+        let NewOrder {
+            token_in,
+            token_out,
+            is_buy,
+            amount,
+            price_num,
+            price_denom,
+            expiry,
+        } = order;
 
         if amount == U256::ZERO {
             return Err(DexError::InvalidAmount);
@@ -144,44 +471,380 @@ impl DexHandler {
             OrderSide::Sell
         };
 
-        let mut pm = self.pool_manager.write();
-        let (order_id, _trade_result) = pm
-            .place_limit_order(token_in, token_out, caller, side, price, amount)
+        // Sells escrow the tokens they sell, buys the tokens they pay with at the limit price
+        let (escrow_token, escrow) = if is_buy {
+            let cost = amount
+                .checked_mul(price_num)
+                .ok_or(DexError::InvalidAmount)?
+                .div_ceil(price_denom);
+            (token_out, cost)
+        } else {
+            (token_in, amount)
+        };
+        funding.check(escrow_token, escrow)?;
+
+        let mut state = self.state.write();
+        let pair_id = state
+            .pair_id(token_in, token_out)
+            .ok_or(DexError::PairDoesNotExist)?;
+        if state.is_halted(pair_id) {
+            return Err(DexError::PairHalted);
+        }
+        if let Some(pair) = state.pair(pair_id) {
+            pair.spec.check_order(
+                pair.token0 == token_in,
+                amount,
+                price_num_u128,
+                price_denom_u128,
+            )?;
+        }
+        // Orders must not be expired by the time they are placed
+        if expiry.is_some_and(|expiry| expiry.block_number == 0 || expiry <= state.position()) {
+            return Err(DexError::InvalidExpiry);
+        }
+        if state.batch_auction() {
+            if !matches!(
+                time_in_force,
+                TimeInForce::GoodTillCancel | TimeInForce::GoodTillExpiry
+            ) {
+                return Err(DexError::UnavailableInBatchAuction);
+            }
+            funding.record(&mut state, escrow_token, owner, escrow);
+            let order_id = state.assign_order_id();
+            state.queue_order(
+                order_id,
+                OrderRecord {
+                    owner,
+                    pair_id,
+                    token_in,
+                    token_out,
+                    is_buy,
+                    price_num: price_num_u128,
+                    price_denom: price_denom_u128,
+                    remaining: amount,
+                    escrow,
+                    expiry,
+                },
+            );
+            return Ok(DexResult::OrderPlaced {
+                order_id: order_id_to_b256(order_id),
+                trader: owner,
+                token_in,
+                token_out,
+                is_buy,
+                amount,
+                price_num,
+                price_denom,
+                fills: Vec::new(),
+            });
+        }
+        if time_in_force == TimeInForce::PostOnly
+            && state.crosses(
+                token_in,
+                token_out,
+                is_buy,
+                price_num_u128,
+                price_denom_u128,
+            )
+        {
+            return Err(DexError::WouldTakeLiquidity);
+        }
+        if time_in_force == TimeInForce::FillOrKill {
+            // Walk the book without trading first, so a thin book fails before it is touched
+            let (fillable, orders_read) = state.fillable(
+                token_in,
+                token_out,
+                is_buy,
+                price_num_u128,
+                price_denom_u128,
+                amount,
+            );
+            state.add_work(DexWork {
+                orders_read,
+                ..Default::default()
+            });
+            if fillable < amount {
+                return Err(DexError::NotFilled);
+            }
+        }
+        let (book_id, trade_result) = state
+            .pool_manager_mut()
+            .place_limit_order(token_in, token_out, owner, side, price, amount)
             .map_err(DexError::from)?;
+        state.check_price_bands(&trade_result.fills)?;
+        let taker = state.taker_fill(&trade_result.fills, token_in);
+        // Rounding in the matching may still leave the order short, which fails it all the same
+        if time_in_force == TimeInForce::FillOrKill && taker.filled < amount {
+            return Err(DexError::NotFilled);
+        }
+        let order_id = state.assign_order_id();
 
-        // Convert OrderId (u64) to B256
-        let mut bytes = [0u8; 32];
-        bytes[24..32].copy_from_slice(&order_id.0.to_be_bytes());
-        let order_id_bytes = B256::from(bytes);
+        funding.record(&mut state, escrow_token, owner, escrow);
+        let fills = state.apply_fills(&trade_result.fills, owner);
+        state.add_work(DexWork {
+            orders_filled: fills.len() as u64,
+            hops: 1,
+            ..Default::default()
+        });
+        let proceeds_token = if is_buy { token_in } else { token_out };
+        let fee = state.pair_fees(pair_id).taker_fee(taker.received);
+        let received = state.charge_fee(proceeds_token, taker.received, fee);
+        state.record_transfer(Transfer::push(proceeds_token, owner, received));
 
-        Ok(DexResult::OrderPlaced {
-            order_id: order_id_bytes,
-            trader: caller,
-            token_in,
-            token_out,
-            amount,
+        let remaining = amount.saturating_sub(taker.filled);
+        let escrow = escrow.saturating_sub(taker.paid);
+        if remaining.is_zero() {
+            state.record_transfer(Transfer::push(escrow_token, owner, escrow));
+        } else if time_in_force.rests() {
+            // Whatever the order didn't fill immediately rests on the book
+            state.insert_order(
+                order_id,
+                book_id,
+                OrderRecord {
+                    owner,
+                    pair_id,
+                    token_in,
+                    token_out,
+                    is_buy,
+                    price_num: price_num_u128,
+                    price_denom: price_denom_u128,
+                    remaining,
+                    escrow,
+                    expiry,
+                },
+            );
+        } else {
+            // Take the remainder off the book again and hand back what backed it
+            state
+                .pool_manager_mut()
+                .cancel_order(token_in, token_out, book_id)
+                .map_err(DexError::from)?;
+            state.record_transfer(Transfer::push(escrow_token, owner, escrow));
+        }
+
+        let order_id = order_id_to_b256(order_id);
+        Ok(match time_in_force {
+            TimeInForce::GoodTillCancel | TimeInForce::GoodTillExpiry => DexResult::OrderPlaced {
+                order_id,
+                trader: owner,
+                token_in,
+                token_out,
+                is_buy,
+                amount,
+                price_num,
+                price_denom,
+                fills,
+            },
+            TimeInForce::ImmediateOrCancel => DexResult::ImmediateOrCancelExecuted {
+                order_id,
+                trader: owner,
+                token_in,
+                token_out,
+                is_buy,
+                amount,
+                filled: taker.filled.min(amount),
+                price_num,
+                price_denom,
+                fills,
+            },
+            TimeInForce::FillOrKill => DexResult::FillOrKillExecuted {
+                order_id,
+                trader: owner,
+                token_in,
+                token_out,
+                is_buy,
+                amount,
+                price_num,
+                price_denom,
+                fills,
+            },
+            TimeInForce::PostOnly => DexResult::PostOnlyPlaced {
+                order_id,
+                trader: owner,
+                token_in,
+                token_out,
+                is_buy,
+                amount,
+                price_num,
+                price_denom,
+            },
+        })
+    }
+
+    /// Handle submitSignedOrder((address,address,address,bool,uint256,uint256,uint256,uint64,uint64,uint64),bytes)
+    ///
+    /// Places a [signed order](super::signed) on behalf of its maker, as a good-till-expiry order
+    /// if it has an expiry and a good-till-cancel order otherwise. Anybody can submit the order,
+    /// but only once, as placing it uses up its nonce. Its escrow is pulled from the maker instead
+    /// of being paid by the caller, so no ETH may be attached.
+    fn handle_submit_signed_order(&self, data: &[u8], value: U256) -> Result<DexResult, DexError> {
+        let (order, signature): (SignedOrder, Bytes) =
+            <(SignedOrder, Bytes)>::abi_decode_params(data).map_err(|e| {
+                DexError::InvalidCalldata(format!("failed to decode submitSignedOrder: {}", e))
+            })?;
+        if !value.is_zero() {
+            return Err(DexError::InvalidValue);
+        }
+
+        let domain = self.state.read().signing_domain();
+        let order_hash = order.verify(&signature, &domain)?;
+        {
+            let mut state = self.state.write();
+            state.add_work(DexWork {
+                signatures: 1,
+                ..Default::default()
+            });
+            state.use_nonce(order.maker, order.nonce)?;
+        }
+
+        let expiry = order.expiry();
+        let time_in_force = if expiry.is_some() {
+            TimeInForce::GoodTillExpiry
+        } else {
+            TimeInForce::GoodTillCancel
+        };
+        let placed = self.place_order(
+            order.maker,
+            NewOrder {
+                token_in: order.tokenIn,
+                token_out: order.tokenOut,
+                is_buy: order.isBuy,
+                amount: order.amount,
+                price_num: order.priceNum,
+                price_denom: order.priceDenom,
+                expiry,
+            },
+            time_in_force,
+            Funding::Pulled,
+        )?;
+        Ok(DexResult::SignedOrderPlaced {
+            maker: order.maker,
+            order_hash,
+            nonce: order.nonce,
+            placed: Box::new(placed),
         })
     }
 
     /// Handle cancelOrder(bytes32)
-    fn handle_cancel_order(&self, _caller: Address, data: &[u8]) -> Result<DexResult, DexError> {
+    fn handle_cancel_order(&self, caller: Address, data: &[u8]) -> Result<DexResult, DexError> {
         let order_id: B256 = <B256>::abi_decode(data).map_err(|e| {
             DexError::InvalidCalldata(format!("failed to decode cancelOrder: {}", e))
         })?;
+        let id = order_id_from_b256(order_id).ok_or(DexError::OrderNotFound)?;
 
-        // For now, we'll just return success
-        // TODO: Implement proper order cancellation with order tracking
-        // The enshrined-dex library needs to be updated to support order cancellation by ID
+        let mut state = self.state.write();
+        let order = state.order(id).ok_or(DexError::OrderNotFound)?;
+        if order.owner != caller {
+            return Err(DexError::Unauthorized);
+        }
+        state.cancel_order(id)?;
+
+        Ok(DexResult::OrderCancelled {
+            order_id,
+            owner: caller,
+        })
+    }
+
+    /// Handle sweepExpiredOrders(uint64)
+    ///
+    /// Advances the DEX to the given flashblock of the current block and takes every order that
+    /// expired by then off the book, refunding its leftover escrow. The builder sweeps at the start
+    /// of each flashblock, but anyone may sweep.
+    fn handle_sweep_expired_orders(&self, data: &[u8]) -> Result<DexResult, DexError> {
+        let flashblock_index: u64 = <u64>::abi_decode(data).map_err(|e| {
+            DexError::InvalidCalldata(format!("failed to decode sweepExpiredOrders: {}", e))
+        })?;
 
-        Ok(DexResult::OrderCancelled { order_id })
+        let mut state = self.state.write();
+        state.advance_flashblock(flashblock_index)?;
+        let expired: Vec<u64> = state.expired_orders(state.position()).collect();
+
+        let mut orders = Vec::with_capacity(expired.len());
+        for id in expired {
+            let order = state.cancel_order(id)?;
+            orders.push((order_id_to_b256(id), order.owner));
+        }
+        state.add_work(DexWork {
+            orders_read: orders.len() as u64,
+            ..Default::default()
+        });
+
+        Ok(DexResult::OrdersExpired { orders })
+    }
+
+    /// Handle clearBatch()
+    ///
+    /// Clears the orders collected since the last batch auction, see
+    /// [`DexState::clear_batch`]. Only the builder may clear, which it does at the end of each
+    /// flashblock in batch auction mode, so nobody else decides when the auction takes place.
+    fn handle_clear_batch(&self, caller: Address) -> Result<DexResult, DexError> {
+        let mut state = self.state.write();
+        if state.builder() != Some(caller) {
+            return Err(DexError::Unauthorized);
+        }
+        let auctions = state.clear_batch()?;
+        Ok(DexResult::BatchCleared { auctions })
+    }
+
+    /// Handle haltPair(address,address)
+    ///
+    /// Halts a pair whose circuit breaker tripped, see [`bands`](super::bands). Violations of
+    /// reverted operations are only counted off-chain, so the builder halts the pair with a
+    /// transaction of its own, and only pairs that are due to halt on the counts of the block.
+    fn handle_halt_pair(&self, caller: Address, data: &[u8]) -> Result<DexResult, DexError> {
+        let (token_a, token_b): (Address, Address) = <(Address, Address)>::abi_decode(data)
+            .map_err(|e| DexError::InvalidCalldata(format!("failed to decode haltPair: {}", e)))?;
+
+        let mut state = self.state.write();
+        if state.builder() != Some(caller) {
+            return Err(DexError::Unauthorized);
+        }
+        let pair_id = state
+            .pair_id(token_a, token_b)
+            .ok_or(DexError::PairDoesNotExist)?;
+        if !state.pairs_to_halt().any(|due| due == pair_id) {
+            return Err(DexError::HaltNotDue);
+        }
+        state.halt_pair(pair_id)?;
+
+        Ok(DexResult::PairHalted { pair_id })
+    }
+
+    /// Handle resumePair(address,address)
+    ///
+    /// Resumes trading on a pair halted by its circuit breaker, see [`bands`](super::bands). Only
+    /// the DEX admin may resume pairs.
+    fn handle_resume_pair(&self, caller: Address, data: &[u8]) -> Result<DexResult, DexError> {
+        let (token_a, token_b): (Address, Address) = <(Address, Address)>::abi_decode(data)
+            .map_err(|e| {
+                DexError::InvalidCalldata(format!("failed to decode resumePair: {}", e))
+            })?;
+
+        let mut state = self.state.write();
+        if state.admin() != Some(caller) {
+            return Err(DexError::Unauthorized);
+        }
+        let pair_id = state
+            .pair_id(token_a, token_b)
+            .ok_or(DexError::PairDoesNotExist)?;
+        state.resume_pair(pair_id)?;
+
+        Ok(DexResult::PairResumed {
+            pair_id,
+            admin: caller,
+        })
     }
 
     /// Handle swap(address,address,uint256,uint256)
+    ///
+    /// The output is charged the taker fee of every pair the swap is routed through, and the swap
+    /// fails if what is left of it falls short of the minimum. Swaps fail if they would trade on
+    /// a halted pair or outside of the price band of a pair.
     fn handle_swap(
         &self,
         caller: Address,
         data: &[u8],
-        _value: U256,
+        value: U256,
     ) -> Result<DexResult, DexError> {
         let (token_in, token_out, amount_in, min_amount_out): (Address, Address, U256, U256) =
             <(Address, Address, U256, U256)>::abi_decode(data)
@@ -190,18 +853,47 @@ impl DexHandler {
         if amount_in == U256::ZERO {
             return Err(DexError::InvalidAmount);
         }
+        check_value(token_in, amount_in, value)?;
 
-        let mut pm = self.pool_manager.write();
-        let result = pm
+        let mut state = self.state.write();
+        if state.batch_auction() {
+            return Err(DexError::UnavailableInBatchAuction);
+        }
+        let result = state
+            .pool_manager_mut()
             .execute_swap(caller, token_in, token_out, amount_in, min_amount_out)
             .map_err(DexError::from)?;
 
+        state.check_price_bands(&result.fills)?;
+
+        // The input is paid into the predeploy first, as the makers are paid out of it
+        record_payment(&mut state, token_in, caller, amount_in);
+        let fills = state.apply_fills(&result.fills, caller);
+
+        let mut route: Vec<B256> = fills.iter().map(|fill| fill.pair_id).collect();
+        route.dedup();
+        let amount_out = route.iter().fold(result.amount_out, |amount, pair_id| {
+            amount - state.pair_fees(*pair_id).taker_fee(amount)
+        });
+        if amount_out < min_amount_out {
+            return Err(DexError::SlippageExceeded);
+        }
+        state.charge_fee(token_out, result.amount_out, result.amount_out - amount_out);
+        state.record_transfer(Transfer::push(token_out, caller, amount_out));
+
+        state.add_work(DexWork {
+            orders_filled: fills.len() as u64,
+            hops: route.len() as u64,
+            ..Default::default()
+        });
         Ok(DexResult::SwapExecuted {
             trader: caller,
             token_in,
             token_out,
             amount_in,
-            amount_out: result.amount_out,
+            amount_out,
+            route,
+            fills,
         })
     }
 
@@ -212,27 +904,306 @@ impl DexHandler {
                 DexError::InvalidCalldata(format!("failed to decode getQuote: {}", e))
             })?;
 
-        let pm = self.pool_manager.read();
-        let result = pm
-            .get_quote(token_in, token_out, amount_in)
-            .map_err(DexError::from)?;
+        let (amount_out, route) = self.quote(token_in, token_out, amount_in)?;
+        self.state.write().add_work(DexWork {
+            hops: route.len() as u64,
+            ..Default::default()
+        });
 
-        // Convert route to B256 array (extract pair IDs from hops)
-        let route: Vec<B256> = result
-            .route
-            .hops
-            .iter()
-            .map(|hop| {
-                let pair_id = hop.pair.id();
-                B256::from_slice(&pair_id.0)
-            })
-            .collect();
+        Ok(DexResult::Quote { amount_out, route })
+    }
 
-        Ok(DexResult::Quote {
-            amount_out: result.amount_out,
-            route,
+    /// Handle getOrderbookDepth(address,address,uint256)
+    fn handle_get_orderbook_depth(&self, data: &[u8]) -> Result<DexResult, DexError> {
+        let (base, quote, levels): (Address, Address, U256) =
+            <(Address, Address, U256)>::abi_decode(data).map_err(|e| {
+                DexError::InvalidCalldata(format!("failed to decode getOrderbookDepth: {}", e))
+            })?;
+
+        let mut state = self.state.write();
+        state
+            .pair_id(base, quote)
+            .ok_or(DexError::PairDoesNotExist)?;
+        state.add_order_scan();
+        let (bids, asks) = state.depth(base, quote, levels.saturating_to());
+
+        let to_abi = |levels: Vec<BookLevel>| -> Vec<DepthLevel> {
+            levels
+                .into_iter()
+                .map(|level| DepthLevel {
+                    priceNum: U256::from(level.price_num),
+                    priceDenom: U256::from(level.price_denom),
+                    amount: level.amount,
+                })
+                .collect()
+        };
+        Ok(DexResult::OrderbookDepth {
+            bids: to_abi(bids),
+            asks: to_abi(asks),
         })
     }
+
+    /// Handle getPairStats(address,address)
+    fn handle_get_pair_stats(&self, data: &[u8]) -> Result<DexResult, DexError> {
+        let (token0, token1): (Address, Address) =
+            <(Address, Address)>::abi_decode(data).map_err(|e| {
+                DexError::InvalidCalldata(format!("failed to decode getPairStats: {}", e))
+            })?;
+
+        let state = self.state.read();
+        let pair = state
+            .pair_id(token0, token1)
+            .and_then(|pair_id| state.pair(pair_id))
+            .ok_or(DexError::PairDoesNotExist)?;
+        let window = pair.stats.window(state.timestamp());
+
+        // Prices are reported in terms of the tokens in the order they were asked for
+        let in_order = pair.token0 == token0;
+        let (last_price_num, last_price_denom, volume0_24h, volume1_24h) = if in_order {
+            (
+                pair.stats.last_price_num,
+                pair.stats.last_price_denom,
+                window.volume0,
+                window.volume1,
+            )
+        } else {
+            (
+                pair.stats.last_price_denom,
+                pair.stats.last_price_num,
+                window.volume1,
+                window.volume0,
+            )
+        };
+
+        Ok(DexResult::PairStats {
+            last_price_num: U256::from(last_price_num),
+            last_price_denom: U256::from(last_price_denom),
+            volume0_24h,
+            volume1_24h,
+            trade_count_24h: U256::from(window.trade_count),
+            trade_count: U256::from(pair.stats.trade_count),
+        })
+    }
+
+    /// Handle getTwap(address,address,uint64)
+    ///
+    /// Returns the time-weighted average price of the pair over the given number of seconds up to
+    /// the current block, see [`oracle`](super::oracle). Like the other prices, it is reported in
+    /// terms of the tokens in the order they were asked for, with a denominator of
+    /// [`PRICE_SCALE`](super::oracle::PRICE_SCALE). Trades of the current block don't count.
+    fn handle_get_twap(&self, data: &[u8]) -> Result<DexResult, DexError> {
+        let (token0, token1, window): (Address, Address, u64) =
+            <(Address, Address, u64)>::abi_decode(data).map_err(|e| {
+                DexError::InvalidCalldata(format!("failed to decode getTwap: {}", e))
+            })?;
+
+        let state = self.state.read();
+        let pair = state
+            .pair_id(token0, token1)
+            .and_then(|pair_id| state.pair(pair_id))
+            .ok_or(DexError::PairDoesNotExist)?;
+        let price_num = pair
+            .oracle
+            .twap(
+                state.timestamp(),
+                window,
+                (pair.stats.last_price_num, pair.stats.last_price_denom),
+                pair.token0 != token0,
+            )
+            .ok_or(DexError::TwapUnavailable(window))?;
+
+        Ok(DexResult::Twap {
+            price_num,
+            price_denom: PRICE_SCALE,
+        })
+    }
+
+    /// Handle getUserOrders(address)
+    fn handle_get_user_orders(&self, data: &[u8]) -> Result<DexResult, DexError> {
+        let owner: Address = <Address>::abi_decode(data).map_err(|e| {
+            DexError::InvalidCalldata(format!("failed to decode getUserOrders: {}", e))
+        })?;
+
+        let mut state = self.state.write();
+        state.add_order_scan();
+        let orders = state
+            .orders_of(owner)
+            .map(|(order_id, order)| order_info(order_id, order))
+            .collect();
+
+        Ok(DexResult::UserOrders { orders })
+    }
+
+    /// Handle getOrder(bytes32)
+    fn handle_get_order(&self, data: &[u8]) -> Result<DexResult, DexError> {
+        let order_id: B256 = <B256>::abi_decode(data)
+            .map_err(|e| DexError::InvalidCalldata(format!("failed to decode getOrder: {}", e)))?;
+
+        let mut state = self.state.write();
+        state.add_work(DexWork {
+            orders_read: 1,
+            ..Default::default()
+        });
+        let order = order_id_from_b256(order_id)
+            .and_then(|id| state.order(id).map(|order| order_info(id, order)))
+            .ok_or(DexError::OrderNotFound)?;
+
+        Ok(DexResult::Order(order))
+    }
+
+    /// Handle getPairListing(address,address)
+    ///
+    /// The pair is reported with its tokens in the order it was listed with, no matter the order
+    /// they were asked for.
+    fn handle_get_pair_listing(&self, data: &[u8]) -> Result<DexResult, DexError> {
+        let (token_a, token_b): (Address, Address) = <(Address, Address)>::abi_decode(data)
+            .map_err(|e| {
+                DexError::InvalidCalldata(format!("failed to decode getPairListing: {}", e))
+            })?;
+
+        let state = self.state.read();
+        let pair = state
+            .pair_id(token_a, token_b)
+            .and_then(|pair_id| state.pair(pair_id))
+            .ok_or(DexError::PairDoesNotExist)?;
+
+        Ok(DexResult::Listing(PairListing {
+            token0: pair.token0,
+            token1: pair.token1,
+            decimals0: pair.spec.decimals0,
+            decimals1: pair.spec.decimals1,
+            symbol0: pair.spec.symbol0.clone(),
+            symbol1: pair.spec.symbol1.clone(),
+            tickSize: pair.spec.tick_size,
+            lotSize: pair.spec.lot_size,
+        }))
+    }
+}
+
+/// How long an order stays on the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeInForce {
+    /// Rests on the book until it is filled or cancelled
+    GoodTillCancel,
+    /// Rests on the book until it is filled, cancelled or expired
+    GoodTillExpiry,
+    /// Trades what it can right away, the rest is cancelled
+    ImmediateOrCancel,
+    /// Trades completely right away or not at all
+    FillOrKill,
+    /// Rests on the book without trading, and fails if it would trade right away
+    PostOnly,
+}
+
+impl TimeInForce {
+    /// Name of the predeploy function placing orders of this kind
+    fn function_name(self) -> &'static str {
+        match self {
+            Self::GoodTillCancel => "placeLimitOrder",
+            Self::GoodTillExpiry => "placeLimitOrderWithExpiry",
+            Self::ImmediateOrCancel => "placeIocOrder",
+            Self::FillOrKill => "placeFokOrder",
+            Self::PostOnly => "placePostOnlyOrder",
+        }
+    }
+
+    /// Whether the unfilled remainder of the order rests on the book
+    fn rests(self) -> bool {
+        matches!(
+            self,
+            Self::GoodTillCancel | Self::GoodTillExpiry | Self::PostOnly
+        )
+    }
+}
+
+/// An order to place, as decoded from the calldata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NewOrder {
+    token_in: Address,
+    token_out: Address,
+    is_buy: bool,
+    amount: U256,
+    price_num: U256,
+    price_denom: U256,
+    /// Position from which on the order is expired, `None` if it doesn't expire
+    expiry: Option<FlashblockPosition>,
+}
+
+/// How the escrow of a new order gets into the predeploy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Funding {
+    /// Paid by the caller placing the order, who attached the given ETH value
+    Attached(U256),
+    /// Pulled from the owner of the order, ETH included, which signed off on the order
+    Pulled,
+}
+
+impl Funding {
+    /// Ensure the escrow of `amount` of `token` can be funded this way
+    fn check(self, token: Address, amount: U256) -> Result<(), DexError> {
+        match self {
+            Self::Attached(value) => check_value(token, amount, value),
+            Self::Pulled => Ok(()),
+        }
+    }
+
+    /// Record the payment of the escrow of `amount` of `token` by `owner`
+    fn record(self, state: &mut DexState, token: Address, owner: Address, amount: U256) {
+        match self {
+            Self::Attached(_) => record_payment(state, token, owner, amount),
+            Self::Pulled => state.record_transfer(Transfer::pull(token, owner, amount)),
+        }
+    }
+}
+
+/// Describe a resting order for the view functions
+fn order_info(order_id: u64, order: &OrderRecord) -> OrderInfo {
+    OrderInfo {
+        orderId: order_id_to_b256(order_id),
+        owner: order.owner,
+        tokenIn: order.token_in,
+        tokenOut: order.token_out,
+        isBuy: order.is_buy,
+        priceNum: U256::from(order.price_num),
+        priceDenom: U256::from(order.price_denom),
+        remaining: order.remaining,
+        escrow: order.escrow,
+    }
+}
+
+/// Ensure the ETH attached to an operation matches what it pays in `token`.
+///
+/// ETH payments have to be attached in full, while ERC-20 payments must not carry any ETH.
+fn check_value(token: Address, amount: U256, value: U256) -> Result<(), DexError> {
+    let expected = if token == NATIVE_TOKEN {
+        amount
+    } else {
+        U256::ZERO
+    };
+    if value != expected {
+        return Err(DexError::InvalidValue);
+    }
+    Ok(())
+}
+
+/// Convert an order id of the enshrined-dex library into the `bytes32` used in the ABI
+pub(crate) fn order_id_to_b256(order_id: u64) -> B256 {
+    U256::from(order_id).into()
+}
+
+/// Convert an order id from the ABI back, if it is one the library could have handed out
+pub(crate) fn order_id_from_b256(order_id: B256) -> Option<u64> {
+    U256::from_be_bytes(order_id.0).try_into().ok()
+}
+
+/// Record the payment of `amount` of `token` by the caller into the predeploy.
+///
+/// ETH is attached as the transaction's value, which the EVM already moved into the predeploy,
+/// so only ERC-20 payments have to be pulled.
+fn record_payment(state: &mut DexState, token: Address, payer: Address, amount: U256) {
+    if token != NATIVE_TOKEN {
+        state.record_transfer(Transfer::pull(token, payer, amount));
+    }
 }
 
 impl Default for DexHandler {
@@ -244,7 +1215,8 @@ impl Default for DexHandler {
 impl Clone for DexHandler {
     fn clone(&self) -> Self {
         Self {
-            pool_manager: Arc::clone(&self.pool_manager),
+            state: Arc::clone(&self.state),
+            checkpoint: Arc::clone(&self.checkpoint),
         }
     }
 }
@@ -252,7 +1224,18 @@ impl Clone for DexHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use alloy_primitives::address;
+    use crate::{
+        dex::{
+            DEX_PREDEPLOY_ADDRESS,
+            bands::PriceBands,
+            fees::{FeeSchedule, PairFees},
+            listing::{DEFAULT_DECIMALS, ListingPolicy},
+            signed::domain,
+            storage,
+        },
+        tx_signer::Signer,
+    };
+    use alloy_primitives::{FixedBytes, address};
 
     #[test]
     fn test_create_pair() {
@@ -326,7 +1309,11 @@ mod tests {
         .concat();
 
         let result = handler
-            .handle_transaction(trader, &place_order_calldata.into(), U256::ZERO)
+            .handle_transaction(
+                trader,
+                &place_order_calldata.into(),
+                U256::from(10u64.pow(18)),
+            )
             .expect("placeLimitOrder should succeed");
 
         match result {
@@ -382,4 +1369,1064 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_resting_order_is_mirrored_to_storage() {
+        let trader = address!("0000000000000000000000000000000000000099");
+        let handler = DexHandler::new();
+
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+
+        let create_pair_calldata = [
+            selectors::CREATE_PAIR.as_slice(),
+            &(eth, usdc).abi_encode(),
+        ]
+        .concat();
+        let DexResult::PairCreated { pair_id, .. } = handler
+            .handle_transaction(trader, &create_pair_calldata.into(), U256::ZERO)
+            .expect("createPair should succeed")
+        else {
+            panic!("expected PairCreated");
+        };
+        let changes = handler.take_storage_changes();
+        assert!(changes.contains(&(
+            storage::pair_slot(pair_id),
+            U256::from_be_slice(eth.as_slice())
+        )));
+
+        let place_order_calldata = [
+            selectors::PLACE_LIMIT_ORDER.as_slice(),
+            &(
+                eth,
+                usdc,
+                false,
+                U256::from(10u64.pow(18)),
+                U256::from(2000),
+                U256::from(1),
+            )
+                .abi_encode(),
+        ]
+        .concat();
+        let DexResult::OrderPlaced { order_id, .. } = handler
+            .handle_transaction(
+                trader,
+                &place_order_calldata.into(),
+                U256::from(10u64.pow(18)),
+            )
+            .expect("placeLimitOrder should succeed")
+        else {
+            panic!("expected OrderPlaced");
+        };
+        let order_id = u64::from_be_bytes(order_id[24..].try_into().unwrap());
+
+        let changes = handler.take_storage_changes();
+        let slot = storage::order_slot(order_id);
+        assert!(changes.contains(&(slot, U256::from_be_slice(trader.as_slice()))));
+        assert!(changes.contains(&(slot + U256::from(6), U256::from(10u64.pow(18)))));
+
+        // nothing changed since the last call
+        assert!(handler.take_storage_changes().is_empty());
+    }
+
+    #[test]
+    fn test_operations_record_settlement_transfers() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let taker = address!("0000000000000000000000000000000000000098");
+        let handler = DexHandler::new();
+
+        let eth = NATIVE_TOKEN;
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let one_eth = U256::from(10u64.pow(18));
+
+        let create_pair_calldata: Bytes =
+            [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()]
+                .concat()
+                .into();
+        handler
+            .handle_transaction(maker, &create_pair_calldata, U256::ZERO)
+            .expect("createPair should succeed");
+
+        // sell 1 ETH at 2000 USDC, the ETH has to be attached
+        let place_order_calldata: Bytes = [
+            selectors::PLACE_LIMIT_ORDER.as_slice(),
+            &(eth, usdc, false, one_eth, U256::from(2000), U256::from(1)).abi_encode(),
+        ]
+        .concat()
+        .into();
+        assert!(matches!(
+            handler.handle_transaction(maker, &place_order_calldata, U256::ZERO),
+            Err(DexError::InvalidValue)
+        ));
+        handler
+            .handle_transaction(maker, &place_order_calldata, one_eth)
+            .expect("placeLimitOrder should succeed");
+        // the attached value already pays for the escrow
+        assert!(handler.take_transfers().is_empty());
+
+        // buy ETH with 100 USDC
+        let amount_in = U256::from(100 * 10u64.pow(6));
+        let swap_calldata: Bytes = [
+            selectors::SWAP.as_slice(),
+            &(usdc, eth, amount_in, U256::ZERO).abi_encode(),
+        ]
+        .concat()
+        .into();
+        let DexResult::SwapExecuted { amount_out, .. } = handler
+            .handle_transaction(taker, &swap_calldata, U256::ZERO)
+            .expect("swap should succeed")
+        else {
+            panic!("expected SwapExecuted");
+        };
+
+        let transfers = handler.take_transfers();
+        assert_eq!(
+            transfers.first(),
+            Some(&Transfer::pull(usdc, taker, amount_in))
+        );
+        assert_eq!(
+            transfers.last(),
+            Some(&Transfer::push(eth, taker, amount_out))
+        );
+        assert!(
+            transfers
+                .iter()
+                .any(|transfer| transfer.token == usdc && transfer.to == maker)
+        );
+    }
+
+    #[test]
+    fn test_cancel_order_refunds_owner() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let other = address!("0000000000000000000000000000000000000098");
+        let handler = DexHandler::new();
+
+        let eth = NATIVE_TOKEN;
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let one_eth = U256::from(10u64.pow(18));
+
+        let create_pair_calldata: Bytes =
+            [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()]
+                .concat()
+                .into();
+        handler
+            .handle_transaction(maker, &create_pair_calldata, U256::ZERO)
+            .expect("createPair should succeed");
+
+        let place_order_calldata: Bytes = [
+            selectors::PLACE_LIMIT_ORDER.as_slice(),
+            &(eth, usdc, false, one_eth, U256::from(2000), U256::from(1)).abi_encode(),
+        ]
+        .concat()
+        .into();
+        let DexResult::OrderPlaced { order_id, .. } = handler
+            .handle_transaction(maker, &place_order_calldata, one_eth)
+            .expect("placeLimitOrder should succeed")
+        else {
+            panic!("expected OrderPlaced");
+        };
+        handler.take_transfers();
+        handler.take_storage_changes();
+
+        let cancel_calldata = |order_id: B256| -> Bytes {
+            [selectors::CANCEL_ORDER.as_slice(), &order_id.abi_encode()]
+                .concat()
+                .into()
+        };
+        assert!(matches!(
+            handler.handle_transaction(
+                maker,
+                &cancel_calldata(B256::with_last_byte(0xff)),
+                U256::ZERO
+            ),
+            Err(DexError::OrderNotFound)
+        ));
+        assert!(matches!(
+            handler.handle_transaction(other, &cancel_calldata(order_id), U256::ZERO),
+            Err(DexError::Unauthorized)
+        ));
+
+        handler
+            .handle_transaction(maker, &cancel_calldata(order_id), U256::ZERO)
+            .expect("cancelOrder should succeed");
+        assert_eq!(
+            handler.take_transfers(),
+            vec![Transfer::push(eth, maker, one_eth)]
+        );
+        let id = order_id_from_b256(order_id).unwrap();
+        assert!(handler.snapshot().order(id).is_none());
+        assert!(
+            handler
+                .take_storage_changes()
+                .contains(&(storage::order_slot(id), U256::ZERO))
+        );
+
+        // the order is gone, so it can't be cancelled twice
+        assert!(matches!(
+            handler.handle_transaction(maker, &cancel_calldata(order_id), U256::ZERO),
+            Err(DexError::OrderNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_time_in_force() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let taker = address!("0000000000000000000000000000000000000098");
+        let handler = DexHandler::new();
+
+        let eth = NATIVE_TOKEN;
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let one_eth = U256::from(10u64.pow(18));
+        let call = |caller: Address, selector: FixedBytes<4>, params: Vec<u8>, value: U256| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(caller, &calldata, value)
+        };
+        let buy = |amount: U256, price: u64| {
+            (eth, usdc, true, amount, U256::from(price), U256::from(1)).abi_encode()
+        };
+        let sell_one_eth = || {
+            call(
+                maker,
+                selectors::PLACE_LIMIT_ORDER,
+                (eth, usdc, false, one_eth, U256::from(2000), U256::from(1)).abi_encode(),
+                one_eth,
+            )
+            .expect("placeLimitOrder should succeed");
+        };
+
+        call(
+            maker,
+            selectors::CREATE_PAIR,
+            (eth, usdc).abi_encode(),
+            U256::ZERO,
+        )
+        .expect("createPair should succeed");
+        sell_one_eth();
+        handler.take_transfers();
+
+        // post-only orders must not take liquidity
+        assert!(matches!(
+            call(
+                taker,
+                selectors::PLACE_POST_ONLY_ORDER,
+                buy(one_eth, 2000),
+                U256::ZERO
+            ),
+            Err(DexError::WouldTakeLiquidity)
+        ));
+        let result = call(
+            taker,
+            selectors::PLACE_POST_ONLY_ORDER,
+            buy(one_eth, 1900),
+            U256::ZERO,
+        )
+        .expect("placePostOnlyOrder should succeed");
+        assert!(matches!(result, DexResult::PostOnlyPlaced { .. }));
+        assert_eq!(handler.snapshot().orders_of(taker).count(), 1);
+        handler.take_transfers();
+        handler.take_work();
+
+        // fill-or-kill orders fail without a trace if the book is too thin, having read the
+        // resting sell
+        let before = handler.snapshot().records();
+        assert!(matches!(
+            call(
+                taker,
+                selectors::PLACE_FOK_ORDER,
+                buy(one_eth * U256::from(2), 2000),
+                U256::ZERO
+            ),
+            Err(DexError::NotFilled)
+        ));
+        assert_eq!(handler.snapshot().records(), before);
+        assert!(handler.take_transfers().is_empty());
+        assert_eq!(handler.take_work().orders_read, 1);
+
+        // immediate-or-cancel orders take what they can and refund the rest
+        let result = call(
+            taker,
+            selectors::PLACE_IOC_ORDER,
+            buy(one_eth * U256::from(2), 2000),
+            U256::ZERO,
+        )
+        .expect("placeIocOrder should succeed");
+        let DexResult::ImmediateOrCancelExecuted { filled, fills, .. } = &result else {
+            panic!("expected ImmediateOrCancelExecuted");
+        };
+        assert_eq!(*filled, one_eth);
+        assert_eq!(fills.len(), 1);
+        let (_, encoded_filled) = <(B256, U256)>::abi_decode_params(&result.encode()).unwrap();
+        assert_eq!(encoded_filled, one_eth);
+        let transfers = handler.take_transfers();
+        assert_eq!(
+            transfers.first(),
+            Some(&Transfer::pull(usdc, taker, one_eth * U256::from(4000)))
+        );
+        assert!(transfers.contains(&Transfer::push(eth, taker, one_eth)));
+        assert_eq!(
+            transfers.last(),
+            Some(&Transfer::push(usdc, taker, one_eth * U256::from(2000)))
+        );
+        // only the post-only order rests
+        assert_eq!(handler.snapshot().orders_of(taker).count(), 1);
+
+        // a complete fill-or-kill fill refunds the price improvement
+        sell_one_eth();
+        handler.take_transfers();
+        let result = call(
+            taker,
+            selectors::PLACE_FOK_ORDER,
+            buy(one_eth, 2100),
+            U256::ZERO,
+        )
+        .expect("placeFokOrder should succeed");
+        assert!(matches!(result, DexResult::FillOrKillExecuted { .. }));
+        assert_eq!(
+            handler.take_transfers().last(),
+            Some(&Transfer::push(usdc, taker, one_eth * U256::from(100)))
+        );
+        assert_eq!(handler.snapshot().orders_of(taker).count(), 1);
+    }
+
+    #[test]
+    fn test_orders_expire() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let sweeper = address!("0000000000000000000000000000000000000098");
+        let handler = DexHandler::new();
+        handler.set_block(10, 1_000);
+
+        let eth = NATIVE_TOKEN;
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let one_eth = U256::from(10u64.pow(18));
+        let call = |caller: Address, selector: FixedBytes<4>, params: Vec<u8>, value: U256| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(caller, &calldata, value)
+        };
+        let sell_until = |block: u64, flashblock_index: u64| {
+            call(
+                maker,
+                selectors::PLACE_LIMIT_ORDER_WITH_EXPIRY,
+                (
+                    eth,
+                    usdc,
+                    false,
+                    one_eth,
+                    U256::from(2000),
+                    U256::from(1),
+                    block,
+                    flashblock_index,
+                )
+                    .abi_encode(),
+                one_eth,
+            )
+        };
+        let sweep = |flashblock_index: u64| {
+            call(
+                sweeper,
+                selectors::SWEEP_EXPIRED_ORDERS,
+                flashblock_index.abi_encode(),
+                U256::ZERO,
+            )
+        };
+
+        call(
+            maker,
+            selectors::CREATE_PAIR,
+            (eth, usdc).abi_encode(),
+            U256::ZERO,
+        )
+        .expect("createPair should succeed");
+
+        // orders can't be placed already expired
+        assert!(matches!(sell_until(0, 0), Err(DexError::InvalidExpiry)));
+        assert!(matches!(sell_until(10, 0), Err(DexError::InvalidExpiry)));
+        let DexResult::OrderPlaced { order_id, .. } =
+            sell_until(10, 2).expect("placeLimitOrderWithExpiry should succeed")
+        else {
+            panic!("expected OrderPlaced");
+        };
+        sell_until(11, 0).expect("placeLimitOrderWithExpiry should succeed");
+        handler.take_transfers();
+        handler.take_storage_changes();
+
+        // sweeps can't run ahead of the flashblock being built
+        handler.set_flashblock_limit(10, 1);
+        assert!(matches!(sweep(2), Err(DexError::FlashblockNotStarted(2))));
+        let DexResult::OrdersExpired { orders } = sweep(1).expect("sweep should succeed") else {
+            panic!("expected OrdersExpired");
+        };
+        assert!(orders.is_empty());
+
+        // once the flashblock started, the order is swept and refunded
+        handler.set_flashblock_limit(10, 2);
+        let result = sweep(2).expect("sweep should succeed");
+        let DexResult::OrdersExpired { orders } = &result else {
+            panic!("expected OrdersExpired");
+        };
+        assert_eq!(orders, &vec![(order_id, maker)]);
+        assert_eq!(U256::abi_decode(&result.encode()).unwrap(), U256::from(1));
+        assert_eq!(
+            handler.take_transfers(),
+            vec![Transfer::push(eth, maker, one_eth)]
+        );
+        let id = order_id_from_b256(order_id).unwrap();
+        assert!(handler.snapshot().order(id).is_none());
+        assert!(
+            handler
+                .take_storage_changes()
+                .contains(&(storage::order_slot(id), U256::ZERO))
+        );
+
+        // orders expiring at a block are swept at its first flashblock, the limit of an earlier
+        // block doesn't apply
+        handler.set_block(11, 1_002);
+        assert_eq!(handler.snapshot().position().flashblock_index, 0);
+        assert!(matches!(
+            sweep(0),
+            Ok(DexResult::OrdersExpired { orders }) if orders.len() == 1
+        ));
+        assert_eq!(handler.snapshot().orders_of(maker).count(), 0);
+    }
+
+    #[test]
+    fn test_batch_auction_clears_at_uniform_price() {
+        let alice = address!("0000000000000000000000000000000000000097");
+        let bob = address!("0000000000000000000000000000000000000098");
+        let carol = address!("0000000000000000000000000000000000000099");
+        let builder = address!("000000000000000000000000000000000000009a");
+        let mut state = DexState::new();
+        state.set_batch_auction(true);
+        state.set_builder(Some(builder));
+        let handler = DexHandler::from_state(state);
+
+        let eth = NATIVE_TOKEN;
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let call = |caller: Address, selector: FixedBytes<4>, params: Vec<u8>, value: U256| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(caller, &calldata, value)
+        };
+        let order = |caller: Address, is_buy: bool, amount: u64, price: u64| {
+            let value = if is_buy { 0 } else { amount };
+            call(
+                caller,
+                selectors::PLACE_LIMIT_ORDER,
+                (
+                    eth,
+                    usdc,
+                    is_buy,
+                    U256::from(amount),
+                    U256::from(price),
+                    U256::from(1),
+                )
+                    .abi_encode(),
+                U256::from(value),
+            )
+        };
+
+        call(
+            alice,
+            selectors::CREATE_PAIR,
+            (eth, usdc).abi_encode(),
+            U256::ZERO,
+        )
+        .expect("createPair should succeed");
+
+        // orders are collected without trading, immediate orders and swaps are unavailable
+        let mut order_ids = Vec::new();
+        for (caller, is_buy, amount, price) in [
+            (alice, false, 10, 2),
+            (bob, false, 10, 3),
+            (carol, true, 15, 4),
+            (bob, true, 5, 2),
+        ] {
+            let placed =
+                order(caller, is_buy, amount, price).expect("placeLimitOrder should succeed");
+            let DexResult::OrderPlaced {
+                order_id, fills, ..
+            } = placed
+            else {
+                panic!("expected OrderPlaced");
+            };
+            assert!(fills.is_empty());
+            order_ids.push(order_id);
+        }
+        assert!(matches!(
+            call(
+                carol,
+                selectors::PLACE_IOC_ORDER,
+                (eth, usdc, true, U256::from(1), U256::from(4), U256::from(1)).abi_encode(),
+                U256::ZERO,
+            ),
+            Err(DexError::UnavailableInBatchAuction)
+        ));
+        assert!(matches!(
+            call(
+                carol,
+                selectors::SWAP,
+                (usdc, eth, U256::from(10), U256::ZERO).abi_encode(),
+                U256::ZERO,
+            ),
+            Err(DexError::UnavailableInBatchAuction)
+        ));
+        assert_eq!(handler.snapshot().batch_len(), 4);
+        assert_eq!(handler.snapshot().depth(eth, usdc, 10), (vec![], vec![]));
+        assert!(handler.batch_clearing_gas().is_some());
+        handler.take_transfers();
+
+        // 15 ETH trade at 3 USDC, leaving the least demand unmatched of the prices trading the most
+        // only the builder may clear
+        assert!(matches!(
+            call(carol, selectors::CLEAR_BATCH, Vec::new(), U256::ZERO),
+            Err(DexError::Unauthorized)
+        ));
+        let result = call(builder, selectors::CLEAR_BATCH, Vec::new(), U256::ZERO)
+            .expect("clearBatch should succeed");
+        assert_eq!(U256::abi_decode(&result.encode()).unwrap(), U256::from(1));
+        let DexResult::BatchCleared { auctions } = result else {
+            panic!("expected BatchCleared");
+        };
+        let [auction] = &auctions[..] else {
+            panic!("expected a single auction");
+        };
+        assert_eq!((auction.price_num, auction.price_denom), (3, 1));
+        assert_eq!(
+            (auction.volume0, auction.volume1),
+            (U256::from(15), U256::from(45))
+        );
+        let fills: Vec<_> = auction
+            .fills
+            .iter()
+            .map(|fill| {
+                (
+                    fill.order_id,
+                    fill.trader,
+                    fill.base_amount.to::<u64>(),
+                    fill.quote_amount.to::<u64>(),
+                    fill.remaining.to::<u64>(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            fills,
+            vec![
+                (order_ids[0], alice, 10, 30, 0),
+                (order_ids[1], bob, 5, 15, 5),
+                (order_ids[2], carol, 15, 45, 0),
+            ]
+        );
+        // carol is refunded what her limit of 4 escrowed beyond the clearing price
+        assert_eq!(
+            handler.take_transfers(),
+            vec![
+                Transfer::push(eth, carol, U256::from(15)),
+                Transfer::push(usdc, carol, U256::from(15)),
+                Transfer::push(usdc, alice, U256::from(30)),
+                Transfer::push(usdc, bob, U256::from(15)),
+            ]
+        );
+
+        // the remainders rest on the book
+        let state = handler.snapshot();
+        assert_eq!(state.batch_len(), 0);
+        let level = |price: u128, amount: u64| BookLevel {
+            price_num: price,
+            price_denom: 1,
+            amount: U256::from(amount),
+        };
+        assert_eq!(
+            state.depth(eth, usdc, 10),
+            (vec![level(2, 5)], vec![level(3, 5)])
+        );
+        let pair = state.pair(auction.pair_id).unwrap();
+        assert_eq!(pair.stats.trade_count, 2);
+        assert_eq!(
+            (pair.stats.last_price_num, pair.stats.last_price_denom),
+            (3, 1)
+        );
+        assert!(handler.batch_clearing_gas().is_none());
+    }
+
+    #[test]
+    fn test_trades_are_charged_fees() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let taker = address!("0000000000000000000000000000000000000098");
+        let treasury = address!("0000000000000000000000000000000000000097");
+        let base = address!("0000000000000000000000000000000000000001");
+        let usdc = address!("0000000000000000000000000000000000000002");
+        let mut state = DexState::new();
+        state.set_fee_schedule(FeeSchedule {
+            default: PairFees {
+                maker_bps: 10,
+                taker_bps: 30,
+            },
+            pairs: Default::default(),
+            treasury: Some(treasury),
+        });
+        let handler = DexHandler::from_state(state);
+        let call = |caller: Address, selector: FixedBytes<4>, params: Vec<u8>| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(caller, &calldata, U256::ZERO)
+        };
+
+        call(maker, selectors::CREATE_PAIR, (base, usdc).abi_encode())
+            .expect("createPair should succeed");
+        let order = |caller: Address, is_buy: bool| {
+            let (amount, price) = (U256::from(1000), U256::from(2));
+            let params = (base, usdc, is_buy, amount, price, U256::from(1));
+            call(caller, selectors::PLACE_LIMIT_ORDER, params.abi_encode())
+        };
+        order(maker, false).expect("sell order should succeed");
+        assert!(handler.pay_fees().is_empty());
+        handler.take_transfers();
+
+        // 1000 base for 2000 USDC, the maker pays 2 USDC and the taker 3 base
+        order(taker, true).expect("buy order should succeed");
+        let transfers = handler.take_transfers();
+        assert!(transfers.contains(&Transfer::push(usdc, maker, U256::from(1998))));
+        assert!(transfers.contains(&Transfer::push(base, taker, U256::from(997))));
+        assert_eq!(
+            handler.pay_fees(),
+            vec![
+                Transfer::push(base, treasury, U256::from(3)),
+                Transfer::push(usdc, treasury, U256::from(2)),
+            ]
+        );
+        assert!(handler.pay_fees().is_empty());
+
+        // swaps fail if the output after fees falls short of the minimum
+        order(maker, false).expect("sell order should succeed");
+        let swap = |min_amount_out: u64| {
+            let params = (usdc, base, U256::from(2000), U256::from(min_amount_out));
+            call(taker, selectors::SWAP, params.abi_encode())
+        };
+        let pre_state = handler.snapshot();
+        assert!(matches!(swap(998), Err(DexError::SlippageExceeded)));
+        handler.restore(pre_state);
+        let Ok(DexResult::SwapExecuted { amount_out, .. }) = swap(997) else {
+            panic!("expected SwapExecuted");
+        };
+        assert_eq!(amount_out, U256::from(997));
+    }
+
+    #[test]
+    fn test_price_bands_halt_pairs() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let taker = address!("0000000000000000000000000000000000000098");
+        let admin = address!("0000000000000000000000000000000000000097");
+        let swapper = address!("0000000000000000000000000000000000000096");
+        let builder = address!("0000000000000000000000000000000000000095");
+        let base = address!("0000000000000000000000000000000000000001");
+        let usdc = address!("0000000000000000000000000000000000000002");
+        let mut state = DexState::new();
+        state.set_price_bands(PriceBands {
+            band_bps: 500,
+            max_violations: 2,
+        });
+        state.set_admin(Some(admin));
+        state.set_builder(Some(builder));
+        let handler = DexHandler::from_state(state);
+        let call = |caller: Address, selector: FixedBytes<4>, params: Vec<u8>| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(caller, &calldata, U256::ZERO)
+        };
+        let order = |caller: Address, is_buy: bool, price_num: u64, price_denom: u64| {
+            let params = (
+                base,
+                usdc,
+                is_buy,
+                U256::from(1000),
+                U256::from(price_num),
+                U256::from(price_denom),
+            );
+            call(caller, selectors::PLACE_LIMIT_ORDER, params.abi_encode())
+        };
+        // like the builder, roll back a failed operation but keep its band violation
+        let attempt = |sender, result: Result<DexResult, DexError>, pre_state| match result {
+            Err(DexError::PriceBandExceeded { pair_id }) => {
+                handler.restore(pre_state);
+                handler.record_band_violation(sender, pair_id)
+            }
+            other => panic!("expected PriceBandExceeded, got {other:?}"),
+        };
+
+        let Ok(DexResult::PairCreated { pair_id, .. }) =
+            call(maker, selectors::CREATE_PAIR, (base, usdc).abi_encode())
+        else {
+            panic!("expected PairCreated");
+        };
+        // the first trade sets the reference price of 2
+        order(maker, false, 2, 1).expect("sell order should succeed");
+        order(taker, true, 2, 1).expect("buy order should succeed");
+
+        // trading at 3 is 50% away from it
+        order(maker, false, 3, 1).expect("resting sell order should succeed");
+        let pre_state = handler.snapshot();
+        assert!(!attempt(taker, order(taker, true, 3, 1), pre_state));
+        // repeated attempts of the same sender only count once
+        let pre_state = handler.snapshot();
+        assert!(!attempt(taker, order(taker, true, 3, 1), pre_state));
+        let pre_state = handler.snapshot();
+        let swap = (usdc, base, U256::from(3000), U256::ZERO);
+        assert!(attempt(
+            swapper,
+            call(swapper, selectors::SWAP, swap.abi_encode()),
+            pre_state
+        ));
+
+        // the pair is due to halt, but only halts once the builder calls the DEX
+        assert!(!handler.snapshot().is_halted(pair_id));
+        assert_eq!(
+            handler.snapshot().pairs_to_halt().collect::<Vec<_>>(),
+            [pair_id]
+        );
+        let halt = |caller: Address| call(caller, selectors::HALT_PAIR, (base, usdc).abi_encode());
+        assert!(matches!(halt(taker), Err(DexError::Unauthorized)));
+        assert!(matches!(
+            halt(builder),
+            Ok(DexResult::PairHalted { pair_id: halted }) if halted == pair_id
+        ));
+        assert!(handler.snapshot().is_halted(pair_id));
+        assert!(matches!(halt(builder), Err(DexError::HaltNotDue)));
+        assert!(matches!(
+            order(taker, true, 41, 20),
+            Err(DexError::PairHalted)
+        ));
+
+        // only the admin resumes the pair, after which trading within the band works again
+        let resume =
+            |caller: Address| call(caller, selectors::RESUME_PAIR, (usdc, base).abi_encode());
+        assert!(matches!(resume(maker), Err(DexError::Unauthorized)));
+        assert!(matches!(
+            resume(admin),
+            Ok(DexResult::PairResumed { admin: a, .. }) if a == admin
+        ));
+        assert!(!handler.snapshot().is_halted(pair_id));
+        order(maker, false, 41, 20).expect("sell order should succeed");
+        let Ok(DexResult::OrderPlaced { fills, .. }) = order(taker, true, 41, 20) else {
+            panic!("expected OrderPlaced");
+        };
+        assert_eq!(fills.len(), 1);
+    }
+
+    #[test]
+    fn test_permissioned_listing_and_pair_specs() {
+        let admin = address!("0000000000000000000000000000000000000097");
+        let lister = address!("0000000000000000000000000000000000000096");
+        let trader = address!("0000000000000000000000000000000000000099");
+        let eth = NATIVE_TOKEN;
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let dai = address!("0000000000000000000000000000000000000002");
+        let mut state = DexState::new();
+        state.set_admin(Some(admin));
+        state.set_listing_policy(ListingPolicy {
+            permissioned: true,
+            listers: [lister].into(),
+            native_eth: true,
+        });
+        let handler = DexHandler::from_state(state);
+        let call = |caller: Address, selector: FixedBytes<4>, params: Vec<u8>| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(caller, &calldata, U256::ZERO)
+        };
+        let lot = U256::from(10u64.pow(15));
+        let listing = PairListing {
+            token0: eth,
+            token1: usdc,
+            decimals0: 18,
+            decimals1: 6,
+            symbol0: "ETH".to_string(),
+            symbol1: "USDC".to_string(),
+            tickSize: PRICE_SCALE / U256::from(100),
+            lotSize: lot,
+        };
+
+        // only the admin and the listers create pairs, ETH being the zero address
+        assert!(matches!(
+            call(trader, selectors::CREATE_PAIR, (eth, usdc).abi_encode()),
+            Err(DexError::Unauthorized)
+        ));
+        assert!(matches!(
+            call(trader, selectors::LIST_PAIR, listing.abi_encode()),
+            Err(DexError::Unauthorized)
+        ));
+        call(lister, selectors::LIST_PAIR, listing.abi_encode())
+            .expect("listers should list pairs");
+        call(admin, selectors::CREATE_PAIR, (eth, dai).abi_encode())
+            .expect("the admin should create pairs");
+
+        // pairs are reported with their tokens in the order they were listed with
+        let listed = call(
+            trader,
+            selectors::GET_PAIR_LISTING,
+            (usdc, eth).abi_encode(),
+        )
+        .expect("getPairListing should succeed");
+        assert_eq!(PairListing::abi_decode(&listed.encode()).unwrap(), listing);
+        let Ok(DexResult::Listing(listed)) =
+            call(trader, selectors::GET_PAIR_LISTING, (dai, eth).abi_encode())
+        else {
+            panic!("expected Listing");
+        };
+        assert_eq!(listed.decimals1, DEFAULT_DECIMALS);
+        assert!(listed.symbol1.is_empty());
+        assert_eq!(listed.tickSize, U256::ZERO);
+
+        // orders trade whole lots of ETH at whole ticks of 0.01 USDC per ETH
+        let buy = |amount: U256, price_num: u64, price_denom: u64| {
+            let price = (U256::from(price_num), U256::from(price_denom));
+            let params = (eth, usdc, true, amount, price.0, price.1);
+            call(trader, selectors::PLACE_LIMIT_ORDER, params.abi_encode())
+        };
+        buy(lot * U256::from(3), 201, 100).expect("order on ticks and lots should succeed");
+        assert!(matches!(buy(lot, 2001, 1000), Err(DexError::OffTickPrice)));
+        assert!(matches!(
+            buy(lot + U256::from(1), 2, 1),
+            Err(DexError::OddLotAmount)
+        ));
+
+        // symbols have to fit into the storage of the pair
+        let long_symbol = PairListing {
+            token0: usdc,
+            token1: dai,
+            symbol0: "X".repeat(33),
+            ..listing
+        };
+        assert!(matches!(
+            call(admin, selectors::LIST_PAIR, long_symbol.abi_encode()),
+            Err(DexError::InvalidSymbol)
+        ));
+    }
+
+    #[test]
+    fn test_twap_ignores_trades_of_the_current_block() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let taker = address!("0000000000000000000000000000000000000098");
+        let base = address!("0000000000000000000000000000000000000001");
+        let usdc = address!("0000000000000000000000000000000000000002");
+        let handler = DexHandler::new();
+        let call = |caller: Address, selector: FixedBytes<4>, params: Vec<u8>| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(caller, &calldata, U256::ZERO)
+        };
+        let trade = |price: u64| {
+            for (caller, is_buy) in [(maker, false), (taker, true)] {
+                let params = (
+                    base,
+                    usdc,
+                    is_buy,
+                    U256::from(1000),
+                    U256::from(price),
+                    U256::from(1),
+                );
+                call(caller, selectors::PLACE_LIMIT_ORDER, params.abi_encode())
+                    .expect("order should succeed");
+            }
+        };
+        let twap = |token0: Address, token1: Address, window: u64| {
+            call(
+                maker,
+                selectors::GET_TWAP,
+                (token0, token1, window).abi_encode(),
+            )
+        };
+
+        handler.set_block(1, 100);
+        call(maker, selectors::CREATE_PAIR, (base, usdc).abi_encode())
+            .expect("createPair should succeed");
+        trade(2);
+        handler.set_block(2, 110);
+        trade(4);
+        // 2 held for 10 seconds and 4 for 30, whatever trades in the current block
+        handler.set_block(3, 140);
+        trade(8);
+        let Ok(DexResult::Twap {
+            price_num,
+            price_denom,
+        }) = twap(base, usdc, 40)
+        else {
+            panic!("expected Twap");
+        };
+        assert_eq!(price_num * U256::from(2), price_denom * U256::from(7));
+        let Ok(DexResult::Twap { price_num, .. }) = twap(usdc, base, 30) else {
+            panic!("expected Twap");
+        };
+        assert_eq!(price_num, PRICE_SCALE / U256::from(4));
+        assert!(matches!(
+            twap(base, usdc, 41),
+            Err(DexError::TwapUnavailable(41))
+        ));
+    }
+
+    #[test]
+    fn test_signed_orders() {
+        let maker = Signer::random();
+        let relayer = address!("0000000000000000000000000000000000000098");
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let mut state = DexState::new();
+        state.set_chain_id(10);
+        let handler = DexHandler::from_state(state);
+        let call = |selector: FixedBytes<4>, params: Vec<u8>| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(relayer, &calldata, U256::ZERO)
+        };
+        call(selectors::CREATE_PAIR, (eth, usdc).abi_encode()).expect("createPair should succeed");
+
+        let order = |nonce: u64| SignedOrder {
+            maker: maker.address,
+            tokenIn: eth,
+            tokenOut: usdc,
+            isBuy: false,
+            amount: U256::from(1000),
+            priceNum: U256::from(2),
+            priceDenom: U256::from(1),
+            expiryBlock: 0,
+            expiryFlashblockIndex: 0,
+            nonce,
+        };
+        let submit = |order: SignedOrder, chain_id: u64, dex: Address| {
+            let signature = maker
+                .sign_message(order.hash(&domain(chain_id, dex)))
+                .expect("sign order");
+            let signature = Bytes::copy_from_slice(&signature.as_bytes());
+            call(
+                selectors::SUBMIT_SIGNED_ORDER,
+                (order, signature).abi_encode_params(),
+            )
+        };
+
+        // orders signed for another chain or another DEX don't verify
+        assert!(matches!(
+            submit(order(1), 1, DEX_PREDEPLOY_ADDRESS),
+            Err(DexError::InvalidSignature)
+        ));
+        assert!(matches!(
+            submit(order(1), 10, relayer),
+            Err(DexError::InvalidSignature)
+        ));
+
+        let Ok(DexResult::SignedOrderPlaced {
+            maker: signer,
+            nonce,
+            placed,
+            ..
+        }) = submit(order(1), 10, DEX_PREDEPLOY_ADDRESS)
+        else {
+            panic!("expected SignedOrderPlaced");
+        };
+        assert_eq!((signer, nonce), (maker.address, 1));
+        let order_id = placed.order_id().and_then(order_id_from_b256).unwrap();
+        assert_eq!(
+            handler.snapshot().order(order_id).map(|order| order.owner),
+            Some(maker.address)
+        );
+        // the escrow is pulled from the maker, even in ETH
+        assert_eq!(
+            handler.take_transfers(),
+            vec![Transfer::pull(eth, maker.address, U256::from(1000))]
+        );
+
+        // the nonce is used up, so the order can't be replayed
+        assert!(matches!(
+            submit(order(1), 10, DEX_PREDEPLOY_ADDRESS),
+            Err(DexError::NonceTooLow { nonce: 1, last: 1 })
+        ));
+        assert!(submit(order(3), 10, DEX_PREDEPLOY_ADDRESS).is_ok());
+        assert!(matches!(
+            submit(order(2), 10, DEX_PREDEPLOY_ADDRESS),
+            Err(DexError::NonceTooLow { nonce: 2, last: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_view_functions() {
+        let maker = address!("0000000000000000000000000000000000000099");
+        let handler = DexHandler::new();
+
+        let eth = NATIVE_TOKEN;
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let one_eth = U256::from(10u64.pow(18));
+        let call = |selector: FixedBytes<4>, params: Vec<u8>, value: U256| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            handler.handle_transaction(maker, &calldata, value)
+        };
+
+        call(selectors::CREATE_PAIR, (eth, usdc).abi_encode(), U256::ZERO)
+            .expect("createPair should succeed");
+        for (amount, price) in [(one_eth, 2100), (one_eth, 2000), (one_eth, 2000)] {
+            call(
+                selectors::PLACE_LIMIT_ORDER,
+                (eth, usdc, false, amount, U256::from(price), U256::from(1)).abi_encode(),
+                amount,
+            )
+            .expect("placeLimitOrder should succeed");
+        }
+
+        // equal prices are merged and asks are sorted from the lowest price up
+        let depth = call(
+            selectors::GET_ORDERBOOK_DEPTH,
+            (eth, usdc, U256::from(1)).abi_encode(),
+            U256::ZERO,
+        )
+        .expect("getOrderbookDepth should succeed");
+        let (bids, asks) =
+            <(Vec<DepthLevel>, Vec<DepthLevel>)>::abi_decode_params(&depth.encode()).unwrap();
+        assert!(bids.is_empty());
+        assert_eq!(
+            asks,
+            vec![DepthLevel {
+                priceNum: U256::from(2000),
+                priceDenom: U256::from(1),
+                amount: one_eth * U256::from(2),
+            }]
+        );
+
+        // seen from the other side, the asks for ETH are bids for USDC
+        let depth = call(
+            selectors::GET_ORDERBOOK_DEPTH,
+            (usdc, eth, U256::from(10)).abi_encode(),
+            U256::ZERO,
+        )
+        .expect("getOrderbookDepth should succeed");
+        let DexResult::OrderbookDepth { bids, asks } = depth else {
+            panic!("expected OrderbookDepth");
+        };
+        assert!(asks.is_empty());
+        assert_eq!(bids.len(), 2);
+        assert_eq!(bids[0].priceDenom, U256::from(2000));
+
+        let orders = call(selectors::GET_USER_ORDERS, maker.abi_encode(), U256::ZERO)
+            .expect("getUserOrders should succeed");
+        let orders = Vec::<OrderInfo>::abi_decode(&orders.encode()).unwrap();
+        assert_eq!(orders.len(), 3);
+        assert!(orders.iter().all(|order| order.owner == maker));
+
+        let order = call(
+            selectors::GET_ORDER,
+            orders[0].orderId.abi_encode(),
+            U256::ZERO,
+        )
+        .expect("getOrder should succeed");
+        assert_eq!(OrderInfo::abi_decode(&order.encode()).unwrap(), orders[0]);
+        assert!(matches!(
+            call(
+                selectors::GET_ORDER,
+                B256::with_last_byte(0xff).abi_encode(),
+                U256::ZERO
+            ),
+            Err(DexError::OrderNotFound)
+        ));
+
+        let stats = call(
+            selectors::GET_PAIR_STATS,
+            (eth, usdc).abi_encode(),
+            U256::ZERO,
+        )
+        .expect("getPairStats should succeed");
+        let (_, _, volume0, volume1, trades_24h, trades) =
+            <(U256, U256, U256, U256, U256, U256)>::abi_decode_params(&stats.encode()).unwrap();
+        assert_eq!(
+            (volume0, volume1, trades_24h, trades),
+            (U256::ZERO, U256::ZERO, U256::ZERO, U256::ZERO)
+        );
+    }
 }
@@ -0,0 +1,708 @@
+//! Journal of DEX states tied to blocks.
+//!
+//! The DEX order book lives in memory, so it has to follow the same lifecycle as the EVM state
+//! the payload builder works on. Every payload job starts from the DEX state of its parent block
+//! and works on a private copy. Whenever a flashblock is sealed, the state it produced is recorded
+//! in the journal under the block hash. Nothing becomes canonical until the canonical state
+//! stream reports that block, and reorgs roll the canonical state back to whatever the new tip
+//! produced. A canonical block that was neither built nor synced by this node leaves its DEX state
+//! unknown until the block is replayed, and nothing can be built on top of it in the meantime.
+//! If [snapshots](DexSnapshots) are enabled, the canonical state is written to disk
+//! every few blocks. The journal also holds the [signed orders](SignedOrderPool) waiting to be
+//! placed, which are dropped once the canonical state used up their nonces.
+
+use super::{
+    DEX_PREDEPLOY_ADDRESS, DexHandler, DexState, bands::PriceBands, fees::FeeSchedule,
+    genesis::GenesisPair, listing::ListingPolicy, signed::SignedOrderPool, snapshot::DexSnapshots,
+};
+use alloy_consensus::BlockHeader;
+use alloy_primitives::{Address, B256};
+use parking_lot::RwLock;
+use reth_node_api::NodePrimitives;
+use reth_provider::CanonStateNotification;
+use std::{
+    collections::HashMap,
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use tracing::{debug, warn};
+
+/// Number of blocks below the canonical tip for which recorded states are retained, so that
+/// payload jobs and reorgs building on recent blocks can still find their DEX state.
+const RETAINED_BLOCKS: u64 = 64;
+
+/// DEX state recorded for a sealed block.
+#[derive(Debug, Clone)]
+struct RecordedDexState {
+    /// Number of the block that produced this state
+    number: u64,
+    /// The DEX state after executing the block
+    state: DexState,
+}
+
+/// DEX state of the canonical chain tip.
+#[derive(Debug, Clone, Default)]
+struct CanonicalDexState {
+    /// Hash of the canonical block this state belongs to.
+    ///
+    /// `None` until the state is rebuilt on startup or a recorded block becomes canonical, the
+    /// state isn't known to belong to any block until then.
+    hash: Option<B256>,
+    /// Number of the canonical block this state belongs to
+    number: u64,
+    /// The DEX state after executing the canonical block
+    state: DexState,
+}
+
+#[derive(Debug, Default)]
+struct JournalInner {
+    /// The DEX state of the newest canonical block it is known of
+    canonical: CanonicalDexState,
+    /// Hash and number of the canonical tip, if its DEX state isn't known
+    unknown_tip: Option<(B256, u64)>,
+    recorded: HashMap<B256, RecordedDexState>,
+    /// Hash of the block whose state was recorded last, i.e. the latest flashblock
+    latest: Option<B256>,
+}
+
+/// Tracks DEX states per block and commits them once the block becomes canonical.
+///
+/// The configuration of the DEX, such as its fees, price bands and address, is set once with the
+/// `set_*` methods and [`Self::enable_batch_auction`]. Only the canonical state and the states
+/// built on top of it pick it up, so it has to be set before the DEX state is rebuilt on startup.
+/// Each setting can only be set once, later calls are ignored.
+#[derive(Debug, Default)]
+pub struct DexJournal {
+    inner: RwLock<JournalInner>,
+    snapshots: OnceLock<DexSnapshots>,
+    max_replay_blocks: OnceLock<u64>,
+    disabled: AtomicBool,
+    batch_auction: AtomicBool,
+    fee_schedule: OnceLock<FeeSchedule>,
+    chain_id: OnceLock<u64>,
+    price_bands: OnceLock<PriceBands>,
+    admin: OnceLock<Address>,
+    builder: OnceLock<Address>,
+    listing_policy: OnceLock<ListingPolicy>,
+    address: OnceLock<Address>,
+    genesis_pairs: OnceLock<Vec<GenesisPair>>,
+    signed_orders: SignedOrderPool,
+}
+
+impl DexJournal {
+    /// Create a journal starting from an empty DEX state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a journal whose canonical state is the given one.
+    pub fn with_canonical_state(state: DexState) -> Self {
+        Self {
+            inner: RwLock::new(JournalInner {
+                canonical: CanonicalDexState {
+                    state,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            snapshots: OnceLock::new(),
+            max_replay_blocks: OnceLock::new(),
+            disabled: AtomicBool::new(false),
+            batch_auction: AtomicBool::new(false),
+            fee_schedule: OnceLock::new(),
+            chain_id: OnceLock::new(),
+            price_bands: OnceLock::new(),
+            admin: OnceLock::new(),
+            builder: OnceLock::new(),
+            listing_policy: OnceLock::new(),
+            address: OnceLock::new(),
+            genesis_pairs: OnceLock::new(),
+            signed_orders: SignedOrderPool::default(),
+        }
+    }
+
+    /// Write snapshots of the canonical state to the given directory from now on.
+    ///
+    /// Snapshots can only be enabled once, later calls are ignored.
+    pub fn enable_snapshots(&self, snapshots: DexSnapshots) {
+        let _ = self.snapshots.set(snapshots);
+    }
+
+    /// Returns the snapshot directory, if snapshots are enabled.
+    pub fn snapshots(&self) -> Option<&DexSnapshots> {
+        self.snapshots.get()
+    }
+
+    /// Refuse to rebuild the DEX state if more than `max_replay_blocks` blocks would have to be
+    /// replayed.
+    pub fn set_max_replay_blocks(&self, max_replay_blocks: u64) {
+        let _ = self.max_replay_blocks.set(max_replay_blocks);
+    }
+
+    /// Returns the number of blocks the DEX state may be rebuilt from at most, if it is limited.
+    pub fn max_replay_blocks(&self) -> Option<u64> {
+        self.max_replay_blocks.get().copied()
+    }
+
+    /// Turn the DEX off: payload jobs and synced blocks are executed without the DEX precompile
+    /// and its state is neither rebuilt nor recorded.
+    pub fn disable(&self) {
+        self.disabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the DEX is executed at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Collect orders and clear them in batch auctions instead of matching them right away.
+    pub fn enable_batch_auction(&self) {
+        self.batch_auction.store(true, Ordering::Relaxed);
+        self.inner.write().canonical.state.set_batch_auction(true);
+    }
+
+    /// Whether orders are cleared in batch auctions.
+    pub fn batch_auction(&self) -> bool {
+        self.batch_auction.load(Ordering::Relaxed)
+    }
+
+    /// Charge trades the fees of the given schedule.
+    pub fn set_fee_schedule(&self, fee_schedule: FeeSchedule) {
+        if self.fee_schedule.set(fee_schedule.clone()).is_ok() {
+            self.inner
+                .write()
+                .canonical
+                .state
+                .set_fee_schedule(fee_schedule);
+        }
+    }
+
+    /// Returns the fees charged on trades.
+    pub fn fee_schedule(&self) -> FeeSchedule {
+        self.fee_schedule.get().cloned().unwrap_or_default()
+    }
+
+    /// Verify signed orders as signed for the chain with the given id.
+    pub fn set_chain_id(&self, chain_id: u64) {
+        if self.chain_id.set(chain_id).is_ok() {
+            self.inner.write().canonical.state.set_chain_id(chain_id);
+        }
+    }
+
+    /// Returns the id of the chain signed orders are signed for.
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id.get().copied().unwrap_or_default()
+    }
+
+    /// Check fills against the given price bands.
+    pub fn set_price_bands(&self, price_bands: PriceBands) {
+        if self.price_bands.set(price_bands).is_ok() {
+            self.inner
+                .write()
+                .canonical
+                .state
+                .set_price_bands(price_bands);
+        }
+    }
+
+    /// Returns the price bands fills are checked against.
+    pub fn price_bands(&self) -> PriceBands {
+        self.price_bands.get().copied().unwrap_or_default()
+    }
+
+    /// Allow `admin` to resume halted pairs.
+    pub fn set_admin(&self, admin: Address) {
+        if self.admin.set(admin).is_ok() {
+            self.inner.write().canonical.state.set_admin(Some(admin));
+        }
+    }
+
+    /// Returns the account allowed to resume halted pairs, if any.
+    pub fn admin(&self) -> Option<Address> {
+        self.admin.get().copied()
+    }
+
+    /// Allow `builder`, the account signing builder transactions, to clear batch auctions and
+    /// halt pairs.
+    pub fn set_builder(&self, builder: Address) {
+        if self.builder.set(builder).is_ok() {
+            self.inner
+                .write()
+                .canonical
+                .state
+                .set_builder(Some(builder));
+        }
+    }
+
+    /// Returns the account allowed to clear batch auctions and halt pairs, if any.
+    pub fn builder(&self) -> Option<Address> {
+        self.builder.get().copied()
+    }
+
+    /// Let the given policy decide who may create pairs.
+    pub fn set_listing_policy(&self, listing_policy: ListingPolicy) {
+        if self.listing_policy.set(listing_policy.clone()).is_ok() {
+            self.inner
+                .write()
+                .canonical
+                .state
+                .set_listing_policy(listing_policy);
+        }
+    }
+
+    /// Returns the policy deciding who may create pairs.
+    pub fn listing_policy(&self) -> ListingPolicy {
+        self.listing_policy.get().cloned().unwrap_or_default()
+    }
+
+    /// Install the DEX at `address` instead of the predeploy address.
+    pub fn set_address(&self, address: Address) {
+        if self.address.set(address).is_ok() {
+            self.inner.write().canonical.state.set_address(address);
+        }
+    }
+
+    /// Returns the address the DEX is installed at.
+    pub fn address(&self) -> Address {
+        self.address.get().copied().unwrap_or(DEX_PREDEPLOY_ADDRESS)
+    }
+
+    /// Start the DEX out with the given pairs when its state is rebuilt from genesis.
+    pub fn set_genesis_pairs(&self, pairs: Vec<GenesisPair>) {
+        let _ = self.genesis_pairs.set(pairs);
+    }
+
+    /// Returns the pairs the DEX starts out with at genesis.
+    pub fn genesis_pairs(&self) -> &[GenesisPair] {
+        self.genesis_pairs.get().map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the signed orders waiting to be placed by the builder.
+    pub fn signed_orders(&self) -> &SignedOrderPool {
+        &self.signed_orders
+    }
+
+    /// Replace the canonical state with the DEX state after the given block.
+    ///
+    /// Used on startup once the DEX state has been rebuilt from the chain. Recorded states are
+    /// discarded, as they may build on top of a different state.
+    pub fn reset_canonical(&self, block_hash: B256, block_number: u64, state: DexState) {
+        *self.inner.write() = JournalInner {
+            canonical: CanonicalDexState {
+                hash: Some(block_hash),
+                number: block_number,
+                state,
+            },
+            ..Default::default()
+        };
+    }
+
+    /// Returns the DEX state after executing the given block, if it is known.
+    ///
+    /// Blocks built or synced by this node are found among the recorded states, otherwise the
+    /// canonical state is used if it belongs to the requested block. No other block has a known
+    /// state, which includes every block before the canonical state is known. The returned state
+    /// shares its pairs and order books with the journal, so the journal is only locked for as
+    /// long as it takes to copy the bookkeeping of the current block.
+    pub fn state_at(&self, block_hash: B256) -> Option<DexState> {
+        let inner = self.inner.read();
+        if let Some(recorded) = inner.recorded.get(&block_hash) {
+            return Some(recorded.state.clone());
+        }
+        (inner.canonical.hash == Some(block_hash)).then(|| inner.canonical.state.clone())
+    }
+
+    /// Returns the hash and DEX state of the newest canonical block whose DEX state is known.
+    ///
+    /// This is the canonical tip unless [`Self::unknown_tip`] returns it.
+    pub fn canonical_state(&self) -> (Option<B256>, DexState) {
+        let inner = self.inner.read();
+        (inner.canonical.hash, inner.canonical.state.clone())
+    }
+
+    /// Returns the hash, number and DEX state of the newest canonical block whose DEX state is
+    /// known, if the DEX state of the canonical tip isn't, so the blocks after it can be replayed.
+    pub fn lagging_canonical_state(&self) -> Option<(B256, u64, DexState)> {
+        let inner = self.inner.read();
+        inner.unknown_tip?;
+        Some((
+            inner.canonical.hash?,
+            inner.canonical.number,
+            inner.canonical.state.clone(),
+        ))
+    }
+
+    /// Returns the hash and number of the canonical tip if its DEX state isn't known.
+    pub fn unknown_tip(&self) -> Option<(B256, u64)> {
+        self.inner.read().unknown_tip
+    }
+
+    /// Returns the DEX state of the pending block.
+    ///
+    /// This is the state after the latest flashblock, as long as it builds on top of the
+    /// canonical tip, and the canonical state otherwise. Like [`Self::state_at`], it shares the
+    /// pairs and order books with the journal.
+    pub fn pending_state(&self) -> DexState {
+        let inner = self.inner.read();
+        inner
+            .latest
+            .and_then(|hash| inner.recorded.get(&hash))
+            .filter(|recorded| recorded.number > inner.canonical.number)
+            .map_or_else(
+                || inner.canonical.state.clone(),
+                |recorded| recorded.state.clone(),
+            )
+    }
+
+    /// Create a handler with a private copy of the DEX state after `block_hash`, if it is known.
+    ///
+    /// Operations executed through the returned handler are not visible to anybody else until
+    /// they are [`recorded`](Self::record) and the block becomes canonical. There is no fallback
+    /// for blocks whose DEX state isn't known: building on top of some other state would produce
+    /// DEX operations that no other node reproduces.
+    pub fn handler_at(&self, block_hash: B256) -> Option<DexHandler> {
+        self.state_at(block_hash).map(DexHandler::from_state)
+    }
+
+    /// Record the DEX state produced by a sealed block.
+    ///
+    /// Each flashblock seals a new block, so a single payload job records one state per
+    /// flashblock. Only the one that ends up canonical is committed.
+    pub fn record(&self, block_hash: B256, block_number: u64, state: DexState) {
+        let mut inner = self.inner.write();
+        inner.recorded.insert(
+            block_hash,
+            RecordedDexState {
+                number: block_number,
+                state,
+            },
+        );
+        inner.latest = Some(block_hash);
+    }
+
+    /// Record the DEX state of a canonical tip that was unknown and had to be replayed, and
+    /// commit it if the block is still the canonical tip.
+    pub fn resolve_unknown_tip(&self, tip_hash: B256, tip_number: u64, state: DexState) {
+        self.record(tip_hash, tip_number, state);
+        if self.unknown_tip() == Some((tip_hash, tip_number)) {
+            self.advance_canonical(tip_hash, tip_number, vec![]);
+        }
+    }
+
+    /// Follow a canonical state notification.
+    ///
+    /// Commits the DEX state recorded for the new canonical tip and drops states that are too
+    /// old to be built upon. On reorgs the reverted blocks are discarded, which rolls the DEX back
+    /// to the state of the new tip.
+    pub fn on_canonical_state<N: NodePrimitives>(&self, notification: &CanonStateNotification<N>) {
+        let reverted = notification
+            .reverted()
+            .map(|chain| chain.blocks_iter().map(|block| block.hash()).collect())
+            .unwrap_or_default();
+        let tip = notification.tip();
+        self.advance_canonical(tip.hash(), tip.header().number(), reverted);
+    }
+
+    fn advance_canonical(&self, tip_hash: B256, tip_number: u64, reverted: Vec<B256>) {
+        let mut inner = self.inner.write();
+
+        for hash in &reverted {
+            inner.recorded.remove(hash);
+        }
+        if !reverted.is_empty() {
+            debug!(
+                target: "dex",
+                reverted_blocks = reverted.len(),
+                ?tip_hash,
+                "Rolling back DEX state after reorg"
+            );
+        }
+
+        let mut committed = None;
+        match inner.recorded.get(&tip_hash).cloned() {
+            Some(recorded) => {
+                inner.canonical = CanonicalDexState {
+                    hash: Some(tip_hash),
+                    number: tip_number,
+                    state: recorded.state.clone(),
+                };
+                inner.unknown_tip = None;
+                committed = Some(recorded.state);
+                debug!(target: "dex", ?tip_hash, tip_number, "Committed DEX state");
+            }
+            None if inner.canonical.hash == Some(tip_hash) => inner.unknown_tip = None,
+            None => {
+                // The block was neither built nor synced by us, so we can't know its DEX
+                // effects. Keep the last known state under its own block, so that the blocks
+                // after it can be replayed, and don't let anybody build on the tip until then.
+                warn!(
+                    target: "dex",
+                    ?tip_hash,
+                    tip_number,
+                    known_hash = ?inner.canonical.hash,
+                    known_number = inner.canonical.number,
+                    "No DEX state recorded for canonical block, DEX state unknown until replayed"
+                );
+                inner.unknown_tip = Some((tip_hash, tip_number));
+            }
+        }
+
+        inner
+            .recorded
+            .retain(|_, recorded| recorded.number + RETAINED_BLOCKS > tip_number);
+        self.signed_orders.prune(&inner.canonical.state);
+        drop(inner);
+
+        if let (Some(snapshots), Some(state)) = (self.snapshots.get(), committed)
+            && snapshots.is_due(tip_number)
+        {
+            match snapshots.write(tip_hash, tip_number, &state) {
+                Ok(()) => debug!(target: "dex", ?tip_hash, tip_number, "Wrote DEX snapshot"),
+                Err(err) => warn!(
+                    target: "dex",
+                    ?tip_hash,
+                    tip_number,
+                    error = %err,
+                    "Failed to write DEX snapshot"
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dex::{DexResult, predeploy::selectors};
+    use alloy_primitives::{Address, Bytes, U256, address};
+    use alloy_sol_types::SolValue;
+
+    fn create_pair(handler: &DexHandler, token0: Address, token1: Address) -> DexResult {
+        let calldata: Bytes = [
+            selectors::CREATE_PAIR.as_slice(),
+            &(token0, token1).abi_encode(),
+        ]
+        .concat()
+        .into();
+        handler
+            .handle_transaction(Address::ZERO, &calldata, U256::ZERO)
+            .expect("should create pair")
+    }
+
+    #[test]
+    fn test_payload_job_state_is_isolated_until_canonical() {
+        let journal = DexJournal::new();
+        let parent = B256::with_last_byte(1);
+        let block = B256::with_last_byte(2);
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        journal.reset_canonical(parent, 1, DexState::default());
+
+        let handler = journal.handler_at(parent).unwrap();
+        create_pair(&handler, eth, usdc);
+        journal.record(block, 2, handler.snapshot());
+
+        // another job building on the same parent doesn't see the pair
+        let sibling = journal.handler_at(parent).unwrap();
+        create_pair(&sibling, eth, usdc);
+
+        // a job building on top of the recorded block does
+        let child = journal.handler_at(block).unwrap();
+        let calldata: Bytes = [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()]
+            .concat()
+            .into();
+        assert!(
+            child
+                .handle_transaction(Address::ZERO, &calldata, U256::ZERO)
+                .is_err()
+        );
+
+        journal.advance_canonical(block, 2, vec![]);
+        assert_eq!(journal.canonical_state().0, Some(block));
+    }
+
+    #[test]
+    fn test_replay_requires_known_parent_state() {
+        let journal = DexJournal::new();
+        let parent = B256::with_last_byte(1);
+        let unknown = B256::with_last_byte(2);
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        journal.reset_canonical(parent, 1, DexState::default());
+
+        assert!(journal.handler_at(unknown).is_none());
+
+        // replays work on a private copy of the parent's state
+        let replay = journal.handler_at(parent).expect("parent state is known");
+        create_pair(&replay, eth, usdc);
+        let builder = journal.handler_at(parent).unwrap();
+        create_pair(&builder, eth, usdc);
+    }
+
+    #[test]
+    fn test_reset_canonical_to_rebuilt_state() {
+        let journal = DexJournal::new();
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let head = B256::with_last_byte(7);
+        journal.record(B256::with_last_byte(8), 8, DexState::default());
+
+        let rebuilt = DexHandler::new();
+        create_pair(&rebuilt, eth, usdc);
+        journal.reset_canonical(head, 7, rebuilt.snapshot());
+
+        assert_eq!(journal.canonical_state().0, Some(head));
+        assert!(journal.state_at(B256::with_last_byte(8)).is_none());
+        let calldata: Bytes = [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()]
+            .concat()
+            .into();
+        let handler = journal.handler_at(head).expect("head state is known");
+        assert!(
+            handler
+                .handle_transaction(Address::ZERO, &calldata, U256::ZERO)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_unknown_canonical_tip_until_replayed() {
+        let journal = DexJournal::new();
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let known = B256::with_last_byte(1);
+        let unknown = B256::with_last_byte(2);
+        journal.reset_canonical(known, 1, DexState::default());
+
+        // a block built by somebody else that we didn't execute
+        journal.advance_canonical(unknown, 2, vec![]);
+        assert_eq!(journal.unknown_tip(), Some((unknown, 2)));
+        assert!(journal.handler_at(unknown).is_none());
+        let (hash, number, state) = journal.lagging_canonical_state().unwrap();
+        assert_eq!((hash, number), (known, 1));
+
+        // replaying it on top of the last known state makes it buildable again
+        let replay = DexHandler::from_state(state);
+        create_pair(&replay, eth, usdc);
+        journal.resolve_unknown_tip(unknown, 2, replay.snapshot());
+        assert_eq!(journal.unknown_tip(), None);
+        assert!(journal.lagging_canonical_state().is_none());
+        assert_eq!(journal.canonical_state().0, Some(unknown));
+        let handler = journal.handler_at(unknown).unwrap();
+        let calldata: Bytes = [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()]
+            .concat()
+            .into();
+        assert!(
+            handler
+                .handle_transaction(Address::ZERO, &calldata, U256::ZERO)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_pending_state_follows_latest_flashblock() {
+        let journal = DexJournal::new();
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let parent = B256::with_last_byte(1);
+        journal.reset_canonical(parent, 1, DexState::default());
+
+        let handler = journal.handler_at(parent).unwrap();
+        journal.record(B256::with_last_byte(2), 2, handler.snapshot());
+        create_pair(&handler, eth, usdc);
+        journal.record(B256::with_last_byte(3), 2, handler.snapshot());
+        assert_eq!(journal.pending_state().pairs().count(), 1);
+
+        // once the block is canonical, the pending state falls back to the canonical one
+        journal.advance_canonical(B256::with_last_byte(2), 2, vec![]);
+        assert_eq!(journal.pending_state().pairs().count(), 0);
+    }
+
+    #[test]
+    fn test_canonical_states_are_snapshotted() {
+        let dir = std::env::temp_dir().join(format!("dex-journal-{}", std::process::id()));
+        let journal = DexJournal::new();
+        journal.enable_snapshots(DexSnapshots::new(&dir, 2));
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+
+        let handler = DexHandler::new();
+        create_pair(&handler, eth, usdc);
+        for number in 1..=3u64 {
+            let hash = B256::with_last_byte(number as u8);
+            journal.record(hash, number, handler.snapshot());
+            journal.advance_canonical(hash, number, vec![]);
+        }
+        // a block we don't know the DEX state of is never snapshotted
+        journal.advance_canonical(B256::with_last_byte(4), 4, vec![]);
+
+        let snapshots = journal.snapshots().unwrap();
+        assert_eq!(
+            snapshots.available().unwrap(),
+            vec![(2, B256::with_last_byte(2))]
+        );
+        let restored = snapshots.read(B256::with_last_byte(2), 2).unwrap();
+        assert_eq!(restored.records(), handler.snapshot().records());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_flashblock_checkpoint_rollback() {
+        let journal = DexJournal::new();
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let dai = address!("0000000000000000000000000000000000000002");
+        journal.reset_canonical(B256::ZERO, 0, DexState::default());
+
+        let handler = journal.handler_at(B256::ZERO).unwrap();
+        create_pair(&handler, eth, usdc);
+        handler.checkpoint();
+
+        // executed in a flashblock that is never sealed
+        create_pair(&handler, eth, dai);
+        handler.revert_to_checkpoint();
+
+        // the second pair is gone, the first one is kept
+        create_pair(&handler, eth, dai);
+        let calldata: Bytes = [selectors::CREATE_PAIR.as_slice(), &(eth, usdc).abi_encode()]
+            .concat()
+            .into();
+        assert!(
+            handler
+                .handle_transaction(Address::ZERO, &calldata, U256::ZERO)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_reorg_rolls_back_to_new_tip() {
+        let journal = DexJournal::new();
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000001");
+        let genesis = B256::with_last_byte(1);
+        let old_block = B256::with_last_byte(2);
+        let new_block = B256::with_last_byte(3);
+        // the genesis state stays recorded once newer blocks are canonical
+        journal.record(genesis, 1, DexState::default());
+        journal.advance_canonical(genesis, 1, vec![]);
+
+        let handler = journal.handler_at(genesis).unwrap();
+        create_pair(&handler, eth, usdc);
+        journal.record(old_block, 2, handler.snapshot());
+        journal.advance_canonical(old_block, 2, vec![]);
+
+        // the sibling block without the pair replaces the old one
+        journal.record(
+            new_block,
+            2,
+            journal.handler_at(genesis).unwrap().snapshot(),
+        );
+        journal.advance_canonical(new_block, 2, vec![old_block]);
+
+        assert!(journal.state_at(old_block).is_none());
+        let handler = journal.handler_at(new_block).unwrap();
+        create_pair(&handler, eth, usdc);
+    }
+}
@@ -0,0 +1,226 @@
+//! Listing of DEX pairs.
+//!
+//! Who may create pairs is decided by the [`ListingPolicy`]. By default anyone may, while a
+//! permissioned policy only lets the DEX admin and the allowlisted listers create pairs. Listers
+//! may be contracts, so an on-chain registry can be allowlisted and list pairs by calling the DEX
+//! itself, applying its own rules on who may list what. Pairs the DEX starts out with at
+//! [genesis](super::genesis) are listed regardless of the policy.
+//!
+//! Whoever lists a pair, its tokens have to be contracts: a call transferring a token without
+//! code succeeds without moving anything, which would let orders be filled with nothing. The only
+//! exception is the [`NATIVE_TOKEN`] sentinel standing for native ETH, if the policy lists ETH.
+//!
+//! Every pair carries a [`PairSpec`] given when it was listed with `listPair`: the decimals and
+//! symbols of its tokens, and the tick and lot sizes its orders have to respect. `createPair`
+//! lists a pair with the [default](PairSpec::default) spec. Tick and lot sizes are in terms of
+//! `token0` of the pair: the limit price of an order, in `token1` per `token0` and scaled by
+//! [`PRICE_SCALE`], has to be a whole number of ticks, and its amount of `token0` a whole number
+//! of lots. Only orders as they are placed are checked, fills may leave any remainder behind.
+
+use super::{DexError, oracle::PRICE_SCALE, settlement::NATIVE_TOKEN};
+use alloy_primitives::{Address, U256};
+use std::collections::BTreeSet;
+
+/// Decimals of tokens listed without metadata, which most ERC-20 tokens and ETH have
+pub const DEFAULT_DECIMALS: u8 = 18;
+
+/// Maximum length of token symbols in bytes, which are stored as `bytes32`
+pub const MAX_SYMBOL_LEN: usize = 32;
+
+/// Policy deciding who may create pairs, and of which tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingPolicy {
+    /// Whether only the DEX admin and the listers may create pairs, rather than anyone
+    pub permissioned: bool,
+    /// Accounts allowed to create pairs besides the DEX admin
+    pub listers: BTreeSet<Address>,
+    /// Whether pairs of native ETH may be created, which the DEX stands for by the
+    /// [`NATIVE_TOKEN`] sentinel
+    pub native_eth: bool,
+}
+
+impl Default for ListingPolicy {
+    fn default() -> Self {
+        Self {
+            permissioned: false,
+            listers: BTreeSet::new(),
+            native_eth: true,
+        }
+    }
+}
+
+impl ListingPolicy {
+    /// Whether `caller` may create pairs, given the DEX admin.
+    pub fn may_list(&self, caller: Address, admin: Option<Address>) -> bool {
+        !self.permissioned || admin == Some(caller) || self.listers.contains(&caller)
+    }
+
+    /// Whether pairs of `token` may be created, given whether its account has code.
+    pub fn may_list_token(&self, token: Address, has_code: bool) -> bool {
+        if token == NATIVE_TOKEN {
+            self.native_eth
+        } else {
+            has_code
+        }
+    }
+}
+
+/// Token metadata and trading granularity of a pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairSpec {
+    /// Decimals of `token0`
+    pub decimals0: u8,
+    /// Decimals of `token1`
+    pub decimals1: u8,
+    /// Symbol of `token0`
+    pub symbol0: String,
+    /// Symbol of `token1`
+    pub symbol1: String,
+    /// Price increment of orders, in `token1` per `token0` scaled by [`PRICE_SCALE`]. Zero
+    /// allows any price
+    pub tick_size: U256,
+    /// Amount increment of orders, in `token0`. Zero allows any amount
+    pub lot_size: U256,
+}
+
+impl Default for PairSpec {
+    fn default() -> Self {
+        Self {
+            decimals0: DEFAULT_DECIMALS,
+            decimals1: DEFAULT_DECIMALS,
+            symbol0: String::new(),
+            symbol1: String::new(),
+            tick_size: U256::ZERO,
+            lot_size: U256::ZERO,
+        }
+    }
+}
+
+impl PairSpec {
+    /// Ensure the spec can be listed, i.e. its symbols fit into storage.
+    pub fn validate(&self) -> Result<(), DexError> {
+        if self.symbol0.len() > MAX_SYMBOL_LEN || self.symbol1.len() > MAX_SYMBOL_LEN {
+            return Err(DexError::InvalidSymbol);
+        }
+        Ok(())
+    }
+
+    /// Ensure an order respects the tick and lot sizes of the pair.
+    ///
+    /// `base_is_token0` tells whether the order's `token_in` is `token0` of the pair. The order
+    /// buys or sells `amount` of its `token_in` at a limit price of `price_num / price_denom`,
+    /// in `token_out` per `token_in`.
+    pub fn check_order(
+        &self,
+        base_is_token0: bool,
+        amount: U256,
+        price_num: u128,
+        price_denom: u128,
+    ) -> Result<(), DexError> {
+        let (price_num, price_denom) = (U256::from(price_num), U256::from(price_denom));
+        // The price in token1 per token0, and the amount of token0
+        let ((num, denom), (amount_num, amount_denom)) = if base_is_token0 {
+            ((price_num, price_denom), (amount, U256::from(1)))
+        } else {
+            (
+                (price_denom, price_num),
+                (amount.saturating_mul(price_num), price_denom),
+            )
+        };
+        if !is_multiple(num.saturating_mul(PRICE_SCALE), denom, self.tick_size) {
+            return Err(DexError::OffTickPrice);
+        }
+        if !is_multiple(amount_num, amount_denom, self.lot_size) {
+            return Err(DexError::OddLotAmount);
+        }
+        Ok(())
+    }
+}
+
+/// Whether `num / denom` is a whole multiple of `step`, which every number is of a zero step.
+fn is_multiple(num: U256, denom: U256, step: U256) -> bool {
+    if step.is_zero() {
+        return true;
+    }
+    denom
+        .checked_mul(step)
+        .is_some_and(|divisor| (num % divisor).is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::address;
+
+    #[test]
+    fn test_listing_policy() {
+        let admin = address!("0000000000000000000000000000000000000097");
+        let lister = address!("0000000000000000000000000000000000000098");
+        let anyone = address!("0000000000000000000000000000000000000099");
+        assert!(ListingPolicy::default().may_list(anyone, Some(admin)));
+
+        let policy = ListingPolicy {
+            permissioned: true,
+            listers: BTreeSet::from([lister]),
+            native_eth: false,
+        };
+        assert!(policy.may_list(admin, Some(admin)));
+        assert!(policy.may_list(lister, Some(admin)));
+        assert!(policy.may_list(lister, None));
+        assert!(!policy.may_list(anyone, Some(admin)));
+        assert!(!policy.may_list(admin, None));
+
+        // tokens have to be contracts, apart from ETH if it is listed
+        let token = address!("0000000000000000000000000000000000001234");
+        assert!(ListingPolicy::default().may_list_token(token, true));
+        assert!(!ListingPolicy::default().may_list_token(token, false));
+        assert!(ListingPolicy::default().may_list_token(NATIVE_TOKEN, false));
+        assert!(!policy.may_list_token(NATIVE_TOKEN, false));
+        assert!(!policy.may_list_token(NATIVE_TOKEN, true));
+    }
+
+    #[test]
+    fn test_tick_and_lot_sizes() {
+        // ticks of 0.01 token1 per token0, lots of 100 token0
+        let spec = PairSpec {
+            tick_size: PRICE_SCALE / U256::from(100),
+            lot_size: U256::from(100),
+            ..Default::default()
+        };
+        assert!(spec.check_order(true, U256::from(300), 201, 100).is_ok());
+        assert!(matches!(
+            spec.check_order(true, U256::from(300), 2001, 1000),
+            Err(DexError::OffTickPrice)
+        ));
+        assert!(matches!(
+            spec.check_order(true, U256::from(350), 2, 1),
+            Err(DexError::OddLotAmount)
+        ));
+
+        // orders of token1 are checked at the inverse price, 2.5 token1 per token0, for the
+        // amount of token0 they trade, 500 token1 for 200 token0
+        assert!(spec.check_order(false, U256::from(500), 2, 5).is_ok());
+        assert!(matches!(
+            spec.check_order(false, U256::from(500), 3, 7),
+            Err(DexError::OffTickPrice)
+        ));
+        assert!(matches!(
+            spec.check_order(false, U256::from(501), 2, 5),
+            Err(DexError::OddLotAmount)
+        ));
+
+        assert!(
+            PairSpec::default()
+                .check_order(false, U256::from(7), 3, 7)
+                .is_ok()
+        );
+        let long_symbol = PairSpec {
+            symbol0: "X".repeat(MAX_SYMBOL_LEN + 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            long_symbol.validate(),
+            Err(DexError::InvalidSymbol)
+        ));
+    }
+}
@@ -1,12 +1,32 @@
+//! Enshrined DEX integration for op-rbuilder
+//!
+//! This module provides the integration between the enshrined-dex library
+//! and the op-rbuilder Flashblocks builder. Calls to the predeploy address
+//! are executed by a precompile using the in-memory DEX.
+
+pub mod api;
+pub mod bands;
+pub mod feed;
+pub mod fees;
+pub mod gas;
+pub mod genesis;
 pub mod handler;
-/// Enshrined DEX integration for op-rbuilder
-///
-/// This module provides the integration between the enshrined-dex library
-/// and the op-rbuilder Flashblocks builder. It intercepts transactions to
-/// the predeploy address and executes them using the in-memory DEX.
+pub mod journal;
+pub mod listing;
+pub mod oracle;
+pub mod precompile;
 pub mod predeploy;
+pub mod rpc;
+pub mod settlement;
+pub mod signed;
+pub mod snapshot;
+pub mod state;
+pub mod storage;
 pub mod types;
 
 pub use handler::DexHandler;
+pub use journal::DexJournal;
+pub use precompile::DexPrecompile;
 pub use predeploy::DEX_PREDEPLOY_ADDRESS;
+pub use state::DexState;
 pub use types::*;
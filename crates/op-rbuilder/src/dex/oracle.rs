@@ -0,0 +1,176 @@
+//! Time-weighted average prices of DEX pairs.
+//!
+//! Every pair accumulates its last traded price over time, in both directions, like a Uniswap V2
+//! pair does. The accumulators are brought up to date right before the first trade of a pair in
+//! every block, with the price the pair was left at by the blocks before, so trades of the
+//! current block never affect them. Each update is kept as an [`Observation`] for
+//! [`MAX_TWAP_WINDOW`], and the average price over any window within it is the difference of the
+//! accumulators at both ends of the window divided by its length. Moving the average requires
+//! holding a price for as long as it should count.
+//!
+//! Prices are fixed point numbers, scaled by [`PRICE_SCALE`].
+
+use alloy_primitives::U256;
+use std::collections::VecDeque;
+
+/// Scale of the prices the accumulators add up
+pub const PRICE_SCALE: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]);
+
+/// Longest window time-weighted average prices can be taken over, in seconds
+pub const MAX_TWAP_WINDOW: u64 = 24 * 60 * 60;
+
+/// The accumulated prices of a pair at some point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Observation {
+    /// Timestamp of the block the accumulators were updated in
+    pub timestamp: u64,
+    /// Sum of the prices in `token1` per `token0` over every second since the first trade
+    pub price0_cumulative: U256,
+    /// Sum of the prices in `token0` per `token1` over every second since the first trade
+    pub price1_cumulative: U256,
+}
+
+/// The price accumulators of a pair along with their recent history.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PriceOracle {
+    /// Observations of the last [`MAX_TWAP_WINDOW`], oldest first, along with the newest one
+    /// before it
+    pub observations: VecDeque<Observation>,
+}
+
+impl PriceOracle {
+    /// Returns the latest observation, `None` before the first trade of the pair.
+    pub fn latest(&self) -> Option<&Observation> {
+        self.observations.back()
+    }
+
+    /// Bring the accumulators up to `now`, before the first trade of the pair in the block at
+    /// `now`. `price` is the last traded price in `token1` per `token0`, which held since the
+    /// last update, and zero before the first trade.
+    ///
+    /// Returns whether the accumulators changed.
+    pub(crate) fn update(&mut self, now: u64, price: (u128, u128)) -> bool {
+        let observation = match self.latest() {
+            None => Observation {
+                timestamp: now,
+                ..Default::default()
+            },
+            Some(latest) if latest.timestamp < now => latest.advance(now, price),
+            Some(_) => return false,
+        };
+        self.observations.push_back(observation);
+        // The newest observation before the longest window is where the window starts from
+        while self
+            .observations
+            .get(1)
+            .is_some_and(|next| next.timestamp + MAX_TWAP_WINDOW <= now)
+        {
+            self.observations.pop_front();
+        }
+        true
+    }
+
+    /// Returns the time-weighted average price over the `window` seconds up to `now`, in `token1`
+    /// per `token0` if `inverse` is false and in `token0` per `token1` otherwise, scaled by
+    /// [`PRICE_SCALE`].
+    ///
+    /// `price` is the last traded price in `token1` per `token0`, which holds since the latest
+    /// update. Returns `None` if the window is empty, longer than [`MAX_TWAP_WINDOW`] or reaches
+    /// back before the first trade of the pair.
+    pub fn twap(&self, now: u64, window: u64, price: (u128, u128), inverse: bool) -> Option<U256> {
+        if window == 0 || window > MAX_TWAP_WINDOW {
+            return None;
+        }
+        let end = self.at(now, price)?;
+        let start = self.at(now.checked_sub(window)?, price)?;
+        let (end, start) = if inverse {
+            (end.price1_cumulative, start.price1_cumulative)
+        } else {
+            (end.price0_cumulative, start.price0_cumulative)
+        };
+        Some(end.wrapping_sub(start) / U256::from(window))
+    }
+
+    /// Returns the accumulators as of `timestamp`, or `None` if that is before the oldest
+    /// observation.
+    fn at(&self, timestamp: u64, price: (u128, u128)) -> Option<Observation> {
+        let index = self
+            .observations
+            .partition_point(|observation| observation.timestamp <= timestamp);
+        let before = self.observations.get(index.checked_sub(1)?)?;
+        let Some(after) = self.observations.get(index) else {
+            return Some(before.advance(timestamp, price));
+        };
+        // The prices held constant between two observations
+        let elapsed = U256::from(after.timestamp - before.timestamp);
+        let interpolate = |before: U256, after: U256| {
+            let per_second = after.wrapping_sub(before) / elapsed;
+            before.wrapping_add(per_second.wrapping_mul(U256::from(timestamp - before.timestamp)))
+        };
+        Some(Observation {
+            timestamp,
+            price0_cumulative: interpolate(before.price0_cumulative, after.price0_cumulative),
+            price1_cumulative: interpolate(before.price1_cumulative, after.price1_cumulative),
+        })
+    }
+}
+
+impl Observation {
+    /// Returns the observation at `timestamp` if `price` held since this one.
+    fn advance(&self, timestamp: u64, (price_num, price_denom): (u128, u128)) -> Self {
+        let elapsed = U256::from(timestamp.saturating_sub(self.timestamp));
+        let accumulate = |cumulative: U256, num: u128, denom: u128| {
+            if num == 0 || denom == 0 {
+                return cumulative;
+            }
+            let scaled = U256::from(num) * PRICE_SCALE / U256::from(denom);
+            cumulative.wrapping_add(scaled.wrapping_mul(elapsed))
+        };
+        Self {
+            timestamp,
+            price0_cumulative: accumulate(self.price0_cumulative, price_num, price_denom),
+            price1_cumulative: accumulate(self.price1_cumulative, price_denom, price_num),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_twap_weights_prices_by_time() {
+        let scaled = |price: u64| U256::from(price) * PRICE_SCALE;
+        let mut oracle = PriceOracle::default();
+        assert!(oracle.update(100, (0, 0)));
+        // trades in the same block don't update the accumulators again
+        assert!(!oracle.update(100, (2, 1)));
+        // 2 held for 10 seconds, then 4 for 30 seconds
+        assert!(oracle.update(110, (2, 1)));
+        assert!(oracle.update(140, (4, 1)));
+
+        assert_eq!(
+            oracle.twap(140, 40, (4, 1), false),
+            Some(scaled(7) / U256::from(2))
+        );
+        assert_eq!(oracle.twap(140, 20, (4, 1), false), Some(scaled(4)));
+        // windows between observations and after the latest one are interpolated
+        assert_eq!(oracle.twap(120, 20, (4, 1), false), Some(scaled(3)));
+        assert_eq!(oracle.twap(150, 20, (8, 1), false), Some(scaled(6)));
+        assert_eq!(
+            oracle.twap(110, 10, (2, 1), true),
+            Some(PRICE_SCALE / U256::from(2))
+        );
+
+        assert_eq!(oracle.twap(140, 41, (4, 1), false), None);
+        assert_eq!(oracle.twap(140, 0, (4, 1), false), None);
+
+        // observations older than the longest window are dropped, except for where it starts
+        assert!(oracle.update(140 + MAX_TWAP_WINDOW, (4, 1)));
+        assert_eq!(oracle.observations.len(), 2);
+        assert_eq!(
+            oracle.twap(140 + MAX_TWAP_WINDOW, MAX_TWAP_WINDOW, (4, 1), false),
+            Some(scaled(4))
+        );
+    }
+}
@@ -0,0 +1,1183 @@
+//! The DEX as a precompile at the predeploy address.
+//!
+//! The precompile is installed at [`DEX_PREDEPLOY_ADDRESS`](super::DEX_PREDEPLOY_ADDRESS), unless
+//! the DEX state names another address to install the DEX at.
+//!
+//! Calls to the predeploy, whether sent by a transaction or made by a contract, are executed by
+//! the EVM like calls to any other precompile: the attached value is moved to the predeploy, a
+//! failing operation reverts the calling frame, and the events of the operation are emitted as
+//! logs of the predeploy, which are dropped again if an enclosing frame reverts. Like a contract
+//! writing its storage, the DEX reverts operations changing its state in a static context.
+//!
+//! The [fees](super::fees) an operation charges are paid out at its end, which is part of its
+//! transfers and reported by `FeesCollected` events following the operation's own events.
+//!
+//! Operations that fail because they would trade outside of a price band still count as a
+//! violation of the band, see [`bands`](super::bands). The violation is handed out along with the
+//! operations of the transaction so it survives the transaction reverting as well.
+//!
+//! Every call is charged gas for the work the operation did, see [`gas`](super::gas), and for the
+//! gas used by the calls to tokens settling it. If the gas left to the call doesn't cover it, the
+//! call fails with out-of-gas like any other precompile.
+//! Calls that revert are charged for the work they did up to the failure as well.
+//!
+//! Operations are executed on a private copy of the DEX state per transaction, and every call
+//! keeps the state it started from to roll back to. Both share everything with the state they
+//! were copied from, so the parts an operation writes to are copied by the operation itself,
+//! which is part of its work.
+//!
+//! Each call [settles](super::settlement) the transfers of its operation and writes the changes
+//! to the predeploy storage in the journaled state of the EVM, so the EVM reverts them along with
+//! the call. To roll the DEX state back along with it, every operation also counts up the
+//! operations slot of the predeploy storage. A call finding fewer operations counted than the
+//! transaction executed rolls the state back to before the first one that is gone, and so does
+//! the end of the transaction.
+
+use super::{
+    DexError, DexHandler, DexResult, DexState, IDex, OrderFill,
+    gas::operation_gas,
+    predeploy::selectors,
+    settlement::{SettlementError, Transfer, has_code, settle},
+    state::DexWork,
+    storage::OPERATIONS_SLOT,
+};
+use alloy_evm::{
+    EvmEnv,
+    precompiles::{
+        DynPrecompile, EvmInternals, EvmInternalsError, PrecompileInput, PrecompilesMap,
+    },
+};
+use alloy_primitives::{Address, B256, Bytes, Log, LogData, U256};
+use alloy_sol_types::SolEvent;
+use op_revm::OpSpecId;
+use parking_lot::Mutex;
+use reth_optimism_evm::OpEvmConfig;
+use revm::{
+    context::Block,
+    interpreter::Gas,
+    precompile::{PrecompileError, PrecompileId, PrecompileOutput, PrecompileResult},
+    state::EvmState,
+};
+use std::sync::Arc;
+use tracing::debug;
+
+/// A state changing DEX operation executed by a transaction
+#[derive(Debug, Clone)]
+pub struct DexCall {
+    /// Events emitted by the operation
+    pub logs: Vec<Log>,
+    /// Transfers paying out the fees charged by the operation
+    pub fees: Vec<Transfer>,
+    /// The DEX state before the operation, to roll back to if the operation is reverted
+    pre_state: DexState,
+}
+
+/// The DEX operations executed by a transaction
+#[derive(Debug)]
+pub struct DexCalls {
+    /// Handler holding the DEX state after the operations
+    pub handler: DexHandler,
+    /// The state changing operations that took effect, in execution order
+    pub calls: Vec<DexCall>,
+    /// Pairs whose price band the transaction ran into, once per violation
+    pub band_violations: Vec<B256>,
+}
+
+#[derive(Debug, Default)]
+struct TransactionContext {
+    /// Working copy of the DEX state, created by the first DEX call of the transaction
+    working: Option<DexHandler>,
+    /// Operations counted in the predeploy storage before the transaction
+    operations: U256,
+    /// State changing operations executed so far
+    calls: Vec<DexCall>,
+    /// Price band violations so far, by pair id
+    band_violations: Vec<B256>,
+}
+
+impl TransactionContext {
+    /// Roll the working state back to before the operations of the transaction that are no
+    /// longer counted by `operations`, the operations slot of the predeploy storage.
+    fn roll_back(&mut self, operations: U256) {
+        let kept = operations
+            .saturating_sub(self.operations)
+            .saturating_to::<usize>();
+        if kept >= self.calls.len() {
+            return;
+        }
+        let reverted = self.calls.split_off(kept);
+        if let (Some(handler), Some(first)) = (&self.working, reverted.into_iter().next()) {
+            handler.restore(first.pre_state);
+        }
+    }
+}
+
+/// The DEX precompile, executing DEX operations on top of a handler's state
+#[derive(Debug, Clone)]
+pub struct DexPrecompile {
+    /// Handler whose state the transactions are executed on top of
+    base: DexHandler,
+    /// The EVM configuration token transfers are executed with
+    evm_config: OpEvmConfig,
+    /// The EVM environment of the block
+    evm_env: EvmEnv<OpSpecId>,
+    /// The DEX operations of the transaction being executed
+    tx: Arc<Mutex<TransactionContext>>,
+}
+
+impl DexPrecompile {
+    /// Create a precompile executing operations on top of the state of `base`, in blocks with
+    /// the given EVM environment.
+    ///
+    /// The state of `base` is never modified by the precompile, the outcome of each transaction
+    /// is handed out by [`Self::finish`] instead.
+    pub fn new(base: DexHandler, evm_config: OpEvmConfig, evm_env: EvmEnv<OpSpecId>) -> Self {
+        Self {
+            base,
+            evm_config,
+            evm_env,
+            tx: Default::default(),
+        }
+    }
+
+    /// Returns the address the precompile is installed at.
+    pub fn address(&self) -> Address {
+        self.base.address()
+    }
+
+    /// Install the precompile at the address of the DEX.
+    pub fn install(&self, precompiles: &mut PrecompilesMap) {
+        let precompile = self.clone();
+        precompiles.apply_precompile(&self.address(), move |_| {
+            Some(DynPrecompile::new_stateful(
+                PrecompileId::custom("dex"),
+                move |input: PrecompileInput<'_>| precompile.call(input),
+            ))
+        });
+    }
+
+    /// Start a new transaction.
+    pub fn begin(&self) {
+        *self.tx.lock() = TransactionContext::default();
+    }
+
+    /// Returns the DEX operations of the transaction that left behind `state`, or `None` if it
+    /// didn't call the DEX at all.
+    ///
+    /// Operations the EVM reverted, along with the transaction or by an enclosing frame, are
+    /// rolled back.
+    pub fn finish(&self, state: &EvmState) -> Option<DexCalls> {
+        let mut tx = std::mem::take(&mut *self.tx.lock());
+        let operations = state
+            .get(&self.address())
+            .and_then(|account| account.storage.get(&OPERATIONS_SLOT))
+            .map_or(tx.operations, |slot| slot.present_value);
+        tx.roll_back(operations);
+        tx.working.map(|handler| DexCalls {
+            handler,
+            calls: tx.calls,
+            band_violations: tx.band_violations,
+        })
+    }
+
+    /// Returns a copy of the state the current transaction executes on top of.
+    pub fn base_state(&self) -> DexState {
+        self.base.snapshot()
+    }
+
+    fn call(&self, mut input: PrecompileInput<'_>) -> PrecompileResult {
+        let address = self.address();
+        let operations = read_operations(&mut input.internals, address)
+            .map_err(|err| PrecompileError::Other(err.to_string()))?;
+        let mut tx = self.tx.lock();
+        let handler = match &tx.working {
+            Some(handler) => handler.clone(),
+            None => {
+                tx.operations = operations;
+                tx.working
+                    .insert(DexHandler::from_state(self.base.snapshot()))
+                    .clone()
+            }
+        };
+        // Enclosing frames that reverted took the operations made within them along
+        tx.roll_back(operations);
+
+        if input.target_address != input.bytecode_address {
+            // Delegated calls would execute the operation on behalf of the delegating account
+            let gas_used = operation_gas(&DexWork::default(), &[]);
+            return revert(&DexError::DelegateCall, gas_used, input.gas);
+        }
+        if input.is_static && !selectors::is_view(input.data) {
+            // The operation would settle and write the predeploy storage within the static frame
+            let gas_used = operation_gas(&DexWork::default(), &[]);
+            return revert(&DexError::StaticCall, gas_used, input.gas);
+        }
+
+        handler.set_block(
+            input.internals.block_number().saturating_to(),
+            input.internals.block_timestamp().saturating_to(),
+        );
+        handler.set_beneficiary(input.internals.block_env().beneficiary());
+        let pre_state = handler.snapshot();
+        let result = match handler.handle_transaction(
+            input.caller,
+            &Bytes::copy_from_slice(input.data),
+            input.value,
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                let work = handler.take_work();
+                handler.restore(pre_state);
+                if let DexError::PriceBandExceeded { pair_id } = err {
+                    tx.band_violations.push(pair_id);
+                }
+                return revert(&err, operation_gas(&work, &[]), input.gas);
+            }
+        };
+
+        // Whether the tokens of a new pair are contracts is only known to the EVM
+        if let DexResult::PairCreated { token0, token1, .. } = result {
+            for token in [token0, token1] {
+                let may_list = has_code(&mut input.internals, token)
+                    .map(|has_code| handler.may_list_token(token, has_code));
+                if may_list.is_ok_and(|may_list| may_list) {
+                    continue;
+                }
+                let work = handler.take_work();
+                handler.restore(pre_state);
+                return match may_list {
+                    Err(err) => Err(PrecompileError::Other(err.to_string())),
+                    _ => revert(
+                        &DexError::InvalidTokenAddress,
+                        operation_gas(&work, &[]),
+                        input.gas,
+                    ),
+                };
+            }
+        }
+
+        // The operation is charged for the work it did, and takes no effect if the caller can't
+        // pay for it
+        let fees = handler.pay_fees();
+        let transfers = handler.take_transfers();
+        let mut gas = Gas::new(input.gas);
+        if !gas.record_cost(operation_gas(&handler.take_work(), &transfers)) {
+            handler.restore(pre_state);
+            return Err(PrecompileError::OutOfGas);
+        }
+
+        // Whatever was settled or written before a failure is reverted along with the call
+        let settled = settle(
+            &mut input.internals,
+            &self.evm_config,
+            &self.evm_env,
+            address,
+            &transfers,
+            &mut gas,
+        );
+        if let Err(err) = settled {
+            debug!(target: "dex", error = ?err, "DEX operation could not be settled");
+            handler.restore(pre_state);
+            return match err {
+                SettlementError::OutOfGas => Err(PrecompileError::OutOfGas),
+                SettlementError::Evm(err) => Err(PrecompileError::Other(err)),
+                _ => revert(&DexError::Unsettled, gas.spent(), input.gas),
+            };
+        }
+        let gas_used = gas.spent();
+        let mut storage = handler.take_storage_changes();
+        if result.is_view() {
+            return Ok(PrecompileOutput::new(gas_used, result.encode().into()));
+        }
+        storage.push((OPERATIONS_SLOT, operations.saturating_add(U256::from(1))));
+        if let Err(err) = write_storage(&mut input.internals, address, storage) {
+            handler.restore(pre_state);
+            return Err(PrecompileError::Other(err.to_string()));
+        }
+
+        let mut logs = create_dex_logs(address, &result);
+        logs.extend(fees.iter().map(|payout| fee_log(address, payout)));
+        for log in &logs {
+            input.internals.log(log.clone());
+        }
+        tx.calls.push(DexCall {
+            logs,
+            fees,
+            pre_state,
+        });
+        Ok(PrecompileOutput::new(gas_used, result.encode().into()))
+    }
+}
+
+/// Returns the operations counted in the storage of the DEX at `address`.
+fn read_operations(
+    internals: &mut EvmInternals<'_>,
+    address: Address,
+) -> Result<U256, EvmInternalsError> {
+    // the journal only reads the storage of loaded accounts
+    internals.load_account(address)?;
+    Ok(internals.sload(address, OPERATIONS_SLOT)?.data)
+}
+
+/// Write storage changes into the account of the DEX at `address`.
+fn write_storage(
+    internals: &mut EvmInternals<'_>,
+    address: Address,
+    changes: Vec<(U256, U256)>,
+) -> Result<(), EvmInternalsError> {
+    let account = internals.load_account(address)?.data;
+    // An empty account would be cleared on touch (EIP-161) together with its storage, so the
+    // predeploy is given a nonce like any contract created after Spurious Dragon. The nonce
+    // only ever makes it into the state along with the storage.
+    if account.info.is_empty() {
+        account.info.nonce = 1;
+    }
+    for (slot, value) in changes {
+        internals.sstore(address, slot, value)?;
+    }
+    internals.touch_account(address);
+    Ok(())
+}
+
+/// Revert the call with the given error, charging the gas used up to the failure.
+fn revert(err: &DexError, gas_used: u64, gas_limit: u64) -> PrecompileResult {
+    if gas_used > gas_limit {
+        return Err(PrecompileError::OutOfGas);
+    }
+    Ok(PrecompileOutput::new_reverted(
+        gas_used,
+        err.encode().into(),
+    ))
+}
+
+/// Create the events of a DEX operation, emitted by the DEX at `address`.
+///
+/// Fills of resting orders are reported first, followed by the event of the operation itself.
+fn create_dex_logs(address: Address, result: &DexResult) -> Vec<Log> {
+    let events = match result {
+        DexResult::PairCreated {
+            token0,
+            token1,
+            pair_id,
+        } => vec![
+            IDex::PairCreated {
+                token0: *token0,
+                token1: *token1,
+                pairId: *pair_id,
+            }
+            .encode_log_data(),
+        ],
+        DexResult::OrderPlaced {
+            order_id,
+            trader,
+            token_in,
+            token_out,
+            is_buy,
+            amount,
+            price_num,
+            price_denom,
+            fills,
+        } => fill_events(*trader, fills)
+            .chain(std::iter::once(
+                IDex::LimitOrderPlaced {
+                    orderId: *order_id,
+                    trader: *trader,
+                    tokenIn: *token_in,
+                    tokenOut: *token_out,
+                    isBuy: *is_buy,
+                    amount: *amount,
+                    priceNum: *price_num,
+                    priceDenom: *price_denom,
+                }
+                .encode_log_data(),
+            ))
+            .collect(),
+        DexResult::ImmediateOrCancelExecuted {
+            order_id,
+            trader,
+            token_in,
+            token_out,
+            is_buy,
+            amount,
+            filled,
+            price_num,
+            price_denom,
+            fills,
+        } => fill_events(*trader, fills)
+            .chain(std::iter::once(
+                IDex::ImmediateOrderExecuted {
+                    orderId: *order_id,
+                    trader: *trader,
+                    tokenIn: *token_in,
+                    tokenOut: *token_out,
+                    isBuy: *is_buy,
+                    amount: *amount,
+                    filled: *filled,
+                    priceNum: *price_num,
+                    priceDenom: *price_denom,
+                }
+                .encode_log_data(),
+            ))
+            .collect(),
+        DexResult::FillOrKillExecuted {
+            order_id,
+            trader,
+            token_in,
+            token_out,
+            is_buy,
+            amount,
+            price_num,
+            price_denom,
+            fills,
+        } => fill_events(*trader, fills)
+            .chain(std::iter::once(
+                IDex::ImmediateOrderExecuted {
+                    orderId: *order_id,
+                    trader: *trader,
+                    tokenIn: *token_in,
+                    tokenOut: *token_out,
+                    isBuy: *is_buy,
+                    amount: *amount,
+                    filled: *amount,
+                    priceNum: *price_num,
+                    priceDenom: *price_denom,
+                }
+                .encode_log_data(),
+            ))
+            .collect(),
+        DexResult::PostOnlyPlaced {
+            order_id,
+            trader,
+            token_in,
+            token_out,
+            is_buy,
+            amount,
+            price_num,
+            price_denom,
+        } => vec![
+            IDex::LimitOrderPlaced {
+                orderId: *order_id,
+                trader: *trader,
+                tokenIn: *token_in,
+                tokenOut: *token_out,
+                isBuy: *is_buy,
+                amount: *amount,
+                priceNum: *price_num,
+                priceDenom: *price_denom,
+            }
+            .encode_log_data(),
+        ],
+        DexResult::SignedOrderPlaced {
+            maker,
+            order_hash,
+            nonce,
+            placed,
+        } => create_dex_logs(address, placed)
+            .into_iter()
+            .map(|log| log.data)
+            .chain(std::iter::once(
+                IDex::SignedOrderPlaced {
+                    orderId: placed.order_id().unwrap_or_default(),
+                    maker: *maker,
+                    orderHash: *order_hash,
+                    nonce: *nonce,
+                }
+                .encode_log_data(),
+            ))
+            .collect(),
+        DexResult::OrderCancelled { order_id, owner } => vec![
+            IDex::OrderCancelled {
+                orderId: *order_id,
+                owner: *owner,
+            }
+            .encode_log_data(),
+        ],
+        DexResult::PairHalted { pair_id } => {
+            vec![IDex::PairHalted { pairId: *pair_id }.encode_log_data()]
+        }
+        DexResult::PairResumed { pair_id, admin } => vec![
+            IDex::PairResumed {
+                pairId: *pair_id,
+                admin: *admin,
+            }
+            .encode_log_data(),
+        ],
+        DexResult::OrdersExpired { orders } => orders
+            .iter()
+            .map(|(order_id, owner)| {
+                IDex::OrderExpired {
+                    orderId: *order_id,
+                    owner: *owner,
+                }
+                .encode_log_data()
+            })
+            .collect(),
+        DexResult::BatchCleared { auctions } => auctions
+            .iter()
+            .flat_map(|auction| {
+                let fills = auction.fills.iter().map(|fill| {
+                    IDex::BatchFilled {
+                        orderId: fill.order_id,
+                        trader: fill.trader,
+                        pairId: auction.pair_id,
+                        baseAmount: fill.base_amount,
+                        quoteAmount: fill.quote_amount,
+                        remaining: fill.remaining,
+                    }
+                    .encode_log_data()
+                });
+                let cleared = IDex::BatchCleared {
+                    pairId: auction.pair_id,
+                    priceNum: U256::from(auction.price_num),
+                    priceDenom: U256::from(auction.price_denom),
+                    volume0: auction.volume0,
+                    volume1: auction.volume1,
+                }
+                .encode_log_data();
+                fills.chain(std::iter::once(cleared))
+            })
+            .collect(),
+        DexResult::SwapExecuted {
+            trader,
+            token_in,
+            token_out,
+            amount_in,
+            amount_out,
+            route,
+            fills,
+        } => fill_events(*trader, fills)
+            .chain(std::iter::once(
+                IDex::Swap {
+                    trader: *trader,
+                    tokenIn: *token_in,
+                    tokenOut: *token_out,
+                    amountIn: *amount_in,
+                    amountOut: *amount_out,
+                    route: route.clone(),
+                }
+                .encode_log_data(),
+            ))
+            .collect(),
+        DexResult::Quote { .. }
+        | DexResult::OrderbookDepth { .. }
+        | DexResult::PairStats { .. }
+        | DexResult::Twap { .. }
+        | DexResult::UserOrders { .. }
+        | DexResult::Order(_)
+        | DexResult::Listing(_) => {
+            // View function, no logs
+            Vec::new()
+        }
+    };
+
+    events
+        .into_iter()
+        .map(|data| Log { address, data })
+        .collect()
+}
+
+/// `FeesCollected` event of a fee payout by the DEX at `address`
+fn fee_log(address: Address, payout: &Transfer) -> Log {
+    Log {
+        address,
+        data: IDex::FeesCollected {
+            token: payout.token,
+            recipient: payout.to,
+            amount: payout.amount,
+        }
+        .encode_log_data(),
+    }
+}
+
+/// `OrderFilled` events of the resting orders `taker` traded against
+fn fill_events(taker: Address, fills: &[OrderFill]) -> impl Iterator<Item = LogData> + '_ {
+    fills.iter().map(move |fill| {
+        IDex::OrderFilled {
+            orderId: fill.order_id,
+            maker: fill.maker,
+            taker,
+            baseAmount: fill.base_amount,
+            quoteAmount: fill.quote_amount,
+            remaining: fill.remaining,
+        }
+        .encode_log_data()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dex::{
+        DEX_PREDEPLOY_ADDRESS,
+        gas::{ENTRY_COPY_GAS, ORDER_FILL_GAS, SLOT_WRITE_GAS},
+        settlement::NATIVE_TOKEN,
+    };
+    use alloy_evm::Evm;
+    use alloy_primitives::{TxKind, address, hex};
+    use alloy_sol_types::SolValue;
+    use op_revm::{OpHaltReason, OpTransaction};
+    use reth_evm::ConfigureEvm;
+    use reth_optimism_chainspec::OP_MAINNET;
+    use revm::{
+        bytecode::Bytecode,
+        context::{
+            TxEnv,
+            result::{ExecutionResult, ResultAndState},
+        },
+        database::{CacheDB, EmptyDB},
+        state::AccountInfo,
+    };
+
+    const CALLER: Address = address!("00000000000000000000000000000000000a11ce");
+    const FORWARDER: Address = address!("000000000000000000000000000000000000f0f0");
+    const TOKEN_A: Address = address!("00000000000000000000000000000000000000aa");
+    const TOKEN_B: Address = address!("00000000000000000000000000000000000000bb");
+
+    fn create_pair_calldata() -> Bytes {
+        let params = (TOKEN_A, TOKEN_B).abi_encode();
+        [selectors::CREATE_PAIR.as_slice(), &params].concat().into()
+    }
+
+    /// The precompile executing on top of `base` in a default environment
+    fn precompile(base: DexHandler) -> DexPrecompile {
+        DexPrecompile::new(
+            base,
+            OpEvmConfig::optimism(OP_MAINNET.clone()),
+            EvmEnv::default(),
+        )
+    }
+
+    /// Code forwarding its calldata to the DEX, reverting afterwards if `revert` is set
+    fn forwarder_code(revert: bool) -> Bytes {
+        let end = if revert {
+            hex!("60006000fd").as_slice()
+        } else {
+            hex!("00").as_slice()
+        };
+        [
+            hex!("36600060003760006000366000600073").as_slice(),
+            DEX_PREDEPLOY_ADDRESS.as_slice(),
+            hex!("5af1").as_slice(),
+            end,
+        ]
+        .concat()
+        .into()
+    }
+
+    /// Code forwarding its calldata to the DEX with a static call, reverting if the call failed
+    fn static_forwarder_code() -> Bytes {
+        [
+            hex!("3660006000376000600036600073").as_slice(),
+            DEX_PREDEPLOY_ADDRESS.as_slice(),
+            hex!("5afa15602957005b60006000fd").as_slice(),
+        ]
+        .concat()
+        .into()
+    }
+
+    /// Execute a transaction with the DEX precompile installed, after the transaction was begun
+    fn transact(
+        dex: &DexPrecompile,
+        to: Address,
+        code: Option<Bytes>,
+        input: Bytes,
+    ) -> (bool, Vec<Log>, Option<DexCalls>) {
+        let (result, calls) = execute(dex, to, code, input, 1_000_000);
+        (result.is_success(), result.logs().to_vec(), calls)
+    }
+
+    /// Execute a transaction with the given gas limit, after the transaction was begun
+    fn execute(
+        dex: &DexPrecompile,
+        to: Address,
+        code: Option<Bytes>,
+        input: Bytes,
+        gas_limit: u64,
+    ) -> (ExecutionResult<OpHaltReason>, Option<DexCalls>) {
+        let (outcome, calls) = execute_on(dex, database(code), to, input, gas_limit);
+        (outcome.result, calls)
+    }
+
+    /// A database with the caller, the tokens, and the forwarder if it has `code`
+    fn database(code: Option<Bytes>) -> CacheDB<EmptyDB> {
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(CALLER, AccountInfo::default());
+        // tokens whose transfers always succeed
+        for token in [TOKEN_A, TOKEN_B] {
+            db.insert_account_info(
+                token,
+                AccountInfo::from_bytecode(Bytecode::new_raw(Bytes::from_static(&[0x00]))),
+            );
+        }
+        if let Some(code) = code {
+            db.insert_account_info(
+                FORWARDER,
+                AccountInfo::from_bytecode(Bytecode::new_raw(code)),
+            );
+        }
+        db
+    }
+
+    /// Execute a transaction on top of `db`, after the transaction was begun
+    fn execute_on(
+        dex: &DexPrecompile,
+        db: CacheDB<EmptyDB>,
+        to: Address,
+        input: Bytes,
+        gas_limit: u64,
+    ) -> (ResultAndState<OpHaltReason>, Option<DexCalls>) {
+        let evm_config = OpEvmConfig::optimism(OP_MAINNET.clone());
+        let mut evm = evm_config.evm_with_env(db, EvmEnv::default());
+        dex.install(evm.precompiles_mut());
+
+        let mut tx = OpTransaction::new(TxEnv {
+            caller: CALLER,
+            kind: TxKind::Call(to),
+            data: input,
+            gas_limit,
+            ..Default::default()
+        });
+        tx.enveloped_tx = Some(Bytes::new());
+
+        let outcome = evm.transact_raw(tx).unwrap();
+        let calls = dex.finish(&outcome.state);
+        (outcome, calls)
+    }
+
+    #[test]
+    fn test_contracts_can_call_the_dex() {
+        let dex = precompile(DexHandler::new());
+        dex.begin();
+        let (success, logs, calls) = transact(
+            &dex,
+            FORWARDER,
+            Some(forwarder_code(false)),
+            create_pair_calldata(),
+        );
+
+        assert!(success);
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].address, DEX_PREDEPLOY_ADDRESS);
+        let calls = calls.expect("the DEX should have been called");
+        assert_eq!(calls.calls.len(), 1);
+        assert_eq!(calls.calls[0].logs, logs);
+
+        // the pair only exists in the state of the transaction
+        assert!(matches!(
+            calls
+                .handler
+                .handle_transaction(CALLER, &create_pair_calldata(), U256::ZERO),
+            Err(DexError::PairAlreadyExists)
+        ));
+        assert!(dex.base_state().pair_id(TOKEN_A, TOKEN_B).is_none());
+    }
+
+    #[test]
+    fn test_dex_installed_at_configured_address() {
+        let address = address!("0000000000000000000000000000000000000dec");
+        let mut state = DexState::new();
+        state.set_address(address);
+        let dex = precompile(DexHandler::from_state(state));
+
+        dex.begin();
+        let (success, logs, calls) = transact(&dex, address, None, create_pair_calldata());
+        assert!(success);
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].address, address);
+        assert!(calls.is_some());
+
+        // nothing answers at the predeploy address
+        dex.begin();
+        let (success, logs, calls) =
+            transact(&dex, DEX_PREDEPLOY_ADDRESS, None, create_pair_calldata());
+        assert!(success);
+        assert!(logs.is_empty());
+        assert!(calls.is_none());
+    }
+
+    #[test]
+    fn test_reverts_follow_evm_semantics() {
+        let dex = precompile(DexHandler::new());
+
+        // a failing operation reverts the call
+        dex.begin();
+        let (success, logs, calls) = transact(
+            &dex,
+            DEX_PREDEPLOY_ADDRESS,
+            None,
+            Bytes::from_static(&[0xde, 0xad, 0xbe, 0xef]),
+        );
+        assert!(!success);
+        assert!(logs.is_empty());
+        assert!(
+            calls
+                .expect("the DEX should have been called")
+                .calls
+                .is_empty()
+        );
+
+        // the operation and its events are dropped with the reverting caller
+        dex.begin();
+        let (success, logs, calls) = transact(
+            &dex,
+            FORWARDER,
+            Some(forwarder_code(true)),
+            create_pair_calldata(),
+        );
+        assert!(!success);
+        assert!(logs.is_empty());
+        let calls = calls.expect("the DEX should have been called");
+        assert!(calls.calls.is_empty());
+        assert!(calls.handler.snapshot().pair_id(TOKEN_A, TOKEN_B).is_none());
+    }
+
+    #[test]
+    fn test_static_calls_cannot_change_the_state() {
+        // a maker rests an order selling 10 ETH for 10 B, escrowed by the DEX
+        let maker = address!("0000000000000000000000000000000000000b0b");
+        let base = DexHandler::new();
+        let create_pair = [
+            selectors::CREATE_PAIR.as_slice(),
+            &(NATIVE_TOKEN, TOKEN_B).abi_encode(),
+        ]
+        .concat();
+        base.handle_transaction(maker, &create_pair.into(), U256::ZERO)
+            .unwrap();
+        let params = (
+            NATIVE_TOKEN,
+            TOKEN_B,
+            false,
+            U256::from(10),
+            U256::from(1),
+            U256::from(1),
+        )
+            .abi_encode();
+        let place: Bytes = [selectors::PLACE_LIMIT_ORDER.as_slice(), &params]
+            .concat()
+            .into();
+        base.handle_transaction(maker, &place, U256::from(10))
+            .unwrap();
+        let dex = precompile(base);
+        let params = (TOKEN_B, NATIVE_TOKEN, U256::from(10), U256::ZERO).abi_encode();
+        let swap: Bytes = [selectors::SWAP.as_slice(), &params].concat().into();
+        let database = |code| {
+            let mut db = database(Some(code));
+            db.insert_account_info(
+                DEX_PREDEPLOY_ADDRESS,
+                AccountInfo::from_balance(U256::from(10)),
+            );
+            db
+        };
+        let balance = |state: &EvmState, address| {
+            state
+                .get(&address)
+                .map_or(U256::ZERO, |account| account.info.balance)
+        };
+
+        // called regularly, the swap pays out the escrow
+        dex.begin();
+        let (outcome, _) = execute_on(
+            &dex,
+            database(forwarder_code(false)),
+            FORWARDER,
+            swap.clone(),
+            1_000_000,
+        );
+        assert!(outcome.result.is_success());
+        assert_eq!(balance(&outcome.state, FORWARDER), U256::from(10));
+
+        // called statically, the swap reverts and moves nothing
+        dex.begin();
+        let (outcome, calls) = execute_on(
+            &dex,
+            database(static_forwarder_code()),
+            FORWARDER,
+            swap,
+            1_000_000,
+        );
+        assert!(matches!(outcome.result, ExecutionResult::Revert { .. }));
+        assert_eq!(balance(&outcome.state, FORWARDER), U256::ZERO);
+        let calls = calls.expect("the DEX should have been called");
+        assert!(calls.calls.is_empty());
+        assert_eq!(calls.handler.snapshot().orders_of(maker).count(), 1);
+
+        // views can still be called statically
+        let params = (TOKEN_B, NATIVE_TOKEN, U256::from(10)).abi_encode();
+        let quote: Bytes = [selectors::GET_QUOTE.as_slice(), &params].concat().into();
+        dex.begin();
+        let (outcome, _) = execute_on(
+            &dex,
+            database(static_forwarder_code()),
+            FORWARDER,
+            quote,
+            1_000_000,
+        );
+        assert!(outcome.result.is_success());
+    }
+
+    #[test]
+    fn test_operations_settle_within_the_call() {
+        // the forwarder rests an order selling 10 ETH, escrowed by the DEX
+        let base = DexHandler::new();
+        base.handle_transaction(
+            FORWARDER,
+            &[
+                selectors::CREATE_PAIR.as_slice(),
+                &(NATIVE_TOKEN, TOKEN_B).abi_encode(),
+            ]
+            .concat()
+            .into(),
+            U256::ZERO,
+        )
+        .unwrap();
+        let params = (
+            NATIVE_TOKEN,
+            TOKEN_B,
+            false,
+            U256::from(10),
+            U256::from(2),
+            U256::from(1),
+        )
+            .abi_encode();
+        let place: Bytes = [selectors::PLACE_LIMIT_ORDER.as_slice(), &params]
+            .concat()
+            .into();
+        let Ok(DexResult::OrderPlaced { order_id, .. }) =
+            base.handle_transaction(FORWARDER, &place, U256::from(10))
+        else {
+            panic!("the order should have been placed");
+        };
+        let dex = precompile(base);
+        let cancel: Bytes = [selectors::CANCEL_ORDER.as_slice(), &order_id.abi_encode()]
+            .concat()
+            .into();
+        let database = |revert| {
+            let mut db = database(Some(forwarder_code(revert)));
+            db.insert_account_info(
+                DEX_PREDEPLOY_ADDRESS,
+                AccountInfo::from_balance(U256::from(10)),
+            );
+            db
+        };
+        let balance = |state: &EvmState, address| {
+            state
+                .get(&address)
+                .map_or(U256::ZERO, |account| account.info.balance)
+        };
+
+        // cancelling the order refunds the escrow and counts the operation
+        dex.begin();
+        let (outcome, calls) =
+            execute_on(&dex, database(false), FORWARDER, cancel.clone(), 1_000_000);
+        assert!(outcome.result.is_success());
+        assert_eq!(balance(&outcome.state, FORWARDER), U256::from(10));
+        assert_eq!(balance(&outcome.state, DEX_PREDEPLOY_ADDRESS), U256::ZERO);
+        let storage = &outcome.state[&DEX_PREDEPLOY_ADDRESS].storage;
+        assert_eq!(storage[&OPERATIONS_SLOT].present_value, U256::from(1));
+        assert_eq!(
+            calls.expect("the DEX should have been called").calls.len(),
+            1
+        );
+
+        // a reverting caller takes the refund and the cancellation along
+        dex.begin();
+        let (outcome, calls) = execute_on(&dex, database(true), FORWARDER, cancel, 1_000_000);
+        assert!(!outcome.result.is_success());
+        assert_eq!(balance(&outcome.state, FORWARDER), U256::ZERO);
+        assert_eq!(
+            balance(&outcome.state, DEX_PREDEPLOY_ADDRESS),
+            U256::from(10)
+        );
+        let calls = calls.expect("the DEX should have been called");
+        assert!(calls.calls.is_empty());
+        assert_eq!(calls.handler.snapshot().orders_of(FORWARDER).count(), 1);
+    }
+
+    #[test]
+    fn test_events_round_trip() {
+        let eth = address!("0000000000000000000000000000000000000000");
+        let usdc = address!("0000000000000000000000000000000000000002");
+        let maker = address!("0000000000000000000000000000000000000b0b");
+        let handler = DexHandler::new();
+        let call = |caller, selector: alloy_primitives::FixedBytes<4>, params: Vec<u8>, value| {
+            let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+            let result = handler
+                .handle_transaction(caller, &calldata, value)
+                .unwrap();
+            create_dex_logs(DEX_PREDEPLOY_ADDRESS, &result)
+        };
+
+        let logs = call(
+            CALLER,
+            selectors::CREATE_PAIR,
+            (eth, usdc).abi_encode(),
+            U256::ZERO,
+        );
+        let created = IDex::PairCreated::decode_log_data(&logs[0].data).unwrap();
+        assert_eq!((created.token0, created.token1), (eth, usdc));
+
+        // a resting sell order of 10 ETH at 2 USDC each
+        let params = (
+            eth,
+            usdc,
+            false,
+            U256::from(10),
+            U256::from(2),
+            U256::from(1),
+        )
+            .abi_encode();
+        let logs = call(maker, selectors::PLACE_LIMIT_ORDER, params, U256::from(10));
+        assert_eq!(logs.len(), 1);
+        let placed = IDex::LimitOrderPlaced::decode_log_data(&logs[0].data).unwrap();
+        assert_eq!(placed.trader, maker);
+        assert_eq!((placed.tokenIn, placed.tokenOut), (eth, usdc));
+        assert!(!placed.isBuy);
+        assert_eq!(placed.amount, U256::from(10));
+        assert_eq!(
+            (placed.priceNum, placed.priceDenom),
+            (U256::from(2), U256::from(1))
+        );
+
+        // a swap filling 4 ETH of it reports the fill before the swap
+        let swap = (usdc, eth, U256::from(8), U256::ZERO).abi_encode();
+        let logs = call(CALLER, selectors::SWAP, swap, U256::ZERO);
+        assert_eq!(logs.len(), 2);
+        let filled = IDex::OrderFilled::decode_log_data(&logs[0].data).unwrap();
+        assert_eq!(filled.orderId, placed.orderId);
+        assert_eq!((filled.maker, filled.taker), (maker, CALLER));
+        assert_eq!(
+            (filled.baseAmount, filled.quoteAmount, filled.remaining),
+            (U256::from(4), U256::from(8), U256::from(6))
+        );
+        let swapped = IDex::Swap::decode_log_data(&logs[1].data).unwrap();
+        assert_eq!(swapped.trader, CALLER);
+        assert_eq!((swapped.tokenIn, swapped.tokenOut), (usdc, eth));
+        assert_eq!(
+            (swapped.amountIn, swapped.amountOut),
+            (U256::from(8), U256::from(4))
+        );
+        assert_eq!(swapped.route, vec![created.pairId]);
+
+        let logs = call(
+            maker,
+            selectors::CANCEL_ORDER,
+            placed.orderId.abi_encode(),
+            U256::ZERO,
+        );
+        let cancelled = IDex::OrderCancelled::decode_log_data(&logs[0].data).unwrap();
+        assert_eq!(
+            (cancelled.orderId, cancelled.owner),
+            (placed.orderId, maker)
+        );
+        assert!(logs.iter().all(|log| log.address == DEX_PREDEPLOY_ADDRESS));
+
+        // logs of one event can't be mistaken for another
+        assert!(IDex::Swap::decode_log_data(&logs[0].data).is_err());
+    }
+
+    #[test]
+    fn test_gas_is_charged_for_the_work_done() {
+        let (token_a, token_b) = (TOKEN_A, TOKEN_B);
+        let maker = address!("0000000000000000000000000000000000000b0b");
+
+        // ten resting orders selling 10 A for 10 B each
+        let base = DexHandler::new();
+        base.handle_transaction(maker, &create_pair_calldata(), U256::ZERO)
+            .unwrap();
+        let params = (
+            token_a,
+            token_b,
+            false,
+            U256::from(10),
+            U256::from(1),
+            U256::from(1),
+        )
+            .abi_encode();
+        let place: Bytes = [selectors::PLACE_LIMIT_ORDER.as_slice(), &params]
+            .concat()
+            .into();
+        for _ in 0..10 {
+            base.handle_transaction(maker, &place, U256::ZERO).unwrap();
+        }
+        let dex = precompile(base);
+        let swap = |amount: u64| -> Bytes {
+            let params = (token_b, token_a, U256::from(amount), U256::ZERO).abi_encode();
+            [selectors::SWAP.as_slice(), &params].concat().into()
+        };
+
+        dex.begin();
+        let (single, _) = execute(&dex, DEX_PREDEPLOY_ADDRESS, None, swap(10), 1_000_000);
+        dex.begin();
+        let (all, _) = execute(&dex, DEX_PREDEPLOY_ADDRESS, None, swap(100), 1_000_000);
+        assert!(single.is_success() && all.is_success());
+        assert!(
+            all.gas_used() - single.gas_used() >= 9 * (ORDER_FILL_GAS + 8 * SLOT_WRITE_GAS),
+            "every filled order should be paid for"
+        );
+
+        // without enough gas for the work the transaction runs out of gas and has no effect
+        dex.begin();
+        let gas_limit = all.gas_used() - 1;
+        let (result, calls) = execute(&dex, DEX_PREDEPLOY_ADDRESS, None, swap(100), gas_limit);
+        assert!(matches!(result, ExecutionResult::Halt { .. }));
+        assert_eq!(result.gas_used(), gas_limit);
+        assert!(result.logs().is_empty());
+        assert!(
+            calls
+                .expect("the DEX should have been called")
+                .calls
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_reverting_calls_pay_for_the_state_they_copied() {
+        let (token_a, token_b) = (TOKEN_A, TOKEN_B);
+        let maker = address!("0000000000000000000000000000000000000b0b");
+
+        // books with one and with ten resting orders selling 10 A for 10 B each
+        let with_orders = |orders: usize| {
+            let base = DexHandler::new();
+            base.handle_transaction(maker, &create_pair_calldata(), U256::ZERO)
+                .unwrap();
+            let params = (
+                token_a,
+                token_b,
+                false,
+                U256::from(10),
+                U256::from(1),
+                U256::from(1),
+            )
+                .abi_encode();
+            let place: Bytes = [selectors::PLACE_LIMIT_ORDER.as_slice(), &params]
+                .concat()
+                .into();
+            for _ in 0..orders {
+                base.handle_transaction(maker, &place, U256::ZERO).unwrap();
+            }
+            precompile(base)
+        };
+        // a swap asking for more than the books hold
+        let params = (token_b, token_a, U256::from(10), U256::from(1_000)).abi_encode();
+        let swap: Bytes = [selectors::SWAP.as_slice(), &params].concat().into();
+
+        let dex = with_orders(1);
+        dex.begin();
+        let (few, _) = execute(&dex, DEX_PREDEPLOY_ADDRESS, None, swap.clone(), 1_000_000);
+        let dex = with_orders(10);
+        dex.begin();
+        let (many, calls) = execute(&dex, DEX_PREDEPLOY_ADDRESS, None, swap, 1_000_000);
+        assert!(matches!(few, ExecutionResult::Revert { .. }));
+        assert!(matches!(many, ExecutionResult::Revert { .. }));
+        assert!(
+            many.gas_used() - few.gas_used() >= 9 * ENTRY_COPY_GAS,
+            "the copy of the books should be paid for"
+        );
+        assert!(
+            calls
+                .expect("the DEX should have been called")
+                .calls
+                .is_empty()
+        );
+    }
+}
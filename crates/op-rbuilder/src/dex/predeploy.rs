@@ -13,29 +13,142 @@ pub mod selectors {
     use alloy_primitives::FixedBytes;
 
     /// createPair(address,address)
-    pub const CREATE_PAIR: FixedBytes<4> = FixedBytes([0x6f, 0x36, 0xc6, 0x28]);
+    pub const CREATE_PAIR: FixedBytes<4> = FixedBytes([0xc9, 0xc6, 0x53, 0x96]);
+
+    /// listPair((address,address,uint8,uint8,string,string,uint256,uint256))
+    pub const LIST_PAIR: FixedBytes<4> = FixedBytes([0x79, 0xd1, 0x56, 0x17]);
 
     /// placeLimitOrder(address,address,bool,uint256,uint256,uint256)
-    pub const PLACE_LIMIT_ORDER: FixedBytes<4> = FixedBytes([0x9e, 0x8c, 0xc0, 0x4b]);
+    pub const PLACE_LIMIT_ORDER: FixedBytes<4> = FixedBytes([0xb5, 0x19, 0x81, 0x3b]);
+
+    /// placeLimitOrderWithExpiry(address,address,bool,uint256,uint256,uint256,uint64,uint64)
+    pub const PLACE_LIMIT_ORDER_WITH_EXPIRY: FixedBytes<4> = FixedBytes([0xd3, 0x4a, 0x6e, 0xff]);
+
+    /// placeIocOrder(address,address,bool,uint256,uint256,uint256)
+    pub const PLACE_IOC_ORDER: FixedBytes<4> = FixedBytes([0xc5, 0x3e, 0x91, 0x1b]);
+
+    /// placeFokOrder(address,address,bool,uint256,uint256,uint256)
+    pub const PLACE_FOK_ORDER: FixedBytes<4> = FixedBytes([0xd7, 0x30, 0x2f, 0x62]);
+
+    /// placePostOnlyOrder(address,address,bool,uint256,uint256,uint256)
+    pub const PLACE_POST_ONLY_ORDER: FixedBytes<4> = FixedBytes([0xc9, 0x77, 0x82, 0xa7]);
+
+    /// submitSignedOrder((address,address,address,bool,uint256,uint256,uint256,uint64,uint64,uint64),bytes)
+    pub const SUBMIT_SIGNED_ORDER: FixedBytes<4> = FixedBytes([0x6c, 0xde, 0xb0, 0x0c]);
 
     /// cancelOrder(bytes32)
-    pub const CANCEL_ORDER: FixedBytes<4> = FixedBytes([0xa0, 0x71, 0x96, 0xf1]);
+    pub const CANCEL_ORDER: FixedBytes<4> = FixedBytes([0x74, 0x89, 0xec, 0x23]);
+
+    /// sweepExpiredOrders(uint64)
+    pub const SWEEP_EXPIRED_ORDERS: FixedBytes<4> = FixedBytes([0x2b, 0x23, 0x52, 0x8a]);
+
+    /// clearBatch()
+    pub const CLEAR_BATCH: FixedBytes<4> = FixedBytes([0x24, 0x5a, 0x35, 0x5a]);
+
+    /// haltPair(address,address)
+    pub const HALT_PAIR: FixedBytes<4> = FixedBytes([0x45, 0x60, 0xaa, 0x42]);
+
+    /// resumePair(address,address)
+    pub const RESUME_PAIR: FixedBytes<4> = FixedBytes([0x60, 0x44, 0x2a, 0xd6]);
 
     /// swap(address,address,uint256,uint256)
-    pub const SWAP: FixedBytes<4> = FixedBytes([0x12, 0x8a, 0xcb, 0x08]);
+    pub const SWAP: FixedBytes<4> = FixedBytes([0xfe, 0x02, 0x91, 0x56]);
 
     /// getQuote(address,address,uint256)
-    pub const GET_QUOTE: FixedBytes<4> = FixedBytes([0x99, 0x8f, 0x94, 0xf8]);
+    pub const GET_QUOTE: FixedBytes<4> = FixedBytes([0xc5, 0x15, 0xee, 0x23]);
 
     /// getOrderbookDepth(address,address,uint256)
-    pub const GET_ORDERBOOK_DEPTH: FixedBytes<4> = FixedBytes([0x4d, 0x4f, 0xb0, 0xb0]);
+    pub const GET_ORDERBOOK_DEPTH: FixedBytes<4> = FixedBytes([0x6b, 0x4d, 0xa0, 0x7b]);
 
     /// getPairStats(address,address)
-    pub const GET_PAIR_STATS: FixedBytes<4> = FixedBytes([0xf7, 0x88, 0x8a, 0xec]);
+    pub const GET_PAIR_STATS: FixedBytes<4> = FixedBytes([0x2f, 0x8a, 0xab, 0x8a]);
+
+    /// getTwap(address,address,uint64)
+    pub const GET_TWAP: FixedBytes<4> = FixedBytes([0x83, 0xa5, 0xa5, 0x36]);
 
     /// getUserOrders(address)
-    pub const GET_USER_ORDERS: FixedBytes<4> = FixedBytes([0x0b, 0x86, 0x5c, 0x52]);
+    pub const GET_USER_ORDERS: FixedBytes<4> = FixedBytes([0x63, 0xc6, 0x9f, 0x08]);
 
     /// getOrder(bytes32)
-    pub const GET_ORDER: FixedBytes<4> = FixedBytes([0xd0, 0x9e, 0xf2, 0x41]);
+    pub const GET_ORDER: FixedBytes<4> = FixedBytes([0x57, 0x78, 0x47, 0x2a]);
+
+    /// getPairListing(address,address)
+    pub const GET_PAIR_LISTING: FixedBytes<4> = FixedBytes([0xeb, 0x61, 0xec, 0x40]);
+
+    /// Selectors of the operations that only read the DEX state
+    pub const VIEWS: [FixedBytes<4>; 7] = [
+        GET_QUOTE,
+        GET_ORDERBOOK_DEPTH,
+        GET_PAIR_STATS,
+        GET_TWAP,
+        GET_USER_ORDERS,
+        GET_ORDER,
+        GET_PAIR_LISTING,
+    ];
+
+    /// Whether `calldata` calls an operation that only reads the DEX state
+    pub fn is_view(calldata: &[u8]) -> bool {
+        calldata
+            .get(..4)
+            .is_some_and(|selector| VIEWS.iter().any(|view| view.as_slice() == selector))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::selectors::*;
+    use alloy_primitives::keccak256;
+
+    #[test]
+    fn test_selectors_match_signatures() {
+        for (selector, signature) in [
+            (CREATE_PAIR, "createPair(address,address)"),
+            (
+                LIST_PAIR,
+                "listPair((address,address,uint8,uint8,string,string,uint256,uint256))",
+            ),
+            (
+                PLACE_LIMIT_ORDER,
+                "placeLimitOrder(address,address,bool,uint256,uint256,uint256)",
+            ),
+            (
+                PLACE_LIMIT_ORDER_WITH_EXPIRY,
+                "placeLimitOrderWithExpiry(address,address,bool,uint256,uint256,uint256,uint64,uint64)",
+            ),
+            (
+                PLACE_IOC_ORDER,
+                "placeIocOrder(address,address,bool,uint256,uint256,uint256)",
+            ),
+            (
+                PLACE_FOK_ORDER,
+                "placeFokOrder(address,address,bool,uint256,uint256,uint256)",
+            ),
+            (
+                PLACE_POST_ONLY_ORDER,
+                "placePostOnlyOrder(address,address,bool,uint256,uint256,uint256)",
+            ),
+            (
+                SUBMIT_SIGNED_ORDER,
+                "submitSignedOrder((address,address,address,bool,uint256,uint256,uint256,uint64,uint64,uint64),bytes)",
+            ),
+            (CANCEL_ORDER, "cancelOrder(bytes32)"),
+            (SWEEP_EXPIRED_ORDERS, "sweepExpiredOrders(uint64)"),
+            (CLEAR_BATCH, "clearBatch()"),
+            (HALT_PAIR, "haltPair(address,address)"),
+            (RESUME_PAIR, "resumePair(address,address)"),
+            (SWAP, "swap(address,address,uint256,uint256)"),
+            (GET_QUOTE, "getQuote(address,address,uint256)"),
+            (
+                GET_ORDERBOOK_DEPTH,
+                "getOrderbookDepth(address,address,uint256)",
+            ),
+            (GET_PAIR_STATS, "getPairStats(address,address)"),
+            (GET_TWAP, "getTwap(address,address,uint64)"),
+            (GET_USER_ORDERS, "getUserOrders(address)"),
+            (GET_ORDER, "getOrder(bytes32)"),
+            (GET_PAIR_LISTING, "getPairListing(address,address)"),
+        ] {
+            assert_eq!(selector[..], keccak256(signature)[..4], "{signature}");
+        }
+    }
 }
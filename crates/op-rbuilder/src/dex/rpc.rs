@@ -0,0 +1,354 @@
+//! RPC overrides that serve calls to the DEX predeploy.
+//!
+//! The DEX precompile is only installed in the EVM of the builder, so the regular `eth_call`,
+//! `eth_estimateGas` and `debug_traceCall` implementations only ever see an account without
+//! code. The overrides in this module execute calls to the predeploy against the
+//! DEX state of the requested block the same way the builder does, including the settlement of
+//! the resulting transfers, and hand every other call to the regular implementations.
+
+use super::{
+    DexError, DexHandler, DexJournal, DexResult,
+    gas::{CALL_GAS, operation_gas},
+    settlement::{NATIVE_TOKEN, Transfer, has_code, settle},
+};
+use alloy_eips::BlockId;
+use alloy_evm::precompiles::EvmInternals;
+use alloy_json_rpc::RpcObject;
+use alloy_primitives::{Address, Bytes, TxKind, U256};
+use alloy_rpc_types_eth::{
+    BlockOverrides, TransactionRequest,
+    state::{EvmOverrides, StateOverride},
+};
+use alloy_rpc_types_trace::geth::{GethDebugTracingCallOptions, GethTrace};
+use jsonrpsee::{
+    core::{RpcResult, async_trait},
+    proc_macros::rpc,
+    types::ErrorObjectOwned,
+};
+use reth::rpc::api::eth::{
+    RpcTxReq,
+    helpers::{EstimateCall, EthCall, FullEthApi},
+};
+use reth_evm::ConfigureEvm;
+use reth_optimism_evm::OpEvmConfig;
+use reth_provider::{BlockReaderIdExt, StateProviderFactory};
+use reth_revm::{State, database::StateProviderDatabase};
+use reth_rpc_api::DebugApiServer;
+use reth_rpc_eth_types::{EthApiError, RevertError, RpcInvalidTransactionError};
+use revm::{Journal, interpreter::Gas};
+use std::{iter, sync::Arc};
+
+// Namespace overrides for calls to the DEX predeploy
+#[rpc(server, namespace = "eth")]
+pub trait DexEthApi<TxReq: RpcObject> {
+    #[method(name = "call")]
+    async fn call(
+        &self,
+        request: TxReq,
+        block_number: Option<BlockId>,
+        state_overrides: Option<StateOverride>,
+        block_overrides: Option<Box<BlockOverrides>>,
+    ) -> RpcResult<Bytes>;
+
+    #[method(name = "estimateGas")]
+    async fn estimate_gas(
+        &self,
+        request: TxReq,
+        block_number: Option<BlockId>,
+        state_override: Option<StateOverride>,
+    ) -> RpcResult<U256>;
+}
+
+#[rpc(server, namespace = "debug")]
+pub trait DexDebugApi<TxReq: RpcObject> {
+    #[method(name = "traceCall")]
+    async fn trace_call(
+        &self,
+        request: TxReq,
+        block_id: Option<BlockId>,
+        opts: Option<GethDebugTracingCallOptions>,
+    ) -> RpcResult<GethTrace>;
+}
+
+#[derive(Clone)]
+pub struct DexRpcExt<Provider, Eth, Debug> {
+    provider: Provider,
+    eth_api: Eth,
+    debug_api: Debug,
+    evm_config: OpEvmConfig,
+    dex_journal: Arc<DexJournal>,
+}
+
+impl<Provider, Eth, Debug> DexRpcExt<Provider, Eth, Debug> {
+    pub fn new(
+        provider: Provider,
+        eth_api: Eth,
+        debug_api: Debug,
+        evm_config: OpEvmConfig,
+        dex_journal: Arc<DexJournal>,
+    ) -> Self {
+        Self {
+            provider,
+            eth_api,
+            debug_api,
+            evm_config,
+            dex_journal,
+        }
+    }
+}
+
+impl<Provider, Eth, Debug> DexRpcExt<Provider, Eth, Debug>
+where
+    Provider: BlockReaderIdExt<Header = alloy_consensus::Header> + StateProviderFactory,
+{
+    /// Execute a call to the DEX predeploy on top of the given block, like the builder would.
+    ///
+    /// State and block overrides are not applied to DEX operations. The outer error is returned
+    /// if the block or its DEX state is unknown, the inner one if the operation fails. A
+    /// successful operation is returned along with the gas the DEX charges for it.
+    fn simulate(
+        &self,
+        request: &TransactionRequest,
+        block_id: BlockId,
+    ) -> Result<Result<(DexResult, u64), DexError>, EthApiError> {
+        let header = self
+            .provider
+            .sealed_header_by_id(block_id)?
+            .ok_or(EthApiError::HeaderNotFound(block_id))?;
+        let state = self.dex_journal.state_at(header.hash()).ok_or_else(|| {
+            EthApiError::InvalidParams(format!(
+                "DEX state of block {} is not available",
+                header.hash()
+            ))
+        })?;
+        let evm_env = self
+            .evm_config
+            .evm_env(header.header())
+            .map_err(|err| EthApiError::EvmCustom(err.to_string()))?;
+        let db = State::builder()
+            .with_database(StateProviderDatabase::new(
+                self.provider.state_by_block_hash(header.hash())?,
+            ))
+            .build();
+        let mut journal = Journal::new(db);
+
+        let caller = request.from.unwrap_or_default();
+        let value = request.value.unwrap_or_default();
+        let input = request.input.input().cloned().unwrap_or_default();
+
+        let dex = state.address();
+        let handler = DexHandler::from_state(state);
+        handler.set_block(header.number, header.timestamp);
+        Ok(handler
+            .handle_transaction(caller, &input, value)
+            .and_then(|result| {
+                let mut internals = EvmInternals::new(&mut journal, &evm_env.block_env);
+                if let DexResult::PairCreated { token0, token1, .. } = result {
+                    for token in [token0, token1] {
+                        if !handler.may_list_token(token, has_code(&mut internals, token)?) {
+                            return Err(DexError::InvalidTokenAddress);
+                        }
+                    }
+                }
+
+                let transfers = handler.take_transfers();
+                let mut gas = Gas::new(u64::MAX);
+                gas.record_cost(operation_gas(&handler.take_work(), &transfers));
+                // The builder lets the EVM move the value to the predeploy before the operation
+                let transfers = iter::once(Transfer::pull(NATIVE_TOKEN, caller, value))
+                    .chain(transfers)
+                    .collect::<Vec<_>>();
+                settle(
+                    &mut internals,
+                    &self.evm_config,
+                    &evm_env,
+                    dex,
+                    &transfers,
+                    &mut gas,
+                )?;
+                Ok((result, gas.spent()))
+            }))
+    }
+}
+
+#[async_trait]
+impl<Provider, Eth, Debug> DexEthApiServer<RpcTxReq<Eth::NetworkTypes>>
+    for DexRpcExt<Provider, Eth, Debug>
+where
+    Provider: BlockReaderIdExt<Header = alloy_consensus::Header>
+        + StateProviderFactory
+        + Send
+        + Sync
+        + Clone
+        + 'static,
+    Eth: FullEthApi + Send + Sync + Clone + 'static,
+    Debug: Send + Sync + 'static,
+{
+    async fn call(
+        &self,
+        request: RpcTxReq<Eth::NetworkTypes>,
+        block_number: Option<BlockId>,
+        state_overrides: Option<StateOverride>,
+        block_overrides: Option<Box<BlockOverrides>>,
+    ) -> RpcResult<Bytes> {
+        if !is_dex_call(request.as_ref(), self.dex_journal.address()) {
+            return EthCall::call(
+                &self.eth_api,
+                request,
+                block_number,
+                EvmOverrides::new(state_overrides, block_overrides),
+            )
+            .await
+            .map_err(Into::into);
+        }
+
+        match self.simulate(request.as_ref(), block_number.unwrap_or_default())? {
+            Ok((result, _)) => Ok(result.encode().into()),
+            Err(err) => Err(revert_error(&err)),
+        }
+    }
+
+    async fn estimate_gas(
+        &self,
+        request: RpcTxReq<Eth::NetworkTypes>,
+        block_number: Option<BlockId>,
+        state_override: Option<StateOverride>,
+    ) -> RpcResult<U256> {
+        let block_id = block_number.unwrap_or_default();
+        let dex_gas = if is_dex_call(request.as_ref(), self.dex_journal.address()) {
+            match self.simulate(request.as_ref(), block_id)? {
+                Ok((_, gas)) => gas,
+                Err(err) => return Err(revert_error(&err)),
+            }
+        } else {
+            0
+        };
+
+        // The regular estimate only covers the call to the predeploy, the DEX charges the gas of
+        // the operation on top of it
+        let estimate =
+            EstimateCall::estimate_gas_at(&self.eth_api, request, block_id, state_override).await?;
+        Ok(estimate.saturating_add(U256::from(dex_gas)))
+    }
+}
+
+#[async_trait]
+impl<Provider, Eth, Debug> DexDebugApiServer<RpcTxReq<Eth::NetworkTypes>>
+    for DexRpcExt<Provider, Eth, Debug>
+where
+    Provider: BlockReaderIdExt<Header = alloy_consensus::Header>
+        + StateProviderFactory
+        + Send
+        + Sync
+        + Clone
+        + 'static,
+    Eth: FullEthApi + Send + Sync + Clone + 'static,
+    Debug: DebugApiServer<RpcTxReq<Eth::NetworkTypes>> + Send + Sync + 'static,
+{
+    async fn trace_call(
+        &self,
+        request: RpcTxReq<Eth::NetworkTypes>,
+        block_id: Option<BlockId>,
+        opts: Option<GethDebugTracingCallOptions>,
+    ) -> RpcResult<GethTrace> {
+        let outcome = if is_dex_call(request.as_ref(), self.dex_journal.address()) {
+            Some(self.simulate(request.as_ref(), block_id.unwrap_or_default())?)
+        } else {
+            None
+        };
+
+        // Trace the call to the predeploy itself and patch in the outcome of the DEX operation
+        // along with the gas it's charged
+        let trace = self
+            .debug_api
+            .debug_trace_call(request, block_id, opts)
+            .await?;
+        Ok(match outcome {
+            Some(Ok((result, gas))) => with_dex_outcome(trace, &Ok(result), gas),
+            Some(Err(err)) => with_dex_outcome(trace, &Err(err), CALL_GAS),
+            None => trace,
+        })
+    }
+}
+
+/// Whether the request calls the DEX installed at `dex`
+fn is_dex_call(request: &TransactionRequest, dex: Address) -> bool {
+    request.to == Some(TxKind::Call(dex))
+}
+
+/// The error returned for a DEX operation that reverts
+fn revert_error(err: &DexError) -> ErrorObjectOwned {
+    EthApiError::InvalidTransaction(RpcInvalidTransactionError::Revert(RevertError::new(
+        err.encode().into(),
+    )))
+    .into()
+}
+
+/// Replace the outcome of the call in traces of the struct logger and the call tracer, and add
+/// the gas charged by the DEX
+fn with_dex_outcome(
+    trace: GethTrace,
+    outcome: &Result<DexResult, DexError>,
+    dex_gas: u64,
+) -> GethTrace {
+    let output: Bytes = match outcome {
+        Ok(result) => result.encode().into(),
+        Err(err) => err.encode().into(),
+    };
+
+    match trace {
+        GethTrace::Default(mut frame) => {
+            frame.failed = outcome.is_err();
+            frame.gas = frame.gas.saturating_add(dex_gas);
+            frame.return_value = output;
+            GethTrace::Default(frame)
+        }
+        GethTrace::CallTracer(mut frame) => {
+            frame.output = Some(output);
+            frame.gas_used = frame.gas_used.saturating_add(U256::from(dex_gas));
+            if let Err(err) = outcome {
+                frame.error = Some("execution reverted".to_string());
+                frame.revert_reason = Some(err.to_string());
+            }
+            GethTrace::CallTracer(frame)
+        }
+        trace => trace,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_rpc_types_trace::geth::{CallFrame, DefaultFrame};
+
+    #[test]
+    fn test_dex_outcome_in_traces() {
+        let result = DexResult::UserOrders { orders: Vec::new() };
+
+        let GethTrace::Default(frame) = with_dex_outcome(
+            GethTrace::Default(DefaultFrame {
+                gas: 21_000,
+                ..Default::default()
+            }),
+            &Ok(result.clone()),
+            5_000,
+        ) else {
+            panic!("expected a struct logger trace");
+        };
+        assert!(!frame.failed);
+        assert_eq!(frame.gas, 26_000);
+        assert_eq!(frame.return_value, Bytes::from(result.encode()));
+
+        let GethTrace::CallTracer(frame) = with_dex_outcome(
+            GethTrace::CallTracer(CallFrame::default()),
+            &Err(DexError::OrderNotFound),
+            CALL_GAS,
+        ) else {
+            panic!("expected a call tracer trace");
+        };
+        assert_eq!(frame.gas_used, U256::from(CALL_GAS));
+        assert_eq!(frame.output, Some(DexError::OrderNotFound.encode().into()));
+        assert_eq!(frame.error.as_deref(), Some("execution reverted"));
+        assert_eq!(frame.revert_reason.as_deref(), Some("Order not found"));
+    }
+}
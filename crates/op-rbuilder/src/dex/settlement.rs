@@ -0,0 +1,560 @@
+//! Token settlement of DEX operations.
+//!
+//! The order book only decides who trades what. The resulting token movements are collected as
+//! [`Transfer`]s and settled by the precompile call that executed the operation, in the journaled
+//! state of the EVM: native ETH is moved between balances, ERC-20 tokens are pulled with
+//! `transferFrom` and pushed with `transfer`, both called by the predeploy. A failing leg reverts
+//! the call and with it the whole operation, and a frame reverting the call reverts its
+//! transfers like any other state change.
+//!
+//! Calls to tokens get at most [`TOKEN_TRANSFER_GAS_LIMIT`] of the gas left to the operation and
+//! the operation is charged for them as for a `CALL`: the access of the token account and the gas
+//! the call actually uses. Tokens calling back into the DEX while it settles are reverted, the
+//! way a contract guarding against re-entrancy would.
+//!
+//! Transfers name the predeploy by [`DEX_PREDEPLOY_ADDRESS`] and are settled against the address
+//! the DEX is actually installed at.
+
+use super::{DEX_PREDEPLOY_ADDRESS, DexError, gas::TOKEN_TRANSFER_GAS_LIMIT};
+use alloy_evm::{
+    Evm, EvmEnv,
+    precompiles::{DynPrecompile, EvmInternals, EvmInternalsError, PrecompileInput},
+};
+use alloy_primitives::{Address, B256, Bytes, TxKind, U256};
+use alloy_sol_types::{SolCall, sol};
+use op_revm::{
+    OpSpecId, OpTransaction,
+    transaction::deposit::{DEPOSIT_TRANSACTION_TYPE, DepositTransactionParts},
+};
+use reth_evm::ConfigureEvm;
+use reth_optimism_evm::OpEvmConfig;
+use revm::{
+    Database as _,
+    bytecode::Bytecode,
+    context::{
+        TxEnv,
+        result::{ExecutionResult, ResultAndState},
+    },
+    interpreter::{
+        Gas,
+        gas::{COLD_ACCOUNT_ACCESS_COST, WARM_STORAGE_READ_COST, calculate_initial_tx_gas},
+    },
+    precompile::{PrecompileId, PrecompileOutput},
+    primitives::KECCAK_EMPTY,
+    state::AccountInfo,
+};
+
+sol! {
+    interface IERC20 {
+        function transfer(address to, uint256 amount) external returns (bool);
+        function transferFrom(address from, address to, uint256 amount) external returns (bool);
+        function balanceOf(address account) external view returns (uint256);
+        function allowance(address owner, address spender) external view returns (uint256);
+    }
+}
+
+/// Token address used by the DEX to represent native ETH.
+pub const NATIVE_TOKEN: Address = Address::ZERO;
+
+/// A token movement required to settle a DEX operation.
+///
+/// Either `from` or `to` is the predeploy, which holds the escrow of all resting orders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    /// Token to move, [`NATIVE_TOKEN`] for ETH
+    pub token: Address,
+    /// Account the tokens are taken from
+    pub from: Address,
+    /// Account the tokens are credited to
+    pub to: Address,
+    /// Amount of tokens to move
+    pub amount: U256,
+}
+
+impl Transfer {
+    /// Pull tokens from `owner` into the predeploy.
+    pub fn pull(token: Address, owner: Address, amount: U256) -> Self {
+        Self {
+            token,
+            from: owner,
+            to: DEX_PREDEPLOY_ADDRESS,
+            amount,
+        }
+    }
+
+    /// Push tokens from the predeploy to `recipient`.
+    pub fn push(token: Address, recipient: Address, amount: U256) -> Self {
+        Self {
+            token,
+            from: DEX_PREDEPLOY_ADDRESS,
+            to: recipient,
+            amount,
+        }
+    }
+
+    /// Whether this transfer moves native ETH.
+    pub fn is_native(&self) -> bool {
+        self.token == NATIVE_TOKEN
+    }
+
+    /// Returns the transfer with the predeploy installed at `dex`.
+    pub fn installed_at(&self, dex: Address) -> Self {
+        let relocate = |address| {
+            if address == DEX_PREDEPLOY_ADDRESS {
+                dex
+            } else {
+                address
+            }
+        };
+        Self {
+            token: self.token,
+            from: relocate(self.from),
+            to: relocate(self.to),
+            amount: self.amount,
+        }
+    }
+}
+
+/// Errors that can occur while settling a DEX operation
+#[derive(Debug, thiserror::Error)]
+pub enum SettlementError {
+    #[error("insufficient ETH balance of {0}")]
+    InsufficientBalance(Address),
+
+    #[error("transfer of token {token} from {from} to {to} failed")]
+    TransferFailed {
+        token: Address,
+        from: Address,
+        to: Address,
+    },
+
+    #[error("out of gas")]
+    OutOfGas,
+
+    #[error("EVM error: {0}")]
+    Evm(String),
+}
+
+/// Settle the given transfers, in order, in the journaled state of a call to the DEX installed at
+/// `dex`.
+///
+/// The changes become part of the calling frame and are reverted along with it. Transfers made
+/// before a failing one are not undone here, the call has to revert to take them back. The gas
+/// used by calls to tokens is recorded in `gas`, including the gas of a call that failed.
+pub(crate) fn settle(
+    internals: &mut EvmInternals<'_>,
+    evm_config: &OpEvmConfig,
+    evm_env: &EvmEnv<OpSpecId>,
+    dex: Address,
+    transfers: &[Transfer],
+    gas: &mut Gas,
+) -> Result<(), SettlementError> {
+    for transfer in transfers
+        .iter()
+        .filter(|transfer| !transfer.amount.is_zero())
+    {
+        let transfer = transfer.installed_at(dex);
+        if transfer.is_native() {
+            native_transfer(internals, &transfer)?;
+        } else {
+            token_transfer(internals, evm_config, evm_env, dex, &transfer, gas)?;
+        }
+    }
+    Ok(())
+}
+
+/// Move ETH from one balance to the other.
+fn native_transfer(
+    internals: &mut EvmInternals<'_>,
+    transfer: &Transfer,
+) -> Result<(), SettlementError> {
+    // both sides have to be loaded into the journal before value can move between them
+    for address in [transfer.from, transfer.to] {
+        internals.load_account(address).map_err(evm_error)?;
+    }
+    match internals
+        .transfer(transfer.from, transfer.to, transfer.amount)
+        .map_err(evm_error)?
+    {
+        None => Ok(()),
+        Some(_) => Err(SettlementError::InsufficientBalance(transfer.from)),
+    }
+}
+
+/// Move ERC-20 tokens with a call from the DEX at `dex` to the token contract.
+///
+/// The call is executed by an EVM of its own on top of the journaled state, and its storage
+/// changes and logs are applied to the journal afterwards. Tokens may only change storage, a
+/// call moving ETH or creating, changing or destroying accounts fails the transfer. The call
+/// doesn't share the accessed accounts and slots, the transient storage or the origin of the
+/// transaction calling the DEX, and the DEX reverts any call the token makes back into it.
+///
+/// Like a system call, the call is a deposit so it is neither charged fees nor checked against
+/// the nonce of the DEX. The operation is charged as for a `CALL` to the token rather than for the
+/// deposit: the cold or warm access of the token account, and the gas the call uses before
+/// refunds, without the intrinsic gas of the deposit. Tokens using less gas than the calldata
+/// floor of EIP-7623 are charged that floor. The call gets 63/64 of the gas left after the
+/// access, at most [`TOKEN_TRANSFER_GAS_LIMIT`]. If that is less than the limit, a failing call
+/// runs the operation out of gas as it might have succeeded with more.
+fn token_transfer(
+    internals: &mut EvmInternals<'_>,
+    evm_config: &OpEvmConfig,
+    evm_env: &EvmEnv<OpSpecId>,
+    dex: Address,
+    transfer: &Transfer,
+    gas: &mut Gas,
+) -> Result<(), SettlementError> {
+    let failed = || SettlementError::TransferFailed {
+        token: transfer.token,
+        from: transfer.from,
+        to: transfer.to,
+    };
+
+    let token = internals
+        .load_account_code(transfer.token)
+        .map_err(evm_error)?;
+    let access_gas = if token.is_cold {
+        COLD_ACCOUNT_ACCESS_COST
+    } else {
+        WARM_STORAGE_READ_COST
+    };
+    // calls to accounts without code always succeed, which would credit tokens out of thin air
+    if token.data.info.code_hash == KECCAK_EMPTY {
+        return Err(failed());
+    }
+    if !gas.record_cost(access_gas) {
+        return Err(SettlementError::OutOfGas);
+    }
+
+    let calldata = if transfer.from == dex {
+        IERC20::transferCall {
+            to: transfer.to,
+            amount: transfer.amount,
+        }
+        .abi_encode()
+    } else {
+        IERC20::transferFromCall {
+            from: transfer.from,
+            to: transfer.to,
+            amount: transfer.amount,
+        }
+        .abi_encode()
+    };
+
+    let gas_limit = (gas.remaining() - gas.remaining() / 64).min(TOKEN_TRANSFER_GAS_LIMIT);
+    let intrinsic = calculate_initial_tx_gas(
+        evm_env.cfg_env.spec.into_eth_spec(),
+        &calldata,
+        false,
+        0,
+        0,
+        0,
+    );
+    let tx = OpTransaction {
+        base: TxEnv {
+            caller: dex,
+            kind: TxKind::Call(transfer.token),
+            data: calldata.into(),
+            gas_limit: (intrinsic.initial_gas + gas_limit).max(intrinsic.floor_gas),
+            tx_type: DEPOSIT_TRANSACTION_TYPE,
+            chain_id: None,
+            ..Default::default()
+        },
+        enveloped_tx: None,
+        deposit: DepositTransactionParts::default(),
+    };
+    let mut evm = evm_config.evm_with_env(JournalDatabase(&mut *internals), evm_env.clone());
+    evm.precompiles_mut()
+        .apply_precompile(&dex, |_| Some(reentrancy_guard()));
+    let ResultAndState { result, state } = evm
+        .transact_raw(tx)
+        .map_err(|err| SettlementError::Evm(err.to_string()))?;
+    drop(evm);
+    // refunds go to the transaction calling the DEX, not to the call
+    let used = match &result {
+        ExecutionResult::Success {
+            gas_used,
+            gas_refunded,
+            ..
+        } => gas_used + gas_refunded,
+        result => result.gas_used(),
+    };
+    if !gas.record_cost(used.saturating_sub(intrinsic.initial_gas)) {
+        return Err(SettlementError::OutOfGas);
+    }
+
+    // tokens that don't return anything are accepted, like SafeERC20 does
+    let succeeded = result.is_success()
+        && result.output().is_none_or(|output| {
+            output.is_empty() || IERC20::transferCall::abi_decode_returns(output).unwrap_or(false)
+        });
+    if !succeeded {
+        if gas_limit < TOKEN_TRANSFER_GAS_LIMIT {
+            return Err(SettlementError::OutOfGas);
+        }
+        return Err(failed());
+    }
+
+    for (address, account) in state {
+        if !account.is_touched() {
+            continue;
+        }
+        let current = internals.load_account(address).map_err(evm_error)?.data;
+        let (before, after) = (&current.info, &account.info);
+        if account.is_selfdestructed()
+            || account.is_created()
+            || after.balance != before.balance
+            || after.code_hash != before.code_hash
+            || (address != dex && after.nonce != before.nonce)
+        {
+            return Err(failed());
+        }
+        for (slot, value) in account.changed_storage_slots() {
+            internals
+                .sstore(address, *slot, value.present_value)
+                .map_err(evm_error)?;
+        }
+        internals.touch_account(address);
+    }
+    for log in result.into_logs() {
+        internals.log(log);
+    }
+    Ok(())
+}
+
+/// The precompile standing in for the DEX while a token is called, reverting every call to it.
+fn reentrancy_guard() -> DynPrecompile {
+    DynPrecompile::new(PrecompileId::custom("dex"), |_: PrecompileInput<'_>| {
+        Ok(PrecompileOutput::new_reverted(
+            0,
+            Bytes::from(DexError::ReentrantCall.encode()),
+        ))
+    })
+}
+
+/// Whether the account at `address` has code in the journaled state.
+pub(crate) fn has_code(
+    internals: &mut EvmInternals<'_>,
+    address: Address,
+) -> Result<bool, SettlementError> {
+    let account = internals.load_account_code(address).map_err(evm_error)?;
+    Ok(account.data.info.code_hash != KECCAK_EMPTY)
+}
+
+fn evm_error(err: EvmInternalsError) -> SettlementError {
+    SettlementError::Evm(err.to_string())
+}
+
+/// The journaled state of the EVM a precompile is called in, as the database of the EVMs the
+/// precompile calls other contracts with.
+///
+/// Reads see every change the transaction made so far. Nothing is written back, the changes of a
+/// nested call are left to the precompile to apply.
+struct JournalDatabase<'a, 'b>(&'a mut EvmInternals<'b>);
+
+impl std::fmt::Debug for JournalDatabase<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JournalDatabase").finish_non_exhaustive()
+    }
+}
+
+impl revm::Database for JournalDatabase<'_, '_> {
+    type Error = EvmInternalsError;
+
+    fn basic(&mut self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        let account = self.0.load_account_code(address)?.data;
+        Ok((!account.is_loaded_as_not_existing()).then(|| account.info.clone()))
+    }
+
+    fn code_by_hash(&mut self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.0.db_mut().code_by_hash(code_hash)
+    }
+
+    fn storage(&mut self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        // the journal only reads the storage of loaded accounts
+        self.0.load_account(address)?;
+        Ok(self.0.sload(address, index)?.data)
+    }
+
+    fn block_hash(&mut self, number: u64) -> Result<B256, Self::Error> {
+        self.0.db_mut().block_hash(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::{address, hex};
+    use reth_optimism_chainspec::OP_MAINNET;
+    use revm::{
+        Journal,
+        context_interface::JournalTr,
+        database::{CacheDB, EmptyDB},
+    };
+
+    const ALICE: Address = address!("00000000000000000000000000000000000a11ce");
+    const BOB: Address = address!("0000000000000000000000000000000000000b0b");
+
+    fn journal_with_balance(balance: u64) -> Journal<CacheDB<EmptyDB>> {
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            ALICE,
+            AccountInfo {
+                balance: U256::from(balance),
+                ..Default::default()
+            },
+        );
+        Journal::new(db)
+    }
+
+    fn balance(journal: &mut Journal<CacheDB<EmptyDB>>, address: Address) -> U256 {
+        journal.load_account(address).unwrap().data.info.balance
+    }
+
+    fn settle_in(
+        journal: &mut Journal<CacheDB<EmptyDB>>,
+        dex: Address,
+        transfers: &[Transfer],
+    ) -> Result<(), SettlementError> {
+        settle_with_gas(journal, dex, transfers, &mut Gas::new(1_000_000))
+    }
+
+    fn settle_with_gas(
+        journal: &mut Journal<CacheDB<EmptyDB>>,
+        dex: Address,
+        transfers: &[Transfer],
+        gas: &mut Gas,
+    ) -> Result<(), SettlementError> {
+        let evm_config = OpEvmConfig::optimism(OP_MAINNET.clone());
+        let mut evm_env = EvmEnv::default();
+        evm_env.cfg_env.spec = OpSpecId::ISTHMUS;
+        let mut internals = EvmInternals::new(journal, &evm_env.block_env);
+        settle(&mut internals, &evm_config, &evm_env, dex, transfers, gas)
+    }
+
+    #[test]
+    fn test_native_settlement() {
+        let mut journal = journal_with_balance(100);
+
+        settle_in(
+            &mut journal,
+            DEX_PREDEPLOY_ADDRESS,
+            &[
+                Transfer::pull(NATIVE_TOKEN, ALICE, U256::from(60)),
+                Transfer::push(NATIVE_TOKEN, BOB, U256::from(40)),
+            ],
+        )
+        .expect("settlement should succeed");
+
+        assert_eq!(balance(&mut journal, ALICE), U256::from(40));
+        assert_eq!(balance(&mut journal, BOB), U256::from(40));
+        assert_eq!(balance(&mut journal, DEX_PREDEPLOY_ADDRESS), U256::from(20));
+    }
+
+    #[test]
+    fn test_settlement_at_configured_address() {
+        let mut journal = journal_with_balance(100);
+        let dex = address!("0000000000000000000000000000000000000dec");
+
+        settle_in(
+            &mut journal,
+            dex,
+            &[Transfer::pull(NATIVE_TOKEN, ALICE, U256::from(60))],
+        )
+        .expect("settlement should succeed");
+
+        assert_eq!(balance(&mut journal, dex), U256::from(60));
+        assert_eq!(balance(&mut journal, DEX_PREDEPLOY_ADDRESS), U256::ZERO);
+    }
+
+    #[test]
+    fn test_failed_leg_fails_settlement() {
+        let mut journal = journal_with_balance(100);
+        let token = address!("0000000000000000000000000000000000001234");
+
+        // the ERC-20 leg fails since the token has no code
+        let err = settle_in(
+            &mut journal,
+            DEX_PREDEPLOY_ADDRESS,
+            &[Transfer::push(token, BOB, U256::from(1))],
+        )
+        .unwrap_err();
+        assert!(matches!(err, SettlementError::TransferFailed { .. }));
+
+        let err = settle_in(
+            &mut journal,
+            DEX_PREDEPLOY_ADDRESS,
+            &[Transfer::pull(NATIVE_TOKEN, ALICE, U256::from(101))],
+        )
+        .unwrap_err();
+        assert!(matches!(err, SettlementError::InsufficientBalance(ALICE)));
+        assert_eq!(balance(&mut journal, ALICE), U256::from(100));
+    }
+
+    #[test]
+    fn test_token_calls_are_charged_the_gas_they_use() {
+        let token = address!("0000000000000000000000000000000000001234");
+        let transfers = [Transfer::push(token, BOB, U256::from(1))];
+        let journal_with_code = |code: &'static [u8]| {
+            let mut db = CacheDB::new(EmptyDB::default());
+            db.insert_account_info(
+                token,
+                AccountInfo::from_bytecode(Bytecode::new_raw(Bytes::from_static(code))),
+            );
+            Journal::new(db)
+        };
+
+        // a token returning right away uses a fraction of its gas limit
+        let mut journal = journal_with_code(&[0x00]);
+        let mut gas = Gas::new(1_000_000);
+        settle_with_gas(&mut journal, DEX_PREDEPLOY_ADDRESS, &transfers, &mut gas)
+            .expect("settlement should succeed");
+        assert!(gas.spent() > 0 && gas.spent() < TOKEN_TRANSFER_GAS_LIMIT);
+
+        // a token looping forever is stopped at the limit and fails the transfer
+        let mut journal = journal_with_code(&hex!("5b600056"));
+        let mut gas = Gas::new(1_000_000);
+        let err =
+            settle_with_gas(&mut journal, DEX_PREDEPLOY_ADDRESS, &transfers, &mut gas).unwrap_err();
+        assert!(matches!(err, SettlementError::TransferFailed { .. }));
+        assert_eq!(
+            gas.spent(),
+            COLD_ACCOUNT_ACCESS_COST + TOKEN_TRANSFER_GAS_LIMIT
+        );
+
+        // with less gas left than the limit, the operation runs out of gas instead
+        let mut gas = Gas::new(TOKEN_TRANSFER_GAS_LIMIT / 2);
+        let err =
+            settle_with_gas(&mut journal, DEX_PREDEPLOY_ADDRESS, &transfers, &mut gas).unwrap_err();
+        assert!(matches!(err, SettlementError::OutOfGas));
+    }
+
+    #[test]
+    fn test_tokens_cannot_reenter_the_dex() {
+        let token = address!("0000000000000000000000000000000000001234");
+        let transfers = [Transfer::push(token, BOB, U256::from(1))];
+        // a token calling `target` during the transfer, failing the transfer if the call fails
+        let journal_calling = |target: Address| {
+            let code = [
+                hex!("6000600060006000600073").as_slice(),
+                target.as_slice(),
+                hex!("5af115602657005b60006000fd").as_slice(),
+            ]
+            .concat();
+            let mut db = CacheDB::new(EmptyDB::default());
+            db.insert_account_info(
+                token,
+                AccountInfo::from_bytecode(Bytecode::new_raw(code.into())),
+            );
+            Journal::new(db)
+        };
+
+        let mut journal = journal_calling(BOB);
+        settle_in(&mut journal, DEX_PREDEPLOY_ADDRESS, &transfers)
+            .expect("calls to other accounts should succeed");
+
+        let mut journal = journal_calling(DEX_PREDEPLOY_ADDRESS);
+        let err = settle_in(&mut journal, DEX_PREDEPLOY_ADDRESS, &transfers).unwrap_err();
+        assert!(matches!(err, SettlementError::TransferFailed { .. }));
+    }
+}
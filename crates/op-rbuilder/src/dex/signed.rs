@@ -0,0 +1,267 @@
+//! Limit orders signed off-chain by their makers.
+//!
+//! Makers updating their quotes shouldn't have to send a transaction for every order. Instead
+//! they sign a [`SignedOrder`] as EIP-712 typed data and submit it with `dex_submitSignedOrder`.
+//! Submitted orders wait in the [`SignedOrderPool`] until the builder places each of them with a
+//! transaction of its own calling `submitSignedOrder` on the predeploy. The predeploy verifies the
+//! signature again, so replaying blocks doesn't depend on the pool, and escrows the order from the
+//! maker like any other order: ERC-20 tokens are pulled with the allowance of the predeploy and
+//! ETH is taken from the maker's balance.
+//!
+//! Every order carries a nonce, which has to be greater than the last nonce of its maker that was
+//! used. Placing an order uses up its nonce, so it can't be placed again, and a maker can drop
+//! orders that are still pending by having an order with a higher nonce placed.
+//!
+//! As the builder pays for placing signed orders, each maker can only have a limited number of
+//! orders pending, and the builder only places orders whose maker can fund the escrow.
+
+use super::{DexError, DexState, SignedOrder, predeploy::selectors, state::FlashblockPosition};
+use crate::tx_signer::recover_signer;
+use alloy_primitives::{Address, B256, Bytes, Signature, U256};
+use alloy_sol_types::{Eip712Domain, SolStruct, SolValue, eip712_domain};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+
+/// Maximum number of signed orders waiting to be placed
+pub const MAX_PENDING_ORDERS: usize = 10_000;
+
+/// Maximum number of signed orders of a single maker waiting to be placed
+pub const MAX_PENDING_ORDERS_PER_MAKER: usize = 100;
+
+/// Returns the EIP-712 domain signed orders are signed in on the chain with the given id, for
+/// the DEX installed at `dex`.
+pub fn domain(chain_id: u64, dex: Address) -> Eip712Domain {
+    eip712_domain! {
+        name: "EnshrinedDex",
+        version: "1",
+        chain_id: chain_id,
+        verifying_contract: dex,
+    }
+}
+
+impl SignedOrder {
+    /// Returns the EIP-712 hash the maker signs in the given domain.
+    pub fn hash(&self, domain: &Eip712Domain) -> B256 {
+        self.eip712_signing_hash(domain)
+    }
+
+    /// Returns the position from which on the order is expired, `None` if it doesn't expire.
+    pub fn expiry(&self) -> Option<FlashblockPosition> {
+        (self.expiryBlock != 0).then_some(FlashblockPosition {
+            block_number: self.expiryBlock,
+            flashblock_index: self.expiryFlashblockIndex,
+        })
+    }
+
+    /// Returns the token the order escrows and how much of it, the tokens it sells for sells and
+    /// the tokens it pays with at its limit price for buys, or `None` if the price is invalid.
+    pub fn escrow(&self) -> Option<(Address, U256)> {
+        if self.priceDenom.is_zero() {
+            return None;
+        }
+        if self.isBuy {
+            let cost = self.amount.checked_mul(self.priceNum)?;
+            Some((self.tokenOut, cost.div_ceil(self.priceDenom)))
+        } else {
+            Some((self.tokenIn, self.amount))
+        }
+    }
+
+    /// Verify that the maker signed the order in `domain` with `signature`, given as the 65 bytes
+    /// `r`, `s` and `v`.
+    ///
+    /// Returns the EIP-712 hash of the order.
+    pub fn verify(&self, signature: &[u8], domain: &Eip712Domain) -> Result<B256, DexError> {
+        let signature = Signature::from_raw(signature).map_err(|_| DexError::InvalidSignature)?;
+        let hash = self.hash(domain);
+        match recover_signer(hash, &signature) {
+            Ok(signer) if signer == self.maker => Ok(hash),
+            _ => Err(DexError::InvalidSignature),
+        }
+    }
+}
+
+/// A signed order waiting to be placed, along with the signature of its maker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSignedOrder {
+    /// The order
+    pub order: SignedOrder,
+    /// Signature of the maker, 65 bytes of `r`, `s` and `v`
+    pub signature: Bytes,
+    /// EIP-712 hash of the order
+    pub hash: B256,
+}
+
+impl PendingSignedOrder {
+    /// Returns the calldata of placing the order with `submitSignedOrder`.
+    pub fn calldata(&self) -> Bytes {
+        [
+            selectors::SUBMIT_SIGNED_ORDER.as_slice(),
+            &(self.order.clone(), self.signature.clone()).abi_encode_params(),
+        ]
+        .concat()
+        .into()
+    }
+}
+
+/// Signed orders waiting to be placed by the builder, by maker and nonce.
+#[derive(Debug, Default)]
+pub struct SignedOrderPool {
+    orders: Mutex<BTreeMap<(Address, u64), PendingSignedOrder>>,
+}
+
+impl SignedOrderPool {
+    /// Add an order whose signature was verified, replacing a pending order of the same maker
+    /// with the same nonce.
+    ///
+    /// Fails if the pool is full or the maker has [`MAX_PENDING_ORDERS_PER_MAKER`] orders pending
+    /// already.
+    pub fn insert(&self, order: PendingSignedOrder) -> Result<(), DexError> {
+        let mut orders = self.orders.lock();
+        let maker = order.order.maker;
+        let key = (maker, order.order.nonce);
+        if !orders.contains_key(&key) {
+            if orders.len() >= MAX_PENDING_ORDERS {
+                return Err(DexError::InvalidState(
+                    "too many pending signed orders".to_string(),
+                ));
+            }
+            let of_maker = orders.range((maker, 0)..=(maker, u64::MAX)).count();
+            if of_maker >= MAX_PENDING_ORDERS_PER_MAKER {
+                return Err(DexError::InvalidState(
+                    "too many pending signed orders of maker".to_string(),
+                ));
+            }
+        }
+        orders.insert(key, order);
+        Ok(())
+    }
+
+    /// Returns the pending orders that can still be placed on top of `state`, by maker and
+    /// nonce.
+    pub fn pending(&self, state: &DexState) -> Vec<PendingSignedOrder> {
+        self.orders
+            .lock()
+            .values()
+            .filter(|pending| is_placeable(&pending.order, state))
+            .cloned()
+            .collect()
+    }
+
+    /// Remove the order of `maker` with the given nonce.
+    pub fn remove(&self, maker: Address, nonce: u64) {
+        self.orders.lock().remove(&(maker, nonce));
+    }
+
+    /// Drop the orders that can no longer be placed on top of `state`, because their nonce was
+    /// used or they expired.
+    pub fn prune(&self, state: &DexState) {
+        self.orders
+            .lock()
+            .retain(|_, pending| is_placeable(&pending.order, state));
+    }
+
+    /// Returns the number of pending orders.
+    pub fn len(&self) -> usize {
+        self.orders.lock().len()
+    }
+
+    /// Whether no orders are pending.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whether the nonce of `order` is unused in `state` and the order isn't expired yet.
+fn is_placeable(order: &SignedOrder, state: &DexState) -> bool {
+    order.nonce > state.nonce(order.maker)
+        && order
+            .expiry()
+            .is_none_or(|expiry| expiry > state.position())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dex::DEX_PREDEPLOY_ADDRESS, tx_signer::Signer};
+    use alloy_primitives::address;
+
+    #[test]
+    fn test_pool_drops_used_and_expired_orders() {
+        let maker = Signer::random();
+        let order = |nonce: u64, expiry_block: u64| SignedOrder {
+            maker: maker.address,
+            tokenIn: address!("0000000000000000000000000000000000000000"),
+            tokenOut: address!("0000000000000000000000000000000000000001"),
+            isBuy: false,
+            amount: U256::from(1000),
+            priceNum: U256::from(2),
+            priceDenom: U256::from(1),
+            expiryBlock: expiry_block,
+            expiryFlashblockIndex: 0,
+            nonce,
+        };
+        let domain = domain(1, DEX_PREDEPLOY_ADDRESS);
+        let pending = |order: SignedOrder| {
+            let signature = maker.sign_message(order.hash(&domain)).expect("sign order");
+            let signature = Bytes::copy_from_slice(&signature.as_bytes());
+            let hash = order
+                .verify(&signature, &domain)
+                .expect("signature should verify");
+            PendingSignedOrder {
+                order,
+                signature,
+                hash,
+            }
+        };
+
+        // tampering with the order invalidates the signature
+        let mut tampered = pending(order(1, 0));
+        tampered.order.amount = U256::from(2000);
+        assert!(matches!(
+            tampered.order.verify(&tampered.signature, &domain),
+            Err(DexError::InvalidSignature)
+        ));
+
+        let pool = SignedOrderPool::default();
+        for (nonce, expiry_block) in [(1, 0), (2, 5), (3, 0)] {
+            pool.insert(pending(order(nonce, expiry_block))).unwrap();
+        }
+        let mut state = DexState::new();
+        state.set_block(5, 0);
+        state.use_nonce(maker.address, 1).unwrap();
+        let nonces = |orders: Vec<PendingSignedOrder>| {
+            orders
+                .iter()
+                .map(|pending| pending.order.nonce)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(nonces(pool.pending(&state)), vec![3]);
+
+        pool.prune(&state);
+        assert_eq!(pool.len(), 1);
+
+        // a sell escrows what it sells, a buy what it pays at its limit price
+        assert_eq!(
+            order(4, 0).escrow(),
+            Some((order(4, 0).tokenIn, U256::from(1000)))
+        );
+        let buy = SignedOrder {
+            isBuy: true,
+            priceNum: U256::from(3),
+            priceDenom: U256::from(2),
+            ..order(4, 0)
+        };
+        assert_eq!(buy.escrow(), Some((buy.tokenOut, U256::from(1500))));
+
+        // makers can only have so many orders pending
+        for nonce in 4..3 + MAX_PENDING_ORDERS_PER_MAKER as u64 {
+            pool.insert(pending(order(nonce, 0))).unwrap();
+        }
+        assert_eq!(pool.len(), MAX_PENDING_ORDERS_PER_MAKER);
+        let next = 3 + MAX_PENDING_ORDERS_PER_MAKER as u64;
+        assert!(pool.insert(pending(order(next, 0))).is_err());
+        // replacing a pending order is fine
+        pool.insert(pending(order(3, 0))).unwrap();
+    }
+}
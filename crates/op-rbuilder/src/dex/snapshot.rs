@@ -0,0 +1,640 @@
+//! Snapshots of the DEX state on disk.
+//!
+//! Rebuilding the DEX from the whole chain history gets slower with every block, so the canonical
+//! DEX state is written to the datadir every few blocks. On startup the newest snapshot of a
+//! block on the canonical chain is loaded and only the blocks after it are replayed.
+//!
+//! A snapshot file consists of a magic, the format version, the keccak256 checksum of the payload
+//! and the ABI encoded [records](DexRecords) of the state. The order books are rebuilt from the
+//! records when the snapshot is loaded.
+
+use super::{
+    DexError, DexState,
+    listing::PairSpec,
+    oracle::{Observation, PriceOracle},
+    state::{DexRecords, FlashblockPosition, OrderRecord, PairRecord, PairStats, TradeSample},
+};
+use alloy_primitives::{B256, keccak256};
+use alloy_sol_types::SolValue;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Magic prefix of snapshot files
+const MAGIC: [u8; 4] = *b"DEXS";
+
+/// Version of the snapshot format, bumped whenever the encoding of the state changes
+pub const SNAPSHOT_VERSION: u32 = 8;
+
+/// Number of snapshots kept on disk, older ones are deleted when a new one is written
+const RETAINED_SNAPSHOTS: usize = 3;
+
+/// File extension of snapshot files
+const EXTENSION: &str = "dexsnap";
+
+/// Length of the header preceding the payload
+const HEADER_LEN: usize = MAGIC.len() + 4 + 32;
+
+mod abi {
+    alloy_sol_types::sol! {
+        struct Trade {
+            uint64 blockNumber;
+            uint64 timestamp;
+            uint64 orderId;
+            address maker;
+            address taker;
+            uint128 priceNum;
+            uint128 priceDenom;
+            uint256 volume0;
+            uint256 volume1;
+        }
+
+        struct Observation {
+            uint64 timestamp;
+            uint256 price0Cumulative;
+            uint256 price1Cumulative;
+        }
+
+        struct Pair {
+            bytes32 pairId;
+            address token0;
+            address token1;
+            uint8 decimals0;
+            uint8 decimals1;
+            string symbol0;
+            string symbol1;
+            uint256 tickSize;
+            uint256 lotSize;
+            bool halted;
+            uint64 tradeCount;
+            uint256 volume0;
+            uint256 volume1;
+            uint128 lastPriceNum;
+            uint128 lastPriceDenom;
+            Trade[] recentTrades;
+            Observation[] observations;
+        }
+
+        struct Order {
+            uint64 orderId;
+            address owner;
+            bytes32 pairId;
+            address tokenIn;
+            address tokenOut;
+            bool isBuy;
+            uint128 priceNum;
+            uint128 priceDenom;
+            uint256 remaining;
+            uint256 escrow;
+            /// Zero if the order doesn't expire
+            uint64 expiryBlock;
+            uint64 expiryFlashblockIndex;
+        }
+
+        struct MakerNonce {
+            address maker;
+            uint64 nonce;
+        }
+
+        struct Snapshot {
+            bytes32 blockHash;
+            uint64 blockNumber;
+            uint64 lastOrderId;
+            uint64 stateBlockNumber;
+            uint64 stateTimestamp;
+            Pair[] pairs;
+            Order[] orders;
+            uint64[] batch;
+            MakerNonce[] nonces;
+        }
+    }
+}
+
+/// Errors that can occur while reading or writing snapshots
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("not a DEX snapshot")]
+    InvalidMagic,
+
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("snapshot checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("failed to decode snapshot: {0}")]
+    Decode(#[from] alloy_sol_types::Error),
+
+    #[error("snapshot is of block {found} instead of {expected}")]
+    BlockMismatch { expected: B256, found: B256 },
+
+    #[error("failed to restore DEX state: {0}")]
+    InvalidState(#[from] DexError),
+}
+
+/// Encode the DEX state after the given block as a snapshot.
+pub fn encode_snapshot(block_hash: B256, block_number: u64, state: &DexState) -> Vec<u8> {
+    let records = state.records();
+    let snapshot = abi::Snapshot {
+        blockHash: block_hash,
+        blockNumber: block_number,
+        lastOrderId: records.last_order_id,
+        stateBlockNumber: records.block_number,
+        stateTimestamp: records.timestamp,
+        pairs: records
+            .pairs
+            .into_iter()
+            .map(|(pair_id, pair)| abi::Pair {
+                pairId: pair_id,
+                token0: pair.token0,
+                token1: pair.token1,
+                decimals0: pair.spec.decimals0,
+                decimals1: pair.spec.decimals1,
+                symbol0: pair.spec.symbol0,
+                symbol1: pair.spec.symbol1,
+                tickSize: pair.spec.tick_size,
+                lotSize: pair.spec.lot_size,
+                halted: pair.halted,
+                tradeCount: pair.stats.trade_count,
+                volume0: pair.stats.volume0,
+                volume1: pair.stats.volume1,
+                lastPriceNum: pair.stats.last_price_num,
+                lastPriceDenom: pair.stats.last_price_denom,
+                recentTrades: pair
+                    .stats
+                    .recent_trades
+                    .into_iter()
+                    .map(|trade| abi::Trade {
+                        blockNumber: trade.block_number,
+                        timestamp: trade.timestamp,
+                        orderId: trade.order_id,
+                        maker: trade.maker,
+                        taker: trade.taker,
+                        priceNum: trade.price_num,
+                        priceDenom: trade.price_denom,
+                        volume0: trade.volume0,
+                        volume1: trade.volume1,
+                    })
+                    .collect(),
+                observations: pair
+                    .oracle
+                    .observations
+                    .into_iter()
+                    .map(|observation| abi::Observation {
+                        timestamp: observation.timestamp,
+                        price0Cumulative: observation.price0_cumulative,
+                        price1Cumulative: observation.price1_cumulative,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        orders: records
+            .orders
+            .into_iter()
+            .map(|(order_id, order)| abi::Order {
+                orderId: order_id,
+                owner: order.owner,
+                pairId: order.pair_id,
+                tokenIn: order.token_in,
+                tokenOut: order.token_out,
+                isBuy: order.is_buy,
+                priceNum: order.price_num,
+                priceDenom: order.price_denom,
+                remaining: order.remaining,
+                escrow: order.escrow,
+                expiryBlock: order.expiry.map_or(0, |expiry| expiry.block_number),
+                expiryFlashblockIndex: order.expiry.map_or(0, |expiry| expiry.flashblock_index),
+            })
+            .collect(),
+        batch: records.batch,
+        nonces: records
+            .nonces
+            .into_iter()
+            .map(|(maker, nonce)| abi::MakerNonce { maker, nonce })
+            .collect(),
+    };
+
+    let payload = snapshot.abi_encode();
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+    bytes.extend_from_slice(keccak256(&payload).as_slice());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Decode a snapshot, verifying its checksum and rebuilding the DEX state.
+///
+/// Returns the hash and number of the block the snapshot was taken after, along with the state.
+pub fn decode_snapshot(bytes: &[u8]) -> Result<(B256, u64, DexState), SnapshotError> {
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+        return Err(SnapshotError::InvalidMagic);
+    }
+    let (version, rest) = bytes[MAGIC.len()..].split_at(4);
+    let version = u32::from_be_bytes(version.try_into().expect("version is 4 bytes"));
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let (checksum, payload) = rest.split_at(32);
+    if keccak256(payload).as_slice() != checksum {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    let snapshot = abi::Snapshot::abi_decode(payload)?;
+    let records = DexRecords {
+        pairs: snapshot
+            .pairs
+            .into_iter()
+            .map(|pair| {
+                let stats = PairStats {
+                    trade_count: pair.tradeCount,
+                    volume0: pair.volume0,
+                    volume1: pair.volume1,
+                    last_price_num: pair.lastPriceNum,
+                    last_price_denom: pair.lastPriceDenom,
+                    recent_trades: pair
+                        .recentTrades
+                        .into_iter()
+                        .map(|trade| TradeSample {
+                            block_number: trade.blockNumber,
+                            timestamp: trade.timestamp,
+                            order_id: trade.orderId,
+                            maker: trade.maker,
+                            taker: trade.taker,
+                            price_num: trade.priceNum,
+                            price_denom: trade.priceDenom,
+                            volume0: trade.volume0,
+                            volume1: trade.volume1,
+                        })
+                        .collect(),
+                };
+                let oracle = PriceOracle {
+                    observations: pair
+                        .observations
+                        .into_iter()
+                        .map(|observation| Observation {
+                            timestamp: observation.timestamp,
+                            price0_cumulative: observation.price0Cumulative,
+                            price1_cumulative: observation.price1Cumulative,
+                        })
+                        .collect(),
+                };
+                let record = PairRecord {
+                    token0: pair.token0,
+                    token1: pair.token1,
+                    spec: PairSpec {
+                        decimals0: pair.decimals0,
+                        decimals1: pair.decimals1,
+                        symbol0: pair.symbol0,
+                        symbol1: pair.symbol1,
+                        tick_size: pair.tickSize,
+                        lot_size: pair.lotSize,
+                    },
+                    halted: pair.halted,
+                    stats,
+                    oracle,
+                };
+                (pair.pairId, record)
+            })
+            .collect(),
+        orders: snapshot
+            .orders
+            .into_iter()
+            .map(|order| {
+                let record = OrderRecord {
+                    owner: order.owner,
+                    pair_id: order.pairId,
+                    token_in: order.tokenIn,
+                    token_out: order.tokenOut,
+                    is_buy: order.isBuy,
+                    price_num: order.priceNum,
+                    price_denom: order.priceDenom,
+                    remaining: order.remaining,
+                    escrow: order.escrow,
+                    expiry: (order.expiryBlock != 0).then_some(FlashblockPosition {
+                        block_number: order.expiryBlock,
+                        flashblock_index: order.expiryFlashblockIndex,
+                    }),
+                };
+                (order.orderId, record)
+            })
+            .collect(),
+        batch: snapshot.batch,
+        last_order_id: snapshot.lastOrderId,
+        nonces: snapshot
+            .nonces
+            .into_iter()
+            .map(|nonce| (nonce.maker, nonce.nonce))
+            .collect(),
+        block_number: snapshot.stateBlockNumber,
+        timestamp: snapshot.stateTimestamp,
+    };
+
+    let state = DexState::from_records(records)?;
+    Ok((snapshot.blockHash, snapshot.blockNumber, state))
+}
+
+/// Directory of DEX snapshots, taken every `interval` blocks.
+#[derive(Debug, Clone)]
+pub struct DexSnapshots {
+    dir: PathBuf,
+    interval: u64,
+}
+
+impl DexSnapshots {
+    /// Create a snapshot directory taking a snapshot every `interval` blocks.
+    pub fn new(dir: impl Into<PathBuf>, interval: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            interval,
+        }
+    }
+
+    /// Returns the directory the snapshots are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether a snapshot should be taken after the given block.
+    pub fn is_due(&self, block_number: u64) -> bool {
+        self.interval != 0 && block_number.is_multiple_of(self.interval)
+    }
+
+    /// Write a snapshot of the DEX state after the given block and delete the oldest ones.
+    ///
+    /// The snapshot is written to a temporary file first, so a crash never leaves a truncated
+    /// snapshot behind under its final name.
+    pub fn write(
+        &self,
+        block_hash: B256,
+        block_number: u64,
+        state: &DexState,
+    ) -> Result<(), SnapshotError> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(block_hash, block_number);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, encode_snapshot(block_hash, block_number, state))?;
+        fs::rename(&tmp, &path)?;
+
+        for (number, hash) in self.available()?.into_iter().skip(RETAINED_SNAPSHOTS) {
+            fs::remove_file(self.path(hash, number))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the blocks snapshots are available for, newest first.
+    pub fn available(&self) -> Result<Vec<(u64, B256)>, SnapshotError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let Some((number, hash)) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split_once('-'))
+            else {
+                continue;
+            };
+            if let (Ok(number), Ok(hash)) = (number.parse(), hash.parse()) {
+                snapshots.push((number, hash));
+            }
+        }
+        snapshots.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(snapshots)
+    }
+
+    /// Read the snapshot of the given block.
+    pub fn read(&self, block_hash: B256, block_number: u64) -> Result<DexState, SnapshotError> {
+        let bytes = fs::read(self.path(block_hash, block_number))?;
+        let (hash, _, state) = decode_snapshot(&bytes)?;
+        if hash != block_hash {
+            return Err(SnapshotError::BlockMismatch {
+                expected: block_hash,
+                found: hash,
+            });
+        }
+        Ok(state)
+    }
+
+    fn path(&self, block_hash: B256, block_number: u64) -> PathBuf {
+        self.dir
+            .join(format!("{block_number:020}-{block_hash:x}.{EXTENSION}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dex::{
+        DexHandler, DexResult, PairListing, oracle::PRICE_SCALE, predeploy::selectors,
+    };
+    use alloy_primitives::{Address, Bytes, FixedBytes, U256, address};
+
+    const ALICE: Address = address!("00000000000000000000000000000000000a11ce");
+    const BOB: Address = address!("0000000000000000000000000000000000000b0b");
+    const ETH: Address = address!("0000000000000000000000000000000000000000");
+    const USDC: Address = address!("0000000000000000000000000000000000000001");
+
+    fn call(
+        handler: &DexHandler,
+        caller: Address,
+        selector: FixedBytes<4>,
+        params: Vec<u8>,
+        value: U256,
+    ) -> DexResult {
+        let calldata: Bytes = [selector.as_slice(), &params].concat().into();
+        handler
+            .handle_transaction(caller, &calldata, value)
+            .expect("operation should succeed")
+    }
+
+    /// A state with a pair listed with metadata, a partially filled order, a cancelled one and
+    /// three resting ones, one of which expires at block 12
+    fn populated_state() -> DexHandler {
+        let handler = DexHandler::new();
+        handler.set_block(10, 1_000);
+        let listing = PairListing {
+            token0: ETH,
+            token1: USDC,
+            decimals0: 18,
+            decimals1: 6,
+            symbol0: "ETH".to_string(),
+            symbol1: "USDC".to_string(),
+            tickSize: PRICE_SCALE,
+            lotSize: U256::from(5),
+        };
+        call(
+            &handler,
+            ALICE,
+            selectors::LIST_PAIR,
+            listing.abi_encode(),
+            U256::ZERO,
+        );
+        let mut order_ids = Vec::new();
+        for (amount, price_num) in [(100u64, 3u64), (50, 2), (70, 2)] {
+            let params = (
+                ETH,
+                USDC,
+                false,
+                U256::from(amount),
+                U256::from(price_num),
+                U256::from(1),
+            )
+                .abi_encode();
+            let placed = call(
+                &handler,
+                ALICE,
+                selectors::PLACE_LIMIT_ORDER,
+                params,
+                U256::from(amount),
+            );
+            let DexResult::OrderPlaced { order_id, .. } = placed else {
+                panic!("expected an order to be placed");
+            };
+            order_ids.push(order_id);
+        }
+        let expiring = (
+            ETH,
+            USDC,
+            false,
+            U256::from(30),
+            U256::from(5),
+            U256::from(1),
+            12u64,
+            0u64,
+        )
+            .abi_encode();
+        call(
+            &handler,
+            ALICE,
+            selectors::PLACE_LIMIT_ORDER_WITH_EXPIRY,
+            expiring,
+            U256::from(30),
+        );
+        let cancel = order_ids[0].abi_encode();
+        call(&handler, ALICE, selectors::CANCEL_ORDER, cancel, U256::ZERO);
+        let swap = (USDC, ETH, U256::from(40), U256::ZERO).abi_encode();
+        call(&handler, BOB, selectors::SWAP, swap, U256::ZERO);
+        handler.take_transfers();
+        handler.take_storage_changes();
+        handler
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let handler = populated_state();
+        let state = handler.snapshot();
+        let block_hash = B256::with_last_byte(42);
+
+        let (hash, number, restored) =
+            decode_snapshot(&encode_snapshot(block_hash, 10, &state)).unwrap();
+        assert_eq!((hash, number), (block_hash, 10));
+        assert_eq!(restored.records(), state.records());
+
+        // the restored books trade exactly like the original ones
+        let restored = DexHandler::from_state(restored);
+        for handler in [&handler, &restored] {
+            let swap = (USDC, ETH, U256::from(100), U256::ZERO).abi_encode();
+            call(handler, BOB, selectors::SWAP, swap, U256::ZERO);
+            let params = (
+                ETH,
+                USDC,
+                false,
+                U256::from(5),
+                U256::from(4),
+                U256::from(1),
+            )
+                .abi_encode();
+            call(
+                handler,
+                ALICE,
+                selectors::PLACE_LIMIT_ORDER,
+                params,
+                U256::from(5),
+            );
+            // the expiring order is swept from both books
+            handler.set_block(12, 1_024);
+            let expired = call(
+                handler,
+                ALICE,
+                selectors::SWEEP_EXPIRED_ORDERS,
+                0u64.abi_encode(),
+                U256::ZERO,
+            );
+            assert!(matches!(expired, DexResult::OrdersExpired { orders } if orders.len() == 1));
+        }
+        assert_eq!(restored.snapshot().records(), handler.snapshot().records());
+        assert_eq!(restored.take_transfers(), handler.take_transfers());
+    }
+
+    #[test]
+    fn test_corrupted_snapshots_are_rejected() {
+        let state = populated_state().snapshot();
+        let bytes = encode_snapshot(B256::ZERO, 10, &state);
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode_snapshot(&corrupted),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        let mut future = bytes.clone();
+        future[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&2u32.to_be_bytes());
+        assert!(matches!(
+            decode_snapshot(&future),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+
+        assert!(matches!(
+            decode_snapshot(&bytes[..HEADER_LEN - 1]),
+            Err(SnapshotError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_directory() {
+        let dir = std::env::temp_dir().join(format!("dex-snapshots-{}", std::process::id()));
+        let snapshots = DexSnapshots::new(&dir, 100);
+        assert!(snapshots.is_due(200));
+        assert!(!snapshots.is_due(201));
+        assert!(snapshots.available().unwrap().is_empty());
+
+        let state = populated_state().snapshot();
+        for number in 1..=5u64 {
+            snapshots
+                .write(B256::with_last_byte(number as u8), number * 100, &state)
+                .unwrap();
+        }
+        let available = snapshots.available().unwrap();
+        assert_eq!(
+            available,
+            vec![
+                (500, B256::with_last_byte(5)),
+                (400, B256::with_last_byte(4)),
+                (300, B256::with_last_byte(3)),
+            ]
+        );
+
+        let restored = snapshots.read(B256::with_last_byte(4), 400).unwrap();
+        assert_eq!(restored.records(), state.records());
+        assert!(matches!(
+            snapshots.read(B256::with_last_byte(1), 100),
+            Err(SnapshotError::Io(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
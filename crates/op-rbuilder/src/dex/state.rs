@@ -0,0 +1,1709 @@
+//! In-memory state of the enshrined DEX.
+//!
+//! [`DexState`] bundles everything the DEX needs to process operations. Cloning it produces an
+//! independent copy, which is what checkpoints and per-block snapshots are built from. The parts
+//! of the state that grow with the number of pairs, orders and makers are shared between clones
+//! though, and only copied by the first clone writing to them. Copying counts as
+//! [work](DexWork), so the operation that has to copy pays for it.
+//!
+//! Besides the order books of the enshrined-dex library, the state keeps a record of every pair
+//! and resting order. The records are what gets mirrored into the predeploy's
+//! [`storage`](super::storage), and changes to them are tracked until they are written out.
+//! Likewise, the token movements an operation requires are collected until they are
+//! [settled](super::settlement), and the [fees](super::fees) they are charged until they are paid
+//! out, and fills are checked against the [price bands](super::bands) of their pairs. Trades keep
+//! the [price accumulators](super::oracle) of their pairs up to date. Pairs are created as the
+//! [listing policy](super::listing) allows, and orders respect the tick and lot sizes of their
+//! pairs.
+
+use super::{
+    BatchAuction, BatchFill, DEX_PREDEPLOY_ADDRESS, DexError, OrderFill,
+    bands::PriceBands,
+    fees::{FeeSchedule, PairFees},
+    handler::order_id_to_b256,
+    listing::{ListingPolicy, PairSpec},
+    oracle::PriceOracle,
+    settlement::Transfer,
+    signed, storage,
+};
+use alloy_primitives::{Address, B256, U256};
+use alloy_sol_types::Eip712Domain;
+use dex::{Fill, OrderId, OrderSide, PoolManager, Price};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+};
+
+/// Length of the window rolling pair statistics are computed over, in seconds
+pub const STATS_WINDOW: u64 = 24 * 60 * 60;
+
+/// A trading pair known to the DEX.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairRecord {
+    /// First token of the pair, as passed to `createPair`
+    pub token0: Address,
+    /// Second token of the pair, as passed to `createPair`
+    pub token1: Address,
+    /// Token metadata and trading granularity the pair was listed with
+    pub spec: PairSpec,
+    /// Whether trading is halted until the admin resumes the pair
+    pub halted: bool,
+    /// Trading statistics of the pair
+    pub stats: PairStats,
+    /// Accumulated prices of the pair
+    pub oracle: PriceOracle,
+}
+
+/// Trading statistics of a pair.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PairStats {
+    /// Number of fills against resting orders of the pair
+    pub trade_count: u64,
+    /// Total amount of `token0` traded
+    pub volume0: U256,
+    /// Total amount of `token1` traded
+    pub volume1: U256,
+    /// Numerator of the last traded price, in `token1` per `token0`
+    pub last_price_num: u128,
+    /// Denominator of the last traded price, in `token1` per `token0`
+    pub last_price_denom: u128,
+    /// Trades of the last [`STATS_WINDOW`], oldest first
+    pub recent_trades: VecDeque<TradeSample>,
+}
+
+impl PairStats {
+    /// Returns the statistics of the [`STATS_WINDOW`] ending at `now`.
+    pub fn window(&self, now: u64) -> WindowStats {
+        self.recent_trades
+            .iter()
+            .filter(|trade| trade.timestamp + STATS_WINDOW > now)
+            .fold(WindowStats::default(), |acc, trade| WindowStats {
+                trade_count: acc.trade_count + 1,
+                volume0: acc.volume0.saturating_add(trade.volume0),
+                volume1: acc.volume1.saturating_add(trade.volume1),
+            })
+    }
+
+    fn record_trade(&mut self, trade: TradeSample) {
+        while self
+            .recent_trades
+            .front()
+            .is_some_and(|oldest| oldest.timestamp + STATS_WINDOW <= trade.timestamp)
+        {
+            self.recent_trades.pop_front();
+        }
+        self.recent_trades.push_back(trade);
+    }
+}
+
+/// A single fill of a pair, kept for the rolling statistics and the trade history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TradeSample {
+    /// Number of the block the trade happened in
+    pub block_number: u64,
+    /// Timestamp of the block the trade happened in
+    pub timestamp: u64,
+    /// Id of the resting order that was filled
+    pub order_id: u64,
+    /// Owner of the resting order
+    pub maker: Address,
+    /// Account whose order or swap filled the resting order
+    pub taker: Address,
+    /// Numerator of the traded price, in `token1` per `token0`
+    pub price_num: u128,
+    /// Denominator of the traded price, in `token1` per `token0`
+    pub price_denom: u128,
+    /// Amount of `token0` traded
+    pub volume0: U256,
+    /// Amount of `token1` traded
+    pub volume1: U256,
+}
+
+/// Trading statistics of a pair over the [`STATS_WINDOW`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Number of fills
+    pub trade_count: u64,
+    /// Amount of `token0` traded
+    pub volume0: U256,
+    /// Amount of `token1` traded
+    pub volume1: U256,
+}
+
+/// Liquidity resting at one price of an order book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BookLevel {
+    /// Numerator of the price, in quote per base token
+    pub price_num: u128,
+    /// Denominator of the price, in quote per base token
+    pub price_denom: u128,
+    /// Amount of the base token available at this price
+    pub amount: U256,
+}
+
+impl BookLevel {
+    fn cmp_price(&self, other: &Self) -> Ordering {
+        (U256::from(self.price_num) * U256::from(other.price_denom))
+            .cmp(&(U256::from(other.price_num) * U256::from(self.price_denom)))
+    }
+}
+
+/// A point in the chain: the start of a flashblock of a block.
+///
+/// Positions are ordered by block first, so `(block_number, 0)` comes before every flashblock of
+/// the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FlashblockPosition {
+    /// Number of the block
+    pub block_number: u64,
+    /// Index of the flashblock within the block
+    pub flashblock_index: u64,
+}
+
+/// A limit order resting on one of the order books.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderRecord {
+    /// Account that placed the order
+    pub owner: Address,
+    /// Pair the order was placed on
+    pub pair_id: B256,
+    /// Base token of the order, in which `remaining` is denominated
+    pub token_in: Address,
+    /// Quote token of the order
+    pub token_out: Address,
+    /// Whether the order buys `token_in` rather than selling it
+    pub is_buy: bool,
+    /// Numerator of the limit price, in `token_out` per `token_in`
+    pub price_num: u128,
+    /// Denominator of the limit price, in `token_out` per `token_in`
+    pub price_denom: u128,
+    /// Amount of `token_in` that is still open
+    pub remaining: U256,
+    /// Tokens held by the predeploy to back the order, in `token_in` for sells and in
+    /// `token_out` for buys
+    pub escrow: U256,
+    /// Position from which on the order is expired, `None` if it rests until filled or cancelled
+    pub expiry: Option<FlashblockPosition>,
+}
+
+impl OrderRecord {
+    /// Token the order escrows and pays with
+    pub fn escrow_token(&self) -> Address {
+        if self.is_buy {
+            self.token_out
+        } else {
+            self.token_in
+        }
+    }
+}
+
+/// What an incoming order traded against resting orders.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct TakerFill {
+    /// Amount of the incoming order's `token_in` that got filled
+    pub filled: U256,
+    /// Amount the incoming order paid to the resting orders
+    pub paid: U256,
+    /// Amount the incoming order received from the resting orders
+    pub received: U256,
+}
+
+/// Work done by DEX operations, which the gas they are charged is derived from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DexWork {
+    /// Resting orders that were read
+    pub orders_read: u64,
+    /// Fills of resting orders
+    pub orders_filled: u64,
+    /// Pairs an order was routed through
+    pub hops: u64,
+    /// Predeploy storage slots that have to be written
+    pub slots_written: u64,
+    /// Order signatures that were verified
+    pub signatures: u64,
+    /// Entries of shared parts of the state that were copied before writing to them
+    pub entries_copied: u64,
+}
+
+impl DexWork {
+    fn add(&mut self, other: Self) {
+        self.orders_read = self.orders_read.saturating_add(other.orders_read);
+        self.orders_filled = self.orders_filled.saturating_add(other.orders_filled);
+        self.hops = self.hops.saturating_add(other.hops);
+        self.slots_written = self.slots_written.saturating_add(other.slots_written);
+        self.signatures = self.signatures.saturating_add(other.signatures);
+        self.entries_copied = self.entries_copied.saturating_add(other.entries_copied);
+    }
+}
+
+/// Everything the DEX state consists of besides the order books, which can be rebuilt from it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DexRecords {
+    /// All pairs with their ids, in the order they were created
+    pub pairs: Vec<(B256, PairRecord)>,
+    /// Resting orders with their ids, by order id
+    pub orders: Vec<(u64, OrderRecord)>,
+    /// Ids of the orders waiting for the next batch auction, which are not on the books yet
+    pub batch: Vec<u64>,
+    /// Id of the last order that was placed
+    pub last_order_id: u64,
+    /// Last nonce of every maker that had a signed order placed, by maker
+    pub nonces: Vec<(Address, u64)>,
+    /// Number of the block operations are executed in
+    pub block_number: u64,
+    /// Timestamp of the block operations are executed in
+    pub timestamp: u64,
+}
+
+/// Maps the order ids handed out by the pool manager to DEX order ids of resting orders.
+///
+/// DEX order ids are handed out in the order orders are placed. The pool manager hands out new
+/// ids whenever an order is placed on its books again, e.g. when the state is
+/// [restored](DexState::from_records) or an order is cleared in a batch auction.
+#[derive(Debug, Clone, Default)]
+struct OrderIds {
+    /// DEX order ids by pool manager id
+    dex: BTreeMap<u64, u64>,
+    /// Pool manager ids by DEX order id
+    book: BTreeMap<u64, u64>,
+}
+
+impl OrderIds {
+    fn link(&mut self, order_id: u64, book_id: OrderId) {
+        self.dex.insert(book_id.0, order_id);
+        self.book.insert(order_id, book_id.0);
+    }
+
+    fn unlink(&mut self, order_id: u64) -> Option<OrderId> {
+        let book_id = self.book.remove(&order_id)?;
+        self.dex.remove(&book_id);
+        Some(OrderId(book_id))
+    }
+}
+
+/// The complete state of the enshrined DEX at a given point of execution.
+///
+/// Everything behind an [`Arc`] is shared with the clones of the state until one of them writes
+/// to it.
+#[derive(Clone)]
+pub struct DexState {
+    /// The order books and pairs managed by the enshrined-dex library
+    pool_manager: Arc<PoolManager>,
+    /// All pairs by pair id
+    pairs: Arc<BTreeMap<B256, Arc<PairRecord>>>,
+    /// Pair ids in the order the pairs were created
+    pair_order: Arc<Vec<B256>>,
+    /// Pair ids by their tokens, in both orders
+    pair_ids: Arc<BTreeMap<(Address, Address), B256>>,
+    /// Resting orders by order id
+    orders: Arc<BTreeMap<u64, OrderRecord>>,
+    /// Translation of pool manager order ids
+    order_ids: Arc<OrderIds>,
+    /// Id of the last order that was placed
+    last_order_id: u64,
+    /// Last nonce of every maker that had a signed order placed
+    nonces: Arc<BTreeMap<Address, u64>>,
+    /// Pairs whose storage representation is out of date
+    dirty_pairs: BTreeSet<B256>,
+    /// Orders whose storage representation is out of date
+    dirty_orders: BTreeSet<u64>,
+    /// Makers whose nonce in storage is out of date
+    dirty_nonces: BTreeSet<Address>,
+    /// Resting orders that expire, by expiry and order id
+    expiries: Arc<BTreeSet<(FlashblockPosition, u64)>>,
+    /// Whether orders are collected and cleared in batch auctions instead of matched right away
+    batch_auction: bool,
+    /// Orders waiting for the next batch auction, which are not on the books yet
+    batch: Arc<BTreeSet<u64>>,
+    /// Token movements that are yet to be settled
+    transfers: Vec<Transfer>,
+    /// Fees charged on trades and where they are paid to
+    fee_schedule: FeeSchedule,
+    /// Fees charged since they were last paid out, by token
+    collected_fees: BTreeMap<Address, U256>,
+    /// Price bands fills have to lie within
+    price_bands: PriceBands,
+    /// Consecutive price band violations of the pairs within the current block, by pair id
+    band_violations: BTreeMap<B256, u32>,
+    /// Senders of the violations counted by `band_violations`, by pair id
+    band_violators: BTreeSet<(B256, Address)>,
+    /// Account allowed to resume halted pairs
+    admin: Option<Address>,
+    /// Account allowed to clear batch auctions and halt pairs, the builder
+    builder: Option<Address>,
+    /// Policy deciding who may create pairs
+    listing_policy: ListingPolicy,
+    /// Fee recipient of the block operations are executed in
+    beneficiary: Address,
+    /// Id of the chain signed orders are signed for
+    chain_id: u64,
+    /// Address the DEX is installed at
+    address: Address,
+    /// Work done by the operations since it was last taken
+    work: DexWork,
+    /// Number of the block operations are executed in
+    block_number: u64,
+    /// Timestamp of the block operations are executed in
+    timestamp: u64,
+    /// Index of the flashblock operations are executed in, as far as sweeps advanced it
+    flashblock_index: u64,
+    /// Last flashblock the builder started, which sweeps must not advance past
+    flashblock_limit: Option<FlashblockPosition>,
+}
+
+impl DexState {
+    /// Create an empty DEX state without any pairs or orders.
+    pub fn new() -> Self {
+        Self::from_pool_manager(PoolManager::new())
+    }
+
+    /// Create a DEX state from an existing PoolManager.
+    pub fn from_pool_manager(pool_manager: PoolManager) -> Self {
+        Self {
+            pool_manager: Arc::new(pool_manager),
+            pairs: Default::default(),
+            pair_order: Default::default(),
+            pair_ids: Default::default(),
+            orders: Default::default(),
+            order_ids: Default::default(),
+            last_order_id: 0,
+            nonces: Default::default(),
+            dirty_pairs: BTreeSet::new(),
+            dirty_orders: BTreeSet::new(),
+            dirty_nonces: BTreeSet::new(),
+            expiries: Default::default(),
+            batch_auction: false,
+            batch: Default::default(),
+            transfers: Vec::new(),
+            fee_schedule: FeeSchedule::default(),
+            collected_fees: BTreeMap::new(),
+            price_bands: PriceBands::default(),
+            band_violations: BTreeMap::new(),
+            band_violators: BTreeSet::new(),
+            admin: None,
+            builder: None,
+            listing_policy: ListingPolicy::default(),
+            beneficiary: Address::ZERO,
+            chain_id: 0,
+            address: DEX_PREDEPLOY_ADDRESS,
+            work: DexWork::default(),
+            block_number: 0,
+            timestamp: 0,
+            flashblock_index: 0,
+            flashblock_limit: None,
+        }
+    }
+
+    /// Restore a DEX state from its records, rebuilding the order books.
+    ///
+    /// The pairs are created again in their original order and the resting orders are placed
+    /// again in the order of their ids, which preserves their time priority. Orders waiting for a
+    /// batch auction stay off the books. Fails if the records are inconsistent, for example if a
+    /// restored order would trade.
+    pub fn from_records(records: DexRecords) -> Result<Self, DexError> {
+        let mut state = Self::new();
+        for (pair_id, pair) in records.pairs {
+            let created = state
+                .pool_manager_mut()
+                .create_pair(pair.token0, pair.token1)?;
+            if B256::from_slice(&created.id().0) != pair_id {
+                return Err(DexError::InvalidState(format!(
+                    "pair id mismatch for {pair_id}"
+                )));
+            }
+            state.insert_pair(pair_id, pair.token0, pair.token1, pair.spec);
+            if let Some(record) = state.pair_mut(pair_id) {
+                record.halted = pair.halted;
+                record.stats = pair.stats;
+                record.oracle = pair.oracle;
+            }
+        }
+
+        state.batch = Arc::new(records.batch.into_iter().collect());
+        let mut orders = records.orders;
+        orders.sort_by_key(|(order_id, _)| *order_id);
+        for (order_id, order) in orders {
+            if let Some(expiry) = order.expiry {
+                state.expiries_mut().insert((expiry, order_id));
+            }
+            if !state.batch.contains(&order_id) {
+                state.place_on_book(order_id, &order)?;
+            }
+            state.orders_mut().insert(order_id, order);
+        }
+
+        state.last_order_id = records.last_order_id;
+        state.nonces = Arc::new(records.nonces.into_iter().collect());
+        state.block_number = records.block_number;
+        state.timestamp = records.timestamp;
+        // Records are restored from a state that was already written out
+        state.dirty_pairs.clear();
+        state.work = DexWork::default();
+        Ok(state)
+    }
+
+    /// Place a recorded order on the book again, which must not trade.
+    fn place_on_book(&mut self, order_id: u64, order: &OrderRecord) -> Result<(), DexError> {
+        let side = if order.is_buy {
+            OrderSide::Buy
+        } else {
+            OrderSide::Sell
+        };
+        let (book_id, trade_result) = self.pool_manager_mut().place_limit_order(
+            order.token_in,
+            order.token_out,
+            order.owner,
+            side,
+            Price::from_u128(order.price_num, order.price_denom),
+            order.remaining,
+        )?;
+        if !trade_result.fills.is_empty() {
+            return Err(DexError::InvalidState(format!(
+                "order {order_id} crosses the book"
+            )));
+        }
+        self.order_ids_mut().link(order_id, book_id);
+        Ok(())
+    }
+
+    /// Returns the records the state can be [restored](Self::from_records) from.
+    pub fn records(&self) -> DexRecords {
+        DexRecords {
+            pairs: self
+                .pairs()
+                .map(|(pair_id, pair)| (pair_id, pair.clone()))
+                .collect(),
+            orders: self
+                .orders
+                .iter()
+                .map(|(order_id, order)| (*order_id, order.clone()))
+                .collect(),
+            batch: self.batch.iter().copied().collect(),
+            last_order_id: self.last_order_id,
+            nonces: self
+                .nonces
+                .iter()
+                .map(|(maker, nonce)| (*maker, *nonce))
+                .collect(),
+            block_number: self.block_number,
+            timestamp: self.timestamp,
+        }
+    }
+
+    /// Returns a reference to the underlying pool manager.
+    pub fn pool_manager(&self) -> &PoolManager {
+        &self.pool_manager
+    }
+
+    /// Returns the underlying pool manager for writing, copying it first if it's shared.
+    ///
+    /// The books hold every resting order, so a copy counts the orders along with the pairs.
+    pub(crate) fn pool_manager_mut(&mut self) -> &mut PoolManager {
+        let entries = self.orders.len() + self.pairs.len();
+        unshare(&mut self.pool_manager, entries, &mut self.work)
+    }
+
+    /// Returns the pair with the given id for writing, copying it first if it's shared.
+    fn pair_mut(&mut self, pair_id: B256) -> Option<&mut PairRecord> {
+        let entries = self.pairs.len();
+        let pair = unshare(&mut self.pairs, entries, &mut self.work).get_mut(&pair_id)?;
+        let entries = 1 + pair.stats.recent_trades.len() + pair.oracle.observations.len();
+        Some(unshare(pair, entries, &mut self.work))
+    }
+
+    fn orders_mut(&mut self) -> &mut BTreeMap<u64, OrderRecord> {
+        let entries = self.orders.len();
+        unshare(&mut self.orders, entries, &mut self.work)
+    }
+
+    fn order_ids_mut(&mut self) -> &mut OrderIds {
+        let entries = self.order_ids.book.len();
+        unshare(&mut self.order_ids, entries, &mut self.work)
+    }
+
+    fn expiries_mut(&mut self) -> &mut BTreeSet<(FlashblockPosition, u64)> {
+        let entries = self.expiries.len();
+        unshare(&mut self.expiries, entries, &mut self.work)
+    }
+
+    fn batch_mut(&mut self) -> &mut BTreeSet<u64> {
+        let entries = self.batch.len();
+        unshare(&mut self.batch, entries, &mut self.work)
+    }
+
+    /// Returns the pair with the given id.
+    pub fn pair(&self, pair_id: B256) -> Option<&PairRecord> {
+        self.pairs.get(&pair_id).map(Arc::as_ref)
+    }
+
+    /// Returns the id of the pair trading the two tokens, in either order.
+    pub fn pair_id(&self, token_a: Address, token_b: Address) -> Option<B256> {
+        self.pair_ids.get(&(token_a, token_b)).copied()
+    }
+
+    /// Returns all pairs with their ids, in the order they were created.
+    pub fn pairs(&self) -> impl Iterator<Item = (B256, &PairRecord)> {
+        self.pair_order
+            .iter()
+            .filter_map(|pair_id| Some((*pair_id, self.pair(*pair_id)?)))
+    }
+
+    /// Returns the trades of a pair since `from_block`, oldest first.
+    ///
+    /// Only the trades of the last [`STATS_WINDOW`] are kept.
+    pub fn trades(&self, pair_id: B256, from_block: u64) -> impl Iterator<Item = &TradeSample> {
+        self.pairs
+            .get(&pair_id)
+            .into_iter()
+            .flat_map(|pair| &pair.stats.recent_trades)
+            .filter(move |trade| trade.block_number >= from_block)
+    }
+
+    /// Returns the resting order with the given id.
+    pub fn order(&self, order_id: u64) -> Option<&OrderRecord> {
+        self.orders.get(&order_id)
+    }
+
+    /// Whether the order with the given id waits for the next batch auction.
+    pub fn is_queued(&self, order_id: u64) -> bool {
+        self.batch.contains(&order_id)
+    }
+
+    /// Whether orders are cleared in batch auctions instead of matched right away.
+    pub fn batch_auction(&self) -> bool {
+        self.batch_auction
+    }
+
+    /// Returns the number of orders waiting for the next batch auction.
+    pub fn batch_len(&self) -> usize {
+        self.batch.len()
+    }
+
+    /// Returns the fees charged on trades.
+    pub fn fee_schedule(&self) -> &FeeSchedule {
+        &self.fee_schedule
+    }
+
+    /// Returns the fees of the pair with the given id.
+    pub fn pair_fees(&self, pair_id: B256) -> PairFees {
+        self.pairs
+            .get(&pair_id)
+            .map(|pair| self.fee_schedule.pair_fees(pair.token0, pair.token1))
+            .unwrap_or(self.fee_schedule.default)
+    }
+
+    /// Returns the last nonce of `maker` that was used by a signed order, zero if none was.
+    pub fn nonce(&self, maker: Address) -> u64 {
+        self.nonces.get(&maker).copied().unwrap_or_default()
+    }
+
+    /// Returns the price bands fills have to lie within.
+    pub fn price_bands(&self) -> PriceBands {
+        self.price_bands
+    }
+
+    /// Whether trading on the pair with the given id is halted.
+    pub fn is_halted(&self, pair_id: B256) -> bool {
+        self.pairs.get(&pair_id).is_some_and(|pair| pair.halted)
+    }
+
+    /// Returns the account allowed to resume halted pairs.
+    pub fn admin(&self) -> Option<Address> {
+        self.admin
+    }
+
+    /// Returns the account allowed to clear batch auctions and halt pairs.
+    pub fn builder(&self) -> Option<Address> {
+        self.builder
+    }
+
+    /// Returns the policy deciding who may create pairs.
+    pub fn listing_policy(&self) -> &ListingPolicy {
+        &self.listing_policy
+    }
+
+    /// Returns the id of the chain signed orders are signed for.
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    /// Returns the address the DEX is installed at.
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Returns the EIP-712 domain signed orders are signed in.
+    pub fn signing_domain(&self) -> Eip712Domain {
+        signed::domain(self.chain_id, self.address)
+    }
+
+    /// Returns the resting orders placed by `owner`, by order id.
+    pub fn orders_of(&self, owner: Address) -> impl Iterator<Item = (u64, &OrderRecord)> {
+        self.orders
+            .iter()
+            .filter(move |(_, order)| order.owner == owner)
+            .map(|(order_id, order)| (*order_id, order))
+    }
+
+    /// Returns the number of the block operations are executed in.
+    pub fn block_number(&self) -> u64 {
+        self.block_number
+    }
+
+    /// Returns the timestamp of the block operations are executed in.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Returns the position operations are executed at.
+    pub fn position(&self) -> FlashblockPosition {
+        FlashblockPosition {
+            block_number: self.block_number,
+            flashblock_index: self.flashblock_index,
+        }
+    }
+
+    /// Returns the ids of the resting orders that are expired at `position`, soonest expiry
+    /// first.
+    pub fn expired_orders(&self, position: FlashblockPosition) -> impl Iterator<Item = u64> + '_ {
+        self.expiries
+            .iter()
+            .take_while(move |(expiry, _)| *expiry <= position)
+            .map(|(_, order_id)| *order_id)
+    }
+
+    /// Returns up to `levels` price levels of bids and asks for `base` quoted in `quote`.
+    ///
+    /// Orders of both directions of the pair are taken into account: an order selling `quote`
+    /// bids for `base` at the inverse price. Bids are sorted from the highest price down and asks
+    /// from the lowest price up.
+    pub fn depth(
+        &self,
+        base: Address,
+        quote: Address,
+        levels: usize,
+    ) -> (Vec<BookLevel>, Vec<BookLevel>) {
+        let (mut bids, mut asks) = (Vec::new(), Vec::new());
+        for order in self.book_orders().map(|(_, order)| order) {
+            if (order.token_in, order.token_out) != (base, quote)
+                && (order.token_in, order.token_out) != (quote, base)
+            {
+                continue;
+            }
+            let (level, is_bid) = book_level(order, base);
+            if is_bid {
+                bids.push(level);
+            } else {
+                asks.push(level);
+            }
+        }
+        sort_book(bids, asks, levels)
+    }
+
+    /// Whether an order for `token_in` at the given limit price would trade against the book
+    /// right away.
+    pub fn crosses(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        is_buy: bool,
+        price_num: u128,
+        price_denom: u128,
+    ) -> bool {
+        let limit = BookLevel {
+            price_num,
+            price_denom,
+            amount: U256::ZERO,
+        };
+        let (bids, asks) = self.depth(token_in, token_out, 1);
+        if is_buy {
+            asks.first()
+                .is_some_and(|ask| ask.cmp_price(&limit).is_le())
+        } else {
+            bids.first()
+                .is_some_and(|bid| bid.cmp_price(&limit).is_ge())
+        }
+    }
+
+    /// Returns how much of `amount` an order for `token_in` at the given limit price would fill
+    /// right away, together with the number of resting orders read to find out.
+    ///
+    /// The book is walked from the best price on without trading against it, stopping once
+    /// `amount` is reached.
+    pub fn fillable(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        is_buy: bool,
+        price_num: u128,
+        price_denom: u128,
+        amount: U256,
+    ) -> (U256, u64) {
+        let limit = BookLevel {
+            price_num,
+            price_denom,
+            amount: U256::ZERO,
+        };
+        let mut levels: Vec<BookLevel> = self
+            .book_orders()
+            .map(|(_, order)| order)
+            .filter(|order| {
+                (order.token_in, order.token_out) == (token_in, token_out)
+                    || (order.token_in, order.token_out) == (token_out, token_in)
+            })
+            .map(|order| book_level(order, token_in))
+            // buys trade against asks and sells against bids
+            .filter(|(_, is_bid)| *is_bid != is_buy)
+            .map(|(level, _)| level)
+            .filter(|level| {
+                if is_buy {
+                    level.cmp_price(&limit).is_le()
+                } else {
+                    level.cmp_price(&limit).is_ge()
+                }
+            })
+            .collect();
+        if is_buy {
+            levels.sort_by(BookLevel::cmp_price);
+        } else {
+            levels.sort_by(|a, b| b.cmp_price(a));
+        }
+
+        let (mut filled, mut orders_read) = (U256::ZERO, 0);
+        for level in levels {
+            if filled >= amount {
+                break;
+            }
+            filled = filled.saturating_add(level.amount);
+            orders_read += 1;
+        }
+        (filled.min(amount), orders_read)
+    }
+
+    /// Returns all price levels of the order books of all pairs, with `token0` of each pair as
+    /// the base token.
+    pub fn books(&self) -> BTreeMap<B256, (Vec<BookLevel>, Vec<BookLevel>)> {
+        let mut books: BTreeMap<B256, (Vec<BookLevel>, Vec<BookLevel>)> = self
+            .pairs
+            .keys()
+            .map(|pair_id| (*pair_id, Default::default()))
+            .collect();
+        for order in self.book_orders().map(|(_, order)| order) {
+            let (Some(pair), Some((bids, asks))) = (
+                self.pairs.get(&order.pair_id),
+                books.get_mut(&order.pair_id),
+            ) else {
+                continue;
+            };
+            let (level, is_bid) = book_level(order, pair.token0);
+            if is_bid {
+                bids.push(level);
+            } else {
+                asks.push(level);
+            }
+        }
+        books
+            .into_iter()
+            .map(|(pair_id, (bids, asks))| (pair_id, sort_book(bids, asks, usize::MAX)))
+            .collect()
+    }
+
+    /// Returns the orders on the books, leaving out the ones waiting for a batch auction.
+    fn book_orders(&self) -> impl Iterator<Item = (u64, &OrderRecord)> {
+        self.orders
+            .iter()
+            .filter(|(order_id, _)| !self.batch.contains(order_id))
+            .map(|(order_id, order)| (*order_id, order))
+    }
+
+    /// Collect orders in batch auctions instead of matching them right away.
+    pub(crate) fn set_batch_auction(&mut self, enabled: bool) {
+        self.batch_auction = enabled;
+    }
+
+    /// Charge trades the fees of the given schedule.
+    pub(crate) fn set_fee_schedule(&mut self, fee_schedule: FeeSchedule) {
+        self.fee_schedule = fee_schedule;
+    }
+
+    /// Check fills against the given price bands.
+    pub(crate) fn set_price_bands(&mut self, price_bands: PriceBands) {
+        self.price_bands = price_bands;
+    }
+
+    /// Allow `admin` to resume halted pairs.
+    pub(crate) fn set_admin(&mut self, admin: Option<Address>) {
+        self.admin = admin;
+    }
+
+    /// Allow `builder` to clear batch auctions and halt pairs.
+    pub(crate) fn set_builder(&mut self, builder: Option<Address>) {
+        self.builder = builder;
+    }
+
+    /// Let the given policy decide who may create pairs.
+    pub(crate) fn set_listing_policy(&mut self, listing_policy: ListingPolicy) {
+        self.listing_policy = listing_policy;
+    }
+
+    /// Set the fee recipient of the block, which fees are paid to unless there is a treasury.
+    pub(crate) fn set_beneficiary(&mut self, beneficiary: Address) {
+        self.beneficiary = beneficiary;
+    }
+
+    /// Verify signed orders as signed for the chain with the given id.
+    pub(crate) fn set_chain_id(&mut self, chain_id: u64) {
+        self.chain_id = chain_id;
+    }
+
+    /// Install the DEX at `address` instead of the predeploy address.
+    pub(crate) fn set_address(&mut self, address: Address) {
+        self.address = address;
+    }
+
+    /// Set the block subsequent operations are executed in.
+    ///
+    /// A new block starts at its first flashblock, without any price band violations.
+    pub(crate) fn set_block(&mut self, block_number: u64, timestamp: u64) {
+        if block_number != self.block_number {
+            self.flashblock_index = 0;
+            self.band_violations.clear();
+            self.band_violators.clear();
+        }
+        self.block_number = block_number;
+        self.timestamp = timestamp;
+    }
+
+    /// Limit sweeps to the flashblock the builder is building.
+    ///
+    /// The limit only applies to the given block, so blocks that are replayed are swept as
+    /// recorded in their transactions.
+    pub(crate) fn set_flashblock_limit(&mut self, position: FlashblockPosition) {
+        self.flashblock_limit = Some(position);
+    }
+
+    /// Advance to flashblock `flashblock_index` of the current block, from which on the orders
+    /// expiring there count as expired. Positions never move backwards.
+    pub(crate) fn advance_flashblock(&mut self, flashblock_index: u64) -> Result<(), DexError> {
+        if let Some(limit) = self.flashblock_limit
+            && limit.block_number == self.block_number
+            && flashblock_index > limit.flashblock_index
+        {
+            return Err(DexError::FlashblockNotStarted(flashblock_index));
+        }
+        self.flashblock_index = self.flashblock_index.max(flashblock_index);
+        Ok(())
+    }
+
+    /// Create a pair of `token0` and `token1` with the given spec, returning its id.
+    ///
+    /// Whether the pair may be created at all is up to the caller to check against the
+    /// [listing policy](Self::listing_policy).
+    pub(crate) fn create_pair(
+        &mut self,
+        token0: Address,
+        token1: Address,
+        spec: PairSpec,
+    ) -> Result<B256, DexError> {
+        spec.validate()?;
+        let pair = self.pool_manager_mut().create_pair(token0, token1)?;
+        let pair_id = B256::from_slice(&pair.id().0);
+        self.insert_pair(pair_id, token0, token1, spec);
+        Ok(pair_id)
+    }
+
+    /// Record a newly created pair.
+    pub(crate) fn insert_pair(
+        &mut self,
+        pair_id: B256,
+        token0: Address,
+        token1: Address,
+        spec: PairSpec,
+    ) {
+        let pair = PairRecord {
+            token0,
+            token1,
+            spec,
+            halted: false,
+            stats: PairStats::default(),
+            oracle: PriceOracle::default(),
+        };
+        let entries = self.pairs.len();
+        unshare(&mut self.pairs, entries, &mut self.work).insert(pair_id, Arc::new(pair));
+        let entries = self.pair_order.len();
+        unshare(&mut self.pair_order, entries, &mut self.work).push(pair_id);
+        let entries = self.pair_ids.len();
+        let pair_ids = unshare(&mut self.pair_ids, entries, &mut self.work);
+        pair_ids.insert((token0, token1), pair_id);
+        pair_ids.insert((token1, token0), pair_id);
+        self.mark_pair_dirty(pair_id);
+    }
+
+    /// Returns the id of a newly placed order.
+    pub(crate) fn assign_order_id(&mut self) -> u64 {
+        self.last_order_id = self.last_order_id.wrapping_add(1);
+        self.last_order_id
+    }
+
+    /// Returns the DEX order id of the resting order a fill was made against.
+    fn maker_id(&self, fill: &Fill) -> u64 {
+        self.order_ids
+            .dex
+            .get(&fill.order_id.0)
+            .copied()
+            .unwrap_or(fill.order_id.0)
+    }
+
+    /// Record an order that rests on the book under `book_id` after placement.
+    pub(crate) fn insert_order(&mut self, order_id: u64, book_id: OrderId, order: OrderRecord) {
+        self.order_ids_mut().link(order_id, book_id);
+        self.record_order(order_id, order);
+    }
+
+    /// Record an order that waits for the next batch auction.
+    pub(crate) fn queue_order(&mut self, order_id: u64, order: OrderRecord) {
+        self.batch_mut().insert(order_id);
+        self.record_order(order_id, order);
+    }
+
+    fn record_order(&mut self, order_id: u64, order: OrderRecord) {
+        if let Some(expiry) = order.expiry {
+            self.expiries_mut().insert((expiry, order_id));
+        }
+        self.orders_mut().insert(order_id, order);
+        self.mark_order_dirty(order_id);
+    }
+
+    /// Take an order off the book and remove it, refunding its leftover escrow to the owner.
+    pub(crate) fn cancel_order(&mut self, order_id: u64) -> Result<OrderRecord, DexError> {
+        let order = self.orders.get(&order_id).ok_or(DexError::OrderNotFound)?;
+        if !self.batch.contains(&order_id)
+            && let Some(book_id) = self.order_ids.book.get(&order_id)
+        {
+            let (token_in, token_out, book_id) = (order.token_in, order.token_out, *book_id);
+            self.pool_manager_mut()
+                .cancel_order(token_in, token_out, OrderId(book_id))
+                .map_err(DexError::from)?;
+        }
+        self.remove_order(order_id).ok_or(DexError::OrderNotFound)
+    }
+
+    /// Remove a resting order and refund its leftover escrow to the owner.
+    ///
+    /// Only the record is removed, the caller is responsible for taking the order off the book.
+    pub(crate) fn remove_order(&mut self, order_id: u64) -> Option<OrderRecord> {
+        let order = self.orders_mut().remove(&order_id)?;
+        self.order_ids_mut().unlink(order_id);
+        if self.batch.contains(&order_id) {
+            self.batch_mut().remove(&order_id);
+        }
+        if let Some(expiry) = order.expiry {
+            self.expiries_mut().remove(&(expiry, order_id));
+        }
+        self.record_transfer(Transfer::push(
+            order.escrow_token(),
+            order.owner,
+            order.escrow,
+        ));
+        self.mark_order_dirty(order_id);
+        Some(order)
+    }
+
+    /// Record a token movement to be settled.
+    pub(crate) fn record_transfer(&mut self, transfer: Transfer) {
+        if !transfer.amount.is_zero() {
+            self.transfers.push(transfer);
+        }
+    }
+
+    /// Charge `fee` of `token` out of `amount`, returning what is left of it.
+    pub(crate) fn charge_fee(&mut self, token: Address, amount: U256, fee: U256) -> U256 {
+        let fee = fee.min(amount);
+        if !fee.is_zero() {
+            let collected = self.collected_fees.entry(token).or_default();
+            *collected = collected.saturating_add(fee);
+        }
+        amount - fee
+    }
+
+    /// Pay out the fees charged since the last call, returning the transfers paying them.
+    ///
+    /// The transfers are recorded to be settled along with the other token movements.
+    pub(crate) fn pay_fees(&mut self) -> Vec<Transfer> {
+        let recipient = self.fee_schedule.recipient(self.beneficiary);
+        let payouts: Vec<Transfer> = std::mem::take(&mut self.collected_fees)
+            .into_iter()
+            .map(|(token, amount)| Transfer::push(token, recipient, amount))
+            .collect();
+        for payout in &payouts {
+            self.record_transfer(payout.clone());
+        }
+        payouts
+    }
+
+    /// Returns what an incoming order with base token `token_in` traded in the given fills.
+    ///
+    /// Fills are denominated in the resting order's tokens, so they are converted to the incoming
+    /// order's side. Must be called before the fills are [applied](Self::apply_fills).
+    pub(crate) fn taker_fill(&self, fills: &[Fill], token_in: Address) -> TakerFill {
+        fills
+            .iter()
+            .filter_map(|fill| {
+                self.orders
+                    .get(&self.maker_id(fill))
+                    .map(|maker| (maker, fill))
+            })
+            .fold(TakerFill::default(), |acc, (maker, fill)| {
+                let filled = if maker.token_in == token_in {
+                    fill.base_amount
+                } else {
+                    fill.quote_amount
+                };
+                // whatever the resting order gives, the incoming order receives and vice versa
+                let (paid, received) = if maker.is_buy {
+                    (fill.base_amount, fill.quote_amount)
+                } else {
+                    (fill.quote_amount, fill.base_amount)
+                };
+                TakerFill {
+                    filled: acc.filled.saturating_add(filled),
+                    paid: acc.paid.saturating_add(paid),
+                    received: acc.received.saturating_add(received),
+                }
+            })
+    }
+
+    /// Check that none of the given fills trades on a halted pair or outside of the price band of
+    /// its pair.
+    ///
+    /// Fills are compared with the last traded price of their pair before any of them. Must be
+    /// called before the fills are [applied](Self::apply_fills).
+    pub(crate) fn check_price_bands(&self, fills: &[Fill]) -> Result<(), DexError> {
+        for fill in fills {
+            let Some(maker) = self.orders.get(&self.maker_id(fill)) else {
+                continue;
+            };
+            let Some(pair) = self.pairs.get(&maker.pair_id) else {
+                continue;
+            };
+            if pair.halted {
+                return Err(DexError::PairHalted);
+            }
+            let price = if maker.token_in == pair.token0 {
+                (maker.price_num, maker.price_denom)
+            } else {
+                (maker.price_denom, maker.price_num)
+            };
+            let reference = (pair.stats.last_price_num, pair.stats.last_price_denom);
+            if !self.price_bands.contains(reference, price) {
+                return Err(DexError::PriceBandExceeded {
+                    pair_id: maker.pair_id,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a price band violation of a pair. Returns whether the pair ran into its band too
+    /// many times in a row within the block and is due to halt.
+    pub(crate) fn record_band_violation(&mut self, pair_id: B256) -> bool {
+        let violations = self.band_violations.entry(pair_id).or_default();
+        *violations = violations.saturating_add(1);
+        self.price_bands.halts(*violations) && !self.is_halted(pair_id)
+    }
+
+    /// Count a price band violation of a pair by a transaction of `sender` that ran into the band
+    /// in an operation which was reverted. Each sender only counts once per pair until the count
+    /// starts over, so a single account can't trip the circuit breaker by repeating reverted
+    /// attempts. Returns whether the pair is due to halt.
+    ///
+    /// The count is kept off-chain, the pair only halts once the builder calls `haltPair`.
+    pub(crate) fn record_band_violation_of(&mut self, sender: Address, pair_id: B256) -> bool {
+        if !self.band_violators.insert((pair_id, sender)) {
+            return false;
+        }
+        self.record_band_violation(pair_id)
+    }
+
+    /// Start the count of price band violations of a pair over.
+    fn reset_band_violations(&mut self, pair_id: B256) {
+        self.band_violations.remove(&pair_id);
+        self.band_violators
+            .retain(|(violated, _)| *violated != pair_id);
+    }
+
+    /// Returns the ids of the pairs that ran into their band too many times in a row within the
+    /// block and aren't halted yet.
+    pub fn pairs_to_halt(&self) -> impl Iterator<Item = B256> + '_ {
+        self.band_violations
+            .iter()
+            .filter(|(pair_id, violations)| {
+                self.price_bands.halts(**violations) && !self.is_halted(**pair_id)
+            })
+            .map(|(pair_id, _)| *pair_id)
+    }
+
+    /// Halt trading on a pair, starting its count of price band violations over.
+    pub(crate) fn halt_pair(&mut self, pair_id: B256) -> Result<(), DexError> {
+        let pair = self.pair_mut(pair_id).ok_or(DexError::PairDoesNotExist)?;
+        pair.halted = true;
+        self.reset_band_violations(pair_id);
+        self.mark_pair_dirty(pair_id);
+        Ok(())
+    }
+
+    /// Resume trading on a halted pair, starting its count of price band violations over.
+    pub(crate) fn resume_pair(&mut self, pair_id: B256) -> Result<(), DexError> {
+        let pair = self.pair_mut(pair_id).ok_or(DexError::PairDoesNotExist)?;
+        pair.halted = false;
+        self.reset_band_violations(pair_id);
+        self.mark_pair_dirty(pair_id);
+        Ok(())
+    }
+
+    /// Apply fills against resting orders, updating their remaining amounts and the pair stats.
+    ///
+    /// Makers are credited from their escrow, less the maker fee of the pair, and orders that are
+    /// completely filled are removed with their leftover escrow refunded. A fill ends the run of
+    /// price band violations of its pair. Returns the fills of the resting orders.
+    pub(crate) fn apply_fills(&mut self, fills: &[Fill], taker: Address) -> Vec<OrderFill> {
+        let mut order_fills = Vec::with_capacity(fills.len());
+        for fill in fills {
+            let order_id = self.maker_id(fill);
+            if !self.orders.contains_key(&order_id) {
+                continue;
+            }
+            let Some(maker) = self.orders_mut().get_mut(&order_id) else {
+                continue;
+            };
+            maker.remaining = maker.remaining.saturating_sub(fill.base_amount);
+            let ((proceeds_token, proceeds), spent) = if maker.is_buy {
+                ((maker.token_in, fill.base_amount), fill.quote_amount)
+            } else {
+                ((maker.token_out, fill.quote_amount), fill.base_amount)
+            };
+            maker.escrow = maker.escrow.saturating_sub(spent);
+            let maker = maker.clone();
+            let fee = self.pair_fees(maker.pair_id).maker_fee(proceeds);
+            let proceeds = self.charge_fee(proceeds_token, proceeds, fee);
+            self.record_transfer(Transfer::push(proceeds_token, maker.owner, proceeds));
+            if maker.remaining.is_zero() {
+                self.remove_order(order_id);
+            }
+            self.mark_order_dirty(order_id);
+            order_fills.push(OrderFill {
+                order_id: order_id_to_b256(order_id),
+                maker: maker.owner,
+                pair_id: maker.pair_id,
+                base_amount: fill.base_amount,
+                quote_amount: fill.quote_amount,
+                remaining: maker.remaining,
+            });
+
+            let (block_number, timestamp) = (self.block_number, self.timestamp);
+            if let Some(pair) = self.pair_mut(maker.pair_id) {
+                let stats = &mut pair.stats;
+                pair.oracle
+                    .update(timestamp, (stats.last_price_num, stats.last_price_denom));
+                let (price_num, price_denom, volume0, volume1) = if maker.token_in == pair.token0 {
+                    (
+                        maker.price_num,
+                        maker.price_denom,
+                        fill.base_amount,
+                        fill.quote_amount,
+                    )
+                } else {
+                    (
+                        maker.price_denom,
+                        maker.price_num,
+                        fill.quote_amount,
+                        fill.base_amount,
+                    )
+                };
+                stats.last_price_num = price_num;
+                stats.last_price_denom = price_denom;
+                stats.trade_count += 1;
+                stats.volume0 = stats.volume0.saturating_add(volume0);
+                stats.volume1 = stats.volume1.saturating_add(volume1);
+                stats.record_trade(TradeSample {
+                    block_number,
+                    timestamp,
+                    order_id,
+                    maker: maker.owner,
+                    taker,
+                    price_num,
+                    price_denom,
+                    volume0,
+                    volume1,
+                });
+                self.reset_band_violations(maker.pair_id);
+                self.mark_pair_dirty(maker.pair_id);
+            }
+        }
+        order_fills
+    }
+
+    /// Clear the orders collected since the last batch auction.
+    ///
+    /// Every pair with collected orders holds an auction over all of its orders, including the
+    /// ones resting on the book. Looking at the orders in terms of `token0` of the pair, every
+    /// crossing order trades at the single price that trades the most, leaves the smallest
+    /// imbalance between demand and supply, and is the lowest, in that order. Better limits fill
+    /// first, and orders at the same limit in the order they were placed. Buyers of `token0` pay
+    /// the clearing price rounded up and sellers receive it rounded down, so the predeploy never
+    /// pays out more than it took in. Orders that were resting on the book are charged the maker
+    /// fee of the pair and collected orders the taker fee.
+    ///
+    /// Pairs that are halted, or whose clearing price lies outside of their price band, don't
+    /// hold an auction and keep their collected orders for the next one, the latter counting as
+    /// a price band violation. The remainders of the other collected orders rest on the book
+    /// afterwards. Returns the auctions of the pairs that traded.
+    pub(crate) fn clear_batch(&mut self) -> Result<Vec<BatchAuction>, DexError> {
+        let pair_ids: BTreeSet<B256> = self
+            .batch
+            .iter()
+            .filter_map(|order_id| self.orders.get(order_id))
+            .map(|order| order.pair_id)
+            .collect();
+        let mut auctions = Vec::new();
+        let mut held = BTreeSet::new();
+        for pair_id in pair_ids {
+            if self.is_halted(pair_id) {
+                held.insert(pair_id);
+                continue;
+            }
+            match self.auction_pair(pair_id) {
+                Ok(Some(auction)) => auctions.push(auction),
+                Ok(None) => {}
+                Err(DexError::PriceBandExceeded { pair_id }) => {
+                    // The auction is part of a committed transaction, so it halts the pair itself
+                    if self.record_band_violation(pair_id) {
+                        self.halt_pair(pair_id)?;
+                    }
+                    held.insert(pair_id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        // Collected orders and resting orders that were partially filled are off the book
+        let batch = self
+            .batch
+            .iter()
+            .filter(|order_id| {
+                self.orders
+                    .get(order_id)
+                    .is_some_and(|order| held.contains(&order.pair_id))
+            })
+            .copied()
+            .collect();
+        self.batch = Arc::new(batch);
+        let unplaced: Vec<u64> = self
+            .orders
+            .keys()
+            .filter(|order_id| {
+                !self.order_ids.book.contains_key(order_id) && !self.batch.contains(order_id)
+            })
+            .copied()
+            .collect();
+        for order_id in unplaced {
+            if let Some(order) = self.orders.get(&order_id).cloned() {
+                self.place_on_book(order_id, &order)?;
+            }
+        }
+        Ok(auctions)
+    }
+
+    /// Hold the batch auction of a pair, or return `None` if none of its orders cross.
+    ///
+    /// Fails without trading if the clearing price lies outside of the price band of the pair.
+    fn auction_pair(&mut self, pair_id: B256) -> Result<Option<BatchAuction>, DexError> {
+        let Some((token0, reference)) = self.pairs.get(&pair_id).map(|pair| {
+            let stats = &pair.stats;
+            (pair.token0, (stats.last_price_num, stats.last_price_denom))
+        }) else {
+            return Ok(None);
+        };
+        let (mut bids, mut asks) = (Vec::new(), Vec::new());
+        let mut owners = BTreeMap::new();
+        for (order_id, order) in self.orders.iter().filter(|(_, o)| o.pair_id == pair_id) {
+            let (level, is_bid) = book_level(order, token0);
+            owners.insert(*order_id, order.owner);
+            if is_bid {
+                bids.push((*order_id, level));
+            } else {
+                asks.push((*order_id, level));
+            }
+        }
+        self.add_work(DexWork {
+            orders_read: owners.len() as u64,
+            ..Default::default()
+        });
+
+        bids.sort_by(|(a_id, a), (b_id, b)| b.cmp_price(a).then(a_id.cmp(b_id)));
+        asks.sort_by(|(a_id, a), (b_id, b)| a.cmp_price(b).then(a_id.cmp(b_id)));
+        let Some(price) = clearing_price(&bids, &asks) else {
+            return Ok(None);
+        };
+        if !self
+            .price_bands
+            .contains(reference, (price.price_num, price.price_denom))
+        {
+            return Err(DexError::PriceBandExceeded { pair_id });
+        }
+        let bid_fills = allocate(&bids, price.amount);
+        let ask_fills = allocate(&asks, price.amount);
+
+        // Every unit of `token0` bought was sold by someone, which makes up the trades
+        let value = |amount0: U256| {
+            amount0.saturating_mul(U256::from(price.price_num)) / U256::from(price.price_denom)
+        };
+        let mut trades = Vec::new();
+        let (mut bid_iter, mut ask_iter) = (bid_fills.iter(), ask_fills.iter());
+        let (mut bid, mut ask) = (bid_iter.next().copied(), ask_iter.next().copied());
+        while let (Some((bid_id, bid_left, _)), Some((ask_id, ask_left, _))) = (bid, ask) {
+            let volume0 = bid_left.min(ask_left);
+            // The order placed first is the maker of the trade
+            let (maker_id, taker_id) = (bid_id.min(ask_id), bid_id.max(ask_id));
+            trades.push(TradeSample {
+                block_number: self.block_number,
+                timestamp: self.timestamp,
+                order_id: maker_id,
+                maker: owners[&maker_id],
+                taker: owners[&taker_id],
+                price_num: price.price_num,
+                price_denom: price.price_denom,
+                volume0,
+                volume1: value(volume0),
+            });
+            bid = if bid_left == volume0 {
+                bid_iter.next().copied()
+            } else {
+                Some((bid_id, bid_left - volume0, false))
+            };
+            ask = if ask_left == volume0 {
+                ask_iter.next().copied()
+            } else {
+                Some((ask_id, ask_left - volume0, false))
+            };
+        }
+        let volume1 = trades
+            .iter()
+            .fold(U256::ZERO, |acc, trade| acc.saturating_add(trade.volume1));
+        let timestamp = self.timestamp;
+        if let Some(pair) = self.pair_mut(pair_id) {
+            let stats = &mut pair.stats;
+            pair.oracle
+                .update(timestamp, (stats.last_price_num, stats.last_price_denom));
+            stats.last_price_num = price.price_num;
+            stats.last_price_denom = price.price_denom;
+            stats.trade_count += trades.len() as u64;
+            stats.volume0 = stats.volume0.saturating_add(price.amount);
+            stats.volume1 = stats.volume1.saturating_add(volume1);
+            for trade in trades {
+                stats.record_trade(trade);
+            }
+            self.reset_band_violations(pair_id);
+            self.mark_pair_dirty(pair_id);
+        }
+
+        let mut fills = Vec::with_capacity(bid_fills.len() + ask_fills.len());
+        let sides = bid_fills
+            .iter()
+            .map(|fill| (true, fill))
+            .chain(ask_fills.iter().map(|fill| (false, fill)));
+        for (is_bid, &(order_id, amount0, complete)) in sides {
+            let amount1 = if is_bid {
+                amount0
+                    .saturating_mul(U256::from(price.price_num))
+                    .div_ceil(U256::from(price.price_denom))
+            } else {
+                value(amount0)
+            };
+            let fill =
+                self.fill_batch_order(order_id, token0, is_bid, amount0, amount1, complete)?;
+            fills.push(fill);
+        }
+        fills.sort_by_key(|fill| fill.order_id);
+        self.add_work(DexWork {
+            orders_filled: fills.len() as u64,
+            hops: 1,
+            ..Default::default()
+        });
+
+        Ok(Some(BatchAuction {
+            pair_id,
+            price_num: price.price_num,
+            price_denom: price.price_denom,
+            volume0: price.amount,
+            volume1,
+            fills,
+        }))
+    }
+
+    /// Fill an order in a batch auction, exchanging `amount0` of `token0` for `amount1` of the
+    /// other token of the pair.
+    ///
+    /// The order is taken off the book, and removed with its leftover escrow refunded if
+    /// `complete`.
+    fn fill_batch_order(
+        &mut self,
+        order_id: u64,
+        token0: Address,
+        is_bid: bool,
+        amount0: U256,
+        amount1: U256,
+        complete: bool,
+    ) -> Result<BatchFill, DexError> {
+        if !self.orders.contains_key(&order_id) {
+            return Err(DexError::OrderNotFound);
+        }
+        let order = self
+            .orders_mut()
+            .get_mut(&order_id)
+            .ok_or(DexError::OrderNotFound)?;
+        let (base_amount, quote_amount) = if order.token_in == token0 {
+            (amount0, amount1)
+        } else {
+            (amount1, amount0)
+        };
+        // Bids pay in the other token for `token0`, asks the other way around
+        let (paid, received) = if is_bid {
+            (amount1, amount0)
+        } else {
+            (amount0, amount1)
+        };
+        order.remaining = if complete {
+            U256::ZERO
+        } else {
+            order.remaining.saturating_sub(base_amount)
+        };
+        order.escrow = order.escrow.saturating_sub(paid);
+        let order = order.clone();
+        let proceeds_token = if order.is_buy {
+            order.token_in
+        } else {
+            order.token_out
+        };
+        let fees = self.pair_fees(order.pair_id);
+        let fee = if self.batch.contains(&order_id) {
+            fees.taker_fee(received)
+        } else {
+            fees.maker_fee(received)
+        };
+        let received = self.charge_fee(proceeds_token, received, fee);
+        self.record_transfer(Transfer::push(proceeds_token, order.owner, received));
+        self.mark_order_dirty(order_id);
+
+        if let Some(book_id) = self.order_ids_mut().unlink(order_id) {
+            self.pool_manager_mut()
+                .cancel_order(order.token_in, order.token_out, book_id)?;
+        }
+        if order.remaining.is_zero() {
+            self.remove_order(order_id);
+        }
+        Ok(BatchFill {
+            order_id: order_id_to_b256(order_id),
+            trader: order.owner,
+            base_amount,
+            quote_amount,
+            remaining: order.remaining,
+        })
+    }
+
+    /// Use up the nonce of a signed order of `maker`, which has to be greater than the last one.
+    pub(crate) fn use_nonce(&mut self, maker: Address, nonce: u64) -> Result<(), DexError> {
+        let last = self.nonce(maker);
+        if nonce <= last {
+            return Err(DexError::NonceTooLow { nonce, last });
+        }
+        let entries = self.nonces.len();
+        unshare(&mut self.nonces, entries, &mut self.work).insert(maker, nonce);
+        if self.dirty_nonces.insert(maker) {
+            self.work.slots_written += 1;
+        }
+        Ok(())
+    }
+
+    /// Mark a pair for being written to storage, accounting for the slots to write once.
+    fn mark_pair_dirty(&mut self, pair_id: B256) {
+        if self.dirty_pairs.insert(pair_id) {
+            self.work.slots_written += storage::PAIR_FIELDS as u64;
+        }
+    }
+
+    /// Mark an order for being written to storage, accounting for the slots to write once.
+    fn mark_order_dirty(&mut self, order_id: u64) {
+        if self.dirty_orders.insert(order_id) {
+            self.work.slots_written += storage::ORDER_FIELDS as u64;
+        }
+    }
+
+    /// Account for work done by an operation.
+    pub(crate) fn add_work(&mut self, work: DexWork) {
+        self.work.add(work);
+    }
+
+    /// Account for reading every resting order.
+    pub(crate) fn add_order_scan(&mut self) {
+        self.work.orders_read = self
+            .work
+            .orders_read
+            .saturating_add(self.orders.len() as u64);
+    }
+
+    /// Returns the work done since the last call.
+    pub(crate) fn take_work(&mut self) -> DexWork {
+        std::mem::take(&mut self.work)
+    }
+
+    /// Returns the token movements recorded since the last call.
+    pub(crate) fn take_transfers(&mut self) -> Vec<Transfer> {
+        std::mem::take(&mut self.transfers)
+    }
+
+    /// Returns the storage slots of the predeploy that changed since the last call, and marks the
+    /// state as written out.
+    pub(crate) fn take_storage_changes(&mut self) -> Vec<(U256, U256)> {
+        let pairs = std::mem::take(&mut self.dirty_pairs)
+            .into_iter()
+            .flat_map(|pair_id| storage::encode_pair(pair_id, self.pair(pair_id)));
+        let orders = std::mem::take(&mut self.dirty_orders)
+            .into_iter()
+            .flat_map(|order_id| storage::encode_order(order_id, self.orders.get(&order_id)));
+        let nonces = std::mem::take(&mut self.dirty_nonces)
+            .into_iter()
+            .map(|maker| storage::encode_nonce(maker, self.nonce(maker)));
+        pairs.chain(orders).chain(nonces).collect()
+    }
+}
+
+/// Returns a part of the state shared between clones for writing, copying it first if another
+/// clone still shares it. Copying counts as work, by the `entries` the part holds.
+fn unshare<'a, T: Clone>(part: &'a mut Arc<T>, entries: usize, work: &mut DexWork) -> &'a mut T {
+    if Arc::get_mut(part).is_none() {
+        work.entries_copied = work.entries_copied.saturating_add(entries as u64);
+    }
+    Arc::make_mut(part)
+}
+
+/// Returns the price level a resting order adds to the book of `base`, and whether it's a bid.
+fn book_level(order: &OrderRecord, base: Address) -> (BookLevel, bool) {
+    if order.token_in == base {
+        let level = BookLevel {
+            price_num: order.price_num,
+            price_denom: order.price_denom,
+            amount: order.remaining,
+        };
+        (level, order.is_buy)
+    } else {
+        let level = BookLevel {
+            price_num: order.price_denom,
+            price_denom: order.price_num,
+            amount: order.remaining.saturating_mul(U256::from(order.price_num))
+                / U256::from(order.price_denom),
+        };
+        (level, !order.is_buy)
+    }
+}
+
+/// Returns the price a batch auction clears at, with the amount of the base token traded at it as
+/// its amount, or `None` if the orders don't cross.
+///
+/// Only the limit prices of the orders are candidates. The one trading the most wins, ties going
+/// to the smallest imbalance between demand and supply and then to the lowest price.
+fn clearing_price(bids: &[(u64, BookLevel)], asks: &[(u64, BookLevel)]) -> Option<BookLevel> {
+    let mut best: Option<(BookLevel, U256)> = None;
+    for (_, candidate) in bids.iter().chain(asks) {
+        let demand = bids
+            .iter()
+            .filter(|(_, bid)| bid.cmp_price(candidate).is_ge())
+            .fold(U256::ZERO, |acc, (_, bid)| acc.saturating_add(bid.amount));
+        let supply = asks
+            .iter()
+            .filter(|(_, ask)| ask.cmp_price(candidate).is_le())
+            .fold(U256::ZERO, |acc, (_, ask)| acc.saturating_add(ask.amount));
+        let volume = demand.min(supply);
+        let imbalance = demand.max(supply) - volume;
+        if volume.is_zero() {
+            continue;
+        }
+        let better = best.as_ref().is_none_or(|(best, best_imbalance)| {
+            volume
+                .cmp(&best.amount)
+                .then(best_imbalance.cmp(&imbalance))
+                .then(best.cmp_price(candidate))
+                .is_gt()
+        });
+        if better {
+            let price = BookLevel {
+                amount: volume,
+                ..*candidate
+            };
+            best = Some((price, imbalance));
+        }
+    }
+    best.map(|(price, _)| price)
+}
+
+/// Hand out `volume` of the base token to orders sorted by priority, returning the id of each
+/// order that fills, the amount it fills and whether that is all of it.
+fn allocate(orders: &[(u64, BookLevel)], volume: U256) -> Vec<(u64, U256, bool)> {
+    let mut left = volume;
+    let mut fills = Vec::new();
+    for (order_id, level) in orders {
+        if left.is_zero() {
+            break;
+        }
+        let amount = level.amount.min(left);
+        if amount.is_zero() {
+            continue;
+        }
+        left -= amount;
+        fills.push((*order_id, amount, amount == level.amount));
+    }
+    fills
+}
+
+/// Sort bids from the highest price down and asks from the lowest price up, and merge them into
+/// at most `levels` levels each.
+fn sort_book(
+    mut bids: Vec<BookLevel>,
+    mut asks: Vec<BookLevel>,
+    levels: usize,
+) -> (Vec<BookLevel>, Vec<BookLevel>) {
+    bids.sort_by(|a, b| b.cmp_price(a));
+    asks.sort_by(BookLevel::cmp_price);
+    (merge_levels(bids, levels), merge_levels(asks, levels))
+}
+
+/// Merge adjacent levels of equal price and keep the first `levels` of them.
+fn merge_levels(sorted: Vec<BookLevel>, levels: usize) -> Vec<BookLevel> {
+    let mut merged: Vec<BookLevel> = Vec::new();
+    for level in sorted {
+        match merged.last_mut() {
+            Some(last) if last.cmp_price(&level).is_eq() => {
+                last.amount = last.amount.saturating_add(level.amount);
+            }
+            _ if merged.len() == levels => break,
+            _ => merged.push(level),
+        }
+    }
+    merged
+}
+
+impl Default for DexState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for DexState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DexState")
+            .field("pairs", &self.pairs.len())
+            .field("orders", &self.orders.len())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,215 @@
+//! Storage layout of the enshrined DEX predeploy.
+//!
+//! The order book is executed in memory, but everything it holds is mirrored into the storage of
+//! the predeploy, [`DEX_PREDEPLOY_ADDRESS`](super::DEX_PREDEPLOY_ADDRESS) unless the DEX is
+//! installed elsewhere, so that DEX effects are part of the state root and can be read by
+//! validators and RPC nodes.
+//!
+//! Entries of the `pairs`, `orders` and `nonces` mappings are found like Solidity mapping entries,
+//! at `keccak256(key . base_slot)`. Beyond that the layout is not Solidity's: nothing is packed,
+//! every field of a pair or order takes a full slot of its own at the offset below from the first
+//! slot of the entry, and addresses, flags and small integers are stored right-aligned in it.
+//!
+//! ```text
+//! pairs[pairId] (PAIR_FIELDS slots)     orders[orderId] (ORDER_FIELDS slots)
+//!  0 token0                              0 owner
+//!  1 token1                              1 tokenIn
+//!  2 tradeCount                          2 tokenOut
+//!  3 volume0                             3 isBuy
+//!  4 volume1                             4 priceNum
+//!  5 lastPriceNum    token1 per token0   5 priceDenom
+//!  6 lastPriceDenom                      6 remaining
+//!  7 halted          until resumed       7 escrow           tokenIn for sells, tokenOut for buys
+//!  8 price0Cumulative  scaled by 1e18    8 expiryBlock      zero if the order doesn't expire
+//!  9 price1Cumulative  summed per second 9 expiryFlashblockIndex
+//! 10 priceTimestamp
+//! 11 decimals0
+//! 12 decimals1
+//! 13 symbol0         left-aligned, zero padded
+//! 14 symbol1
+//! 15 tickSize        token1 per token0, scaled by 1e18
+//! 16 lotSize         in token0
+//!
+//! slot 0  pairs       bytes32 pairId => Pair
+//! slot 1  orders      uint256 orderId => Order
+//! slot 2  nonces      address maker => last nonce used by a signed order
+//! slot 3  operations  state changing operations executed
+//! ```
+//!
+//! Filled, cancelled or expired orders are zeroed out. The operations counter is what the
+//! [precompile](super::precompile) tells reverted operations by.
+
+use super::state::{OrderRecord, PairRecord};
+use alloy_primitives::{Address, B256, U256, keccak256};
+
+/// Base slot of the `pairs` mapping
+pub const PAIRS_SLOT: U256 = U256::ZERO;
+
+/// Base slot of the `orders` mapping
+pub const ORDERS_SLOT: U256 = U256::from_limbs([1, 0, 0, 0]);
+
+/// Base slot of the `nonces` mapping
+pub const NONCES_SLOT: U256 = U256::from_limbs([2, 0, 0, 0]);
+
+/// Slot of the number of state changing operations executed
+pub const OPERATIONS_SLOT: U256 = U256::from_limbs([3, 0, 0, 0]);
+
+/// Number of slots occupied by a `Pair`
+pub(super) const PAIR_FIELDS: usize = 17;
+
+/// Number of slots occupied by an `Order`
+pub(super) const ORDER_FIELDS: usize = 10;
+
+/// Returns the first slot of the `Pair` stored under `pair_id`.
+pub fn pair_slot(pair_id: B256) -> U256 {
+    mapping_slot(pair_id, PAIRS_SLOT)
+}
+
+/// Returns the first slot of the `Order` stored under `order_id`.
+pub fn order_slot(order_id: u64) -> U256 {
+    mapping_slot(U256::from(order_id).into(), ORDERS_SLOT)
+}
+
+/// Returns the slot of the last nonce `maker` used for a signed order.
+pub fn nonce_slot(maker: Address) -> U256 {
+    mapping_slot(maker.into_word(), NONCES_SLOT)
+}
+
+fn mapping_slot(key: B256, base_slot: U256) -> U256 {
+    let mut preimage = [0u8; 64];
+    preimage[..32].copy_from_slice(key.as_slice());
+    preimage[32..].copy_from_slice(&base_slot.to_be_bytes::<32>());
+    keccak256(preimage).into()
+}
+
+fn address_word(address: Address) -> U256 {
+    U256::from_be_slice(address.as_slice())
+}
+
+/// Encode a symbol like a Solidity `bytes32` string, truncated to [`MAX_SYMBOL_LEN`] bytes.
+///
+/// [`MAX_SYMBOL_LEN`]: super::listing::MAX_SYMBOL_LEN
+fn symbol_word(symbol: &str) -> U256 {
+    let mut word = B256::ZERO;
+    let len = symbol.len().min(B256::len_bytes());
+    word[..len].copy_from_slice(&symbol.as_bytes()[..len]);
+    word.into()
+}
+
+/// Encode a pair into its storage slots, or zero slots if the pair is gone.
+pub(super) fn encode_pair(pair_id: B256, pair: Option<&PairRecord>) -> Vec<(U256, U256)> {
+    let values = pair.map_or([U256::ZERO; PAIR_FIELDS], |pair| {
+        let latest = pair.oracle.latest().copied().unwrap_or_default();
+        [
+            address_word(pair.token0),
+            address_word(pair.token1),
+            U256::from(pair.stats.trade_count),
+            pair.stats.volume0,
+            pair.stats.volume1,
+            U256::from(pair.stats.last_price_num),
+            U256::from(pair.stats.last_price_denom),
+            U256::from(pair.halted),
+            latest.price0_cumulative,
+            latest.price1_cumulative,
+            U256::from(latest.timestamp),
+            U256::from(pair.spec.decimals0),
+            U256::from(pair.spec.decimals1),
+            symbol_word(&pair.spec.symbol0),
+            symbol_word(&pair.spec.symbol1),
+            pair.spec.tick_size,
+            pair.spec.lot_size,
+        ]
+    });
+    with_slots(pair_slot(pair_id), values)
+}
+
+/// Encode a resting order into its storage slots, or zero slots if the order is gone.
+pub(super) fn encode_order(order_id: u64, order: Option<&OrderRecord>) -> Vec<(U256, U256)> {
+    let values = order.map_or([U256::ZERO; ORDER_FIELDS], |order| {
+        [
+            address_word(order.owner),
+            address_word(order.token_in),
+            address_word(order.token_out),
+            U256::from(order.is_buy),
+            U256::from(order.price_num),
+            U256::from(order.price_denom),
+            order.remaining,
+            order.escrow,
+            U256::from(order.expiry.map_or(0, |expiry| expiry.block_number)),
+            U256::from(order.expiry.map_or(0, |expiry| expiry.flashblock_index)),
+        ]
+    });
+    with_slots(order_slot(order_id), values)
+}
+
+/// Encode the last nonce `maker` used for a signed order into its storage slot.
+pub(super) fn encode_nonce(maker: Address, nonce: u64) -> (U256, U256) {
+    (nonce_slot(maker), U256::from(nonce))
+}
+
+fn with_slots<const N: usize>(base: U256, values: [U256; N]) -> Vec<(U256, U256)> {
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(offset, value)| (base + U256::from(offset), value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dex::{listing::PairSpec, oracle::PriceOracle, state::PairStats};
+    use alloy_primitives::{address, b256};
+
+    #[test]
+    fn test_mapping_slots_are_keccak_of_key_and_base_slot() {
+        // keccak256(abi.encode(uint256(1), uint256(1)))
+        assert_eq!(
+            order_slot(1),
+            U256::from(b256!(
+                "cc69885fda6bcc1a4ace058b4a62bf5e179ea78fd58a1ccd71c22cc9b688792f"
+            ))
+        );
+        assert_ne!(pair_slot(B256::with_last_byte(1)), order_slot(1));
+    }
+
+    #[test]
+    fn test_pair_fields_take_a_slot_each() {
+        let pair_id = B256::with_last_byte(7);
+        let pair = PairRecord {
+            token0: address!("0000000000000000000000000000000000000001"),
+            token1: address!("0000000000000000000000000000000000000002"),
+            spec: PairSpec {
+                symbol0: "WETH".to_string(),
+                ..Default::default()
+            },
+            halted: false,
+            stats: PairStats::default(),
+            oracle: PriceOracle::default(),
+        };
+
+        let slots = encode_pair(pair_id, Some(&pair));
+        assert_eq!(
+            slots[1],
+            (
+                pair_slot(pair_id) + U256::from(1),
+                address_word(pair.token1)
+            )
+        );
+        // bytes32("WETH")
+        assert_eq!(
+            slots[13],
+            (
+                pair_slot(pair_id) + U256::from(13),
+                U256::from(b256!(
+                    "5745544800000000000000000000000000000000000000000000000000000000"
+                ))
+            )
+        );
+
+        // removing the pair zeroes its slots
+        let slots = encode_pair(pair_id, None);
+        assert_eq!(slots[0].0, pair_slot(pair_id));
+        assert!(slots.iter().all(|(_, value)| value.is_zero()));
+    }
+}
@@ -1,5 +1,157 @@
 /// Type conversions between op-rbuilder types and enshrined-dex types
 use alloy_primitives::{Address, B256, U256};
+use alloy_sol_types::sol;
+
+sol! {
+    /// Liquidity resting at one price of an order book, as returned by `getOrderbookDepth`
+    #[derive(Debug, PartialEq, Eq)]
+    struct DepthLevel {
+        uint256 priceNum;
+        uint256 priceDenom;
+        uint256 amount;
+    }
+
+    /// A resting order, as returned by `getOrder` and `getUserOrders`
+    #[derive(Debug, PartialEq, Eq)]
+    struct OrderInfo {
+        bytes32 orderId;
+        address owner;
+        address tokenIn;
+        address tokenOut;
+        bool isBuy;
+        uint256 priceNum;
+        uint256 priceDenom;
+        uint256 remaining;
+        uint256 escrow;
+    }
+
+    /// A limit order signed by its maker with EIP-712, see [`signed`](super::signed).
+    /// The order expires at flashblock `expiryFlashblockIndex` of block `expiryBlock`, or never
+    /// if `expiryBlock` is zero
+    #[derive(Debug, PartialEq, Eq)]
+    struct SignedOrder {
+        address maker;
+        address tokenIn;
+        address tokenOut;
+        bool isBuy;
+        uint256 amount;
+        uint256 priceNum;
+        uint256 priceDenom;
+        uint64 expiryBlock;
+        uint64 expiryFlashblockIndex;
+        uint64 nonce;
+    }
+
+    /// A pair along with the metadata it was listed with, as passed to `listPair` and returned
+    /// by `getPairListing`. See [`listing`](super::listing)
+    #[derive(Debug, PartialEq, Eq)]
+    struct PairListing {
+        address token0;
+        address token1;
+        uint8 decimals0;
+        uint8 decimals1;
+        string symbol0;
+        string symbol1;
+        uint256 tickSize;
+        uint256 lotSize;
+    }
+
+    /// Events emitted by the DEX predeploy
+    interface IDex {
+        event PairCreated(address indexed token0, address indexed token1, bytes32 indexed pairId);
+
+        event LimitOrderPlaced(
+            bytes32 indexed orderId,
+            address indexed trader,
+            address indexed tokenIn,
+            address tokenOut,
+            bool isBuy,
+            uint256 amount,
+            uint256 priceNum,
+            uint256 priceDenom
+        );
+
+        /// Emitted for immediate-or-cancel and fill-or-kill orders, which never rest on the book.
+        /// `amount - filled` of `tokenIn` was left unfilled and its escrow refunded
+        event ImmediateOrderExecuted(
+            bytes32 indexed orderId,
+            address indexed trader,
+            address indexed tokenIn,
+            address tokenOut,
+            bool isBuy,
+            uint256 amount,
+            uint256 filled,
+            uint256 priceNum,
+            uint256 priceDenom
+        );
+
+        event OrderCancelled(bytes32 indexed orderId, address indexed owner);
+
+        /// Emitted for every resting order swept from the book once it expired, its leftover
+        /// escrow was refunded to `owner`
+        event OrderExpired(bytes32 indexed orderId, address indexed owner);
+
+        event Swap(
+            address indexed trader,
+            address indexed tokenIn,
+            address indexed tokenOut,
+            uint256 amountIn,
+            uint256 amountOut,
+            bytes32[] route
+        );
+
+        /// Emitted for every fill of a resting order, `baseAmount` of the order's `tokenIn` was
+        /// exchanged for `quoteAmount` of its `tokenOut`
+        event OrderFilled(
+            bytes32 indexed orderId,
+            address indexed maker,
+            address indexed taker,
+            uint256 baseAmount,
+            uint256 quoteAmount,
+            uint256 remaining
+        );
+
+        /// Emitted for every order filled in a batch auction, `baseAmount` of the order's
+        /// `tokenIn` was exchanged for `quoteAmount` of its `tokenOut` at the clearing price
+        event BatchFilled(
+            bytes32 indexed orderId,
+            address indexed trader,
+            bytes32 indexed pairId,
+            uint256 baseAmount,
+            uint256 quoteAmount,
+            uint256 remaining
+        );
+
+        /// Emitted for every pair cleared in a batch auction, after its `BatchFilled` events.
+        /// The clearing price is in `token1` per `token0` of the pair
+        event BatchCleared(
+            bytes32 indexed pairId,
+            uint256 priceNum,
+            uint256 priceDenom,
+            uint256 volume0,
+            uint256 volume1
+        );
+
+        /// Emitted at the end of every operation that charged fees, for each token it charged
+        /// fees in, after the operation's own events
+        event FeesCollected(address indexed token, address indexed recipient, uint256 amount);
+
+        /// Emitted for every signed order placed on behalf of its maker, after the events of
+        /// placing it. `orderHash` is the EIP-712 hash the maker signed
+        event SignedOrderPlaced(
+            bytes32 indexed orderId,
+            address indexed maker,
+            bytes32 orderHash,
+            uint64 nonce
+        );
+
+        /// Emitted when the builder halts trading on a pair whose circuit breaker tripped
+        event PairHalted(bytes32 indexed pairId);
+
+        /// Emitted when the admin resumes trading on a pair halted by its circuit breaker
+        event PairResumed(bytes32 indexed pairId, address indexed admin);
+    }
+}
 
 /// Convert between alloy U256 and enshrined-dex Amount
 pub fn to_dex_amount(amount: U256) -> U256 {
@@ -11,6 +163,55 @@ pub fn to_token_id(addr: Address) -> Address {
     addr
 }
 
+/// A fill of a resting order by an incoming order or swap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderFill {
+    /// Id of the resting order
+    pub order_id: B256,
+    /// Account that placed the resting order
+    pub maker: Address,
+    /// Pair the resting order was placed on
+    pub pair_id: B256,
+    /// Amount of the resting order's `token_in` that was filled
+    pub base_amount: U256,
+    /// Amount of the resting order's `token_out` it was exchanged for
+    pub quote_amount: U256,
+    /// Amount of the resting order that is still open after the fill
+    pub remaining: U256,
+}
+
+/// An order filled in a batch auction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchFill {
+    /// Id of the order
+    pub order_id: B256,
+    /// Account that placed the order
+    pub trader: Address,
+    /// Amount of the order's `token_in` that was filled
+    pub base_amount: U256,
+    /// Amount of the order's `token_out` it was exchanged for
+    pub quote_amount: U256,
+    /// Amount of the order that is still open after the auction
+    pub remaining: U256,
+}
+
+/// The batch auction of a pair, clearing all its crossing orders at a single price
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchAuction {
+    /// Pair the auction was held for
+    pub pair_id: B256,
+    /// Numerator of the clearing price, in `token1` per `token0`
+    pub price_num: u128,
+    /// Denominator of the clearing price, in `token1` per `token0`
+    pub price_denom: u128,
+    /// Amount of `token0` traded
+    pub volume0: U256,
+    /// Amount of `token1` traded
+    pub volume1: U256,
+    /// Orders filled in the auction, by order id
+    pub fills: Vec<BatchFill>,
+}
+
 /// Result of a DEX operation
 #[derive(Debug, Clone)]
 pub enum DexResult {
@@ -26,10 +227,79 @@ pub enum DexResult {
         trader: Address,
         token_in: Address,
         token_out: Address,
+        is_buy: bool,
+        amount: U256,
+        price_num: U256,
+        price_denom: U256,
+        /// Resting orders the new order traded against
+        fills: Vec<OrderFill>,
+    },
+    /// Immediate-or-cancel order executed, the unfilled remainder was refunded instead of resting
+    ImmediateOrCancelExecuted {
+        order_id: B256,
+        trader: Address,
+        token_in: Address,
+        token_out: Address,
+        is_buy: bool,
+        amount: U256,
+        /// Amount of `token_in` that got filled
+        filled: U256,
+        price_num: U256,
+        price_denom: U256,
+        /// Resting orders the order traded against
+        fills: Vec<OrderFill>,
+    },
+    /// Fill-or-kill order executed in full
+    FillOrKillExecuted {
+        order_id: B256,
+        trader: Address,
+        token_in: Address,
+        token_out: Address,
+        is_buy: bool,
+        amount: U256,
+        price_num: U256,
+        price_denom: U256,
+        /// Resting orders the order traded against
+        fills: Vec<OrderFill>,
+    },
+    /// Post-only order placed on the book without trading
+    PostOnlyPlaced {
+        order_id: B256,
+        trader: Address,
+        token_in: Address,
+        token_out: Address,
+        is_buy: bool,
         amount: U256,
+        price_num: U256,
+        price_denom: U256,
+    },
+    /// Signed order placed on behalf of its maker
+    SignedOrderPlaced {
+        /// Account that signed the order
+        maker: Address,
+        /// EIP-712 hash of the order
+        order_hash: B256,
+        /// Nonce the order used up
+        nonce: u64,
+        /// Outcome of placing the order, a [`DexResult::OrderPlaced`]
+        placed: Box<DexResult>,
     },
     /// Order cancelled successfully
-    OrderCancelled { order_id: B256 },
+    OrderCancelled { order_id: B256, owner: Address },
+    /// Pair halted by the builder after running into its price band too often
+    PairHalted { pair_id: B256 },
+    /// Halted pair resumed by the admin
+    PairResumed { pair_id: B256, admin: Address },
+    /// Expired orders swept from the book
+    OrdersExpired {
+        /// Ids and owners of the swept orders, soonest expiry first
+        orders: Vec<(B256, Address)>,
+    },
+    /// Orders collected since the last batch auction cleared
+    BatchCleared {
+        /// Auctions of the pairs that traded
+        auctions: Vec<BatchAuction>,
+    },
     /// Swap executed successfully
     SwapExecuted {
         trader: Address,
@@ -37,12 +307,64 @@ pub enum DexResult {
         token_out: Address,
         amount_in: U256,
         amount_out: U256,
+        /// Pairs the swap traded through, in order
+        route: Vec<B256>,
+        /// Resting orders the swap traded against
+        fills: Vec<OrderFill>,
     },
     /// Quote result
     Quote { amount_out: U256, route: Vec<B256> },
+    /// Price levels of an order book
+    OrderbookDepth {
+        bids: Vec<DepthLevel>,
+        asks: Vec<DepthLevel>,
+    },
+    /// Trading statistics of a pair
+    PairStats {
+        last_price_num: U256,
+        last_price_denom: U256,
+        volume0_24h: U256,
+        volume1_24h: U256,
+        trade_count_24h: U256,
+        trade_count: U256,
+    },
+    /// Time-weighted average price of a pair
+    Twap { price_num: U256, price_denom: U256 },
+    /// Resting orders of an account
+    UserOrders { orders: Vec<OrderInfo> },
+    /// A single resting order
+    Order(OrderInfo),
+    /// A pair with its metadata
+    Listing(PairListing),
 }
 
 impl DexResult {
+    /// Returns the id of the order placed by the operation, if it placed one.
+    pub fn order_id(&self) -> Option<B256> {
+        match self {
+            DexResult::OrderPlaced { order_id, .. }
+            | DexResult::ImmediateOrCancelExecuted { order_id, .. }
+            | DexResult::FillOrKillExecuted { order_id, .. }
+            | DexResult::PostOnlyPlaced { order_id, .. } => Some(*order_id),
+            DexResult::SignedOrderPlaced { placed, .. } => placed.order_id(),
+            _ => None,
+        }
+    }
+
+    /// Whether the result is that of a view function, which leaves the state untouched.
+    pub fn is_view(&self) -> bool {
+        matches!(
+            self,
+            DexResult::Quote { .. }
+                | DexResult::OrderbookDepth { .. }
+                | DexResult::PairStats { .. }
+                | DexResult::Twap { .. }
+                | DexResult::UserOrders { .. }
+                | DexResult::Order(_)
+                | DexResult::Listing(_)
+        )
+    }
+
     /// Encode the result as return data for EVM
     pub fn encode(&self) -> Vec<u8> {
         use alloy_sol_types::SolValue;
@@ -52,22 +374,74 @@ impl DexResult {
                 // Return pair_id as bytes32
                 pair_id.abi_encode()
             }
-            DexResult::OrderPlaced { order_id, .. } => {
+            DexResult::OrderPlaced { order_id, .. }
+            | DexResult::FillOrKillExecuted { order_id, .. }
+            | DexResult::PostOnlyPlaced { order_id, .. } => {
                 // Return order_id as bytes32
                 order_id.abi_encode()
             }
-            DexResult::OrderCancelled { .. } => {
+            DexResult::ImmediateOrCancelExecuted {
+                order_id, filled, ..
+            } => {
+                // Return (bytes32 orderId, uint256 filled)
+                (*order_id, *filled).abi_encode_params()
+            }
+            DexResult::SignedOrderPlaced { placed, .. } => placed.encode(),
+            DexResult::OrderCancelled { .. }
+            | DexResult::PairHalted { .. }
+            | DexResult::PairResumed { .. } => {
                 // Return success (empty return data)
                 vec![]
             }
+            DexResult::OrdersExpired { orders } => {
+                // Return the number of swept orders as uint256
+                U256::from(orders.len()).abi_encode()
+            }
+            DexResult::BatchCleared { auctions } => {
+                // Return the number of pairs that traded as uint256
+                U256::from(auctions.len()).abi_encode()
+            }
             DexResult::SwapExecuted { amount_out, .. } => {
                 // Return amountOut as uint256
                 amount_out.abi_encode()
             }
             DexResult::Quote { amount_out, route } => {
                 // Return (uint256 amountOut, bytes32[] route)
-                (*amount_out, route.as_slice()).abi_encode()
+                (*amount_out, route.as_slice()).abi_encode_params()
+            }
+            DexResult::OrderbookDepth { bids, asks } => {
+                // Return (DepthLevel[] bids, DepthLevel[] asks)
+                (bids.as_slice(), asks.as_slice()).abi_encode_params()
+            }
+            DexResult::PairStats {
+                last_price_num,
+                last_price_denom,
+                volume0_24h,
+                volume1_24h,
+                trade_count_24h,
+                trade_count,
+            } => (
+                *last_price_num,
+                *last_price_denom,
+                *volume0_24h,
+                *volume1_24h,
+                *trade_count_24h,
+                *trade_count,
+            )
+                .abi_encode_params(),
+            DexResult::Twap {
+                price_num,
+                price_denom,
+            } => {
+                // Return (uint256 priceNum, uint256 priceDenom)
+                (*price_num, *price_denom).abi_encode_params()
             }
+            DexResult::UserOrders { orders } => {
+                // Return OrderInfo[]
+                orders.as_slice().abi_encode()
+            }
+            DexResult::Order(order) => order.abi_encode(),
+            DexResult::Listing(listing) => listing.abi_encode(),
         }
     }
 }
@@ -93,12 +467,54 @@ pub enum DexError {
     #[error("Invalid price")]
     InvalidPrice,
 
+    #[error("Fill-or-kill order could not be filled completely")]
+    NotFilled,
+
+    #[error("Post-only order would take liquidity")]
+    WouldTakeLiquidity,
+
     #[error("Order not found")]
     OrderNotFound,
 
+    #[error("Invalid order expiry")]
+    InvalidExpiry,
+
+    #[error("Flashblock {0} has not started yet")]
+    FlashblockNotStarted(u64),
+
+    #[error("Operation is not available in batch auction mode")]
+    UnavailableInBatchAuction,
+
     #[error("Unauthorized")]
     Unauthorized,
 
+    #[error("Fill outside of the price band of pair {pair_id}")]
+    PriceBandExceeded { pair_id: B256 },
+
+    #[error("Trading on the pair is halted")]
+    PairHalted,
+
+    #[error("The pair didn't run into its price band often enough to halt")]
+    HaltNotDue,
+
+    #[error("Price is not a multiple of the tick size of the pair")]
+    OffTickPrice,
+
+    #[error("Amount is not a multiple of the lot size of the pair")]
+    OddLotAmount,
+
+    #[error("Invalid token symbol")]
+    InvalidSymbol,
+
+    #[error("No price history covering a TWAP window of {0} seconds")]
+    TwapUnavailable(u64),
+
+    #[error("Invalid order signature")]
+    InvalidSignature,
+
+    #[error("Nonce {nonce} was already used, the last nonce of the maker is {last}")]
+    NonceTooLow { nonce: u64, last: u64 },
+
     #[error("Slippage exceeded")]
     SlippageExceeded,
 
@@ -108,6 +524,27 @@ pub enum DexError {
     #[error("No route found")]
     NoRouteFound,
 
+    #[error("Invalid ETH value")]
+    InvalidValue,
+
+    #[error("Delegated calls to the DEX are not supported")]
+    DelegateCall,
+
+    #[error("State changing calls to the DEX are not allowed in a static context")]
+    StaticCall,
+
+    #[error("Tokens may not call back into the DEX while it settles")]
+    ReentrantCall,
+
+    #[error("DEX operation could not be settled")]
+    Unsettled,
+
+    #[error("Invalid DEX state: {0}")]
+    InvalidState(String),
+
+    #[error("Settlement failed: {0}")]
+    SettlementFailed(#[from] super::settlement::SettlementError),
+
     #[error("DEX error: {0}")]
     DexLibraryError(String),
 }
@@ -131,3 +568,12 @@ impl From<dex::PoolError> for DexError {
         }
     }
 }
+
+impl DexError {
+    /// Encode the error as revert data, the way a contract reverting with a reason would
+    pub fn encode(&self) -> Vec<u8> {
+        use alloy_sol_types::SolError;
+
+        alloy_sol_types::Revert::from(self.to_string()).abi_encode()
+    }
+}
@@ -4,6 +4,10 @@ use reth_optimism_rpc::OpEthApiBuilder;
 use crate::{
     args::*,
     builders::{BuilderConfig, BuilderMode, FlashblocksBuilder, PayloadBuilder, StandardBuilder},
+    dex::{
+        api::{DexApiExt, DexApiServer},
+        rpc::{DexDebugApiServer, DexEthApiServer, DexRpcExt},
+    },
     metrics::{VERSION, record_flag_gauge_metrics},
     monitor_tx_pool::monitor_tx_pool,
     primitives::reth::engine_api_builder::OpEngineApiBuilder,
@@ -12,7 +16,10 @@ use crate::{
 };
 use core::fmt::Debug;
 use moka::future::Cache;
-use reth::builder::{NodeBuilder, WithLaunchContext};
+use reth::{
+    builder::{NodeBuilder, WithLaunchContext},
+    rpc::builder::RethRpcModule,
+};
 use reth_cli_commands::launcher::Launcher;
 use reth_db::mdbx::DatabaseEnv;
 use reth_optimism_chainspec::OpChainSpec;
@@ -105,6 +112,7 @@ where
 
         let da_config = builder_config.da_config.clone();
         let gas_limit_config = builder_config.gas_limit_config.clone();
+        let dex_journal = builder_config.dex_journal.clone();
         let rollup_args = builder_args.rollup_args;
         let op_node = OpNode::new(rollup_args.clone());
         let reverted_cache = Cache::builder().max_capacity(100).build();
@@ -164,6 +172,26 @@ where
                         .add_or_replace_configured(revert_protection_ext.into_rpc())?;
                 }
 
+                if builder_args.flashblocks.enabled && !builder_args.dex.disabled {
+                    let dex_api_ext = DexApiExt::new(ctx.provider().clone(), dex_journal.clone());
+                    ctx.modules.merge_configured(dex_api_ext.into_rpc())?;
+
+                    let dex_rpc_ext = DexRpcExt::new(
+                        ctx.provider().clone(),
+                        ctx.registry.eth_api().clone(),
+                        ctx.registry.debug_api(),
+                        ctx.node().evm_config().clone(),
+                        dex_journal,
+                    );
+
+                    ctx.modules.add_or_replace_if_module_configured(
+                        RethRpcModule::Debug,
+                        DexDebugApiServer::into_rpc(dex_rpc_ext.clone()),
+                    )?;
+                    ctx.modules
+                        .add_or_replace_configured(DexEthApiServer::into_rpc(dex_rpc_ext))?;
+                }
+
                 Ok(())
             })
             .on_node_started(move |ctx| {
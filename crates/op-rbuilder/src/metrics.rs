@@ -139,6 +139,12 @@ pub struct OpRBuilderMetrics {
     pub reverted_tx_gas_used: Histogram,
     /// Gas used by reverted transactions in the latest block
     pub payload_reverted_tx_gas_used: Gauge,
+    /// Number of DEX transactions that executed successfully
+    pub successful_dex_txs: Counter,
+    /// Number of DEX transactions that reverted
+    pub reverted_dex_txs: Counter,
+    /// Histogram of gas used by DEX transactions included in a block
+    pub dex_tx_gas_used: Histogram,
     /// Histogram of tx simulation duration
     pub tx_simulation_duration: Histogram,
     /// Byte size of transactions
@@ -22,6 +22,8 @@ pub enum TxnExecutionResult {
     Reverted,
     RevertedAndExcluded,
     MaxGasUsageExceeded,
+    DexSuccess,
+    DexReverted,
 }
 
 #[derive(Default, Debug)]
@@ -1,12 +1,22 @@
 use crate::{
-    dex::{predeploy::selectors, DEX_PREDEPLOY_ADDRESS},
-    tests::LocalInstance,
+    args::{DexArgs, OpRbuilderArgs},
+    dex::{
+        api::{RpcOrder, RpcOrderBook, RpcPair, RpcQuote, RpcSignedOrder},
+        predeploy::selectors,
+        signed, OrderInfo, SignedOrder, DEX_PREDEPLOY_ADDRESS,
+    },
+    tests::{
+        BlockTransactionsExt, ChainDriver, ChainDriverExt, Ipc, LocalInstance,
+        TransactionBuilderExt,
+    },
+    tx_signer::Signer,
 };
-use alloy_network::ReceiptResponse;
-use alloy_primitives::{address, Address, Bytes, U256};
+use alloy_network::{ReceiptResponse, TransactionBuilder};
+use alloy_primitives::{address, hex, Address, Bytes, B256, U256, U64};
 use alloy_provider::Provider;
 use alloy_sol_types::SolValue;
 use macros::rb_test;
+use op_alloy_rpc_types::OpTransactionRequest;
 use tracing::info;
 
 /// Integration test: Create a pair, add liquidity via limit orders, and execute a swap
@@ -16,7 +26,7 @@ async fn dex_create_pair_add_liquidity_and_swap(rbuilder: LocalInstance) -> eyre
 
     // Define tokens
     let eth = address!("0000000000000000000000000000000000000000");
-    let usdc = address!("0000000000000000000000000000000000000001");
+    let usdc = deploy_test_token(&driver).await?;
 
     info!("=== DEX Integration Test ===");
     info!("ETH: {:?}", eth);
@@ -70,10 +80,12 @@ async fn dex_create_pair_add_liquidity_and_swap(rbuilder: LocalInstance) -> eyre
         U256::from(1),                 // price denominator
     );
 
+    // The ETH being sold is escrowed by the predeploy
     let sell_order_tx = driver
         .create_transaction()
         .with_to(DEX_PREDEPLOY_ADDRESS)
         .with_input(sell_order_calldata)
+        .with_value(10u128.pow(18))
         .send()
         .await?;
 
@@ -108,6 +120,7 @@ async fn dex_create_pair_add_liquidity_and_swap(rbuilder: LocalInstance) -> eyre
         .create_transaction()
         .with_to(DEX_PREDEPLOY_ADDRESS)
         .with_input(sell_order_2_calldata)
+        .with_value(5 * 10u128.pow(17))
         .send()
         .await?;
 
@@ -125,6 +138,13 @@ async fn dex_create_pair_add_liquidity_and_swap(rbuilder: LocalInstance) -> eyre
     );
     info!("✓ Second sell limit order placed (0.5 ETH @ 2100 USDC)");
 
+    let escrowed = provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?;
+    assert_eq!(
+        escrowed,
+        U256::from(15 * 10u64.pow(17)),
+        "The predeploy should hold the escrow of both orders"
+    );
+
     // ============================================================================
     // Step 3: Get a quote before swapping
     // ============================================================================
@@ -155,7 +175,7 @@ async fn dex_create_pair_add_liquidity_and_swap(rbuilder: LocalInstance) -> eyre
     info!("✓ Quote obtained successfully");
 
     // ============================================================================
-    // Step 4: Execute a swap (buy ETH with USDC)
+    // Step 4: Try to swap USDC the trader doesn't have for ETH
     // ============================================================================
     info!("\n[4/4] Executing swap: 100 USDC → ETH...");
 
@@ -184,22 +204,892 @@ async fn dex_create_pair_add_liquidity_and_swap(rbuilder: LocalInstance) -> eyre
         .await?
         .expect("Swap receipt should exist");
 
-    assert!(swap_receipt.status(), "Swap should succeed");
-    info!("✓ Swap executed successfully!");
-
-    // Verify events were emitted
-    assert!(!swap_receipt.inner.logs().is_empty(), "Swap should emit events");
-    info!("Events emitted: {}", swap_receipt.inner.logs().len());
+    // The trader can't pay with USDC, so settlement fails and the whole swap is reverted
+    assert!(
+        !swap_receipt.status(),
+        "Swap without USDC to pay with should revert"
+    );
+    assert!(swap_receipt.inner.logs().is_empty(), "Reverted swap should not emit events");
+    assert_eq!(
+        provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?,
+        escrowed,
+        "Escrow should be untouched by the reverted swap"
+    );
+    info!("✓ Unfunded swap reverted atomically");
 
     info!("\n=== Test Completed Successfully ===");
     info!("✓ Created trading pair");
     info!("✓ Placed 2 limit orders for liquidity");
     info!("✓ Got quote");
-    info!("✓ Executed swap");
+    info!("✓ Reverted unfunded swap");
+
+    Ok(())
+}
+
+/// DEX transactions pay for their nonce, gas and L1 data like any other transaction, and a
+/// failing DEX operation refunds the attached value
+#[rb_test(flashblocks)]
+async fn dex_transactions_are_charged_like_evm_transactions(
+    rbuilder: LocalInstance,
+) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let trader = driver
+        .fund_accounts(1, 10_000_000_000_000_000_000u128)
+        .await?
+        .remove(0);
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = address!("0000000000000000000000000000000000000001");
+    let balance_before = provider.get_balance(trader.address).await?;
+
+    // There is no ETH/USDC pair yet, so the swap fails
+    let swap_tx = driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_swap(eth, usdc, U256::from(10u64.pow(18)), U256::ZERO))
+        .with_value(10u128.pow(18))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let receipt = provider
+        .get_transaction_receipt(*swap_tx.tx_hash())
+        .await?
+        .expect("Swap receipt should exist");
+    assert!(!receipt.status(), "Swap without a pair should revert");
+    assert_eq!(provider.get_transaction_count(trader.address).await?, 1);
+
+    let fees = U256::from(receipt.gas_used()) * U256::from(receipt.effective_gas_price())
+        + U256::from(receipt.l1_block_info.l1_fee.unwrap_or_default());
+    assert!(!fees.is_zero(), "DEX transactions should be charged fees");
+    assert_eq!(
+        provider.get_balance(trader.address).await?,
+        balance_before - fees,
+        "Only the fees should be deducted, the value should be refunded"
+    );
+    assert_eq!(provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?, U256::ZERO);
+
+    // The same transaction can't be included again
+    let block = driver.build_new_block_with_current_timestamp(None).await?;
+    assert!(
+        !block.includes(swap_tx.tx_hash()),
+        "Executed DEX transaction should not be included twice"
+    );
+
+    Ok(())
+}
+
+/// DEX transactions are subject to the same per-transaction gas limit as EVM transactions, and
+/// a transaction that doesn't make it into the block leaves the DEX untouched
+#[rb_test(flashblocks, args = OpRbuilderArgs {
+    max_gas_per_txn: Some(21_100),
+    ..Default::default()
+})]
+async fn dex_transactions_respect_max_gas_per_txn(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = address!("0000000000000000000000000000000000000001");
+
+    // createPair costs more than the limit because of its calldata
+    let create_pair_tx = driver
+        .create_transaction()
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    let block = driver.build_new_block_with_current_timestamp(None).await?;
+    assert!(
+        !block.includes(create_pair_tx.tx_hash()),
+        "DEX transaction above the gas limit should not be included"
+    );
+
+    // The pair was never written to the predeploy, which would have given it a nonce
+    assert_eq!(
+        driver
+            .provider()
+            .get_transaction_count(DEX_PREDEPLOY_ADDRESS)
+            .await?,
+        0,
+        "Excluded DEX transaction should not change the DEX state"
+    );
 
     Ok(())
 }
 
+/// Calls to the predeploy through `eth_call` and `eth_estimateGas` see the DEX state and
+/// behave like the transactions the builder would execute
+#[rb_test(flashblocks)]
+async fn dex_calls_are_served_from_dex_state(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let trader = driver
+        .fund_accounts(1, 10_000_000_000_000_000_000u128)
+        .await?
+        .remove(0);
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+    let call = |input: Bytes| {
+        OpTransactionRequest::default()
+            .with_from(trader.address)
+            .with_to(DEX_PREDEPLOY_ADDRESS)
+            .with_input(input)
+    };
+
+    // The estimate matches the gas the builder charges
+    let estimate = provider
+        .estimate_gas(call(encode_create_pair(eth, usdc)))
+        .await?;
+    let create_pair_tx = driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+    let receipt = provider
+        .get_transaction_receipt(*create_pair_tx.tx_hash())
+        .await?
+        .expect("CreatePair receipt should exist");
+    assert!(receipt.status(), "CreatePair transaction should succeed");
+    assert_eq!(receipt.gas_used(), estimate);
+
+    // The pair exists now, so creating it again reverts
+    assert!(
+        provider
+            .call(call(encode_create_pair(eth, usdc)))
+            .await
+            .is_err()
+    );
+    assert!(
+        provider
+            .estimate_gas(call(encode_create_pair(eth, usdc)))
+            .await
+            .is_err()
+    );
+
+    let sell_order_tx = driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_place_limit_order(
+            eth,
+            usdc,
+            false,
+            U256::from(10u64.pow(18)),
+            U256::from(2000),
+            U256::from(1),
+        ))
+        .with_value(10u128.pow(18))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+    let receipt = provider
+        .get_transaction_receipt(*sell_order_tx.tx_hash())
+        .await?
+        .expect("Sell order receipt should exist");
+    assert!(receipt.status(), "Sell limit order should succeed");
+    let order_id = receipt.inner.logs()[0].topics()[1];
+
+    let order = OrderInfo::abi_decode(
+        &provider
+            .call(call(
+                [selectors::GET_ORDER.as_slice(), order_id.as_slice()]
+                    .concat()
+                    .into(),
+            ))
+            .await?,
+    )?;
+    assert_eq!(order.owner, trader.address);
+    assert!(!order.isBuy);
+    assert_eq!(order.remaining, U256::from(10u64.pow(18)));
+
+    let (amount_out, route) = <(U256, Vec<B256>)>::abi_decode_params(
+        &provider
+            .call(call(encode_get_quote(
+                usdc,
+                eth,
+                U256::from(100 * 10u64.pow(6)),
+            )))
+            .await?,
+    )?;
+    assert!(
+        !amount_out.is_zero(),
+        "Quote should be served from the book"
+    );
+    assert_eq!(route.len(), 1);
+
+    // Settlement is part of the call, the trader has no USDC to pay with
+    assert!(
+        provider
+            .call(call(encode_swap(
+                usdc,
+                eth,
+                U256::from(100 * 10u64.pow(6)),
+                U256::ZERO,
+            )))
+            .await
+            .is_err(),
+        "Unfunded swap should revert like it would in a block"
+    );
+
+    Ok(())
+}
+
+/// The `dex` namespace serves pairs, order books and orders of the latest block and of the
+/// latest flashblock
+#[rb_test(flashblocks)]
+async fn dex_namespace_serves_order_books(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let trader = driver
+        .fund_accounts(1, 10_000_000_000_000_000_000u128)
+        .await?
+        .remove(0);
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+
+    driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_place_limit_order(
+            eth,
+            usdc,
+            false,
+            U256::from(10u64.pow(18)),
+            U256::from(2000),
+            U256::from(1),
+        ))
+        .with_value(10u128.pow(18))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    for block in ["latest", "pending"] {
+        let pairs = provider
+            .raw_request::<_, Vec<RpcPair>>("dex_getPairs".into(), (block,))
+            .await?;
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].token0, pairs[0].token1), (eth, usdc));
+        let pair_id = pairs[0].pair_id;
+
+        let book = provider
+            .raw_request::<_, RpcOrderBook>("dex_getOrderBook".into(), (pair_id, 10, block))
+            .await?;
+        assert!(book.bids.is_empty());
+        assert_eq!(book.asks.len(), 1);
+        assert_eq!(book.asks[0].amount, U256::from(10u64.pow(18)));
+
+        let orders = provider
+            .raw_request::<_, Vec<RpcOrder>>("dex_getOpenOrders".into(), (trader.address, block))
+            .await?;
+        assert_eq!(orders.len(), 1);
+        let order = provider
+            .raw_request::<_, Option<RpcOrder>>("dex_getOrder".into(), (orders[0].order_id, block))
+            .await?;
+        assert_eq!(order.as_ref(), Some(&orders[0]));
+
+        let quote = provider
+            .raw_request::<_, RpcQuote>("dex_getQuote".into(), (usdc, eth, U256::from(2000), block))
+            .await?;
+        assert_eq!(quote.amount_out, U256::from(1));
+        assert_eq!(quote.route, vec![pair_id]);
+    }
+
+    Ok(())
+}
+
+/// A swap against a resting order settles both tokens within the transaction: the trader pays
+/// with an ERC-20, the maker is paid out of the predeploy and the trader receives ETH out of the
+/// maker's escrow
+#[rb_test(flashblocks)]
+async fn dex_swap_settles_tokens_and_escrow(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let mut accounts = driver
+        .fund_accounts(2, 10_000_000_000_000_000_000u128)
+        .await?;
+    let (maker, trader) = (accounts.remove(0), accounts.remove(0));
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+    let usdc_in = U256::from(2000 * 10u64.pow(6));
+    fund_test_token(&driver, usdc, trader, usdc_in).await?;
+
+    driver
+        .create_transaction()
+        .with_signer(maker)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    driver
+        .create_transaction()
+        .with_signer(maker)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_place_limit_order(
+            eth,
+            usdc,
+            false,
+            U256::from(10u64.pow(18)),
+            U256::from(2000),
+            U256::from(1),
+        ))
+        .with_value(10u128.pow(18))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    // 2000 USDC buy 10^6 wei at 2000 USDC per wei
+    let eth_out = U256::from(10u64.pow(6));
+    let balance_before = provider.get_balance(trader.address).await?;
+    let swap_tx = driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_swap(usdc, eth, usdc_in, eth_out))
+        .with_gas_limit(1_000_000)
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let receipt = provider
+        .get_transaction_receipt(*swap_tx.tx_hash())
+        .await?
+        .expect("Swap receipt should exist");
+    assert!(receipt.status(), "Funded swap should succeed");
+    assert!(!receipt.inner.logs().is_empty(), "Swap should emit events");
+
+    let fees = U256::from(receipt.gas_used()) * U256::from(receipt.effective_gas_price())
+        + U256::from(receipt.l1_block_info.l1_fee.unwrap_or_default());
+    assert_eq!(
+        provider.get_balance(trader.address).await?,
+        balance_before + eth_out - fees,
+        "The trader should receive the ETH bought"
+    );
+    assert_eq!(
+        token_balance(&driver, usdc, trader.address).await?,
+        U256::ZERO
+    );
+    assert_eq!(token_balance(&driver, usdc, maker.address).await?, usdc_in);
+
+    // The predeploy keeps the escrow of what is left of the order and none of the USDC
+    assert_eq!(
+        provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?,
+        U256::from(10u64.pow(18)) - eth_out
+    );
+    assert_eq!(
+        token_balance(&driver, usdc, DEX_PREDEPLOY_ADDRESS).await?,
+        U256::ZERO
+    );
+    let orders = provider
+        .raw_request::<_, Vec<RpcOrder>>("dex_getOpenOrders".into(), (maker.address, "latest"))
+        .await?;
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].remaining, U256::from(10u64.pow(18)) - eth_out);
+    assert_eq!(orders[0].escrow, U256::from(10u64.pow(18)) - eth_out);
+
+    Ok(())
+}
+
+/// Contracts call the DEX like any other contract, trading on their own behalf, and a revert of
+/// the DEX call reverts the calling contract's transaction
+#[rb_test(flashblocks)]
+async fn dex_can_be_called_by_contracts(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+
+    let deploy_tx = driver
+        .create_transaction()
+        .deploy_dex_forwarder()
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+    let forwarder = provider
+        .get_transaction_receipt(*deploy_tx.tx_hash())
+        .await?
+        .expect("forwarder deployment not mined")
+        .contract_address()
+        .expect("forwarder receipt does not contain a contract address");
+
+    driver
+        .create_transaction()
+        .with_to(forwarder)
+        .with_input(encode_create_pair(eth, usdc))
+        .with_gas_limit(500_000)
+        .send()
+        .await?;
+    let sell_order_tx = driver
+        .create_transaction()
+        .with_to(forwarder)
+        .with_input(encode_place_limit_order(
+            eth,
+            usdc,
+            false,
+            U256::from(10u64.pow(18)),
+            U256::from(2000),
+            U256::from(1),
+        ))
+        .with_value(10u128.pow(18))
+        .with_gas_limit(500_000)
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let receipt = provider
+        .get_transaction_receipt(*sell_order_tx.tx_hash())
+        .await?
+        .expect("Sell order receipt should exist");
+    assert!(
+        receipt.status(),
+        "Sell limit order through a contract should succeed"
+    );
+
+    // The order belongs to the contract, which paid the escrow out of the attached value
+    let orders = provider
+        .raw_request::<_, Vec<RpcOrder>>("dex_getOpenOrders".into(), (forwarder, "latest"))
+        .await?;
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].remaining, U256::from(10u64.pow(18)));
+    assert_eq!(
+        provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?,
+        U256::from(10u64.pow(18))
+    );
+    assert_eq!(provider.get_balance(forwarder).await?, U256::ZERO);
+
+    // The contract has no USDC to swap with, the failing DEX call reverts the whole transaction
+    let swap_tx = driver
+        .create_transaction()
+        .with_to(forwarder)
+        .with_input(encode_swap(usdc, eth, U256::from(2000), U256::ZERO))
+        .with_gas_limit(500_000)
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+    let receipt = provider
+        .get_transaction_receipt(*swap_tx.tx_hash())
+        .await?
+        .expect("Swap receipt should exist");
+    assert!(
+        !receipt.status(),
+        "Unfunded swap through a contract should revert"
+    );
+    assert_eq!(
+        provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?,
+        U256::from(10u64.pow(18)),
+        "Escrow should be untouched by the reverted swap"
+    );
+
+    Ok(())
+}
+
+/// Orders that expire are swept off the book by the builder at the start of the flashblock they
+/// expire at, and their escrow is refunded
+#[rb_test(flashblocks)]
+async fn dex_expired_orders_are_swept(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let trader = driver
+        .fund_accounts(1, 10_000_000_000_000_000_000u128)
+        .await?
+        .remove(0);
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+
+    // The order is placed in the next block and expires at the start of the one after
+    let expiry_block = provider.get_block_number().await? + 2;
+    let balance_before = provider.get_balance(trader.address).await?;
+    let create_pair_tx = driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    let sell_order_tx = driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_place_limit_order_with_expiry(
+            eth,
+            usdc,
+            false,
+            U256::from(10u64.pow(18)),
+            U256::from(2000),
+            U256::from(1),
+            expiry_block,
+            0,
+        ))
+        .with_value(10u128.pow(18))
+        .send()
+        .await?;
+    let block = driver.build_new_block_with_current_timestamp(None).await?;
+    assert_eq!(block.header.number + 1, expiry_block);
+
+    let mut fees = U256::ZERO;
+    for tx_hash in [create_pair_tx.tx_hash(), sell_order_tx.tx_hash()] {
+        let receipt = provider
+            .get_transaction_receipt(*tx_hash)
+            .await?
+            .expect("DEX receipt should exist");
+        assert!(receipt.status(), "DEX transaction should succeed");
+        fees += U256::from(receipt.gas_used()) * U256::from(receipt.effective_gas_price())
+            + U256::from(receipt.l1_block_info.l1_fee.unwrap_or_default());
+    }
+    let orders = provider
+        .raw_request::<_, Vec<RpcOrder>>("dex_getOpenOrders".into(), (trader.address, "latest"))
+        .await?;
+    assert_eq!(orders.len(), 1);
+
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let orders = provider
+        .raw_request::<_, Vec<RpcOrder>>("dex_getOpenOrders".into(), (trader.address, "latest"))
+        .await?;
+    assert!(orders.is_empty(), "Expired order should be swept");
+    assert_eq!(
+        provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?,
+        U256::ZERO
+    );
+    assert_eq!(
+        provider.get_balance(trader.address).await?,
+        balance_before - fees,
+        "The escrow of the swept order should be refunded"
+    );
+
+    Ok(())
+}
+
+/// In batch auction mode, crossing orders don't trade when they are placed but are cleared by
+/// the builder at the end of the flashblock
+#[rb_test(flashblocks, args = OpRbuilderArgs {
+    dex: DexArgs {
+        batch_auction: true,
+        ..Default::default()
+    },
+    ..Default::default()
+})]
+async fn dex_batch_auction_clears_crossing_orders(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let mut accounts = driver
+        .fund_accounts(2, 10_000_000_000_000_000_000u128)
+        .await?;
+    let (maker, buyer) = (accounts.remove(0), accounts.remove(0));
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+    let eth_amount = U256::from(10u64.pow(6));
+    let usdc_amount = eth_amount * U256::from(2000);
+    fund_test_token(&driver, usdc, buyer, usdc_amount).await?;
+
+    driver
+        .create_transaction()
+        .with_signer(maker)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let balance_before = provider.get_balance(buyer.address).await?;
+    driver
+        .create_transaction()
+        .with_signer(maker)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_place_limit_order(
+            eth,
+            usdc,
+            false,
+            eth_amount,
+            U256::from(2000),
+            U256::from(1),
+        ))
+        .with_value(10u128.pow(6))
+        .send()
+        .await?;
+    let buy_order_tx = driver
+        .create_transaction()
+        .with_signer(buyer)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_place_limit_order(
+            eth,
+            usdc,
+            true,
+            eth_amount,
+            U256::from(2000),
+            U256::from(1),
+        ))
+        .with_gas_limit(1_000_000)
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let receipt = provider
+        .get_transaction_receipt(*buy_order_tx.tx_hash())
+        .await?
+        .expect("Buy order receipt should exist");
+    assert!(receipt.status(), "Buy limit order should succeed");
+    let fees = U256::from(receipt.gas_used()) * U256::from(receipt.effective_gas_price())
+        + U256::from(receipt.l1_block_info.l1_fee.unwrap_or_default());
+
+    // Both orders were filled by the auction at the end of their flashblock
+    for owner in [maker.address, buyer.address] {
+        let orders = provider
+            .raw_request::<_, Vec<RpcOrder>>("dex_getOpenOrders".into(), (owner, "latest"))
+            .await?;
+        assert!(orders.is_empty(), "Crossing orders should be cleared");
+    }
+    assert_eq!(
+        provider.get_balance(buyer.address).await?,
+        balance_before + eth_amount - fees
+    );
+    assert_eq!(
+        token_balance(&driver, usdc, buyer.address).await?,
+        U256::ZERO
+    );
+    assert_eq!(
+        token_balance(&driver, usdc, maker.address).await?,
+        usdc_amount
+    );
+    assert_eq!(
+        provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?,
+        U256::ZERO
+    );
+    assert_eq!(
+        token_balance(&driver, usdc, DEX_PREDEPLOY_ADDRESS).await?,
+        U256::ZERO
+    );
+
+    Ok(())
+}
+
+/// Orders signed off-chain and submitted over RPC are placed by the builder, escrowing from the
+/// maker, who pays no gas
+#[rb_test(flashblocks)]
+async fn dex_signed_orders_are_placed_by_the_builder(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let maker = driver
+        .fund_accounts(1, 10_000_000_000_000_000_000u128)
+        .await?
+        .remove(0);
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+
+    driver
+        .create_transaction()
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let order = SignedOrder {
+        maker: maker.address,
+        tokenIn: eth,
+        tokenOut: usdc,
+        isBuy: false,
+        amount: U256::from(10u64.pow(18)),
+        priceNum: U256::from(2000),
+        priceDenom: U256::from(1),
+        expiryBlock: 0,
+        expiryFlashblockIndex: 0,
+        nonce: 1,
+    };
+    let domain = signed::domain(provider.get_chain_id().await?, DEX_PREDEPLOY_ADDRESS);
+    let signature = maker.sign_message(order.hash(&domain))?;
+    let hash = provider
+        .raw_request::<_, B256>(
+            "dex_submitSignedOrder".into(),
+            (RpcSignedOrder {
+                maker: maker.address,
+                token_in: eth,
+                token_out: usdc,
+                is_buy: false,
+                amount: order.amount,
+                price_num: order.priceNum,
+                price_denom: order.priceDenom,
+                expiry: None,
+                nonce: order.nonce,
+                signature: Bytes::copy_from_slice(&signature.as_bytes()),
+            },),
+        )
+        .await?;
+    assert_eq!(hash, order.hash(&domain));
+
+    let balance_before = provider.get_balance(maker.address).await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    let orders = provider
+        .raw_request::<_, Vec<RpcOrder>>("dex_getOpenOrders".into(), (maker.address, "latest"))
+        .await?;
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].remaining, U256::from(10u64.pow(18)));
+    let nonce = provider
+        .raw_request::<_, U64>("dex_getNonce".into(), (maker.address, "latest"))
+        .await?;
+    assert_eq!(nonce, U64::from(1));
+
+    // The maker only paid the escrow, the builder paid for the transaction
+    assert_eq!(
+        provider.get_balance(maker.address).await?,
+        balance_before - U256::from(10u64.pow(18))
+    );
+    assert_eq!(
+        provider.get_balance(DEX_PREDEPLOY_ADDRESS).await?,
+        U256::from(10u64.pow(18))
+    );
+    assert_eq!(provider.get_transaction_count(maker.address).await?, 0);
+
+    Ok(())
+}
+
+/// Rebuilding the DEX state from chain history, as the builder does on startup, reproduces the
+/// state the builder tracked, including blocks whose DEX calls left no event behind
+#[rb_test(flashblocks)]
+async fn dex_state_is_rebuilt_from_chain_history(rbuilder: LocalInstance) -> eyre::Result<()> {
+    let driver = rbuilder.driver().await?;
+    let provider = driver.provider();
+    let trader = driver
+        .fund_accounts(1, 10_000_000_000_000_000_000u128)
+        .await?
+        .remove(0);
+
+    let eth = address!("0000000000000000000000000000000000000000");
+    let usdc = deploy_test_token(&driver).await?;
+
+    driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_create_pair(eth, usdc))
+        .send()
+        .await?;
+    driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_place_limit_order(
+            eth,
+            usdc,
+            false,
+            U256::from(10u64.pow(18)),
+            U256::from(2000),
+            U256::from(1),
+        ))
+        .with_value(10u128.pow(18))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+
+    // A quote emits no event, but still moves the DEX on to the block it is executed in
+    let quote_tx = driver
+        .create_transaction()
+        .with_signer(trader)
+        .with_to(DEX_PREDEPLOY_ADDRESS)
+        .with_input(encode_get_quote(usdc, eth, U256::from(2000)))
+        .send()
+        .await?;
+    let block = driver.build_new_block_with_current_timestamp(None).await?;
+    let receipt = provider
+        .get_transaction_receipt(*quote_tx.tx_hash())
+        .await?
+        .expect("getQuote receipt should exist");
+    assert!(receipt.status(), "getQuote transaction should succeed");
+    assert!(receipt.inner.logs().is_empty());
+
+    let tracked = rbuilder
+        .dex_state_at(block.header.hash)
+        .expect("DEX state of the block should be known")
+        .records();
+    assert_eq!(tracked.block_number, block.header.number);
+    assert_eq!(tracked.orders.len(), 1);
+    assert_eq!(rbuilder.rebuild_dex_state()?.records(), tracked);
+
+    Ok(())
+}
+
+/// Deploys a test ERC-20 and returns its address, since the DEX only lists tokens that are
+/// contracts
+async fn deploy_test_token(driver: &ChainDriver<Ipc>) -> eyre::Result<Address> {
+    let deploy_tx = driver
+        .create_transaction()
+        .deploy_test_token()
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+    let receipt = driver
+        .provider()
+        .get_transaction_receipt(*deploy_tx.tx_hash())
+        .await?
+        .expect("test token deployment not mined");
+    Ok(receipt
+        .contract_address()
+        .expect("test token receipt does not contain a contract address"))
+}
+
+/// Mints `amount` of the test token at `token` to `account` and approves the DEX to spend it
+async fn fund_test_token(
+    driver: &ChainDriver<Ipc>,
+    token: Address,
+    account: Signer,
+    amount: U256,
+) -> eyre::Result<()> {
+    driver
+        .create_transaction()
+        .with_to(token)
+        .with_input(encode_token_call(MINT, account.address, amount))
+        .send()
+        .await?;
+    driver
+        .create_transaction()
+        .with_signer(account)
+        .with_to(token)
+        .with_input(encode_token_call(APPROVE, DEX_PREDEPLOY_ADDRESS, amount))
+        .send()
+        .await?;
+    driver.build_new_block_with_current_timestamp(None).await?;
+    assert_eq!(token_balance(driver, token, account.address).await?, amount);
+    Ok(())
+}
+
+/// Returns the balance of `owner` in the test token at `token`
+async fn token_balance(
+    driver: &ChainDriver<Ipc>,
+    token: Address,
+    owner: Address,
+) -> eyre::Result<U256> {
+    let input = [BALANCE_OF.as_slice(), &owner.abi_encode()].concat();
+    let output = driver
+        .provider()
+        .call(
+            OpTransactionRequest::default()
+                .with_to(token)
+                .with_input(Bytes::from(input)),
+        )
+        .await?;
+    Ok(U256::abi_decode(&output)?)
+}
+
 // ============================================================================
 // Helper functions to encode calldata
 // ============================================================================
@@ -225,6 +1115,33 @@ fn encode_place_limit_order(
         .into()
 }
 
+#[allow(clippy::too_many_arguments)]
+fn encode_place_limit_order_with_expiry(
+    token_in: Address,
+    token_out: Address,
+    is_buy: bool,
+    amount: U256,
+    price_num: U256,
+    price_denom: U256,
+    expiry_block: u64,
+    expiry_flashblock_index: u64,
+) -> Bytes {
+    let params = (
+        token_in,
+        token_out,
+        is_buy,
+        amount,
+        price_num,
+        price_denom,
+        expiry_block,
+        expiry_flashblock_index,
+    )
+        .abi_encode();
+    [selectors::PLACE_LIMIT_ORDER_WITH_EXPIRY.as_slice(), &params]
+        .concat()
+        .into()
+}
+
 fn encode_swap(
     token_in: Address,
     token_out: Address,
@@ -239,3 +1156,14 @@ fn encode_get_quote(token_in: Address, token_out: Address, amount_in: U256) -> B
     let params = (token_in, token_out, amount_in).abi_encode();
     [selectors::GET_QUOTE.as_slice(), &params].concat().into()
 }
+
+/// Selectors of the test token's mint(address,uint256), approve(address,uint256) and
+/// balanceOf(address)
+const MINT: [u8; 4] = hex!("40c10f19");
+const APPROVE: [u8; 4] = hex!("095ea7b3");
+const BALANCE_OF: [u8; 4] = hex!("70a08231");
+
+fn encode_token_call(selector: [u8; 4], account: Address, amount: U256) -> Bytes {
+    let params = (account, amount).abi_encode();
+    [selector.as_slice(), &params].concat().into()
+}
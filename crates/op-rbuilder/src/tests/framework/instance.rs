@@ -1,6 +1,14 @@
 use crate::{
     args::OpRbuilderArgs,
-    builders::{BuilderConfig, FlashblocksBuilder, PayloadBuilder, StandardBuilder},
+    builders::{
+        BuilderConfig, FlashblocksBuilder, PayloadBuilder, StandardBuilder,
+        flashblocks::dex_recovery::rebuild_dex_state,
+    },
+    dex::{
+        DexJournal, DexState,
+        api::{DexApiExt, DexApiServer},
+        rpc::{DexDebugApiServer, DexEthApiServer, DexRpcExt},
+    },
     primitives::reth::engine_api_builder::OpEngineApiBuilder,
     revert_protection::{EthApiExtServer, RevertProtectionExt},
     tests::{
@@ -33,6 +41,7 @@ use parking_lot::Mutex;
 use reth::{
     args::{DatadirArgs, NetworkArgs, RpcServerArgs},
     core::exit::NodeExitFuture,
+    rpc::builder::RethRpcModule,
     tasks::TaskManager,
 };
 use reth_node_builder::{NodeBuilder, NodeConfig};
@@ -47,12 +56,15 @@ use reth_transaction_pool::{AllTransactionsEvents, TransactionPool};
 use rollup_boost::FlashblocksPayloadV1;
 use std::{
     net::SocketAddr,
-    sync::{Arc, LazyLock},
+    sync::{Arc, LazyLock, OnceLock},
 };
 use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 use tokio_util::sync::CancellationToken;
 
+/// Rebuilds the DEX state of a journal from the chain of the node, see [`rebuild_dex_state`].
+type DexRebuild = Box<dyn Fn(&DexJournal) -> eyre::Result<()> + Send + Sync>;
+
 /// Represents a type that emulates a local in-process instance of the OP builder node.
 /// This node uses IPC as the communication channel for the RPC server Engine API.
 pub struct LocalInstance {
@@ -64,6 +76,8 @@ pub struct LocalInstance {
     _node_handle: Box<dyn Any + Send>,
     pool_observer: TransactionPoolObserver,
     attestation_server: Option<AttestationServer>,
+    dex_journal: Arc<DexJournal>,
+    dex_rebuild: Arc<OnceLock<DexRebuild>>,
 }
 
 impl LocalInstance {
@@ -112,6 +126,10 @@ impl LocalInstance {
             .expect("Failed to convert rollup args to builder config");
         let da_config = builder_config.da_config.clone();
         let gas_limit_config = builder_config.gas_limit_config.clone();
+        let dex_journal = builder_config.dex_journal.clone();
+        let dex_rebuild: Arc<OnceLock<DexRebuild>> = Arc::default();
+        let rpc_dex_journal = dex_journal.clone();
+        let rpc_dex_rebuild = dex_rebuild.clone();
 
         let addons: OpAddOns<
             _,
@@ -153,6 +171,33 @@ impl LocalInstance {
                         .add_or_replace_configured(revert_protection_ext.into_rpc())?;
                 }
 
+                if args.flashblocks.enabled && !args.dex.disabled {
+                    let dex_api_ext =
+                        DexApiExt::new(ctx.provider().clone(), rpc_dex_journal.clone());
+                    ctx.modules.merge_configured(dex_api_ext.into_rpc())?;
+
+                    let dex_rpc_ext = DexRpcExt::new(
+                        ctx.provider().clone(),
+                        ctx.registry.eth_api().clone(),
+                        ctx.registry.debug_api(),
+                        ctx.node().evm_config().clone(),
+                        rpc_dex_journal,
+                    );
+
+                    ctx.modules.add_or_replace_if_module_configured(
+                        RethRpcModule::Debug,
+                        DexDebugApiServer::into_rpc(dex_rpc_ext.clone()),
+                    )?;
+                    ctx.modules
+                        .add_or_replace_configured(DexEthApiServer::into_rpc(dex_rpc_ext))?;
+
+                    let provider = ctx.provider().clone();
+                    let evm_config = ctx.node().evm_config().clone();
+                    let _ = rpc_dex_rebuild.set(Box::new(move |journal: &DexJournal| {
+                        rebuild_dex_state(&provider, &evm_config, journal)
+                    }));
+                }
+
                 Ok(())
             })
             .on_rpc_started(move |_, _| {
@@ -187,6 +232,8 @@ impl LocalInstance {
             task_manager: Some(task_manager),
             pool_observer: TransactionPoolObserver::new(pool_monitor, reverted_cache_clone),
             attestation_server,
+            dex_journal,
+            dex_rebuild,
         })
     }
 
@@ -271,6 +318,22 @@ impl LocalInstance {
         ChainDriver::<Ipc>::local(self).await
     }
 
+    /// Returns the DEX state after the given block as the builder tracked it, if it is known.
+    pub fn dex_state_at(&self, block_hash: B256) -> Option<DexState> {
+        self.dex_journal.state_at(block_hash)
+    }
+
+    /// Rebuilds the DEX state of the canonical head from chain history, the way the builder does
+    /// on startup, and returns it.
+    pub fn rebuild_dex_state(&self) -> eyre::Result<DexState> {
+        let rebuild = self
+            .dex_rebuild
+            .get()
+            .ok_or_else(|| eyre::eyre!("the DEX is not enabled"))?;
+        rebuild(&self.dex_journal)?;
+        Ok(self.dex_journal.canonical_state().1)
+    }
+
     pub async fn provider(&self) -> eyre::Result<RootProvider<Optimism>> {
         ProviderBuilder::<Identity, Identity, Optimism>::default()
             .connect_ipc(self.rpc_ipc().to_string().into())
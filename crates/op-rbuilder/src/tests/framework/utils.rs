@@ -42,6 +42,9 @@ pub trait TransactionBuilderExt {
     fn add_workload_to_policy(self) -> Self;
     fn deploy_mock_dcap_contract(self) -> Self;
     fn add_mock_quote(self) -> Self;
+    // dex methods
+    fn deploy_test_token(self) -> Self;
+    fn deploy_dex_forwarder(self) -> Self;
 }
 
 impl TransactionBuilderExt for TransactionBuilder {
@@ -168,6 +171,26 @@ impl TransactionBuilderExt for TransactionBuilder {
             .with_gas_limit(500_000)
             .with_signer(flashtestations_signer())
     }
+
+    // A minimal ERC-20 without events that anyone can mint to, supporting
+    // transfer, transferFrom, approve, balanceOf, allowance and
+    // mint(address,uint256). Balances live at the slot of the holder's address.
+    fn deploy_test_token(self) -> Self {
+        self.with_create().with_input(
+            hex!("6101018061000d6000396000f360003560e01c8063a9059cbb1461009f57806323b872dd146100ae578063095ea7b31461008757806370a082311461004d578063dd62ed3e1461005a57806340c10f1914610075575b600080fd5b6004355460005260206000f35b60043560005260243560205260406000205460005260206000f35b602435600435805482019055506100f6565b336000526004356020526024356040600020556100f6565b6100f6602435600435336100de565b600435600052336020526040600020805460443581811161004857900390556100f66044356024356004356100de565b80548381811161004857900381555080548201905550565b600160005260206000f3")
+                .into(),
+        )
+    }
+
+    // A contract forwarding its calldata and value to the DEX predeploy at
+    // 0x4200000000000000000000000000000000000042, returning what the DEX returns
+    // and bubbling up its reverts.
+    fn deploy_dex_forwarder(self) -> Self {
+        self.with_create().with_input(
+            hex!("603780600b6000396000f336600060003760006000366000347342000000000000000000000000000000000000425af13d600060003e6032573d6000fd5b3d6000f3")
+                .into(),
+        )
+    }
 }
 
 pub trait ChainDriverExt {
@@ -6,7 +6,11 @@ use k256::sha2::Sha256;
 use op_alloy_consensus::OpTypedTransaction;
 use reth_optimism_primitives::OpTransactionSigned;
 use reth_primitives::Recovered;
-use secp256k1::{Message, PublicKey, SECP256K1, Secp256k1, SecretKey, rand::rngs::OsRng};
+use secp256k1::{
+    Message, PublicKey, SECP256K1, Secp256k1, SecretKey,
+    ecdsa::{RecoverableSignature, RecoveryId},
+    rand::rngs::OsRng,
+};
 use sha3::{Digest, Keccak256};
 
 /// Simple struct to sign txs/messages.
@@ -93,6 +97,17 @@ pub fn generate_signer() -> Signer {
     }
 }
 
+/// Recovers the address whose key signed `message` with `signature`
+pub fn recover_signer(message: B256, signature: &Signature) -> Result<Address, secp256k1::Error> {
+    let mut data = [0u8; 64];
+    data[..32].copy_from_slice(&signature.r().to_be_bytes::<32>());
+    data[32..].copy_from_slice(&signature.s().to_be_bytes::<32>());
+    let signature =
+        RecoverableSignature::from_compact(&data, RecoveryId::try_from(signature.v() as i32)?)?;
+    let pubkey = SECP256K1.recover_ecdsa(&Message::from_digest_slice(&message[..])?, &signature)?;
+    Ok(public_key_to_address(&pubkey))
+}
+
 /// Converts a public key to an Ethereum address
 pub fn public_key_to_address(public_key: &PublicKey) -> Address {
     // Get uncompressed public key (65 bytes: 0x04 + 64 bytes)
@@ -157,6 +172,21 @@ mod test {
         assert_eq!(signed.recover_signer().ok(), Some(address));
     }
 
+    #[test]
+    fn test_recover_signer() {
+        let signer = Signer::random();
+        let message = B256::random();
+        let signature = signer.sign_message(message).expect("sign message");
+        assert_eq!(
+            recover_signer(message, &signature).ok(),
+            Some(signer.address)
+        );
+        assert_ne!(
+            recover_signer(B256::random(), &signature).ok(),
+            Some(signer.address)
+        );
+    }
+
     #[test]
     fn test_public_key_format() {
         let secp = Secp256k1::new();